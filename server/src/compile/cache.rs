@@ -0,0 +1,229 @@
+//! A bounded in-memory cache of rendered output, keyed by the compile's
+//! inputs, and the byte-bounded [`LruCache`] it and the other compile caches
+//! are built on.
+//!
+//! The key is derived from content hashes alone (see [`inputs_key`]), so an
+//! entry can never go stale: any edit changes the key instead. Old entries
+//! just stop being asked for, and are evicted least recently used first once
+//! the cache outgrows its byte budget.
+
+use std::borrow::Borrow;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use sha2::{Digest, Sha256};
+
+use super::TYPST_VERSION;
+
+/// A key for everything a compile reads: the Typst version, the entry, each
+/// file's path and content hash (a binary's storage key, a text file's
+/// SHA-256), the storage keys of the fonts besides the bundled ones, and the
+/// specs of the packages it resolved (a package version never changes).
+/// Equal keys compile to the same document.
+pub fn inputs_key<'a>(
+    entry: &str,
+    files: impl IntoIterator<Item = (&'a str, &'a str)>,
+    fonts: impl IntoIterator<Item = &'a str>,
+    packages: impl IntoIterator<Item = &'a str>,
+) -> String {
+    let mut files: Vec<_> = files.into_iter().collect();
+    files.sort_unstable();
+    let mut fonts: Vec<_> = fonts.into_iter().collect();
+    fonts.sort_unstable();
+    let mut packages: Vec<_> = packages.into_iter().collect();
+    packages.sort_unstable();
+    let mut hasher = Sha256::new();
+    hasher.update(TYPST_VERSION.to_string());
+    hasher.update([0]);
+    hasher.update(entry);
+    for (path, hash) in files {
+        hasher.update([0]);
+        hasher.update(path);
+        hasher.update([0]);
+        hasher.update(hash);
+    }
+    // A different separator, so a font can't pass for a file.
+    for font in fonts {
+        hasher.update([1]);
+        hasher.update(font);
+    }
+    for package in packages {
+        hasher.update([2]);
+        hasher.update(package);
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// A map bounded by the total size of its values, shared between threads.
+/// Past its byte budget, the least recently used entries are evicted. Values
+/// are handed out cloned, so they should be cheap to clone.
+pub struct LruCache<K, V> {
+    max_bytes: usize,
+    state: Mutex<LruState<K, V>>,
+}
+
+struct LruState<K, V> {
+    /// Each value, with its size.
+    entries: HashMap<K, (V, usize)>,
+    /// Keys from least to most recently used.
+    order: VecDeque<K>,
+    bytes: usize,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            state: Mutex::new(LruState {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+                bytes: 0,
+            }),
+        }
+    }
+
+    /// The value kept for `key`, now the most recently used.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut state = self.state.lock().unwrap();
+        let value = state.entries.get(key)?.0.clone();
+        if let Some(i) = state.order.iter().position(|used| used.borrow() == key) {
+            let used = state.order.remove(i).expect("a position in range");
+            state.order.push_back(used);
+        }
+        Some(value)
+    }
+
+    /// Keep `value`, of `size` bytes, evicting the least recently used to
+    /// make room. A value larger than the whole budget isn't kept, and one
+    /// already kept isn't replaced.
+    pub fn insert(&self, key: K, value: V, size: usize) {
+        if size > self.max_bytes {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.entries.contains_key(&key) {
+            return;
+        }
+        while state.bytes + size > self.max_bytes {
+            let Some(oldest) = state.order.pop_front() else {
+                break;
+            };
+            if let Some((_, evicted)) = state.entries.remove(&oldest) {
+                state.bytes -= evicted;
+            }
+        }
+        state.bytes += size;
+        state.order.push_back(key.clone());
+        state.entries.insert(key, (value, size));
+    }
+
+    /// The total size of the values kept.
+    pub fn bytes(&self) -> usize {
+        self.state.lock().unwrap().bytes
+    }
+}
+
+pub struct RenderCache(LruCache<String, Arc<[u8]>>);
+
+impl RenderCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self(LruCache::new(max_bytes))
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<[u8]>> {
+        self.0.get(key)
+    }
+
+    /// Cache `value` (see [`LruCache::insert`]).
+    pub fn insert(&self, key: String, value: Arc<[u8]>) {
+        let size = value.len();
+        self.0.insert(key, value, size);
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inputs_key_ignores_order_but_not_content() {
+        let key = inputs_key("main.typ", [("main.typ", "a"), ("b.typ", "b")], [], []);
+        assert_eq!(
+            key,
+            inputs_key("main.typ", [("b.typ", "b"), ("main.typ", "a")], [], [])
+        );
+        assert_ne!(
+            key,
+            inputs_key("main.typ", [("main.typ", "a"), ("b.typ", "c")], [], [])
+        );
+        assert_ne!(
+            key,
+            inputs_key("b.typ", [("main.typ", "a"), ("b.typ", "b")], [], [])
+        );
+        assert_ne!(
+            key,
+            inputs_key("main.typ", [("main.typ", "a"), ("c.typ", "b")], [], [])
+        );
+        assert_ne!(
+            key,
+            inputs_key("main.typ", [("main.typ", "a"), ("b.typ", "b")], ["f"], [])
+        );
+        assert_ne!(
+            key,
+            inputs_key(
+                "main.typ",
+                [("main.typ", "a"), ("b.typ", "b")],
+                [],
+                ["@preview/cetz:0.2.2"]
+            )
+        );
+        // A package can't pass for a font.
+        assert_ne!(
+            inputs_key("main.typ", [], ["f"], []),
+            inputs_key("main.typ", [], [], ["f"])
+        );
+    }
+
+    #[test]
+    fn test_lru_cache_evicts_least_recently_used_past_its_budget() {
+        let cache = LruCache::new(4);
+        cache.insert("a".to_string(), 1, 2);
+        cache.insert("b".to_string(), 2, 2);
+        assert_eq!(cache.get("a"), Some(1));
+
+        // `b` is now the least recently used, so it makes room.
+        cache.insert("c".to_string(), 3, 1);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!((cache.get("a"), cache.get("c")), (Some(1), Some(3)));
+        assert_eq!(cache.bytes(), 3);
+
+        // Kept values aren't replaced, and ones over the budget aren't kept.
+        cache.insert("a".to_string(), 4, 1);
+        assert_eq!(cache.get("a"), Some(1));
+        cache.insert("huge".to_string(), 5, 5);
+        assert_eq!(cache.get("huge"), None);
+        assert_eq!(cache.bytes(), 3);
+    }
+
+    #[test]
+    fn test_render_cache_evicts_least_recently_used_past_its_budget() {
+        let cache = RenderCache::new(4);
+        cache.insert("a".to_string(), Arc::from(&b"12"[..]));
+        cache.insert("b".to_string(), Arc::from(&b"34"[..]));
+        assert_eq!(cache.get("a").as_deref(), Some(&b"12"[..]));
+
+        cache.insert("c".to_string(), Arc::from(&b"5"[..]));
+        assert_eq!(cache.get("b"), None);
+        assert!(cache.get("a").is_some() && cache.get("c").is_some());
+
+        cache.insert("huge".to_string(), Arc::from(&b"12345"[..]));
+        assert_eq!(cache.get("huge"), None);
+        assert!(cache.get("a").is_some());
+    }
+}
@@ -0,0 +1,83 @@
+//! Typst diagnostics in a form the editor can place.
+//!
+//! A span is resolved to the `ProjectFile` it falls in, by id rather than
+//! path, so a squiggle stays attached to its file across a rename. Positions
+//! are zero-based lines and UTF-16 columns, the units of JavaScript strings
+//! and LSP.
+
+use bson::oid::ObjectId;
+use bson::serde_helpers::serialize_object_id_as_hex_string;
+use serde::{Deserialize, Serialize};
+use typst::diag::{self, SourceDiagnostic};
+use typst::syntax::{Lines, Span};
+use typst::{World, WorldExt};
+
+use super::ProjectWorld;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiagnosticSpan {
+    #[serde(serialize_with = "serialize_object_id_as_hex_string")]
+    pub file_id: ObjectId,
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub hints: Vec<String>,
+    /// Where in the project the problem is. `None` when Typst gave no
+    /// location, or the location is outside the project (e.g. in a package).
+    pub span: Option<DiagnosticSpan>,
+}
+
+impl Diagnostic {
+    pub fn new(world: &ProjectWorld, diagnostic: &SourceDiagnostic) -> Self {
+        Self {
+            severity: match diagnostic.severity {
+                diag::Severity::Error => Severity::Error,
+                diag::Severity::Warning => Severity::Warning,
+            },
+            message: diagnostic.message.to_string(),
+            hints: diagnostic
+                .hints
+                .iter()
+                .map(|hint| hint.to_string())
+                .collect(),
+            span: resolve(world, diagnostic.span),
+        }
+    }
+}
+
+fn resolve(world: &ProjectWorld, span: Span) -> Option<DiagnosticSpan> {
+    let id = span.id()?;
+    let file_id = world.project_file(id)?;
+    let range = world.range(span)?;
+    let source = world.source(id).ok()?;
+    let lines = source.lines();
+    Some(DiagnosticSpan {
+        file_id,
+        start: position(lines, range.start)?,
+        end: position(lines, range.end)?,
+    })
+}
+
+pub(super) fn position(lines: &Lines<String>, byte: usize) -> Option<Position> {
+    let line = lines.byte_to_line(byte)?;
+    let column = lines.byte_to_utf16(byte)? - lines.byte_to_utf16(lines.line_to_byte(line)?)?;
+    Some(Position { line, column })
+}
@@ -0,0 +1,61 @@
+//! Parsed team fonts, shared by every compilation.
+//!
+//! A font file is parsed into its faces once and kept by storage key. The key
+//! is the file's content hash, so a kept entry is never stale. The least
+//! recently used files are dropped once the cache outgrows its byte budget.
+
+use typst::text::Font;
+
+use super::cache::LruCache;
+use crate::config::CompileConfig;
+
+pub struct FontCache(LruCache<String, Vec<Font>>);
+
+impl Default for FontCache {
+    fn default() -> Self {
+        Self::new(CompileConfig::default().font_cache_bytes)
+    }
+}
+
+impl FontCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self(LruCache::new(max_bytes))
+    }
+
+    /// The faces of the font file at `storage_key`, if it was parsed before.
+    pub fn get(&self, storage_key: &str) -> Option<Vec<Font>> {
+        self.0.get(storage_key)
+    }
+
+    /// Keep the faces parsed from a font file of `size` bytes (see
+    /// [`LruCache::insert`]).
+    pub fn insert(&self, storage_key: String, faces: Vec<Font>, size: usize) {
+        self.0.insert(storage_key, faces, size);
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use typst::foundations::Bytes;
+
+    #[test]
+    fn test_font_cache_evicts_least_recently_used_past_its_budget() {
+        let font = typst_assets::fonts().next().unwrap();
+        let faces: Vec<Font> = Font::iter(Bytes::new(font)).collect();
+        let cache = FontCache::new(4);
+        cache.insert("a".to_string(), faces.clone(), 2);
+        cache.insert("b".to_string(), faces.clone(), 2);
+        assert_eq!(cache.get("a").map(|faces| faces.len()), Some(faces.len()));
+
+        // `b` is now the least recently used, so it makes room.
+        cache.insert("c".to_string(), faces.clone(), 1);
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some() && cache.get("c").is_some());
+
+        cache.insert("huge".to_string(), faces, 5);
+        assert!(cache.get("huge").is_none());
+        assert!(cache.get("a").is_some());
+    }
+}
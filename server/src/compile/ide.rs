@@ -0,0 +1,521 @@
+//! Language analysis over a [`ProjectWorld`]: what the editor asks for while
+//! typing, answered by `typst-ide`.
+//!
+//! Requests locate the cursor by `ProjectFile` id and a [`Position`] (zero-based
+//! line, UTF-16 column), and answers locate things the same way, like
+//! diagnostics. Nothing is compiled: `typst-ide` evaluates just what it needs,
+//! and without a laid-out document it doesn't complete or resolve labels from
+//! it. Renaming is done here, since `typst-ide` has no rename: a label is
+//! renamed everywhere it's defined or referenced, an identifier wherever it
+//! resolves to the same definition.
+
+use bson::oid::ObjectId;
+use bson::serde_helpers::serialize_object_id_as_hex_string;
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+use typst::syntax::ast::{self, AstNode};
+use typst::syntax::{LinkedNode, Side, Source, Span, SyntaxKind, is_ident};
+use typst::{World, WorldExt};
+use typst_ide::{Completion, Definition, Tooltip};
+
+use super::ProjectWorld;
+use super::diagnostic::{DiagnosticSpan, Position};
+
+/// A request, by `method`, with its `params`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "lowercase")]
+pub enum IdeRequest {
+    Completion {
+        file_id: ObjectId,
+        position: Position,
+        /// The user asked for completions (e.g. Ctrl+Space), rather than
+        /// them popping up while typing.
+        #[serde(default)]
+        explicit: bool,
+    },
+    Hover {
+        file_id: ObjectId,
+        position: Position,
+    },
+    Definition {
+        file_id: ObjectId,
+        position: Position,
+    },
+    Rename {
+        file_id: ObjectId,
+        position: Position,
+        new_name: String,
+    },
+}
+
+#[derive(Debug, Display, PartialEq, Eq)]
+pub enum IdeError {
+    #[display("File not found")]
+    FileNotFound,
+    #[display("Position {}:{} is outside the file", _0.line, _0.column)]
+    InvalidPosition(Position),
+    #[display("Nothing to rename here")]
+    NothingToRename,
+    #[display("Standard library items can't be renamed")]
+    StdItem,
+    #[display("Invalid name: {_0}")]
+    InvalidName(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct Completions {
+    /// Where the completed text starts; it runs to the cursor.
+    pub from: Position,
+    pub items: Vec<Completion>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "lowercase")]
+pub enum Hover {
+    Text(String),
+    /// Typst code, to be shown highlighted.
+    Code(String),
+}
+
+/// A change to a project file: the text between `start` and `end` becomes
+/// `new_text`.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct TextEdit {
+    #[serde(serialize_with = "serialize_object_id_as_hex_string")]
+    pub file_id: ObjectId,
+    pub start: Position,
+    pub end: Position,
+    pub new_text: String,
+}
+
+/// Answer `request` as JSON: [`Completions`], a [`Hover`], the definition's
+/// [`DiagnosticSpan`] or a list of [`TextEdit`]s. An empty answer (nothing to
+/// complete, a definition in the standard library) is `null`.
+pub fn respond(world: &ProjectWorld, request: IdeRequest) -> Result<serde_json::Value, IdeError> {
+    let value = match request {
+        IdeRequest::Completion {
+            file_id,
+            position,
+            explicit,
+        } => {
+            let (source, cursor) = locate(world, file_id, position)?;
+            typst_ide::autocomplete(world, None, &source, cursor, explicit).and_then(
+                |(from, items)| {
+                    let from = to_position(&source, from)?;
+                    Some(serde_json::json!(Completions { from, items }))
+                },
+            )
+        }
+        IdeRequest::Hover { file_id, position } => {
+            let (source, cursor) = locate(world, file_id, position)?;
+            typst_ide::tooltip(world, None, &source, cursor, Side::After).map(|tooltip| {
+                serde_json::json!(match tooltip {
+                    Tooltip::Text(text) => Hover::Text(text.into()),
+                    Tooltip::Code(code) => Hover::Code(code.into()),
+                })
+            })
+        }
+        IdeRequest::Definition { file_id, position } => {
+            let (source, cursor) = locate(world, file_id, position)?;
+            match typst_ide::definition(world, None, &source, cursor, Side::After) {
+                Some(Definition::Span(span)) => {
+                    resolve(world, span).map(|span| serde_json::json!(span))
+                }
+                _ => None,
+            }
+        }
+        IdeRequest::Rename {
+            file_id,
+            position,
+            new_name,
+        } => {
+            let (source, cursor) = locate(world, file_id, position)?;
+            Some(serde_json::json!(rename(
+                world, &source, cursor, &new_name
+            )?))
+        }
+    };
+    Ok(value.unwrap_or(serde_json::Value::Null))
+}
+
+/// The edits that rename the label or identifier at `cursor` to `new_name`.
+fn rename(
+    world: &ProjectWorld,
+    source: &Source,
+    cursor: usize,
+    new_name: &str,
+) -> Result<Vec<TextEdit>, IdeError> {
+    let root = LinkedNode::new(source.root());
+    let leaf = [Side::After, Side::Before]
+        .into_iter()
+        .filter_map(|side| root.leaf_at(cursor, side))
+        .find(|leaf| {
+            matches!(
+                leaf.kind(),
+                SyntaxKind::Label
+                    | SyntaxKind::RefMarker
+                    | SyntaxKind::Ident
+                    | SyntaxKind::MathIdent
+            )
+        })
+        .ok_or(IdeError::NothingToRename)?;
+
+    let mut edits = Vec::new();
+    if let Some(name) = label_name(&leaf) {
+        if !typst::syntax::is_valid_label_literal_id(new_name) {
+            return Err(IdeError::InvalidName(new_name.to_string()));
+        }
+        each_leaf(world, |source, node| {
+            if label_name(node) == Some(name) {
+                // Past the `<` or `@`, and short of a label's `>`.
+                let start = node.offset() + 1;
+                let end = start + name.len();
+                edits.extend(edit(world, source, start..end, new_name));
+            }
+        });
+        return Ok(edits);
+    }
+
+    if !is_ident(new_name) {
+        return Err(IdeError::InvalidName(new_name.to_string()));
+    }
+    let target = resolve_ident(world, source, &leaf)?;
+    // The definition and every use resolving to it, including imports of it
+    // in other files.
+    let name = leaf.text().as_str();
+    each_leaf(world, |source, node| {
+        if matches!(node.kind(), SyntaxKind::Ident | SyntaxKind::MathIdent)
+            && node.text() == name
+            && resolve_ident(world, source, node) == Ok(target)
+        {
+            edits.extend(edit(world, source, node.range(), new_name));
+        }
+    });
+    Ok(edits)
+}
+
+/// The binding an identifier stands for: the span of the name it was bound
+/// to in the project. An imported name is followed to the file it comes from.
+fn resolve_ident(
+    world: &ProjectWorld,
+    source: &Source,
+    ident: &LinkedNode,
+) -> Result<Span, IdeError> {
+    let name = ident.text().as_str();
+    if is_binding(ident) || ident.parent_kind() == Some(SyntaxKind::RenamedImportItem) {
+        return Ok(ident.span());
+    }
+    let span = if ident.parent_kind() == Some(SyntaxKind::ImportItemPath) {
+        ident.span()
+    } else {
+        match typst_ide::definition(world, None, source, ident.offset(), Side::After) {
+            Some(Definition::Span(span)) => span,
+            Some(Definition::Std(_)) => return Err(IdeError::StdItem),
+            None => return Err(IdeError::NothingToRename),
+        }
+    };
+    binding(world, span, name, 0).ok_or(IdeError::NothingToRename)
+}
+
+/// Follow a definition at `span` through imports to where `name` is bound;
+/// `None` if that's outside the project.
+fn binding(world: &ProjectWorld, span: Span, name: &str, depth: usize) -> Option<Span> {
+    let id = span.id()?;
+    world.project_file(id)?;
+    let source = world.source(id).ok()?;
+    let node = source.find(span)?;
+    // An import of `name`: a named item, or `*`.
+    let import = std::iter::successors(Some(node.clone()), |node| node.parent().cloned())
+        .take_while(|node| node.kind() != SyntaxKind::RenamedImportItem)
+        .find_map(|node| {
+            let import = node.cast::<ast::ModuleImport>()?;
+            Some(match import.source() {
+                ast::Expr::Str(path) => Some(path.get()),
+                _ => None,
+            })
+        });
+    let Some(path) = import else {
+        return Some(span);
+    };
+    // Only files are followed, not packages or modules in variables.
+    let path = path?;
+    // Re-exports chain imports; a cycle wouldn't compile, but mustn't hang.
+    if depth > 16 {
+        return None;
+    }
+    let from = world.source(id.join(&path)).ok()?;
+    let mut found = None;
+    each_source_leaf(&from, &mut |_, node| {
+        if found.is_none()
+            && node.text() == name
+            && (is_binding(node) || node.parent_kind() == Some(SyntaxKind::ImportItemPath))
+        {
+            found = Some(node.span());
+        }
+    });
+    binding(world, found?, name, depth + 1)
+}
+
+/// Whether an identifier is the name a `let` binds: `x` in `let x = …` or
+/// `f` in `let f(x) = …`.
+fn is_binding(ident: &LinkedNode) -> bool {
+    let Some(binding) = ident.parent().and_then(|parent| {
+        parent
+            .cast::<ast::LetBinding>()
+            .or_else(|| parent.parent()?.cast::<ast::LetBinding>())
+    }) else {
+        return false;
+    };
+    let name = match binding.kind() {
+        ast::LetBindingKind::Normal(ast::Pattern::Normal(ast::Expr::Ident(name))) => name,
+        ast::LetBindingKind::Closure(name) => name,
+        _ => return false,
+    };
+    name.span() == ident.span()
+}
+
+/// The name a label or reference leaf stands for.
+fn label_name<'a>(leaf: &'a LinkedNode) -> Option<&'a str> {
+    match leaf.kind() {
+        SyntaxKind::Label => leaf.cast::<ast::Label>().map(|label| label.get()),
+        SyntaxKind::RefMarker => leaf.text().strip_prefix('@'),
+        _ => None,
+    }
+}
+
+/// Visit every leaf of every project source.
+fn each_leaf(world: &ProjectWorld, mut visit: impl FnMut(&Source, &LinkedNode)) {
+    for source in world.project_sources() {
+        each_source_leaf(source, &mut visit);
+    }
+}
+
+fn each_source_leaf(source: &Source, visit: &mut impl FnMut(&Source, &LinkedNode)) {
+    fn walk(source: &Source, node: &LinkedNode, visit: &mut impl FnMut(&Source, &LinkedNode)) {
+        if node.children().len() == 0 {
+            visit(source, node);
+        }
+        for child in node.children() {
+            walk(source, &child, visit);
+        }
+    }
+    walk(source, &LinkedNode::new(source.root()), visit);
+}
+
+fn edit(
+    world: &ProjectWorld,
+    source: &Source,
+    range: std::ops::Range<usize>,
+    new_text: &str,
+) -> Option<TextEdit> {
+    Some(TextEdit {
+        file_id: world.project_file(source.id())?,
+        start: to_position(source, range.start)?,
+        end: to_position(source, range.end)?,
+        new_text: new_text.to_string(),
+    })
+}
+
+/// The source of a project file and the byte offset of `position` in it.
+fn locate(
+    world: &ProjectWorld,
+    file_id: ObjectId,
+    position: Position,
+) -> Result<(Source, usize), IdeError> {
+    let source = world
+        .project_sources()
+        .find(|source| world.project_file(source.id()) == Some(file_id))
+        .ok_or(IdeError::FileNotFound)?
+        .clone();
+    let lines = source.lines();
+    let offset = lines
+        .line_to_range(position.line)
+        .and_then(|line| {
+            let utf16 = lines.byte_to_utf16(line.start)? + position.column;
+            lines.utf16_to_byte(utf16).filter(|byte| *byte <= line.end)
+        })
+        .ok_or(IdeError::InvalidPosition(position))?;
+    Ok((source, offset))
+}
+
+fn to_position(source: &Source, byte: usize) -> Option<Position> {
+    super::diagnostic::position(source.lines(), byte)
+}
+
+fn resolve(world: &ProjectWorld, span: Span) -> Option<DiagnosticSpan> {
+    let id = span.id()?;
+    let file_id = world.project_file(id)?;
+    let range = world.range(span)?;
+    let source = world.source(id).ok()?;
+    Some(DiagnosticSpan {
+        file_id,
+        start: to_position(&source, range.start)?,
+        end: to_position(&source, range.end)?,
+    })
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::compile::{WorldContent, WorldFile};
+
+    fn world(files: &[(&str, &str)]) -> (ProjectWorld, Vec<ObjectId>) {
+        let files: Vec<_> = files
+            .iter()
+            .map(|(path, text)| WorldFile {
+                id: ObjectId::new(),
+                path: path.to_string(),
+                content: WorldContent::Text(text.to_string()),
+            })
+            .collect();
+        let ids: Vec<_> = files.iter().map(|file| file.id).collect();
+        (ProjectWorld::new(ids[0], files).unwrap(), ids)
+    }
+
+    fn at(line: usize, column: usize) -> Position {
+        Position { line, column }
+    }
+
+    #[test]
+    fn test_completion_hover_and_definition() {
+        let (world, ids) = world(&[
+            (
+                "main.typ",
+                "#import \"lib.typ\": greet, gap\n#greet[x] #gap\n#gre",
+            ),
+            (
+                "lib.typ",
+                "#let gap = 2pt\n#let greet(name) = [Hello, #name!]",
+            ),
+        ]);
+        let (main, lib) = (ids[0], ids[1]);
+
+        let completions = respond(
+            &world,
+            IdeRequest::Completion {
+                file_id: main,
+                position: at(2, 4),
+                explicit: true,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            completions["from"],
+            serde_json::json!({"line": 2, "column": 1})
+        );
+        let labels: Vec<_> = completions["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["label"].as_str().unwrap())
+            .collect();
+        assert!(labels.contains(&"greet"), "{labels:?}");
+
+        let hover = respond(
+            &world,
+            IdeRequest::Hover {
+                file_id: main,
+                position: at(1, 11),
+            },
+        )
+        .unwrap();
+        assert_eq!(hover["kind"], "code");
+        assert!(hover["value"].as_str().unwrap().starts_with("2pt"));
+
+        let definition = respond(
+            &world,
+            IdeRequest::Definition {
+                file_id: main,
+                position: at(1, 2),
+            },
+        )
+        .unwrap();
+        assert_eq!(definition["file_id"], lib.to_hex());
+        assert_eq!(
+            definition["start"],
+            serde_json::json!({"line": 1, "column": 5})
+        );
+
+        assert_eq!(
+            respond(
+                &world,
+                IdeRequest::Hover {
+                    file_id: ObjectId::new(),
+                    position: at(0, 0),
+                },
+            ),
+            Err(IdeError::FileNotFound)
+        );
+        assert_eq!(
+            respond(
+                &world,
+                IdeRequest::Hover {
+                    file_id: main,
+                    position: at(2, 9),
+                },
+            ),
+            Err(IdeError::InvalidPosition(at(2, 9)))
+        );
+    }
+
+    #[test]
+    fn test_rename_identifiers_across_files_and_labels() {
+        let (world, ids) = world(&[
+            (
+                "main.typ",
+                "#import \"lib.typ\": greet\n= Intro <intro>\n#greet[x] @intro\n#let other = 1",
+            ),
+            (
+                "lib.typ",
+                "#let greet(name) = [Hello, #name!]\n#let x = greet",
+            ),
+        ]);
+        let (main, lib) = (ids[0], ids[1]);
+        let rename = |file_id, position, new_name: &str| {
+            respond(
+                &world,
+                IdeRequest::Rename {
+                    file_id,
+                    position,
+                    new_name: new_name.to_string(),
+                },
+            )
+        };
+        let edit = |file_id, line, start, end, new_text: &str| {
+            serde_json::json!(TextEdit {
+                file_id,
+                start: at(line, start),
+                end: at(line, end),
+                new_text: new_text.to_string(),
+            })
+        };
+
+        let edits = rename(main, at(2, 2), "welcome").unwrap();
+        let mut edits = edits.as_array().unwrap().clone();
+        edits.sort_by_key(|edit| edit.to_string());
+        let mut expected = vec![
+            edit(lib, 0, 5, 10, "welcome"),
+            edit(lib, 1, 9, 14, "welcome"),
+            edit(main, 0, 19, 24, "welcome"),
+            edit(main, 2, 1, 6, "welcome"),
+        ];
+        expected.sort_by_key(|edit| edit.to_string());
+        assert_eq!(edits, expected);
+
+        let edits = rename(main, at(2, 12), "start").unwrap();
+        assert_eq!(
+            edits,
+            serde_json::json!([
+                edit(main, 1, 9, 14, "start"),
+                edit(main, 2, 11, 16, "start")
+            ])
+        );
+
+        assert_eq!(
+            rename(main, at(2, 2), "not valid"),
+            Err(IdeError::InvalidName("not valid".to_string()))
+        );
+        assert_eq!(rename(main, at(1, 3), "x"), Err(IdeError::NothingToRename));
+    }
+}
@@ -0,0 +1,159 @@
+//! Counting what each compilation allocates, so the compile queue holds it to
+//! the memory limit rather than only estimating it up front.
+//!
+//! The global allocator charges every allocation and free on a thread to the
+//! [`MemoryUse`] that thread is compiling for, if any (see
+//! [`MemoryUse::track`]). Once a compilation goes over its limit, its cancel
+//! flag is raised, so its world fails every read from then on, and the queue
+//! gives up on it. An allocation can't fail without aborting the process, so a
+//! compilation that reads nothing more runs on until it ends, holding its
+//! worker like one past its time limit. Work Typst hands to its own thread
+//! pool isn't charged.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+thread_local! {
+    /// The compilation the thread's allocations are charged to, or null.
+    static CHARGED: Cell<*const MemoryUse> = const { Cell::new(ptr::null()) };
+}
+
+/// The memory one compilation holds, against its limit.
+pub struct MemoryUse {
+    live: AtomicIsize,
+    limit: usize,
+    /// What it held when it first went over the limit, or 0 while it hasn't.
+    over: AtomicUsize,
+    cancel: Arc<AtomicBool>,
+}
+
+impl MemoryUse {
+    /// Raises `cancel` once more than `limit` bytes are held.
+    pub fn new(limit: usize, cancel: Arc<AtomicBool>) -> Arc<Self> {
+        Arc::new(Self {
+            live: AtomicIsize::new(0),
+            limit,
+            over: AtomicUsize::new(0),
+            cancel,
+        })
+    }
+
+    /// What the compilation held when it went over its limit, or `None` if it
+    /// hasn't.
+    pub fn exceeded(&self) -> Option<usize> {
+        Some(self.over.load(Ordering::Relaxed)).filter(|&over| over > 0)
+    }
+
+    /// Charge the current thread's allocations to `self` until the returned
+    /// guard is dropped, on this same thread.
+    pub fn track(self: &Arc<Self>) -> Tracking {
+        let previous = CHARGED.with(|charged| charged.replace(Arc::as_ptr(self)));
+        Tracking {
+            _charged: self.clone(),
+            previous,
+        }
+    }
+
+    fn charge(&self, bytes: isize) {
+        let live = self.live.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if live > 0 && live as usize > self.limit && self.over.load(Ordering::Relaxed) == 0 {
+            self.over.store(live as usize, Ordering::Relaxed);
+            self.cancel.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A thread's allocations being charged to a [`MemoryUse`]. Not `Send`: it
+/// must end on the thread it started on.
+pub struct Tracking {
+    /// Keeps what `CHARGED` points at alive.
+    _charged: Arc<MemoryUse>,
+    previous: *const MemoryUse,
+}
+
+impl Drop for Tracking {
+    fn drop(&mut self) {
+        CHARGED.with(|charged| charged.set(self.previous));
+    }
+}
+
+fn charge(bytes: isize) {
+    // `try_with`: the allocator also runs while the thread's locals are torn
+    // down.
+    let _ = CHARGED.try_with(|charged| {
+        let charged = charged.get();
+        if !charged.is_null() {
+            // SAFETY: a non-null `CHARGED` is set by a live `Tracking` on this
+            // thread, which holds the `Arc` it points into.
+            unsafe { (*charged).charge(bytes) }
+        }
+    });
+}
+
+struct CountingAlloc;
+
+// SAFETY: every call is passed straight to `System`; the counting on the side
+// doesn't allocate.
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            charge(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            charge(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        charge(-(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new.is_null() {
+            charge(new_size as isize - layout.size() as isize);
+        }
+        new
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracked_allocations_count_against_the_limit() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let memory = MemoryUse::new(1 << 20, cancel.clone());
+        let small = {
+            let _tracking = memory.track();
+            let small = vec![0u8; 1 << 10];
+            assert_eq!(memory.exceeded(), None);
+            small
+        };
+        // Untracked, this isn't charged.
+        let untracked = vec![0u8; 2 << 20];
+        assert_eq!(memory.exceeded(), None);
+
+        let _tracking = memory.track();
+        let big = vec![0u8; 2 << 20];
+        assert!(memory.exceeded().is_some_and(|over| over > 1 << 20));
+        assert!(cancel.load(Ordering::Relaxed));
+        drop((small, untracked, big));
+    }
+}
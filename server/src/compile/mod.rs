@@ -0,0 +1,233 @@
+//! Server-side Typst compilation.
+//!
+//! The server links exactly one Typst version, [`TYPST_VERSION`]. A project
+//! pinned to a release it isn't compatible with is refused up front rather
+//! than compiled with a different language version than the one it was
+//! written against. Typst's `0.x` releases break between minors, so the
+//! compatible range is the same major and minor.
+//!
+//! [`ProjectWorld`] holds the project's files, and the packages they import,
+//! in memory; compiling it is synchronous, CPU-bound work. Problems come back
+//! as [`Diagnostic`]s located by `ProjectFile` id. Every compilation runs
+//! through the [`CompileQueue`], which bounds how many run at once and for how
+//! long.
+
+use derive_more::Display;
+use semver::Version;
+use typst::diag::{SourceDiagnostic, Warned};
+use typst::ecow::EcoVec;
+use typst::layout::PagedDocument;
+use typst_pdf::PdfOptions;
+
+pub mod cache;
+pub mod diagnostic;
+pub mod font;
+pub mod ide;
+pub mod memory;
+pub mod outline;
+pub mod package;
+pub mod preview;
+pub mod queue;
+pub mod render;
+pub mod world;
+
+pub use cache::RenderCache;
+pub use diagnostic::{Diagnostic, Severity};
+pub use font::FontCache;
+pub use package::{PackageStore, Packages};
+pub use queue::{CompileQueue, JobKind};
+pub use render::ImageFormat;
+pub use world::{ProjectWorld, WorldContent, WorldFile};
+
+/// The Typst release the server compiles with.
+pub const TYPST_VERSION: Version = Version::new(0, 14, 2);
+
+#[derive(Debug, Display, PartialEq, Eq)]
+pub enum CompileError {
+    #[display("The project has no entry file")]
+    NoEntry,
+    #[display(
+        "The project is pinned to Typst {_0}, but the server compiles with Typst {TYPST_VERSION}"
+    )]
+    UnsupportedVersion(Version),
+    /// The document has errors; the diagnostics are the errors only.
+    #[display("Compilation failed: {}", messages(_0))]
+    Failed(Vec<Diagnostic>),
+    /// Typst itself panicked. Kept apart from [`Self::Failed`]: it's a server
+    /// fault, not a problem with the document.
+    #[display("The compiler crashed")]
+    Panicked,
+    /// Every worker is busy and the queue is full.
+    #[display("The compile queue is full; try again shortly")]
+    Busy,
+    #[display("Compilation took longer than {_0} s")]
+    TimedOut(u64),
+    /// Refused up front on the project's estimated footprint, or stopped for
+    /// what it allocated while compiling.
+    #[display("The project needs about {estimate} bytes to compile, over the {limit}-byte limit")]
+    TooLarge { estimate: usize, limit: usize },
+    /// An earlier compilation of the project, past its time or memory limit,
+    /// still holds a worker.
+    #[display("An earlier compilation of this project is still running; try again shortly")]
+    Overrunning,
+    /// A newer revision of the project was sent to compile the same way.
+    #[display("Compilation was superseded by a newer revision")]
+    Cancelled,
+}
+
+/// Whether a project pinned to `pinned` (or to nothing) can be compiled here.
+pub fn check_version(pinned: Option<&Version>) -> Result<(), CompileError> {
+    match pinned {
+        Some(version)
+            if version.major != TYPST_VERSION.major || version.minor != TYPST_VERSION.minor =>
+        {
+            Err(CompileError::UnsupportedVersion(version.clone()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Compile the world's entry file to a laid-out document.
+pub fn compile_document(world: &ProjectWorld) -> Result<PagedDocument, CompileError> {
+    let Warned { output, .. } = typst::compile::<PagedDocument>(world);
+    output.map_err(|errors| failed(world, &errors))
+}
+
+/// Compile the world's entry file to PDF bytes.
+pub fn compile_pdf(world: &ProjectWorld) -> Result<Vec<u8>, CompileError> {
+    let document = compile_document(world)?;
+    typst_pdf::pdf(&document, &PdfOptions::default()).map_err(|errors| failed(world, &errors))
+}
+
+/// Compile the world's entry file for its errors and warnings alone.
+pub fn diagnose(world: &ProjectWorld) -> Vec<Diagnostic> {
+    let Warned { output, warnings } = typst::compile::<PagedDocument>(world);
+    let errors = output.err().unwrap_or_default();
+    errors
+        .iter()
+        .chain(&warnings)
+        .map(|diagnostic| Diagnostic::new(world, diagnostic))
+        .collect()
+}
+
+fn failed(world: &ProjectWorld, errors: &EcoVec<SourceDiagnostic>) -> CompileError {
+    CompileError::Failed(errors.iter().map(|e| Diagnostic::new(world, e)).collect())
+}
+
+fn messages(diagnostics: &[Diagnostic]) -> String {
+    let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+    messages.join("; ")
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::compile::diagnostic::{DiagnosticSpan, Position};
+    use bson::oid::ObjectId;
+    use typst::World;
+    use typst::foundations::Bytes;
+    use typst::text::Font;
+
+    fn text(path: &str, text: &str) -> WorldFile {
+        WorldFile {
+            id: ObjectId::new(),
+            path: path.to_string(),
+            content: WorldContent::Text(text.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_check_version_accepts_compatible_pins() {
+        assert_eq!(check_version(None), Ok(()));
+        assert_eq!(check_version(Some(&Version::new(0, 14, 0))), Ok(()));
+        assert_eq!(
+            check_version(Some(&Version::new(0, 13, 1))),
+            Err(CompileError::UnsupportedVersion(Version::new(0, 13, 1)))
+        );
+        assert!(check_version(Some(&Version::new(1, 14, 0))).is_err());
+    }
+
+    #[test]
+    fn test_compile_pdf_resolves_imports_and_assets_from_the_root() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"/>"#;
+        let main = text(
+            "chapters/main.typ",
+            "#import \"/lib.typ\": greet\n#greet[World]\n#image(\"/img/dot.svg\")",
+        );
+        let entry = main.id;
+        let files = vec![
+            main,
+            text("lib.typ", "#let greet(name) = [Hello, #name!]"),
+            WorldFile {
+                id: ObjectId::new(),
+                path: "img/dot.svg".to_string(),
+                content: WorldContent::Binary(svg.to_vec()),
+            },
+        ];
+
+        let pdf = compile_pdf(&ProjectWorld::new(entry, files).unwrap()).unwrap();
+        assert!(pdf.starts_with(b"%PDF-"));
+    }
+
+    #[test]
+    fn test_world_needs_its_entry_among_the_files() {
+        assert!(ProjectWorld::new(ObjectId::new(), vec![text("main.typ", "")]).is_none());
+    }
+
+    #[test]
+    fn test_diagnostics_point_at_project_files() {
+        let main = text("main.typ", "#import \"lib.typ\": f\n#f()");
+        // The error is on the second line, after a multi-byte character: the
+        // column counts UTF-16 units, not bytes.
+        let lib = text("lib.typ", "#let f() = {\n  \"é\"; 1 + \"a\"\n}");
+        let (entry, lib_id) = (main.id, lib.id);
+        let world = ProjectWorld::new(entry, vec![main, lib]).unwrap();
+
+        let Err(CompileError::Failed(errors)) = compile_pdf(&world) else {
+            panic!("expected a compile failure");
+        };
+        assert_eq!(errors, diagnose(&world));
+        let error = &errors[0];
+        assert_eq!(error.severity, Severity::Error);
+        assert!(error.message.contains("cannot add"), "{}", error.message);
+        assert_eq!(
+            error.span,
+            Some(DiagnosticSpan {
+                file_id: lib_id,
+                start: Position { line: 1, column: 7 },
+                end: Position {
+                    line: 1,
+                    column: 14
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_extra_fonts_are_indexed_after_the_bundled_ones() {
+        let fonts = || typst_assets::fonts().flat_map(|data| Font::iter(Bytes::new(data)));
+        let bundled = fonts().count();
+        let main = text("main.typ", "Hello");
+        let world = ProjectWorld::new(main.id, vec![main]).unwrap();
+        assert!(world.font(bundled).is_none());
+
+        let world = world.with_fonts(fonts().take(1).collect());
+        assert!(world.book().info(bundled).is_some());
+        assert!(world.font(bundled).is_some());
+        assert!(world.font(bundled + 1).is_none());
+    }
+
+    #[test]
+    fn test_diagnose_reports_warnings_of_a_successful_compile() {
+        let main = text("main.typ", "#set text(font: \"No Such Font\")\nHello");
+        let main_id = main.id;
+        let world = ProjectWorld::new(main_id, vec![main]).unwrap();
+
+        assert!(compile_pdf(&world).is_ok());
+        let diagnostics = diagnose(&world);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].span.as_ref().unwrap().file_id, main_id);
+    }
+}
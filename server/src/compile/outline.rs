@@ -0,0 +1,204 @@
+//! A project's outline: what the editor navigates and autocompletes from.
+//!
+//! This works from the syntax alone, without compiling. Each `.typ` file is
+//! parsed for its headings, the labels defined in its markup and its `@`
+//! references; `.bib` and `.yml`/`.yaml` bibliographies are read for their
+//! citation keys. Offsets are in UTF-16 units from the start of the file, like
+//! diagnostic columns.
+
+use bson::oid::ObjectId;
+use bson::serde_helpers::serialize_object_id_as_hex_string;
+use serde::Serialize;
+use typst::syntax::ast::{self, AstNode};
+use typst::syntax::{Lines, LinkedNode, SyntaxKind};
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Outline {
+    pub headings: Vec<Heading>,
+    /// Labels attached to markup (`= Intro <intro>`), i.e. the targets that
+    /// references can point at.
+    pub labels: Vec<Located>,
+    /// `@target` references, plus labels used as values in code
+    /// (`#ref(<target>)`, `#cite(<key>)`).
+    pub references: Vec<Located>,
+    pub citations: Vec<Citation>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Heading {
+    pub level: usize,
+    /// The heading's markup as written, trimmed, without its label.
+    pub text: String,
+    #[serde(serialize_with = "serialize_object_id_as_hex_string")]
+    pub file_id: ObjectId,
+    pub offset: usize,
+}
+
+/// A label or reference: its name, without `<>` or `@`, and where it is.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Located {
+    pub name: String,
+    #[serde(serialize_with = "serialize_object_id_as_hex_string")]
+    pub file_id: ObjectId,
+    pub offset: usize,
+}
+
+/// A citation key, and the bibliography file that defines it.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Citation {
+    pub key: String,
+    #[serde(serialize_with = "serialize_object_id_as_hex_string")]
+    pub file_id: ObjectId,
+}
+
+/// A heading's markup, without the label attached to it.
+fn heading_text(heading: ast::Heading) -> String {
+    let mut text = String::new();
+    for child in heading.body().to_untyped().children() {
+        if child.kind() != SyntaxKind::Label {
+            text.push_str(&child.clone().into_text());
+        }
+    }
+    text.trim().to_string()
+}
+
+/// Whether the outline reads a file at `path`.
+pub fn is_indexed(path: &str) -> bool {
+    [".typ", ".bib", ".yml", ".yaml"]
+        .iter()
+        .any(|extension| path.ends_with(extension))
+}
+
+impl Outline {
+    /// Add a file's entries. A file the outline doesn't read is skipped, and
+    /// so is a bibliography that doesn't parse.
+    pub fn add(&mut self, file_id: ObjectId, path: &str, text: &str) {
+        if path.ends_with(".typ") {
+            let root = typst::syntax::parse(text);
+            let lines = Lines::new(text);
+            self.walk(file_id, &lines, &LinkedNode::new(&root));
+        } else if path.ends_with(".bib") {
+            if let Ok(library) = hayagriva::io::from_biblatex_str(text) {
+                self.cite(file_id, library.keys());
+            }
+        } else if (path.ends_with(".yml") || path.ends_with(".yaml"))
+            && let Ok(library) = hayagriva::io::from_yaml_str(text)
+        {
+            self.cite(file_id, library.keys());
+        }
+    }
+
+    fn walk(&mut self, file_id: ObjectId, lines: &Lines<&str>, node: &LinkedNode) {
+        let offset = || lines.byte_to_utf16(node.offset()).unwrap_or_default();
+        match node.kind() {
+            SyntaxKind::Heading => {
+                if let Some(heading) = node.cast::<ast::Heading>() {
+                    self.headings.push(Heading {
+                        level: heading.depth().get(),
+                        text: heading_text(heading),
+                        file_id,
+                        offset: offset(),
+                    });
+                }
+            }
+            SyntaxKind::Label => {
+                if let Some(label) = node.cast::<ast::Label>() {
+                    let located = Located {
+                        name: label.get().to_string(),
+                        file_id,
+                        offset: offset(),
+                    };
+                    match node.parent_kind() {
+                        Some(SyntaxKind::Markup) => self.labels.push(located),
+                        _ => self.references.push(located),
+                    }
+                }
+            }
+            SyntaxKind::Ref => {
+                if let Some(reference) = node.cast::<ast::Ref>() {
+                    self.references.push(Located {
+                        name: reference.target().to_string(),
+                        file_id,
+                        offset: offset(),
+                    });
+                }
+            }
+            _ => {}
+        }
+        for child in node.children() {
+            self.walk(file_id, lines, &child);
+        }
+    }
+
+    fn cite<'a>(&mut self, file_id: ObjectId, keys: impl Iterator<Item = &'a str>) {
+        self.citations.extend(keys.map(|key| Citation {
+            key: key.to_string(),
+            file_id,
+        }));
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outline_indexes_typst_and_bibliographies() {
+        let (main, bib, yml) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let mut outline = Outline::default();
+        outline.add(
+            main,
+            "main.typ",
+            "= Intro <intro>\nSee @intro and @knuth.\n== Détails *here*\n#figure[x] <fig>\n#ref(<fig>)",
+        );
+        outline.add(
+            bib,
+            "refs.bib",
+            "@book{knuth, title = {TAOCP}, author = {Knuth}}",
+        );
+        outline.add(
+            yml,
+            "more.yml",
+            "lamport:\n  type: article\n  title: Paxos\n",
+        );
+        outline.add(ObjectId::new(), "data.json", "{}");
+        outline.add(ObjectId::new(), "broken.bib", "@book{");
+
+        let heading = |level, text: &str, offset| Heading {
+            level,
+            text: text.to_string(),
+            file_id: main,
+            offset,
+        };
+        assert_eq!(
+            outline.headings,
+            vec![heading(1, "Intro", 0), heading(2, "Détails *here*", 39)]
+        );
+        let located = |name: &str, offset| Located {
+            name: name.to_string(),
+            file_id: main,
+            offset,
+        };
+        assert_eq!(
+            outline.labels,
+            vec![located("intro", 8), located("fig", 68)]
+        );
+        assert_eq!(
+            outline.references,
+            vec![
+                located("intro", 20),
+                located("knuth", 31),
+                located("fig", 79)
+            ]
+        );
+        let citation = |key: &str, file_id| Citation {
+            key: key.to_string(),
+            file_id,
+        };
+        assert_eq!(
+            outline.citations,
+            vec![citation("knuth", bib), citation("lamport", yml)]
+        );
+    }
+}
@@ -0,0 +1,495 @@
+//! Typst packages, resolved without a network.
+//!
+//! A document names a package by spec, `#import "@preview/cetz:0.2.2"`. The
+//! server can't reach the package registry, so a package is looked up, in
+//! order:
+//!
+//! 1. unpacked in the cache directory, at `{cache_dir}/{namespace}/{name}/{version}/`
+//!    (the layout of Typst's own package cache, so one can be copied over);
+//! 2. as a tarball in the mirror directory, at
+//!    `{mirror_dir}/{namespace}/{name}-{version}.tar.gz` (the registry's layout);
+//! 3. as a tarball an admin uploaded, stored at
+//!    `packages/{namespace}/{name}/{version}.tar.gz` in object storage.
+//!
+//! Like the project's own files, packages are loaded before compilation:
+//! [`PackageStore::resolve`] finds the specs the sources import, and the
+//! packages those import in turn, so the world itself never does I/O.
+
+use std::collections::HashMap;
+use std::io::{self, Read as _};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+
+use bson::oid::ObjectId;
+use derive_more::Display;
+use flate2::read::GzDecoder;
+use regex::Regex;
+use typst::diag::{PackageError, eco_format};
+use typst::foundations::Bytes;
+use typst::syntax::VirtualPath;
+use typst::syntax::package::{PackageManifest, PackageSpec};
+
+use super::cache::LruCache;
+use crate::config::PackagesConfig;
+use crate::storage::{ObjectStore, StorageError};
+
+/// The namespaces packages are served from: the registry's, and the one
+/// Typst reserves for packages installed by hand.
+pub const NAMESPACES: [&str; 2] = ["preview", "local"];
+
+/// Largest a package may be once unpacked, so a small tarball can't expand
+/// without bound.
+const MAX_UNPACKED_BYTES: u64 = 256 * 1024 * 1024;
+
+/// The packages a world can import, by spec. A package that failed to load
+/// keeps its error, which is what an import of it then reports.
+pub type Packages = HashMap<PackageSpec, Result<Arc<Package>, PackageError>>;
+
+#[derive(Debug, Display)]
+pub enum PackageStoreError {
+    #[display("Only package admins can upload packages")]
+    Forbidden,
+    #[display("Invalid package spec: {_0}")]
+    InvalidSpec(String),
+    #[display("Unsupported package namespace: {_0}")]
+    UnsupportedNamespace(String),
+    #[display("Invalid package: {_0}")]
+    Invalid(String),
+    #[display("Package {_0} already exists")]
+    AlreadyExists(PackageSpec),
+    #[display("Package exceeds the {_0}-byte upload limit")]
+    PayloadTooLarge(usize),
+    #[display("Object storage is not configured")]
+    StorageUnavailable,
+    #[display("Storage error: {_0}")]
+    Storage(StorageError),
+}
+
+/// A package's files, keyed by their path from the package root.
+#[derive(Debug)]
+pub struct Package {
+    files: HashMap<String, Bytes>,
+}
+
+impl Package {
+    /// Read a package from a `.tar.gz`, whose entries are rooted at the
+    /// package root.
+    pub fn unpack(tarball: &[u8]) -> Result<Self, PackageError> {
+        let mut archive = tar::Archive::new(GzDecoder::new(tarball));
+        let mut files = HashMap::new();
+        let mut unpacked = 0;
+        for entry in archive.entries().map_err(malformed)? {
+            let mut entry = entry.map_err(malformed)?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path().map_err(malformed)?;
+            let Some(path) = relative(&path) else {
+                return Err(PackageError::MalformedArchive(Some(eco_format!(
+                    "entry `{}` is outside the package",
+                    path.display()
+                ))));
+            };
+            unpacked += entry.size();
+            if unpacked > MAX_UNPACKED_BYTES {
+                return Err(PackageError::MalformedArchive(Some(eco_format!(
+                    "unpacks to more than {MAX_UNPACKED_BYTES} bytes"
+                ))));
+            }
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes).map_err(malformed)?;
+            files.insert(path, Bytes::new(bytes));
+        }
+        Ok(Self { files })
+    }
+
+    /// Read an unpacked package from `root`, or `None` if there's no such
+    /// directory.
+    fn read_dir(root: &Path) -> io::Result<Option<Self>> {
+        if !root.is_dir() {
+            return Ok(None);
+        }
+        let mut files = HashMap::new();
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if let Some(key) = path.strip_prefix(root).ok().and_then(relative) {
+                    files.insert(key, Bytes::new(std::fs::read(&path)?));
+                }
+            }
+        }
+        Ok(Some(Self { files }))
+    }
+
+    /// The file at `path`, rooted at the package root.
+    pub fn file(&self, path: &VirtualPath) -> Option<&Bytes> {
+        self.files.get(&relative(path.as_rootless_path())?)
+    }
+
+    /// The total size of the package's files, in bytes.
+    pub fn size(&self) -> usize {
+        self.files.values().map(|bytes| bytes.len()).sum()
+    }
+
+    /// The package's `typst.toml`.
+    pub fn manifest(&self) -> Result<PackageManifest, String> {
+        let manifest = self.files.get("typst.toml").ok_or("missing typst.toml")?;
+        let manifest = std::str::from_utf8(manifest).map_err(|e| e.to_string())?;
+        toml::from_str(manifest).map_err(|e| e.message().to_string())
+    }
+
+    /// The text of the package's Typst sources.
+    fn sources(&self) -> impl Iterator<Item = &str> {
+        self.files
+            .iter()
+            .filter(|(path, _)| path.ends_with(".typ"))
+            .filter_map(|(_, bytes)| std::str::from_utf8(bytes).ok())
+    }
+}
+
+/// Where packages are found, and the ones already loaded. A package version
+/// never changes, so a loaded package stays loaded until the least recently
+/// used ones make room past the memory budget; a missing one is looked for
+/// again next time, in case it has been uploaded since.
+pub struct PackageStore {
+    cache_dir: Option<PathBuf>,
+    mirror_dir: Option<PathBuf>,
+    store: Option<Arc<dyn ObjectStore>>,
+    admins: Vec<String>,
+    loaded: LruCache<PackageSpec, Arc<Package>>,
+}
+
+impl Default for PackageStore {
+    fn default() -> Self {
+        Self::new(&PackagesConfig::default(), None)
+    }
+}
+
+impl PackageStore {
+    pub fn new(config: &PackagesConfig, store: Option<Arc<dyn ObjectStore>>) -> Self {
+        Self {
+            cache_dir: config.cache_dir.clone(),
+            mirror_dir: config.mirror_dir.clone(),
+            store,
+            admins: config.admins.clone(),
+            loaded: LruCache::new(config.cache_bytes),
+        }
+    }
+
+    /// The packages `sources` import, directly or through other packages.
+    pub async fn resolve<'a>(&self, sources: impl IntoIterator<Item = &'a str>) -> Packages {
+        let mut pending: Vec<_> = sources.into_iter().flat_map(imports).collect();
+        let mut packages = Packages::new();
+        while let Some(spec) = pending.pop() {
+            if packages.contains_key(&spec) {
+                continue;
+            }
+            let package = self.get(&spec).await;
+            if let Ok(package) = &package {
+                pending.extend(package.sources().flat_map(imports));
+            }
+            packages.insert(spec, package);
+        }
+        packages
+    }
+
+    /// The package `spec` names, from wherever it is found first.
+    pub async fn get(&self, spec: &PackageSpec) -> Result<Arc<Package>, PackageError> {
+        if let Some(package) = self.loaded.get(spec) {
+            return Ok(package);
+        }
+        let package = Arc::new(self.fetch(spec).await?);
+        self.loaded
+            .insert(spec.clone(), package.clone(), package.size());
+        Ok(package)
+    }
+
+    async fn fetch(&self, spec: &PackageSpec) -> Result<Package, PackageError> {
+        if !NAMESPACES.contains(&spec.namespace.as_str()) {
+            return Err(PackageError::NotFound(spec.clone()));
+        }
+        if let Some(dir) = &self.cache_dir {
+            let dir = dir
+                .join(spec.namespace.as_str())
+                .join(spec.name.as_str())
+                .join(spec.version.to_string());
+            let package = tokio::task::spawn_blocking(move || Package::read_dir(&dir))
+                .await
+                .map_err(other)?
+                .map_err(other)?;
+            if let Some(package) = package {
+                return Ok(package);
+            }
+        }
+        if let Some(dir) = &self.mirror_dir {
+            let path = dir
+                .join(spec.namespace.as_str())
+                .join(format!("{}-{}.tar.gz", spec.name, spec.version));
+            match tokio::fs::read(&path).await {
+                Ok(tarball) => return unpack(tarball).await,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(other(e)),
+            }
+        }
+        if let Some(store) = &self.store
+            && let Some(tarball) = store.get_object(&object_key(spec)).await.map_err(other)?
+        {
+            return unpack(tarball).await;
+        }
+        Err(PackageError::NotFound(spec.clone()))
+    }
+
+    /// Store a tarball `user_id` uploaded as package `spec`, once it unpacks
+    /// and its manifest matches the spec. Only admins may upload, and
+    /// versions are immutable: the write only lands if the version isn't
+    /// stored yet, so of two racing uploads one is refused.
+    pub async fn upload(
+        &self,
+        user_id: ObjectId,
+        spec: &PackageSpec,
+        tarball: Vec<u8>,
+    ) -> Result<(), PackageStoreError> {
+        if !self.admins.contains(&user_id.to_hex()) {
+            return Err(PackageStoreError::Forbidden);
+        }
+        if !NAMESPACES.contains(&spec.namespace.as_str()) {
+            return Err(PackageStoreError::UnsupportedNamespace(
+                spec.namespace.to_string(),
+            ));
+        }
+        let store = self
+            .store
+            .as_ref()
+            .ok_or(PackageStoreError::StorageUnavailable)?;
+        let package = unpack(tarball.clone())
+            .await
+            .map_err(|e| PackageStoreError::Invalid(e.to_string()))?;
+        package
+            .manifest()
+            .and_then(|manifest| manifest.validate(spec).map_err(|e| e.to_string()))
+            .map_err(PackageStoreError::Invalid)?;
+        match store.create_object(&object_key(spec), &tarball).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(PackageStoreError::AlreadyExists(spec.clone())),
+            Err(e) => Err(PackageStoreError::Storage(e)),
+        }
+    }
+}
+
+/// The specs of the packages a source imports. Only string literals are
+/// found, which is how imports are written in practice.
+pub fn imports(source: &str) -> Vec<PackageSpec> {
+    static SPEC: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#""(@[^"\s]+)""#).unwrap());
+    SPEC.captures_iter(source)
+        .filter_map(|captures| captures[1].parse().ok())
+        .collect()
+}
+
+fn object_key(spec: &PackageSpec) -> String {
+    format!(
+        "packages/{}/{}/{}.tar.gz",
+        spec.namespace, spec.name, spec.version
+    )
+}
+
+/// Unpack on a blocking thread: decompression is CPU-bound.
+async fn unpack(tarball: Vec<u8>) -> Result<Package, PackageError> {
+    tokio::task::spawn_blocking(move || Package::unpack(&tarball))
+        .await
+        .map_err(other)?
+}
+
+/// `path` as a key into a package's files: relative, `/`-separated, and
+/// without `..`.
+fn relative(path: &Path) -> Option<String> {
+    let mut segments = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(segment) => segments.push(segment.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!segments.is_empty()).then(|| segments.join("/"))
+}
+
+fn malformed(e: io::Error) -> PackageError {
+    PackageError::MalformedArchive(Some(eco_format!("{e}")))
+}
+
+fn other(e: impl std::fmt::Display) -> PackageError {
+    PackageError::Other(Some(eco_format!("{e}")))
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::compile::{ProjectWorld, WorldContent, WorldFile, compile_pdf};
+    use crate::storage::InMemoryObjectStore;
+    use flate2::{Compression, write::GzEncoder};
+
+    fn spec(spec: &str) -> PackageSpec {
+        spec.parse().unwrap()
+    }
+
+    fn manifest(name: &str, version: &str) -> String {
+        format!("[package]\nname = \"{name}\"\nversion = \"{version}\"\nentrypoint = \"lib.typ\"\n")
+    }
+
+    fn tarball(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        for (path, text) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(text.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, path, text.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    /// A fresh, empty directory under the system temp dir.
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("caduceus-packages-{}", ObjectId::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_imports_finds_package_specs_in_string_literals() {
+        let source = "#import \"@preview/cetz:0.2.2\": canvas\n\
+                      #import \"lib.typ\"\n\
+                      #include \"@local/notes:1.0.0\"\n\
+                      #import \"@preview/not a spec\"";
+        assert_eq!(
+            imports(source),
+            vec![spec("@preview/cetz:0.2.2"), spec("@local/notes:1.0.0")]
+        );
+    }
+
+    #[test]
+    fn test_package_keys_stay_inside_the_package() {
+        assert_eq!(
+            relative(Path::new("./src/lib.typ")).as_deref(),
+            Some("src/lib.typ")
+        );
+        assert_eq!(relative(Path::new("../lib.typ")), None);
+        assert_eq!(relative(Path::new("/etc/passwd")), None);
+
+        let package = Package::unpack(&tarball(&[("src/lib.typ", "x")])).unwrap();
+        assert!(package.file(&VirtualPath::new("/src/lib.typ")).is_some());
+        assert!(matches!(
+            Package::unpack(b"not a tarball"),
+            Err(PackageError::MalformedArchive(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_loads_imports_transitively_from_both_directories() {
+        // `@preview/outer` comes from the mirror and imports `@local/inner`,
+        // which is unpacked in the cache.
+        let (cache_dir, mirror_dir) = (temp_dir(), temp_dir());
+        let inner = cache_dir.join("local/inner/1.0.0");
+        std::fs::create_dir_all(&inner).unwrap();
+        std::fs::write(inner.join("typst.toml"), manifest("inner", "1.0.0")).unwrap();
+        std::fs::write(inner.join("lib.typ"), "#let shout(body) = upper(body)").unwrap();
+        std::fs::create_dir_all(mirror_dir.join("preview")).unwrap();
+        std::fs::write(
+            mirror_dir.join("preview/outer-0.1.0.tar.gz"),
+            tarball(&[
+                ("typst.toml", &manifest("outer", "0.1.0")),
+                (
+                    "lib.typ",
+                    "#import \"@local/inner:1.0.0\": shout\n#let greet(name) = shout[Hello, #name]",
+                ),
+            ]),
+        )
+        .unwrap();
+        let packages = PackageStore::new(
+            &PackagesConfig {
+                cache_dir: Some(cache_dir.clone()),
+                mirror_dir: Some(mirror_dir.clone()),
+                ..PackagesConfig::default()
+            },
+            None,
+        );
+
+        let main = "#import \"@preview/outer:0.1.0\": greet\n#greet[World]\n\
+                    #import \"@preview/missing:1.0.0\"";
+        let resolved = packages.resolve([main]).await;
+        assert!(resolved[&spec("@preview/outer:0.1.0")].is_ok());
+        assert!(resolved[&spec("@local/inner:1.0.0")].is_ok());
+        assert!(matches!(
+            resolved[&spec("@preview/missing:1.0.0")],
+            Err(PackageError::NotFound(_))
+        ));
+
+        let file = WorldFile {
+            id: ObjectId::new(),
+            path: "main.typ".to_string(),
+            content: WorldContent::Text(main.lines().take(2).collect::<Vec<_>>().join("\n")),
+        };
+        let world = ProjectWorld::new(file.id, [file])
+            .unwrap()
+            .with_packages(resolved);
+        assert!(compile_pdf(&world).is_ok());
+
+        std::fs::remove_dir_all(cache_dir).unwrap();
+        std::fs::remove_dir_all(mirror_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upload_is_admin_only_validated_and_immutable() {
+        let admin = ObjectId::new();
+        let packages = PackageStore::new(
+            &PackagesConfig {
+                admins: vec![admin.to_hex()],
+                ..PackagesConfig::default()
+            },
+            Some(Arc::new(InMemoryObjectStore::default())),
+        );
+        let example = spec("@preview/example:0.1.0");
+        let tarball = tarball(&[
+            ("typst.toml", &manifest("example", "0.1.0")),
+            ("lib.typ", "#let x = 1"),
+        ]);
+
+        assert!(matches!(
+            packages
+                .upload(ObjectId::new(), &example, tarball.clone())
+                .await,
+            Err(PackageStoreError::Forbidden)
+        ));
+        assert!(matches!(
+            packages
+                .upload(admin, &spec("@preview/example:0.2.0"), tarball.clone())
+                .await,
+            Err(PackageStoreError::Invalid(_))
+        ));
+        assert!(matches!(
+            packages
+                .upload(admin, &spec("@other/example:0.1.0"), tarball.clone())
+                .await,
+            Err(PackageStoreError::UnsupportedNamespace(_))
+        ));
+
+        assert!(matches!(
+            packages.get(&example).await,
+            Err(PackageError::NotFound(_))
+        ));
+        packages
+            .upload(admin, &example, tarball.clone())
+            .await
+            .unwrap();
+        assert!(packages.get(&example).await.is_ok());
+        assert!(matches!(
+            packages.upload(admin, &example, tarball).await,
+            Err(PackageStoreError::AlreadyExists(_))
+        ));
+    }
+}
@@ -0,0 +1,138 @@
+//! Incremental page renders for the live preview.
+//!
+//! A compile produces every page, but after a keystroke usually only one or
+//! two of them look different. Each page is fingerprinted with
+//! [`page_hash`], and only pages whose fingerprint differs from what the
+//! viewer already has are rendered and sent.
+
+use std::collections::HashMap;
+
+use super::render::{ImageFormat, page_hash, render_page};
+use super::{CompileError, ProjectWorld, compile_document};
+
+/// What changed in one format's pages since the previous render.
+#[derive(Debug)]
+pub struct PageUpdate {
+    pub format: ImageFormat,
+    /// The document's page count now. Pages past it were removed.
+    pub page_count: usize,
+    /// `(index, hash, image)` of each page that is new or looks different.
+    pub changed: Vec<(usize, u128, Vec<u8>)>,
+}
+
+/// The pages last rendered, per format: the hash and image of each page.
+#[derive(Debug, Default)]
+pub struct PreviewPages {
+    pages: HashMap<ImageFormat, Vec<(u128, Vec<u8>)>>,
+}
+
+impl PreviewPages {
+    /// The hashes of the pages rendered in `format`, for [`render_preview`] to
+    /// diff against.
+    pub fn hashes(&self, format: ImageFormat) -> Vec<u128> {
+        self.pages
+            .get(&format)
+            .map(|pages| pages.iter().map(|(hash, _)| *hash).collect())
+            .unwrap_or_default()
+    }
+
+    /// Every page rendered in `format`, or `None` if it hasn't been yet.
+    pub fn get(&self, format: ImageFormat) -> Option<&[(u128, Vec<u8>)]> {
+        self.pages.get(&format).map(Vec::as_slice)
+    }
+
+    pub fn apply(&mut self, update: &PageUpdate) {
+        let pages = self.pages.entry(update.format).or_default();
+        pages.resize_with(update.page_count, Default::default);
+        for (index, hash, image) in &update.changed {
+            pages[*index] = (*hash, image.clone());
+        }
+    }
+
+    /// Forget the renders of formats nobody views any more.
+    pub fn retain(&mut self, formats: &[ImageFormat]) {
+        self.pages.retain(|format, _| formats.contains(format));
+    }
+}
+
+/// Compile the world and render, in each requested format, the pages whose
+/// hash isn't the one at the same index in `previous`.
+pub fn render_preview(
+    world: &ProjectWorld,
+    previous: &[(ImageFormat, Vec<u128>)],
+) -> Result<Vec<PageUpdate>, CompileError> {
+    let document = compile_document(world)?;
+    let hashes: Vec<u128> = document.pages.iter().map(page_hash).collect();
+    Ok(previous
+        .iter()
+        .map(|(format, before)| PageUpdate {
+            format: *format,
+            page_count: hashes.len(),
+            changed: document
+                .pages
+                .iter()
+                .zip(&hashes)
+                .enumerate()
+                .filter(|(index, (_, hash))| before.get(*index) != Some(*hash))
+                .map(|(index, (page, hash))| (index, *hash, render_page(page, *format)))
+                .collect(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::compile::{WorldContent, WorldFile};
+    use bson::oid::ObjectId;
+
+    fn world(text: &str) -> ProjectWorld {
+        let id = ObjectId::new();
+        let main = WorldFile {
+            id,
+            path: "main.typ".to_string(),
+            content: WorldContent::Text(text.to_string()),
+        };
+        ProjectWorld::new(id, vec![main]).unwrap()
+    }
+
+    #[test]
+    fn test_render_preview_renders_only_changed_pages() {
+        let mut pages = PreviewPages::default();
+        let render = |pages: &PreviewPages, text: &str| {
+            let previous = vec![
+                (ImageFormat::Svg, pages.hashes(ImageFormat::Svg)),
+                (ImageFormat::Png, pages.hashes(ImageFormat::Png)),
+            ];
+            render_preview(&world(text), &previous).unwrap()
+        };
+
+        let updates = render(&pages, "One\n#pagebreak()\nTwo");
+        assert_eq!(updates.len(), 2);
+        for update in &updates {
+            assert_eq!(update.page_count, 2);
+            assert_eq!(update.changed.len(), 2);
+            pages.apply(update);
+        }
+        let svg = &updates[0].changed[0].2;
+        assert!(svg.starts_with(b"<svg"));
+        let png = &updates[1].changed[0].2;
+        assert!(png.starts_with(b"\x89PNG"));
+
+        // Editing the second page leaves the first one alone.
+        let updates = render(&pages, "One\n#pagebreak()\nTwo!");
+        let changed: Vec<_> = updates[0].changed.iter().map(|(i, ..)| *i).collect();
+        assert_eq!(changed, [1]);
+        pages.apply(&updates[0]);
+
+        // Dropping a page shrinks the count.
+        let updates = render(&pages, "One");
+        assert_eq!(updates[0].page_count, 1);
+        pages.apply(&updates[0]);
+        assert_eq!(pages.get(ImageFormat::Svg).unwrap().len(), 1);
+
+        pages.retain(&[ImageFormat::Svg]);
+        assert!(pages.get(ImageFormat::Png).is_none());
+    }
+}
@@ -0,0 +1,603 @@
+//! The compile queue: a fixed pool of workers that every compilation runs on,
+//! so one runaway document can't take the server's CPUs from everyone else.
+//!
+//! A compilation first has its [`ProjectWorld::footprint`] checked against
+//! the memory limit, then waits for a worker; if none is free and
+//! `max_queued` compilations are already waiting, it is turned away with
+//! [`CompileError::Busy`] instead. Each runs under a wall-clock limit, and
+//! what it allocates is counted against the memory limit as it runs (see
+//! [`super::memory`]); one that goes over is answered with
+//! [`CompileError::TooLarge`].
+//!
+//! Compilations are grouped by project and [`JobKind`]. A new one for a
+//! different [`ProjectWorld::revision`] than those in its group cancels them:
+//! a waiting one gives up before it starts, a running one has its world's
+//! cancel flag set. Typst can't be interrupted, so a running compilation stops
+//! early only if it still reads files; either way its caller hears
+//! [`CompileError::Cancelled`] and its worker is busy until it really ends.
+//! The same goes for a compilation past its time or memory limit, whose caller
+//! is answered right away. Until such a compilation ends, its project's new
+//! ones are refused with [`CompileError::Overrunning`], so one document can't
+//! tie up every worker. Dropping the future waiting on a compilation cancels
+//! it too.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bson::oid::ObjectId;
+use serde::Serialize;
+use tokio::sync::{Notify, Semaphore};
+
+use super::memory::MemoryUse;
+use super::{CompileError, ProjectWorld};
+use crate::config::CompileConfig;
+
+/// What a compilation is for. Only compilations of the same kind supersede one
+/// another: a PDF download isn't cancelled by a collaborator's live preview.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JobKind {
+    Pdf,
+    Diagnostics,
+    Render,
+    Preview,
+    /// Language analysis: completion, hover and the like.
+    Analysis,
+}
+
+/// How often a running compilation's memory is looked at.
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The queue's configuration and counters, as `GET /api/compile/status`
+/// reports them. The last five count compilations since startup.
+#[derive(Debug, Serialize)]
+pub struct QueueStatus {
+    pub workers: usize,
+    pub max_queued: usize,
+    pub timeout_secs: u64,
+    pub max_memory_bytes: usize,
+    pub running: usize,
+    pub queued: usize,
+    pub completed: u64,
+    pub failed: u64,
+    pub timed_out: u64,
+    pub cancelled: u64,
+    pub rejected: u64,
+}
+
+pub struct CompileQueue {
+    workers: usize,
+    max_queued: usize,
+    timeout: Duration,
+    max_memory_bytes: usize,
+    permits: Arc<Semaphore>,
+    jobs: Mutex<HashMap<(ObjectId, JobKind), Vec<Job>>>,
+    next_id: AtomicU64,
+    counters: Arc<Counters>,
+    overrunning: Overrunning,
+}
+
+/// Projects with compilations still running that their callers gave up on, by
+/// how many.
+type Overrunning = Arc<Mutex<HashMap<ObjectId, usize>>>;
+
+/// A compilation waiting or running, as its group sees it.
+struct Job {
+    id: u64,
+    revision: u64,
+    cancel: Arc<AtomicBool>,
+    /// Wakes the compilation if it is waiting for a worker.
+    wake: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct Counters {
+    running: AtomicUsize,
+    queued: AtomicUsize,
+    completed: AtomicU64,
+    failed: AtomicU64,
+    timed_out: AtomicU64,
+    cancelled: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl Default for CompileQueue {
+    fn default() -> Self {
+        Self::new(&CompileConfig::default())
+    }
+}
+
+impl CompileQueue {
+    pub fn new(config: &CompileConfig) -> Self {
+        // No workers would mean nothing ever compiles.
+        let workers = config.workers.max(1);
+        Self {
+            workers,
+            max_queued: config.max_queued,
+            timeout: Duration::from_secs(config.timeout_secs),
+            max_memory_bytes: config.max_memory_bytes,
+            permits: Arc::new(Semaphore::new(workers)),
+            jobs: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            counters: Arc::default(),
+            overrunning: Arc::default(),
+        }
+    }
+
+    /// Run `compile` over `world` on a worker once one is free.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        project_id: ObjectId,
+        kind: JobKind,
+        world: ProjectWorld,
+        compile: impl FnOnce(&ProjectWorld) -> Result<T, CompileError> + Send + 'static,
+    ) -> Result<T, CompileError> {
+        let counters = &self.counters;
+        let estimate = world.footprint();
+        if estimate > self.max_memory_bytes {
+            counters.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(CompileError::TooLarge {
+                estimate,
+                limit: self.max_memory_bytes,
+            });
+        }
+        if self.overrunning.lock().unwrap().contains_key(&project_id) {
+            counters.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(CompileError::Overrunning);
+        }
+
+        let (permit, job) = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => {
+                let job = self.register(project_id, kind, world.revision());
+                (permit, job)
+            }
+            Err(_) => {
+                if counters.queued.fetch_add(1, Ordering::Relaxed) >= self.max_queued {
+                    counters.queued.fetch_sub(1, Ordering::Relaxed);
+                    counters.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Err(CompileError::Busy);
+                }
+                let _queued = Decrement(&counters.queued);
+                let job = self.register(project_id, kind, world.revision());
+                let permit = tokio::select! {
+                    permit = self.permits.clone().acquire_owned() => {
+                        permit.expect("the semaphore is never closed")
+                    }
+                    // Superseded while waiting: give up the place in the queue
+                    // now rather than when a worker frees up.
+                    () = job.wake.notified() => {
+                        counters.cancelled.fetch_add(1, Ordering::Relaxed);
+                        return Err(CompileError::Cancelled);
+                    }
+                };
+                (permit, job)
+            }
+        };
+        if job.cancelled() {
+            counters.cancelled.fetch_add(1, Ordering::Relaxed);
+            return Err(CompileError::Cancelled);
+        }
+
+        let world = world.with_cancel(job.cancel.clone());
+        let memory = MemoryUse::new(self.max_memory_bytes, job.cancel.clone());
+        let hold = Arc::new(Hold {
+            project_id,
+            overrunning: self.overrunning.clone(),
+            ended: AtomicBool::new(false),
+            abandoned: AtomicBool::new(false),
+        });
+        counters.running.fetch_add(1, Ordering::Relaxed);
+        let running = counters.clone();
+        let (tracked, held) = (memory.clone(), hold.clone());
+        let mut task = tokio::task::spawn_blocking(move || {
+            // Held until the compilation really ends, even past its caller
+            // giving up on it, so abandoned work still occupies its worker.
+            let _permit = permit;
+            let _running = Decrement(&running.running);
+            let _ended = Ended(held);
+            let _tracking = tracked.track();
+            compile(&world)
+        });
+        let deadline = tokio::time::sleep(self.timeout);
+        tokio::pin!(deadline);
+        let mut check = tokio::time::interval(MEMORY_CHECK_INTERVAL);
+        let joined = loop {
+            tokio::select! {
+                joined = &mut task => break joined,
+                () = &mut deadline => {
+                    hold.abandon();
+                    counters.timed_out.fetch_add(1, Ordering::Relaxed);
+                    return Err(CompileError::TimedOut(self.timeout.as_secs()));
+                }
+                _ = check.tick() => {
+                    if let Some(used) = memory.exceeded() {
+                        hold.abandon();
+                        counters.rejected.fetch_add(1, Ordering::Relaxed);
+                        return Err(self.too_large(used));
+                    }
+                }
+            }
+        };
+        let result = joined.unwrap_or(Err(CompileError::Panicked));
+        // A cancelled world fails its reads, so whatever came back may be down
+        // to the cancellation, or to going over the memory limit, rather than
+        // the document.
+        let (counter, result) = match result {
+            _ if let Some(used) = memory.exceeded() => {
+                (&counters.rejected, Err(self.too_large(used)))
+            }
+            _ if job.cancelled() => (&counters.cancelled, Err(CompileError::Cancelled)),
+            Err(e) => (&counters.failed, Err(e)),
+            Ok(output) => (&counters.completed, Ok(output)),
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    fn too_large(&self, used: usize) -> CompileError {
+        CompileError::TooLarge {
+            estimate: used,
+            limit: self.max_memory_bytes,
+        }
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        let counters = &self.counters;
+        QueueStatus {
+            workers: self.workers,
+            max_queued: self.max_queued,
+            timeout_secs: self.timeout.as_secs(),
+            max_memory_bytes: self.max_memory_bytes,
+            running: counters.running.load(Ordering::Relaxed),
+            queued: counters.queued.load(Ordering::Relaxed),
+            completed: counters.completed.load(Ordering::Relaxed),
+            failed: counters.failed.load(Ordering::Relaxed),
+            timed_out: counters.timed_out.load(Ordering::Relaxed),
+            cancelled: counters.cancelled.load(Ordering::Relaxed),
+            rejected: counters.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Add a compilation to its group, cancelling those in it for other
+    /// revisions.
+    fn register(&self, project_id: ObjectId, kind: JobKind, revision: u64) -> Registration<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let wake = Arc::new(Notify::new());
+        let mut jobs = self.jobs.lock().unwrap();
+        let group = jobs.entry((project_id, kind)).or_default();
+        for job in group.iter().filter(|job| job.revision != revision) {
+            job.cancel.store(true, Ordering::Relaxed);
+            job.wake.notify_one();
+        }
+        group.push(Job {
+            id,
+            revision,
+            cancel: cancel.clone(),
+            wake: wake.clone(),
+        });
+        Registration {
+            queue: self,
+            key: (project_id, kind),
+            id,
+            cancel,
+            wake,
+        }
+    }
+}
+
+/// A compilation's place in its group, given up when its caller is done with
+/// it, one way or another. A caller that goes away cancels its compilation.
+struct Registration<'a> {
+    queue: &'a CompileQueue,
+    key: (ObjectId, JobKind),
+    id: u64,
+    cancel: Arc<AtomicBool>,
+    wake: Arc<Notify>,
+}
+
+impl Registration<'_> {
+    fn cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+        let mut jobs = self.queue.jobs.lock().unwrap();
+        if let Some(group) = jobs.get_mut(&self.key) {
+            group.retain(|job| job.id != self.id);
+            if group.is_empty() {
+                jobs.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// A running compilation's standing with its project, which is refused new
+/// compilations while one its caller gave up on still runs.
+struct Hold {
+    project_id: ObjectId,
+    overrunning: Overrunning,
+    /// Both only change under `overrunning`'s lock.
+    ended: AtomicBool,
+    abandoned: AtomicBool,
+}
+
+impl Hold {
+    /// The caller gave up on the compilation; count it against its project
+    /// until it ends, unless it just has.
+    fn abandon(&self) {
+        let mut overrunning = self.overrunning.lock().unwrap();
+        if !self.ended.load(Ordering::Relaxed) {
+            self.abandoned.store(true, Ordering::Relaxed);
+            *overrunning.entry(self.project_id).or_default() += 1;
+        }
+    }
+
+    fn end(&self) {
+        let mut overrunning = self.overrunning.lock().unwrap();
+        self.ended.store(true, Ordering::Relaxed);
+        if self.abandoned.load(Ordering::Relaxed)
+            && let Entry::Occupied(mut count) = overrunning.entry(self.project_id)
+        {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+    }
+}
+
+/// Ends a [`Hold`] when dropped, however the compilation finished.
+struct Ended(Arc<Hold>);
+
+impl Drop for Ended {
+    fn drop(&mut self) {
+        self.0.end();
+    }
+}
+
+/// Decrements a counter when dropped.
+struct Decrement<'a>(&'a AtomicUsize);
+
+impl Drop for Decrement<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::compile::{self, WorldContent, WorldFile};
+    use std::sync::mpsc;
+    use typst::World;
+
+    fn queue(workers: usize, max_queued: usize) -> Arc<CompileQueue> {
+        Arc::new(CompileQueue::new(&CompileConfig {
+            workers,
+            max_queued,
+            ..CompileConfig::default()
+        }))
+    }
+
+    fn world(text: &str) -> ProjectWorld {
+        let main = WorldFile {
+            id: ObjectId::new(),
+            path: "main.typ".to_string(),
+            content: WorldContent::Text(text.to_string()),
+        };
+        ProjectWorld::new(main.id, vec![main]).unwrap()
+    }
+
+    /// A job that holds its worker until `release` is sent to.
+    fn blocked(
+        queue: &Arc<CompileQueue>,
+        project_id: ObjectId,
+        text: &str,
+    ) -> (
+        mpsc::Sender<()>,
+        tokio::task::JoinHandle<Result<Vec<u8>, CompileError>>,
+    ) {
+        let (release, wait) = mpsc::channel();
+        let (queue, world) = (queue.clone(), world(text));
+        let job = tokio::spawn(async move {
+            queue
+                .run(project_id, JobKind::Pdf, world, move |world| {
+                    wait.recv().unwrap();
+                    compile::compile_pdf(world)
+                })
+                .await
+        });
+        (release, job)
+    }
+
+    async fn until(mut done: impl FnMut() -> bool) {
+        while !done() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_turns_jobs_away() {
+        let queue = queue(1, 0);
+        let (release, running) = blocked(&queue, ObjectId::new(), "Hello");
+        until(|| queue.status().running == 1).await;
+
+        let result = queue
+            .run(
+                ObjectId::new(),
+                JobKind::Pdf,
+                world("Hi"),
+                compile::compile_pdf,
+            )
+            .await;
+        assert_eq!(result, Err(CompileError::Busy));
+
+        release.send(()).unwrap();
+        assert!(running.await.unwrap().is_ok());
+        let status = queue.status();
+        assert_eq!((status.completed, status.rejected), (1, 1));
+        assert_eq!((status.running, status.queued), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_newer_revisions_cancel_older_ones() {
+        let queue = queue(2, 4);
+        let project_id = ObjectId::new();
+        let (release, running) = blocked(&queue, project_id, "First");
+        until(|| queue.status().running == 1).await;
+
+        // Other kinds and revisions equal to the running one don't supersede it.
+        let diagnostics = queue
+            .run(project_id, JobKind::Diagnostics, world("Second"), |world| {
+                Ok(compile::diagnose(world))
+            })
+            .await;
+        assert_eq!(diagnostics, Ok(vec![]));
+        let (release_same, same) = blocked(&queue, project_id, "First");
+        until(|| queue.status().running == 2).await;
+        release_same.send(()).unwrap();
+        assert!(same.await.unwrap().is_ok());
+
+        let newer = queue
+            .run(
+                project_id,
+                JobKind::Pdf,
+                world("Second"),
+                compile::compile_pdf,
+            )
+            .await;
+        assert!(newer.unwrap().starts_with(b"%PDF-"));
+        release.send(()).unwrap();
+        assert_eq!(running.await.unwrap(), Err(CompileError::Cancelled));
+        assert_eq!(queue.status().cancelled, 1);
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_jobs_keep_their_worker() {
+        let mut queue = CompileQueue::new(&CompileConfig {
+            workers: 1,
+            ..CompileConfig::default()
+        });
+        queue.timeout = Duration::from_millis(20);
+        let project_id = ObjectId::new();
+        let (release, wait) = mpsc::channel::<()>();
+        let result = queue
+            .run(project_id, JobKind::Pdf, world("Hi"), move |world| {
+                wait.recv().unwrap();
+                compile::compile_pdf(world)
+            })
+            .await;
+        assert_eq!(result, Err(CompileError::TimedOut(0)));
+        assert_eq!(queue.status().running, 1);
+
+        // The project can't start another while that one still runs.
+        let again = queue
+            .run(
+                project_id,
+                JobKind::Render,
+                world("Hi"),
+                compile::compile_pdf,
+            )
+            .await;
+        assert_eq!(again, Err(CompileError::Overrunning));
+
+        release.send(()).unwrap();
+        until(|| queue.status().running == 0).await;
+        assert_eq!(queue.status().timed_out, 1);
+        let again = queue
+            .run(project_id, JobKind::Pdf, world("Hi"), compile::compile_pdf)
+            .await;
+        assert!(again.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_superseded_waiting_jobs_leave_the_queue() {
+        let queue = queue(1, 4);
+        let (release, running) = blocked(&queue, ObjectId::new(), "Hello");
+        until(|| queue.status().running == 1).await;
+
+        let project_id = ObjectId::new();
+        let waiting = {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                queue
+                    .run(
+                        project_id,
+                        JobKind::Pdf,
+                        world("First"),
+                        compile::compile_pdf,
+                    )
+                    .await
+            })
+        };
+        until(|| queue.status().queued == 1).await;
+        let newer = {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                queue
+                    .run(
+                        project_id,
+                        JobKind::Pdf,
+                        world("Second"),
+                        compile::compile_pdf,
+                    )
+                    .await
+            })
+        };
+        // Answered while the worker is still busy.
+        assert_eq!(waiting.await.unwrap(), Err(CompileError::Cancelled));
+        assert_eq!(queue.status().queued, 1);
+
+        release.send(()).unwrap();
+        assert!(running.await.unwrap().is_ok());
+        assert!(newer.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_projects_over_the_memory_limit_are_refused() {
+        let queue = CompileQueue::new(&CompileConfig {
+            max_memory_bytes: 4,
+            ..CompileConfig::default()
+        });
+        let world = world("Hello");
+        assert_eq!(world.footprint(), 5);
+        assert!(world.source(world.main()).is_ok());
+        let result = queue
+            .run(ObjectId::new(), JobKind::Pdf, world, compile::compile_pdf)
+            .await;
+        assert_eq!(
+            result,
+            Err(CompileError::TooLarge {
+                estimate: 5,
+                limit: 4
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_jobs_allocating_past_the_memory_limit_are_stopped() {
+        let queue = CompileQueue::new(&CompileConfig {
+            max_memory_bytes: 1 << 20,
+            ..CompileConfig::default()
+        });
+        let result = queue
+            .run(ObjectId::new(), JobKind::Pdf, world("Hi"), |world| {
+                let big = std::hint::black_box(vec![1u8; 4 << 20]);
+                drop(big);
+                compile::compile_pdf(world)
+            })
+            .await;
+        assert!(matches!(
+            result,
+            Err(CompileError::TooLarge { estimate, limit: 1048576 }) if estimate > 1 << 20
+        ));
+        assert_eq!(queue.status().rejected, 1);
+    }
+}
@@ -0,0 +1,93 @@
+//! Rendering single pages to images.
+
+use serde::Deserialize;
+use typst::layout::Page;
+
+use super::{CompileError, ProjectWorld, compile_document};
+
+/// Default resolution of PNG renders, 2 pixels per point, which stays sharp
+/// on high-density screens.
+pub const DEFAULT_PPI: f32 = 144.0;
+
+/// Highest resolution a PNG may be requested at.
+pub const MAX_PPI: f32 = 600.0;
+
+/// Most pixels a PNG may have (about 8000 × 8000), so one request can't ask
+/// for a raster of arbitrary size: a large page at a high resolution is
+/// refused before it's rasterized.
+pub const MAX_PIXELS: u64 = 64_000_000;
+
+/// Why a page of a compiled document wasn't rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageError {
+    /// The document has no such page.
+    Missing,
+    /// The PNG would have this many pixels, over [`MAX_PIXELS`].
+    TooLarge(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Svg,
+    Png,
+}
+
+impl ImageFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageFormat::Svg => "image/svg+xml",
+            ImageFormat::Png => "image/png",
+        }
+    }
+}
+
+pub fn render_page(page: &Page, format: ImageFormat) -> Vec<u8> {
+    render_page_at(page, format, DEFAULT_PPI)
+}
+
+/// Render a page, a PNG at `ppi` pixels per inch. An SVG is resolution
+/// independent and ignores it.
+pub fn render_page_at(page: &Page, format: ImageFormat, ppi: f32) -> Vec<u8> {
+    match format {
+        ImageFormat::Svg => typst_svg::svg(page).into_bytes(),
+        ImageFormat::Png => typst_render::render(page, ppi / 72.0)
+            .encode_png()
+            .expect("a pixmap encodes to PNG in memory"),
+    }
+}
+
+/// Pixels in a PNG of `page` at `ppi`.
+pub fn png_pixels(page: &Page, ppi: f32) -> u64 {
+    let size = page.frame.size();
+    let scale = f64::from(ppi) / 72.0;
+    let side = |pt: f64| (pt * scale).round().max(1.0) as u64;
+    side(size.x.to_pt()).saturating_mul(side(size.y.to_pt()))
+}
+
+/// Compile the world and render its page at `index` (zero-based), unless the
+/// document has no such page or its PNG would be over [`MAX_PIXELS`].
+pub fn render_document_page(
+    world: &ProjectWorld,
+    index: usize,
+    format: ImageFormat,
+    ppi: f32,
+) -> Result<Result<Vec<u8>, PageError>, CompileError> {
+    let document = compile_document(world)?;
+    let Some(page) = document.pages.get(index) else {
+        return Ok(Err(PageError::Missing));
+    };
+    if format == ImageFormat::Png {
+        let pixels = png_pixels(page, ppi);
+        if pixels > MAX_PIXELS {
+            return Ok(Err(PageError::TooLarge(pixels)));
+        }
+    }
+    Ok(Ok(render_page_at(page, format, ppi)))
+}
+
+/// A fingerprint of everything a page renders from. Equal hashes mean the
+/// page would render to the same image, so it needn't be rendered again.
+pub fn page_hash(page: &Page) -> u128 {
+    typst::utils::hash128(page)
+}
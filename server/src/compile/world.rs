@@ -0,0 +1,317 @@
+//! The Typst [`World`] a project compiles in.
+//!
+//! Everything is read up front: text files become [`Source`]s, binary files
+//! arrive as bytes already fetched from object storage, and imported packages
+//! arrive already loaded. Compilation is then pure CPU work with no I/O, which
+//! is what lets it run on a blocking thread.
+//!
+//! Having everything in hand also lets the world say what it will cost before
+//! it is compiled ([`ProjectWorld::footprint`]) and which revision of the
+//! project it is ([`ProjectWorld::revision`]), which the compile queue uses to
+//! turn away oversized projects and to cancel superseded compilations.
+
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+
+use bson::oid::ObjectId;
+use time::{OffsetDateTime, UtcOffset};
+use typst::diag::{FileError, FileResult, PackageError};
+use typst::foundations::{Bytes, Datetime};
+use typst::syntax::{FileId, Source, VirtualPath};
+use typst::text::{Font, FontBook};
+use typst::utils::LazyHash;
+use typst::{Library, LibraryExt, World};
+use typst_ide::IdeWorld;
+
+use super::package::{Packages, imports};
+
+/// The standard library, shared by every compilation.
+static LIBRARY: LazyLock<LazyHash<Library>> = LazyLock::new(|| LazyHash::new(Library::default()));
+
+/// Fonts bundled with the server (`typst-assets`), parsed once.
+static FONTS: LazyLock<(LazyHash<FontBook>, Vec<Font>)> = LazyLock::new(|| {
+    let fonts: Vec<Font> = typst_assets::fonts()
+        .flat_map(|data| Font::iter(Bytes::new(data)))
+        .collect();
+    (LazyHash::new(FontBook::from_fonts(&fonts)), fonts)
+});
+
+/// A project file as the world sees it.
+pub struct WorldFile {
+    /// The `ProjectFile` id, which diagnostics point at instead of the path.
+    pub id: ObjectId,
+    pub path: String,
+    pub content: WorldContent,
+}
+
+pub enum WorldContent {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// A project's files, keyed by their path from the project root, with one of
+/// them as the entry. Cloning is cheap: sources, bytes, packages and fonts are
+/// all shared.
+#[derive(Clone)]
+pub struct ProjectWorld {
+    main: FileId,
+    sources: HashMap<FileId, Source>,
+    binaries: HashMap<FileId, Bytes>,
+    project_files: HashMap<FileId, ObjectId>,
+    packages: Packages,
+    /// The bundled fonts plus the project's own (its team's), or `None` for
+    /// the bundled fonts alone.
+    fonts: Option<(LazyHash<FontBook>, Vec<Font>)>,
+    now: OffsetDateTime,
+    revision: u64,
+    footprint: usize,
+    /// Set to abandon the compilation; see [`Self::with_cancel`].
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl ProjectWorld {
+    /// The world over `files`, starting from the file whose id is `entry`.
+    /// `None` if no such file is among them.
+    pub fn new(entry: ObjectId, files: impl IntoIterator<Item = WorldFile>) -> Option<Self> {
+        let mut main = None;
+        let mut sources = HashMap::new();
+        let mut binaries = HashMap::new();
+        let mut project_files = HashMap::new();
+        let mut revision = DefaultHasher::new();
+        let mut footprint = 0;
+        for file in files {
+            let id = file_id(&file.path);
+            if file.id == entry {
+                main = Some(id);
+            }
+            project_files.insert(id, file.id);
+            file.path.hash(&mut revision);
+            match file.content {
+                WorldContent::Text(text) => {
+                    text.hash(&mut revision);
+                    footprint += text.len();
+                    sources.insert(id, Source::new(id, text));
+                }
+                WorldContent::Binary(bytes) => {
+                    bytes.hash(&mut revision);
+                    footprint += bytes.len() + decoded_size(&bytes);
+                    binaries.insert(id, Bytes::new(bytes));
+                }
+            }
+        }
+        main.hash(&mut revision);
+        Some(Self {
+            main: main?,
+            sources,
+            binaries,
+            project_files,
+            packages: Packages::new(),
+            fonts: None,
+            now: OffsetDateTime::now_utc(),
+            revision: revision.finish(),
+            footprint,
+            cancel: None,
+        })
+    }
+
+    /// The world with `packages` importable. A package not among them is
+    /// reported as not found.
+    pub fn with_packages(mut self, packages: Packages) -> Self {
+        self.footprint += packages
+            .values()
+            .flatten()
+            .map(|package| package.size())
+            .sum::<usize>();
+        self.packages = packages;
+        self
+    }
+
+    /// The world with `fonts` available besides the bundled ones.
+    pub fn with_fonts(mut self, fonts: Vec<Font>) -> Self {
+        if !fonts.is_empty() {
+            let book = FontBook::from_fonts(FONTS.1.iter().chain(&fonts));
+            self.footprint += fonts.iter().map(|font| font.data().len()).sum::<usize>();
+            self.fonts = Some((LazyHash::new(book), fonts));
+        }
+        self
+    }
+
+    /// The world with `cancel` checked whenever the compiler reads a file:
+    /// once it is set, reads fail and the compilation winds down with errors.
+    /// Typst has no way to interrupt a compilation, so this is as close as it
+    /// gets; a document that stops reading files runs to its end regardless.
+    pub fn with_cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Which revision of the project this is: a hash of its files and entry,
+    /// equal for two worlds over the same content.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// An estimate of the memory compiling takes, in bytes: the files, fonts
+    /// and packages, plus each raster image decoded to RGBA. Layout and the
+    /// output come on top, so this is a floor rather than a measurement.
+    pub fn footprint(&self) -> usize {
+        self.footprint
+    }
+
+    fn check_cancelled(&self) -> FileResult<()> {
+        match &self.cancel {
+            Some(cancel) if cancel.load(Ordering::Relaxed) => {
+                Err(FileError::Other(Some("compilation cancelled".into())))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Bring the sources up to date with `texts`, by `ProjectFile` id,
+    /// re-parsing only what changed. `false` if one of the files isn't in
+    /// this world, or now imports a package it hasn't loaded: the world needs
+    /// building again.
+    pub fn update_sources(&mut self, texts: impl IntoIterator<Item = (ObjectId, String)>) -> bool {
+        let ids: HashMap<ObjectId, FileId> = self
+            .project_files
+            .iter()
+            .map(|(id, file)| (*file, *id))
+            .collect();
+        let mut revision = DefaultHasher::new();
+        self.revision.hash(&mut revision);
+        for (file, text) in texts {
+            let Some(source) = ids.get(&file).and_then(|id| self.sources.get_mut(id)) else {
+                return false;
+            };
+            if source.text() == text {
+                continue;
+            }
+            source.replace(&text);
+            if !imports(&text)
+                .iter()
+                .all(|spec| self.packages.contains_key(spec))
+            {
+                return false;
+            }
+            (file, text).hash(&mut revision);
+        }
+        self.revision = revision.finish();
+        true
+    }
+
+    /// The project's own sources, packages' aside.
+    pub fn project_sources(&self) -> impl Iterator<Item = &Source> {
+        self.sources.values()
+    }
+
+    /// The `ProjectFile` a Typst file id stands for; `None` for a file outside
+    /// the project, such as one in a package.
+    pub fn project_file(&self, id: FileId) -> Option<ObjectId> {
+        self.project_files.get(&id).copied()
+    }
+}
+
+/// The size of a raster image decoded to RGBA, from its header alone; 0 for
+/// anything that isn't a raster image.
+fn decoded_size(bytes: &[u8]) -> usize {
+    image::ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok())
+        .map_or(0, |(width, height)| width as usize * height as usize * 4)
+}
+
+/// The id of a project file. Paths are rooted at the project, so `#import`
+/// and `#image` resolve the same way from whichever file is the entry.
+fn file_id(path: &str) -> FileId {
+    FileId::new(None, VirtualPath::new(path))
+}
+
+impl IdeWorld for ProjectWorld {
+    fn upcast(&self) -> &dyn World {
+        self
+    }
+
+    fn files(&self) -> Vec<FileId> {
+        self.project_files.keys().copied().collect()
+    }
+}
+
+impl World for ProjectWorld {
+    fn library(&self) -> &LazyHash<Library> {
+        &LIBRARY
+    }
+
+    fn book(&self) -> &LazyHash<FontBook> {
+        match &self.fonts {
+            Some((book, _)) => book,
+            None => &FONTS.0,
+        }
+    }
+
+    fn main(&self) -> FileId {
+        self.main
+    }
+
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        self.check_cancelled()?;
+        if let Some(source) = self.sources.get(&id) {
+            return Ok(source.clone());
+        }
+        if id.package().is_some() {
+            let text = std::str::from_utf8(&self.file(id)?)
+                .map_err(|_| FileError::InvalidUtf8)?
+                .to_owned();
+            return Ok(Source::new(id, text));
+        }
+        // Not a text file: report why (a package, a missing file), or that a
+        // binary was imported as a source.
+        self.file(id)?;
+        Err(FileError::NotSource)
+    }
+
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        self.check_cancelled()?;
+        if let Some(spec) = id.package() {
+            return match self.packages.get(spec) {
+                Some(Ok(package)) => package
+                    .file(id.vpath())
+                    .cloned()
+                    .ok_or_else(|| not_found(id)),
+                Some(Err(e)) => Err(FileError::Package(e.clone())),
+                None => Err(FileError::Package(PackageError::NotFound(spec.clone()))),
+            };
+        }
+        if let Some(bytes) = self.binaries.get(&id) {
+            return Ok(bytes.clone());
+        }
+        self.sources
+            .get(&id)
+            .map(|source| Bytes::from_string(source.text().to_owned()))
+            .ok_or_else(|| not_found(id))
+    }
+
+    fn font(&self, index: usize) -> Option<Font> {
+        // The book lists the bundled fonts first, then the project's.
+        let bundled = &FONTS.1;
+        match index.checked_sub(bundled.len()) {
+            Some(index) => self.fonts.as_ref()?.1.get(index).cloned(),
+            None => bundled.get(index).cloned(),
+        }
+    }
+
+    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
+        // Without an explicit offset "local" means the server's clock, and a
+        // shared server has no meaningful local zone, so UTC it is.
+        let offset = UtcOffset::from_hms(offset.unwrap_or(0).try_into().ok()?, 0, 0).ok()?;
+        let today = self.now.to_offset(offset).date();
+        Datetime::from_ymd(today.year(), today.month().into(), today.day())
+    }
+}
+
+fn not_found(id: FileId) -> FileError {
+    FileError::NotFound(id.vpath().as_rooted_path().into())
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use actix_cors::Cors;
 use serde::Deserialize;
 mod cors;
@@ -23,6 +25,32 @@ pub struct WsConfig {
     /// Seconds between CRDT-to-MongoDB persistence flushes.
     #[serde(default = "WsConfig::default_persist_interval_secs")]
     pub persist_interval_secs: u64,
+    /// Seconds a room with no connections stays in memory before it is
+    /// snapshotted and evicted. Ignored without object storage, since an
+    /// evicted room can only come back from its snapshot.
+    #[serde(default = "WsConfig::default_room_idle_timeout_secs")]
+    pub room_idle_timeout_secs: u64,
+    /// Milliseconds a room's document must stay unchanged before the live
+    /// preview is re-rendered for the connections subscribed to it.
+    #[serde(default = "WsConfig::default_preview_debounce_ms")]
+    pub preview_debounce_ms: u64,
+    /// Longest wait, in seconds, between retries of a failed room write. The
+    /// first retry waits one persist interval, doubling from there.
+    #[serde(default = "WsConfig::default_persist_retry_max_secs")]
+    pub persist_retry_max_secs: u64,
+    /// Seconds a room may hold edits that aren't in MongoDB before it is
+    /// logged and reported as degraded by the health check.
+    #[serde(default = "WsConfig::default_unsaved_alert_secs")]
+    pub unsaved_alert_secs: u64,
+    /// Room-manager threads. Each project's room lives on the one its id
+    /// hashes to, so a busy document only holds up the rooms sharing its
+    /// thread. Defaults to the number of CPUs.
+    #[serde(default = "WsConfig::default_room_shards")]
+    pub room_shards: usize,
+    /// Seconds a shutdown waits for the rooms to flush before the server
+    /// stops anyway. A second signal stops it at once.
+    #[serde(default = "WsConfig::default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
 }
 
 impl WsConfig {
@@ -35,6 +63,24 @@ impl WsConfig {
     fn default_persist_interval_secs() -> u64 {
         3
     }
+    fn default_room_idle_timeout_secs() -> u64 {
+        60
+    }
+    fn default_preview_debounce_ms() -> u64 {
+        300
+    }
+    fn default_persist_retry_max_secs() -> u64 {
+        300
+    }
+    fn default_unsaved_alert_secs() -> u64 {
+        60
+    }
+    fn default_room_shards() -> usize {
+        std::thread::available_parallelism().map_or(1, |cpus| cpus.get())
+    }
+    fn default_shutdown_timeout_secs() -> u64 {
+        30
+    }
 }
 
 impl Default for WsConfig {
@@ -43,21 +89,44 @@ impl Default for WsConfig {
             heartbeat_interval_secs: Self::default_heartbeat_interval_secs(),
             client_timeout_secs: Self::default_client_timeout_secs(),
             persist_interval_secs: Self::default_persist_interval_secs(),
+            room_idle_timeout_secs: Self::default_room_idle_timeout_secs(),
+            preview_debounce_ms: Self::default_preview_debounce_ms(),
+            persist_retry_max_secs: Self::default_persist_retry_max_secs(),
+            unsaved_alert_secs: Self::default_unsaved_alert_secs(),
+            room_shards: Self::default_room_shards(),
+            shutdown_timeout_secs: Self::default_shutdown_timeout_secs(),
         }
     }
 }
 
-/// Object-storage (MinIO / S3) connection settings. Optional so a checkout can
-/// run without a storage backend configured; wired into an [`ObjectStore`] by
-/// whichever component first needs blob storage.
+/// Object-storage settings. Optional so a checkout can run without a storage
+/// backend configured; turned into an [`ObjectStore`] by
+/// [`storage::from_config`] at startup. `backend` picks the implementation:
+///
+/// ```yaml
+/// storage:
+///   backend: filesystem
+///   root: /var/lib/caduceus/objects
+/// ```
 ///
 /// [`ObjectStore`]: crate::storage::ObjectStore
+/// [`storage::from_config`]: crate::storage::from_config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    /// MinIO or any S3-compatible endpoint.
+    Minio(MinioConfig),
+    /// A directory on the local disk, for single-box deployments.
+    Filesystem(FilesystemConfig),
+}
+
+/// MinIO / S3 connection settings.
 #[derive(Debug, Clone, Deserialize)]
-pub struct StorageConfig {
+pub struct MinioConfig {
     /// Full base URL of the endpoint, e.g. `http://localhost:9000`.
     pub endpoint: String,
     /// S3 region. Arbitrary for MinIO but part of the request signature.
-    #[serde(default = "StorageConfig::default_region")]
+    #[serde(default = "MinioConfig::default_region")]
     pub region: String,
     /// Bucket that holds `blobs/{sha256}` objects.
     pub bucket: String,
@@ -65,12 +134,211 @@ pub struct StorageConfig {
     pub secret_key: String,
 }
 
-impl StorageConfig {
+impl MinioConfig {
     fn default_region() -> String {
         "us-east-1".to_string()
     }
 }
 
+/// Local-directory storage settings.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilesystemConfig {
+    /// Directory that holds `blobs/` and named objects. Created if missing;
+    /// should be on one filesystem, since writes rename into place.
+    pub root: PathBuf,
+}
+
+/// Limits on REST asset uploads and project imports.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadConfig {
+    /// Largest accepted asset, in bytes. Enforced while the body streams in,
+    /// so an oversized upload is rejected before it's buffered whole. Also the
+    /// largest file an imported archive may unpack.
+    #[serde(default = "UploadConfig::default_max_bytes")]
+    pub max_bytes: usize,
+    /// Largest accepted zip archive for an import, in bytes.
+    #[serde(default = "UploadConfig::default_max_archive_bytes")]
+    pub max_archive_bytes: usize,
+    /// Most an imported archive may unpack to in total, in bytes.
+    #[serde(default = "UploadConfig::default_max_unpacked_bytes")]
+    pub max_unpacked_bytes: usize,
+    /// Most entries an imported archive may hold.
+    #[serde(default = "UploadConfig::default_max_archive_files")]
+    pub max_archive_files: usize,
+}
+
+impl UploadConfig {
+    fn default_max_bytes() -> usize {
+        32 * 1024 * 1024
+    }
+
+    fn default_max_archive_bytes() -> usize {
+        64 * 1024 * 1024
+    }
+
+    fn default_max_unpacked_bytes() -> usize {
+        256 * 1024 * 1024
+    }
+
+    fn default_max_archive_files() -> usize {
+        2000
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: Self::default_max_bytes(),
+            max_archive_bytes: Self::default_max_archive_bytes(),
+            max_unpacked_bytes: Self::default_max_unpacked_bytes(),
+            max_archive_files: Self::default_max_archive_files(),
+        }
+    }
+}
+
+/// Page renders (`GET /api/project/{id}/render`).
+#[derive(Debug, Clone, Deserialize)]
+pub struct RenderConfig {
+    /// Memory budget of the render cache, in bytes.
+    #[serde(default = "RenderConfig::default_cache_bytes")]
+    pub cache_bytes: usize,
+}
+
+impl RenderConfig {
+    fn default_cache_bytes() -> usize {
+        64 * 1024 * 1024
+    }
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            cache_bytes: Self::default_cache_bytes(),
+        }
+    }
+}
+
+/// The compile queue (see `compile::queue`) every compilation waits in, from
+/// REST compiles and renders to live previews.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompileConfig {
+    /// Compilations running at once. Defaults to the number of CPUs.
+    #[serde(default = "CompileConfig::default_workers")]
+    pub workers: usize,
+    /// Compilations that may wait for a worker; past that, new ones are
+    /// turned away until the queue drains.
+    #[serde(default = "CompileConfig::default_max_queued")]
+    pub max_queued: usize,
+    /// Seconds a compilation may run before its caller gets a timeout.
+    #[serde(default = "CompileConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Memory, in bytes, a compilation may take. A project whose files, fonts
+    /// and packages (raster images counted decoded) come to more isn't
+    /// compiled, and one that allocates more while compiling is stopped.
+    #[serde(default = "CompileConfig::default_max_memory_bytes")]
+    pub max_memory_bytes: usize,
+    /// Memory budget, in bytes, of the team fonts kept parsed between
+    /// compilations.
+    #[serde(default = "CompileConfig::default_font_cache_bytes")]
+    pub font_cache_bytes: usize,
+}
+
+impl CompileConfig {
+    fn default_workers() -> usize {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    }
+    fn default_max_queued() -> usize {
+        64
+    }
+    fn default_timeout_secs() -> u64 {
+        30
+    }
+    fn default_max_memory_bytes() -> usize {
+        1024 * 1024 * 1024
+    }
+    fn default_font_cache_bytes() -> usize {
+        128 * 1024 * 1024
+    }
+}
+
+impl Default for CompileConfig {
+    fn default() -> Self {
+        Self {
+            workers: Self::default_workers(),
+            max_queued: Self::default_max_queued(),
+            timeout_secs: Self::default_timeout_secs(),
+            max_memory_bytes: Self::default_max_memory_bytes(),
+            font_cache_bytes: Self::default_font_cache_bytes(),
+        }
+    }
+}
+
+/// Typst packages (see `compile::package`). The server has no internet
+/// access, so `@preview` and `@local` packages come from these directories and
+/// from tarballs uploaded through the API.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PackagesConfig {
+    /// Unpacked packages, at `{cache_dir}/{namespace}/{name}/{version}/`.
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+    /// Mirrored tarballs, at `{mirror_dir}/{namespace}/{name}-{version}.tar.gz`.
+    #[serde(default)]
+    pub mirror_dir: Option<PathBuf>,
+    /// Ids of the users allowed to upload packages.
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// Memory budget of the loaded packages, in bytes.
+    #[serde(default = "PackagesConfig::default_cache_bytes")]
+    pub cache_bytes: usize,
+}
+
+impl PackagesConfig {
+    fn default_cache_bytes() -> usize {
+        256 * 1024 * 1024
+    }
+}
+
+impl Default for PackagesConfig {
+    fn default() -> Self {
+        Self {
+            cache_dir: None,
+            mirror_dir: None,
+            admins: Vec::new(),
+            cache_bytes: Self::default_cache_bytes(),
+        }
+    }
+}
+
+/// Blob garbage collection (see `services::gc`). Only runs when this section
+/// and `storage` are both configured. Defaults to a daily dry run, so turning
+/// it on first reports what it would delete; set `dry_run: false` to sweep.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GcConfig {
+    /// Seconds between passes.
+    #[serde(default = "GcConfig::default_interval_secs")]
+    pub interval_secs: u64,
+    /// Minimum age, in seconds, of an unreferenced blob before it's swept.
+    /// Must comfortably exceed the time between a blob upload and recording
+    /// its hash.
+    #[serde(default = "GcConfig::default_grace_secs")]
+    pub grace_secs: u64,
+    /// Log what would be swept without deleting anything.
+    #[serde(default = "GcConfig::default_dry_run")]
+    pub dry_run: bool,
+}
+
+impl GcConfig {
+    fn default_interval_secs() -> u64 {
+        24 * 60 * 60
+    }
+    fn default_grace_secs() -> u64 {
+        24 * 60 * 60
+    }
+    fn default_dry_run() -> bool {
+        true
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     cors: Option<CorsConfig>,
@@ -82,6 +350,16 @@ pub struct Config {
     pub ws: WsConfig,
     #[serde(default)]
     pub storage: Option<StorageConfig>,
+    #[serde(default)]
+    pub upload: UploadConfig,
+    #[serde(default)]
+    pub render: RenderConfig,
+    #[serde(default)]
+    pub packages: PackagesConfig,
+    #[serde(default)]
+    pub compile: CompileConfig,
+    #[serde(default)]
+    pub gc: Option<GcConfig>,
 }
 
 impl Config {
@@ -126,6 +404,7 @@ mod tests {
         test, web, App, HttpResponse,
     };
     use serial_test::serial;
+    use std::path::Path;
 
     #[tokio::test]
     #[serial]
@@ -137,6 +416,30 @@ mod tests {
         assert!(!config.jwt_secret.is_empty());
     }
 
+    #[tokio::test]
+    async fn test_storage_config_selects_backend() {
+        let parse = |yaml: &str| {
+            config::Config::builder()
+                .add_source(config::File::from_str(yaml, config::FileFormat::Yaml))
+                .build()
+                .unwrap()
+                .try_deserialize::<StorageConfig>()
+        };
+
+        let fs = parse("backend: filesystem\nroot: /var/lib/caduceus").unwrap();
+        assert!(
+            matches!(fs, StorageConfig::Filesystem(c) if c.root == Path::new("/var/lib/caduceus"))
+        );
+
+        let minio = parse(
+            "backend: minio\nendpoint: http://localhost:9000\nbucket: caduceus\naccess_key: a\nsecret_key: b",
+        )
+        .unwrap();
+        assert!(matches!(minio, StorageConfig::Minio(c) if c.region == "us-east-1"));
+
+        assert!(parse("root: /var/lib/caduceus").is_err());
+    }
+
     #[tokio::test]
     #[serial]
     async fn test_config_load_nonsexists() {
@@ -164,6 +467,11 @@ mod tests {
             jwt_secret: "secret".to_string(),
             ws: WsConfig::default(),
             storage: None,
+            upload: UploadConfig::default(),
+            render: RenderConfig::default(),
+            packages: PackagesConfig::default(),
+            compile: CompileConfig::default(),
+            gc: None,
         };
 
         let app = test::init_service(
@@ -11,8 +11,12 @@
 //! [`ProjectTree`] domain model: [`read_tree`] decodes, [`write_node`] /
 //! [`write_tree`] encode. It performs no validation — the caller runs
 //! [`ProjectTree::validate`] on the decoded tree. Field-level *mutation* (the
-//! authority applying a single incoming change) is a later, room-layer concern;
-//! here `write_*` set whole nodes, which is what seeding and tests need.
+//! authority applying a single incoming change) is a room-layer concern; here
+//! `write_*` set whole nodes, which is what seeding and tests need, and
+//! [`restore_tree`] is the authority's rollback: it rewrites the map back to a
+//! previously accepted tree.
+
+use std::collections::HashSet;
 
 use yrs::{Any, Doc, Map, MapPrelim, MapRef, Out, ReadTxn, TransactionMut};
 
@@ -116,6 +120,47 @@ pub fn write_tree(txn: &mut TransactionMut, nodes: &MapRef, tree: &ProjectTree)
     }
 }
 
+/// Rewrite the `nodes` map so it decodes to exactly `tree` again, touching only
+/// the entries that differ: ids absent from `tree` are removed, and entries that
+/// are missing, malformed, or decode to a different node are rewritten. Returns
+/// whether anything was written.
+///
+/// This is how the authority rolls back an illegal structural change. The
+/// compensating ops are ordinary CRDT writes, so every peer (including the one
+/// that made the change) converges on the restored tree once they're synced.
+/// Untouched entries keep their cells, so an unrelated concurrent field edit to
+/// another node still merges.
+pub fn restore_tree(txn: &mut TransactionMut, nodes: &MapRef, tree: &ProjectTree) -> bool {
+    let mut remove = Vec::new();
+    let mut intact = HashSet::new();
+    for (id, value) in nodes.iter(txn) {
+        match tree.get(id) {
+            None => remove.push(id.to_string()),
+            Some(want) => {
+                let same = match value {
+                    Out::YMap(m) => read_node(txn, id, &m).is_ok_and(|have| &have == want),
+                    _ => false,
+                };
+                if same {
+                    intact.insert(id.to_string());
+                }
+            }
+        }
+    }
+
+    let rewrite: Vec<&Node> = tree.iter().filter(|n| !intact.contains(&n.id)).collect();
+    let changed = !remove.is_empty() || !rewrite.is_empty();
+    for id in remove {
+        nodes.remove(txn, &id);
+    }
+    // `write_node` replaces the whole entry, so a malformed or diverged node
+    // doesn't keep any of its bad cells.
+    for node in rewrite {
+        write_node(txn, nodes, node);
+    }
+    changed
+}
+
 fn req_str<T: ReadTxn>(
     txn: &T,
     m: &MapRef,
@@ -249,6 +294,47 @@ mod tests {
         assert_eq!(read_tree(&txn, &nodes).unwrap().get("r").unwrap().parent, None);
     }
 
+    #[test]
+    fn test_restore_tree_undoes_changes_and_keeps_intact_entries() {
+        let accepted = ProjectTree::from_nodes([
+            folder("d", None, "chapters"),
+            file("f", Some("d"), "intro.typ", 1),
+        ]);
+        let doc = Doc::new();
+        let nodes = nodes_map(&doc);
+        {
+            let mut txn = doc.transact_mut();
+            write_tree(&mut txn, &nodes, &accepted);
+        }
+        // Diverge: rename one node, add a stray one, leave `d` untouched.
+        let untouched = {
+            let mut txn = doc.transact_mut();
+            write_node(&mut txn, &nodes, &file("f", Some("d"), "renamed.typ", 1));
+            write_node(&mut txn, &nodes, &file("x", None, "stray.typ", 1));
+            match nodes.get(&txn, "d").unwrap() {
+                Out::YMap(m) => m,
+                _ => panic!("expected a map"),
+            }
+        };
+
+        {
+            let mut txn = doc.transact_mut();
+            assert!(restore_tree(&mut txn, &nodes, &accepted));
+        }
+        let txn = doc.transact();
+        assert_eq!(read_tree(&txn, &nodes).unwrap(), accepted);
+        // The unchanged entry is the same CRDT map, not a rewritten copy.
+        match nodes.get(&txn, "d").unwrap() {
+            Out::YMap(m) => assert_eq!(m, untouched),
+            _ => panic!("expected a map"),
+        }
+        drop(txn);
+
+        // Already matching: nothing to write.
+        let mut txn = doc.transact_mut();
+        assert!(!restore_tree(&mut txn, &nodes, &accepted));
+    }
+
     #[test]
     fn test_missing_field_is_a_codec_error() {
         // A node map with a kind but no name.
@@ -5,16 +5,27 @@
 //! opposed to the immutable content-addressed `blobs/{sha}`. Loading rebuilds a
 //! `Doc` by applying that update onto an empty one.
 //!
+//! Alongside the update, a snapshot records the stored version of each file its
+//! text derives from, so a room rehydrating from it can tell which files were
+//! saved outside the room since (see [`Snapshot::versions`]). Snapshots written
+//! before versions were recorded are a bare update, and load with none.
+//!
 //! This is the durable source of truth for a room's CRDT state: a room rehydrates
 //! from its snapshot on cold start (rather than re-seeding from text, which would
 //! duplicate content), and the Mongo projection is a rebuildable cache derived
 //! from it.
 
+use std::collections::HashMap;
+
 use yrs::updates::decoder::Decode;
 use yrs::{Doc, ReadTxn, StateVector, Transact, Update};
 
 use crate::storage::{ObjectStore, StorageError};
 
+/// Leads a snapshot that records file versions: then the length of the JSON
+/// versions map (`u32`, little-endian), the map, and the update.
+const MAGIC: &[u8] = b"YSNAPv1\0";
+
 /// A snapshot couldn't be persisted or restored.
 #[derive(Debug, derive_more::Display)]
 pub enum SnapshotError {
@@ -33,6 +44,15 @@ impl From<StorageError> for SnapshotError {
     }
 }
 
+/// A project's Y.Doc as last snapshotted.
+pub struct Snapshot {
+    pub doc: Doc,
+    /// Text-root key (file id hex) -> the stored version the file's text in
+    /// `doc` was saved at or edited on top of. A file the snapshot has no
+    /// version for may have been saved since.
+    pub versions: HashMap<String, i32>,
+}
+
 /// Object key for a project's Y.Doc snapshot.
 fn snapshot_key(project_id: &str) -> String {
     format!("ydoc/{project_id}")
@@ -44,34 +64,60 @@ pub fn encode_doc(doc: &Doc) -> Vec<u8> {
         .encode_state_as_update_v1(&StateVector::default())
 }
 
-/// Save `doc`'s full state to `ydoc/{project_id}`, replacing any prior snapshot.
+/// Encode `doc` with the stored `versions` of its files, as a snapshot.
+pub fn encode_snapshot(doc: &Doc, versions: &HashMap<String, i32>) -> Vec<u8> {
+    let meta = serde_json::to_vec(versions).expect("a map of versions serializes");
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&meta);
+    bytes.extend_from_slice(&encode_doc(doc));
+    bytes
+}
+
+/// Decode a snapshot made by [`encode_snapshot`], or a bare update written
+/// before versions were recorded.
+pub fn decode_snapshot(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+    let (versions, update) = match bytes.strip_prefix(MAGIC) {
+        Some(rest) => split_versions(rest)
+            .ok_or_else(|| SnapshotError::Decode("malformed file versions".to_string()))?,
+        None => (HashMap::new(), bytes),
+    };
+    let update = Update::decode_v1(update).map_err(|e| SnapshotError::Decode(e.to_string()))?;
+    let doc = Doc::new();
+    doc.transact_mut()
+        .apply_update(update)
+        .map_err(|e| SnapshotError::Decode(e.to_string()))?;
+    Ok(Snapshot { doc, versions })
+}
+
+/// Split what follows [`MAGIC`] into the versions map and the update.
+fn split_versions(bytes: &[u8]) -> Option<(HashMap<String, i32>, &[u8])> {
+    let (len, rest) = bytes.split_first_chunk::<4>()?;
+    let (meta, update) = rest.split_at_checked(u32::from_le_bytes(*len) as usize)?;
+    Some((serde_json::from_slice(meta).ok()?, update))
+}
+
+/// Save snapshot `bytes` (see [`encode_snapshot`]) to `ydoc/{project_id}`,
+/// replacing any prior snapshot.
 pub async fn save_snapshot(
     store: &dyn ObjectStore,
     project_id: &str,
-    doc: &Doc,
+    bytes: &[u8],
 ) -> Result<(), SnapshotError> {
-    store
-        .put_object(&snapshot_key(project_id), &encode_doc(doc))
-        .await?;
+    store.put_object(&snapshot_key(project_id), bytes).await?;
     Ok(())
 }
 
-/// Load a project's `Doc` from its snapshot, or `None` if it has none yet (a
-/// brand-new project). Errors only if a snapshot exists but is corrupt.
+/// Load a project's snapshot, or `None` if it has none yet (a brand-new
+/// project). Errors only if a snapshot exists but is corrupt.
 pub async fn load_snapshot(
     store: &dyn ObjectStore,
     project_id: &str,
-) -> Result<Option<Doc>, SnapshotError> {
+) -> Result<Option<Snapshot>, SnapshotError> {
     let Some(bytes) = store.get_object(&snapshot_key(project_id)).await? else {
         return Ok(None);
     };
-    let update =
-        Update::decode_v1(&bytes).map_err(|e| SnapshotError::Decode(e.to_string()))?;
-    let doc = Doc::new();
-    doc.transact_mut()
-        .apply_update(update)
-        .map_err(|e| SnapshotError::Decode(e.to_string()))?;
-    Ok(Some(doc))
+    decode_snapshot(&bytes).map(Some)
 }
 
 #[cfg(test)]
@@ -116,15 +162,30 @@ mod tests {
             let mut txn = doc.transact_mut();
             write_tree(&mut txn, &nodes, &tree);
         }
-        save_snapshot(&store, "proj1", &doc).await.unwrap();
+        let versions = HashMap::from([("f".to_string(), 3)]);
+        save_snapshot(&store, "proj1", &encode_snapshot(&doc, &versions))
+            .await
+            .unwrap();
 
         // Load into a fresh doc and decode the tree back. Take the map handle
         // *before* opening the read txn — yrs allows only one live transaction
         // per doc, so creating the map inside the same expression would deadlock.
         let loaded = load_snapshot(&store, "proj1").await.unwrap().unwrap();
-        let nodes = nodes_map(&loaded);
-        let read = read_tree(&loaded.transact(), &nodes).unwrap();
+        let nodes = nodes_map(&loaded.doc);
+        let read = read_tree(&loaded.doc.transact(), &nodes).unwrap();
         assert_eq!(read, tree);
+        assert_eq!(loaded.versions, versions);
+    }
+
+    #[test]
+    fn test_bare_update_decodes_without_versions() {
+        let doc = Doc::new();
+        let nodes = nodes_map(&doc);
+        write_tree(&mut doc.transact_mut(), &nodes, &sample_tree());
+
+        let loaded = decode_snapshot(&encode_doc(&doc)).unwrap();
+        assert!(loaded.versions.is_empty());
+        assert_eq!(encode_doc(&loaded.doc), encode_doc(&doc));
     }
 
     #[tokio::test]
@@ -141,5 +202,11 @@ mod tests {
             load_snapshot(&store, "proj1").await,
             Err(SnapshotError::Decode(_))
         ));
+        let mut truncated = MAGIC.to_vec();
+        truncated.extend_from_slice(&100u32.to_le_bytes());
+        assert!(matches!(
+            decode_snapshot(&truncated),
+            Err(SnapshotError::Decode(_))
+        ));
     }
 }
@@ -0,0 +1,11 @@
+use actix_web::{HttpResponse, Result, web};
+
+use crate::models::response::ApiResponse;
+
+/// The compile queue's limits and load: how many compilations are running and
+/// waiting, and how those since startup ended.
+pub async fn status(data: web::Data<crate::AppState>) -> Result<HttpResponse> {
+    let status = data.project_service.compiler.status();
+    let response = ApiResponse::success("Compile queue status", status);
+    Ok(HttpResponse::Ok().json(response))
+}
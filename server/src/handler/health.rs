@@ -1,6 +1,7 @@
-use actix_web::{HttpResponse, Result};
+use actix_web::{HttpResponse, Result, web};
 use serde::{Deserialize, Serialize};
 
+use crate::handler::ws::ProjectServer;
 use crate::models::response::ApiResponse;
 
 #[derive(Serialize, Deserialize, Default)]
@@ -8,15 +9,36 @@ enum HealthStatus {
     #[default]
     #[serde(rename = "healthy")]
     Healthy,
+    /// Serving, but some collaboration rooms hold edits that haven't reached
+    /// MongoDB for longer than `ws.unsaved_alert_secs`; a restart now would
+    /// lose them.
+    #[serde(rename = "degraded")]
+    Degraded,
 }
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct HealthPayload {
     status: HealthStatus,
+    /// Rooms with overdue unsaved edits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unsaved_rooms: Option<usize>,
+    /// Age, in seconds, of the oldest of those edits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    oldest_unsaved_secs: Option<u64>,
 }
 
-pub async fn health() -> Result<HttpResponse> {
-    let response = ApiResponse::success("Service is healthy", HealthPayload::default());
+pub async fn health(project_server: web::Data<ProjectServer>) -> Result<HttpResponse> {
+    let unsaved = project_server.unsaved_rooms().await;
+    let Some(oldest) = unsaved.iter().map(|room| room.unsaved_for).max() else {
+        let response = ApiResponse::success("Service is healthy", HealthPayload::default());
+        return Ok(HttpResponse::Ok().json(response));
+    };
+    let payload = HealthPayload {
+        status: HealthStatus::Degraded,
+        unsaved_rooms: Some(unsaved.len()),
+        oldest_unsaved_secs: Some(oldest.as_secs()),
+    };
+    let response = ApiResponse::success("Service has unsaved edits", payload);
     Ok(HttpResponse::Ok().json(response))
 }
 
@@ -28,7 +50,12 @@ mod tests {
 
     #[actix_web::test]
     async fn test_health_returns_200() {
-        let app = test::init_service(App::new().route("/health", web::get().to(health))).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(ProjectServer::detached()))
+                .route("/health", web::get().to(health)),
+        )
+        .await;
 
         let req = test::TestRequest::get().uri("/health").to_request();
         let resp = test::call_service(&app, req).await;
@@ -37,5 +64,6 @@ mod tests {
         let body: serde_json::Value = test::read_body_json(resp).await;
         assert_eq!(body["message"], "Service is healthy");
         assert_eq!(body["payload"]["status"], "healthy");
+        assert!(body["payload"].get("unsaved_rooms").is_none());
     }
 }
@@ -1,5 +1,8 @@
+pub mod compile;
 pub mod health;
+pub mod package;
 pub mod project;
 pub mod team;
+pub mod upload;
 pub mod user;
 pub mod ws;
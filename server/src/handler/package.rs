@@ -0,0 +1,95 @@
+use actix_web::{HttpResponse, ResponseError, http::StatusCode, web};
+use typst::syntax::package::PackageSpec;
+
+use crate::{
+    compile::package::PackageStoreError,
+    config::UploadConfig,
+    handler::upload::{UploadError, read_limited},
+    models::{response::ApiResponse, user::UserClaims},
+};
+
+impl ResponseError for PackageStoreError {
+    fn error_response(&self) -> HttpResponse {
+        let response = ApiResponse::error(&self.to_string());
+        HttpResponse::build(self.status_code()).json(response)
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            PackageStoreError::Forbidden => StatusCode::FORBIDDEN,
+            PackageStoreError::InvalidSpec(_)
+            | PackageStoreError::UnsupportedNamespace(_)
+            | PackageStoreError::Invalid(_) => StatusCode::BAD_REQUEST,
+            PackageStoreError::AlreadyExists(_) => StatusCode::CONFLICT,
+            PackageStoreError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            PackageStoreError::StorageUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            PackageStoreError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<UploadError> for PackageStoreError {
+    fn from(e: UploadError) -> Self {
+        match e {
+            UploadError::Invalid(reason) => PackageStoreError::Invalid(reason),
+            UploadError::TooLarge(limit) => PackageStoreError::PayloadTooLarge(limit),
+        }
+    }
+}
+
+/// Upload a package version as a `.tar.gz` body, rooted at the package root
+/// like the registry's tarballs. Admins only; the rules live in
+/// `PackageStore::upload`.
+pub async fn upload(
+    path: web::Path<(String, String, String)>,
+    payload: web::Payload,
+    data: web::Data<crate::AppState>,
+    upload_config: web::Data<UploadConfig>,
+    user: UserClaims,
+) -> Result<HttpResponse, PackageStoreError> {
+    let (namespace, name, version) = path.into_inner();
+    let spec = format!("@{namespace}/{name}:{version}");
+    let spec: PackageSpec = spec
+        .parse()
+        .map_err(|e| PackageStoreError::InvalidSpec(format!("{spec}: {e}")))?;
+
+    let tarball = read_limited(payload, upload_config.max_bytes).await?;
+
+    data.project_service
+        .packages
+        .upload(user.sub, &spec, tarball)
+        .await?;
+    let response = ApiResponse::success("Package uploaded successfully", spec.to_string());
+    Ok(HttpResponse::Created().json(response))
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_package_store_error_status_codes() {
+        let spec: PackageSpec = "@preview/example:0.1.0".parse().unwrap();
+        assert_eq!(
+            PackageStoreError::Forbidden.status_code(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            PackageStoreError::Invalid("missing typst.toml".to_string()).status_code(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            PackageStoreError::AlreadyExists(spec).status_code(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            PackageStoreError::PayloadTooLarge(1).status_code(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            PackageStoreError::StorageUnavailable.status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
@@ -1,15 +1,45 @@
-use actix_web::{HttpResponse, ResponseError, http::StatusCode};
+use actix_multipart::Multipart;
+use actix_web::http::header::{
+    self, ByteRangeSpec, CacheControl, CacheDirective, Charset, ContentDisposition, ContentRange,
+    ContentRangeSpec, DispositionParam, DispositionType, ETag, EntityTag, ExtendedValue,
+    Header as _, IfMatch, IfNoneMatch, IfRange, Range,
+};
+use actix_web::{
+    HttpRequest, HttpResponse, HttpResponseBuilder, ResponseError, guard::GuardContext,
+    http::StatusCode, web,
+};
 use bson::oid::ObjectId;
 use bson::serde_helpers::serialize_object_id_as_hex_string;
+use futures_util::TryStreamExt as _;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    compile::{CompileError, ImageFormat, RenderCache, render::DEFAULT_PPI},
+    config::UploadConfig,
+    handler::{
+        upload::{UploadError, read_limited, read_multipart_file},
+        ws::ProjectServer,
+    },
     models::{project::OwnerType, response::ApiResponse, user::UserClaims},
-    services::project::ProjectServiceError,
+    services::{archive::UnpackLimits, project::ProjectServiceError},
+    storage::ByteStream,
 };
 
 impl ResponseError for ProjectServiceError {
     fn error_response(&self) -> HttpResponse {
+        // A failed compile carries its errors, located in the project.
+        if let ProjectServiceError::Compile(CompileError::Failed(errors)) = self {
+            let response = ApiResponse::error_with_payload(&self.to_string(), errors);
+            return HttpResponse::build(self.status_code()).json(response);
+        }
+        // A refused save says which version it lost to.
+        if let ProjectServiceError::VersionConflict(version) = self {
+            let response = ApiResponse::error_with_payload(
+                &self.to_string(),
+                serde_json::json!({ "version": version }),
+            );
+            return HttpResponse::build(self.status_code()).json(response);
+        }
         let response = ApiResponse::error(&self.to_string());
         HttpResponse::build(self.status_code()).json(response)
     }
@@ -18,12 +48,38 @@ impl ResponseError for ProjectServiceError {
         match *self {
             ProjectServiceError::UserNotFound
             | ProjectServiceError::OwnerNotFound(_)
-            | ProjectServiceError::ProjectNotFound => StatusCode::NOT_FOUND,
+            | ProjectServiceError::ProjectNotFound
+            | ProjectServiceError::FileNotFound
+            | ProjectServiceError::PageNotFound(_) => StatusCode::NOT_FOUND,
             ProjectServiceError::AccessDenied
             | ProjectServiceError::CreatorNotMatchOwner
             | ProjectServiceError::CreatorNotMemberOfTeam => StatusCode::FORBIDDEN,
-            ProjectServiceError::InvalidOwnerType => StatusCode::BAD_REQUEST,
-            ProjectServiceError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ProjectServiceError::InvalidOwnerType
+            | ProjectServiceError::InvalidPath(_)
+            | ProjectServiceError::InvalidUpload(_)
+            | ProjectServiceError::InvalidEntry(_)
+            | ProjectServiceError::InvalidRender(_)
+            // Only an import's archive is read before responding; an export's
+            // errors surface mid-stream.
+            | ProjectServiceError::Archive(_) => StatusCode::BAD_REQUEST,
+            ProjectServiceError::PathConflict(_)
+            | ProjectServiceError::VersionConflict(_)
+            | ProjectServiceError::EntryRequired => StatusCode::CONFLICT,
+            ProjectServiceError::Compile(CompileError::Panicked) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            ProjectServiceError::Compile(CompileError::Busy | CompileError::Overrunning) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ProjectServiceError::Compile(CompileError::TimedOut(_)) => StatusCode::GATEWAY_TIMEOUT,
+            ProjectServiceError::Compile(CompileError::Cancelled) => StatusCode::CONFLICT,
+            ProjectServiceError::Compile(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ProjectServiceError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ProjectServiceError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ProjectServiceError::StorageUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ProjectServiceError::Storage(_) | ProjectServiceError::Database(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
         }
     }
 }
@@ -59,6 +115,68 @@ pub async fn create(
     }
 }
 
+#[derive(Deserialize)]
+pub struct ImportProjectQuery {
+    pub owner_id: ObjectId,
+    pub owner_type: OwnerType,
+    pub name: Option<String>,
+}
+
+/// Create a project from a zip archive. The body is either the raw archive or
+/// `multipart/form-data` with a `file` part; the project is named `?name=`,
+/// else after the uploaded file, else "Imported project". Owner rules are
+/// those of `create`; archive rules are the service's.
+pub async fn import(
+    query: web::Query<ImportProjectQuery>,
+    req: HttpRequest,
+    payload: web::Payload,
+    data: web::Data<crate::AppState>,
+    upload_config: web::Data<UploadConfig>,
+    user: UserClaims,
+) -> Result<HttpResponse, ProjectServiceError> {
+    let ImportProjectQuery {
+        owner_id,
+        owner_type,
+        name,
+    } = query.into_inner();
+    let limit = upload_config.max_archive_bytes;
+
+    let is_multipart = req
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+    let (filename, bytes) = if is_multipart {
+        read_multipart_file(Multipart::new(req.headers(), payload), limit).await?
+    } else {
+        (None, read_limited(payload, limit).await?)
+    };
+    let name = name
+        .or_else(|| {
+            let filename = filename?;
+            let stem = filename.strip_suffix(".zip").unwrap_or(&filename);
+            Some(stem.to_string()).filter(|stem| !stem.is_empty())
+        })
+        .unwrap_or_else(|| "Imported project".to_string());
+    let limits = UnpackLimits {
+        max_file_bytes: upload_config.max_bytes,
+        max_total_bytes: upload_config.max_unpacked_bytes,
+        max_files: upload_config.max_archive_files,
+    };
+
+    match data
+        .project_service
+        .import(user.sub, owner_id, owner_type, name, bytes, limits)
+        .await
+    {
+        Ok(project) => {
+            let response = ApiResponse::success("Project imported successfully", project);
+            Ok(HttpResponse::Created().json(response))
+        }
+        Err(e) => Err(e),
+    }
+}
+
 pub async fn find_by_id(
     id: actix_web::web::Path<String>,
     data: actix_web::web::Data<crate::AppState>,
@@ -144,35 +262,549 @@ pub async fn update(
 #[derive(Deserialize, Serialize)]
 pub struct UpdateFileRequest {
     pub text: String,
+    /// The file version this edit was made on; see [`update_file`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<i32>,
 }
 
+/// Save a text file. A save made on a known version (the body's `version`,
+/// or `If-Match: "<version>"`) only lands if the file is still at it, and is
+/// otherwise answered `409` with the current version. The new version comes
+/// back in the payload and as the `ETag`. A file open in a collaboration room
+/// is saved through the room, so its editors see the change live.
 pub async fn update_file(
     path: actix_web::web::Path<(String, String)>,
+    req: HttpRequest,
     body: actix_web::web::Json<UpdateFileRequest>,
     data: actix_web::web::Data<crate::AppState>,
+    project_server: web::Data<ProjectServer>,
     user: UserClaims,
 ) -> Result<HttpResponse, ProjectServiceError> {
     let (id, file_id) = path.into_inner();
     let project_id = ObjectId::parse_str(id).map_err(|_| ProjectServiceError::ProjectNotFound)?;
     let file_id = ObjectId::parse_str(file_id).map_err(|_| ProjectServiceError::ProjectNotFound)?;
+    let UpdateFileRequest { text, version } = body.into_inner();
+    let Ok(expected_version) = expected_version(&req, version) else {
+        let response = ApiResponse::error("If-Match must name one file version");
+        return Ok(HttpResponse::PreconditionFailed().json(response));
+    };
 
     match data
         .project_service
-        .update_file(project_id, user.sub, file_id, body.text.clone())
+        .update_file(
+            project_id,
+            user.sub,
+            file_id,
+            text,
+            expected_version,
+            &project_server,
+        )
         .await
     {
         Ok(payload) => {
+            let etag = EntityTag::new_strong(payload.version.to_string());
             let response = ApiResponse::success("File updated successfully", payload);
+            Ok(HttpResponse::Ok().insert_header(ETag(etag)).json(response))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The version a save is conditional on: the body's, else the one `If-Match`
+/// names. `Err` if `If-Match` names anything but a single version (as a
+/// strong tag), which no file could ever match.
+fn expected_version(req: &HttpRequest, body: Option<i32>) -> Result<Option<i32>, ()> {
+    if body.is_some() || !req.headers().contains_key(header::IF_MATCH) {
+        return Ok(body);
+    }
+    // Unparsable tags are dropped from the list, not reported.
+    match IfMatch::parse(req) {
+        Ok(IfMatch::Any) => Ok(None),
+        Ok(IfMatch::Items(tags)) => match tags.as_slice() {
+            [tag] if !tag.weak => tag.tag().parse().map(Some).map_err(|_| ()),
+            _ => Err(()),
+        },
+        Err(_) => Err(()),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CreateFileRequest {
+    pub path: String,
+    #[serde(default)]
+    pub text: String,
+}
+
+/// Route guard for `POST /file`: a JSON body creates a text file
+/// ([`create_file`]); any other body is an asset upload ([`upload_file`]).
+pub fn is_json(ctx: &GuardContext) -> bool {
+    ctx.header::<header::ContentType>()
+        .is_some_and(|content_type| content_type.0.essence_str() == "application/json")
+}
+
+/// Create a text file. Path rules live in `ProjectService::create_file`.
+pub async fn create_file(
+    id: web::Path<String>,
+    body: web::Json<CreateFileRequest>,
+    data: web::Data<crate::AppState>,
+    project_server: web::Data<ProjectServer>,
+    user: UserClaims,
+) -> Result<HttpResponse, ProjectServiceError> {
+    let project_id =
+        ObjectId::parse_str(id.into_inner()).map_err(|_| ProjectServiceError::ProjectNotFound)?;
+    let CreateFileRequest { path, text } = body.into_inner();
+
+    match data
+        .project_service
+        .create_file(project_id, user.sub, path, text, &project_server)
+        .await
+    {
+        Ok(payload) => {
+            let response = ApiResponse::success("File created successfully", payload);
+            Ok(HttpResponse::Created().json(response))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MoveFileRequest {
+    pub path: String,
+}
+
+/// Rename and/or move a file to a new path.
+pub async fn move_file(
+    path: web::Path<(String, String)>,
+    body: web::Json<MoveFileRequest>,
+    data: web::Data<crate::AppState>,
+    project_server: web::Data<ProjectServer>,
+    user: UserClaims,
+) -> Result<HttpResponse, ProjectServiceError> {
+    let (id, file_id) = path.into_inner();
+    let project_id = ObjectId::parse_str(id).map_err(|_| ProjectServiceError::ProjectNotFound)?;
+    let file_id = ObjectId::parse_str(file_id).map_err(|_| ProjectServiceError::FileNotFound)?;
+
+    match data
+        .project_service
+        .move_file(
+            project_id,
+            user.sub,
+            file_id,
+            body.into_inner().path,
+            &project_server,
+        )
+        .await
+    {
+        Ok(payload) => {
+            let response = ApiResponse::success("File moved successfully", payload);
+            Ok(HttpResponse::Ok().json(response))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeleteFileQuery {
+    /// The file to make the project's entry in the same write; required when
+    /// deleting the current entry.
+    pub entry: Option<String>,
+}
+
+/// Delete a file, e.g. `DELETE …/file/{file_id}?entry={other_id}` to remove
+/// the entry file while switching the entry to another.
+pub async fn delete_file(
+    path: web::Path<(String, String)>,
+    query: web::Query<DeleteFileQuery>,
+    data: web::Data<crate::AppState>,
+    project_server: web::Data<ProjectServer>,
+    user: UserClaims,
+) -> Result<HttpResponse, ProjectServiceError> {
+    let (id, file_id) = path.into_inner();
+    let project_id = ObjectId::parse_str(id).map_err(|_| ProjectServiceError::ProjectNotFound)?;
+    let file_id = ObjectId::parse_str(file_id).map_err(|_| ProjectServiceError::FileNotFound)?;
+    let entry = query
+        .into_inner()
+        .entry
+        .map(|entry| {
+            ObjectId::parse_str(&entry).map_err(|_| ProjectServiceError::InvalidEntry(entry))
+        })
+        .transpose()?;
+
+    match data
+        .project_service
+        .delete_file(project_id, user.sub, file_id, entry, &project_server)
+        .await
+    {
+        Ok(()) => {
+            let response = ApiResponse::success_no_payload("File deleted successfully");
             Ok(HttpResponse::Ok().json(response))
         }
         Err(e) => Err(e),
     }
 }
 
+/// Download a file's bytes as stored. The content hash is a strong `ETag`, so
+/// `If-None-Match` revalidates to `304` without reading object storage, and a
+/// single byte `Range` (what PDF viewers and font loaders send) is answered with
+/// `206 Partial Content`. The body is streamed from object storage, reading
+/// only the requested range; a text file open in a collaboration room is
+/// served from the room's live text.
+pub async fn raw_file(
+    path: web::Path<(String, String)>,
+    req: HttpRequest,
+    data: web::Data<crate::AppState>,
+    project_server: web::Data<ProjectServer>,
+    user: UserClaims,
+) -> Result<HttpResponse, ProjectServiceError> {
+    let (id, file_id) = path.into_inner();
+    let project_id = ObjectId::parse_str(id).map_err(|_| ProjectServiceError::ProjectNotFound)?;
+    let file_id = ObjectId::parse_str(file_id).map_err(|_| ProjectServiceError::FileNotFound)?;
+
+    let live = project_server.live_texts(project_id).await;
+    let file = data
+        .project_service
+        .raw_file(project_id, user.sub, file_id, live)
+        .await?;
+    let etag = EntityTag::new_strong(file.sha256.clone());
+    if is_not_modified(&req, &etag) {
+        return Ok(raw_headers(HttpResponse::NotModified(), etag).finish());
+    }
+    let (content_type, len) = (file.content_type, file.size);
+    let range = match raw_range(&req, &etag, len) {
+        Ok(range) => range,
+        Err(unsatisfiable) => return Ok(unsatisfiable),
+    };
+    let body = data.project_service.raw_body(file, range).await?;
+    Ok(raw_response(etag, content_type, len, range, body))
+}
+
+/// Compile the project on the server and return the PDF.
+pub async fn compile(
+    id: web::Path<String>,
+    data: web::Data<crate::AppState>,
+    user: UserClaims,
+) -> Result<HttpResponse, ProjectServiceError> {
+    let project_id =
+        ObjectId::parse_str(id.into_inner()).map_err(|_| ProjectServiceError::ProjectNotFound)?;
+
+    let pdf = data
+        .project_service
+        .compile_pdf(project_id, user.sub)
+        .await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/pdf")
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .body(pdf))
+}
+
+/// The project's compile errors and warnings. A document that doesn't compile
+/// still answers `200`: its errors are the payload.
+pub async fn diagnostics(
+    id: web::Path<String>,
+    data: web::Data<crate::AppState>,
+    user: UserClaims,
+) -> Result<HttpResponse, ProjectServiceError> {
+    let project_id =
+        ObjectId::parse_str(id.into_inner()).map_err(|_| ProjectServiceError::ProjectNotFound)?;
+
+    match data.project_service.diagnostics(project_id, user.sub).await {
+        Ok(diagnostics) => {
+            let response = ApiResponse::success("Diagnostics fetched successfully", diagnostics);
+            Ok(HttpResponse::Ok().json(response))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The project's headings, labels, references and citation keys. While the
+/// project is open in a collaboration room, its sources are read from the
+/// room's live text rather than the last flush to MongoDB.
+pub async fn outline(
+    id: web::Path<String>,
+    data: web::Data<crate::AppState>,
+    project_server: web::Data<ProjectServer>,
+    user: UserClaims,
+) -> Result<HttpResponse, ProjectServiceError> {
+    let project_id =
+        ObjectId::parse_str(id.into_inner()).map_err(|_| ProjectServiceError::ProjectNotFound)?;
+
+    let live = project_server.live_texts(project_id).await;
+    match data
+        .project_service
+        .outline(project_id, user.sub, live)
+        .await
+    {
+        Ok(outline) => {
+            let response = ApiResponse::success("Outline fetched successfully", outline);
+            Ok(HttpResponse::Ok().json(response))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    /// Add the compiled PDF next to the entry file.
+    #[serde(default)]
+    pub pdf: bool,
+    /// Add a `manifest.json` naming the entry and the pinned Typst version.
+    #[serde(default)]
+    pub manifest: bool,
+}
+
+/// Download the whole project as a zip archive, e.g.
+/// `GET …/export.zip?pdf=true&manifest=true`. Like the outline, it has the
+/// live text of a project open in a collaboration room. The archive is
+/// streamed as it's written.
+pub async fn export(
+    id: web::Path<String>,
+    query: web::Query<ExportQuery>,
+    data: web::Data<crate::AppState>,
+    project_server: web::Data<ProjectServer>,
+    user: UserClaims,
+) -> Result<HttpResponse, ProjectServiceError> {
+    let project_id =
+        ObjectId::parse_str(id.into_inner()).map_err(|_| ProjectServiceError::ProjectNotFound)?;
+    let query = query.into_inner();
+
+    let live = project_server.live_texts(project_id).await;
+    let export = data
+        .project_service
+        .export(project_id, user.sub, live, query.pdf, query.manifest)
+        .await?;
+    let file_name = format!("{}.zip", export.name);
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::FilenameExt(ExtendedValue {
+                charset: Charset::Ext("UTF-8".to_string()),
+                language_tag: None,
+                value: file_name.into_bytes(),
+            })],
+        })
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .streaming(
+            export
+                .into_stream()
+                .map_ok(web::Bytes::from)
+                .map_err(actix_web::Error::from),
+        ))
+}
+
+#[derive(Deserialize)]
+pub struct RenderQuery {
+    /// The page to render, counting from 1. Defaults to the first.
+    pub page: Option<usize>,
+    /// `png` (the default) or `svg`.
+    pub format: Option<ImageFormat>,
+    /// Resolution of a PNG, in pixels per inch.
+    pub ppi: Option<f32>,
+}
+
+/// Render one page of the compiled project, e.g. a first-page thumbnail. The
+/// ETag is derived from the compile's inputs, so a client that already has
+/// the image is answered `304` without compiling anything.
+pub async fn render(
+    id: web::Path<String>,
+    query: web::Query<RenderQuery>,
+    req: HttpRequest,
+    data: web::Data<crate::AppState>,
+    cache: web::Data<RenderCache>,
+    user: UserClaims,
+) -> Result<HttpResponse, ProjectServiceError> {
+    let project_id =
+        ObjectId::parse_str(id.into_inner()).map_err(|_| ProjectServiceError::ProjectNotFound)?;
+    let query = query.into_inner();
+
+    let render = data
+        .project_service
+        .page_render(
+            project_id,
+            user.sub,
+            query.page.unwrap_or(1),
+            query.format.unwrap_or(ImageFormat::Png),
+            query.ppi.unwrap_or(DEFAULT_PPI),
+        )
+        .await?;
+    let etag = EntityTag::new_strong(render.key.clone());
+    let cache_control = CacheControl(vec![CacheDirective::Private, CacheDirective::NoCache]);
+    if is_not_modified(&req, &etag) {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .insert_header(cache_control)
+            .finish());
+    }
+    let content_type = render.format.content_type();
+    let image = data.project_service.render_bytes(render, &cache).await?;
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ETag(etag))
+        .insert_header(cache_control)
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .body(image.to_vec()))
+}
+
+/// Whether the client's `If-None-Match` already names this content. Entity
+/// tags compare weakly here, as RFC 9110 prescribes for this header.
+fn is_not_modified(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        Err(_) => false,
+    }
+}
+
+/// Headers every raw download carries. User assets can be SVG, so the response
+/// may never be sniffed or run as script in the API's origin.
+fn raw_headers(mut builder: HttpResponseBuilder, etag: EntityTag) -> HttpResponseBuilder {
+    builder
+        .insert_header(ETag(etag))
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(CacheControl(vec![
+            CacheDirective::Private,
+            CacheDirective::NoCache,
+        ]))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .insert_header((
+            header::CONTENT_SECURITY_POLICY,
+            "default-src 'none'; style-src 'unsafe-inline'; sandbox",
+        ));
+    builder
+}
+
+/// The inclusive byte range of a `len`-byte body that a `Range` header asks
+/// for, `None` for the full body, or the `416` to answer with instead.
+fn raw_range(
+    req: &HttpRequest,
+    etag: &EntityTag,
+    len: u64,
+) -> Result<Option<(u64, u64)>, HttpResponse> {
+    let Some(spec) = requested_range(req, etag) else {
+        return Ok(None);
+    };
+    match spec.to_satisfiable_range(len) {
+        Some(range) => Ok(Some(range)),
+        None => Err(
+            raw_headers(HttpResponse::RangeNotSatisfiable(), etag.clone())
+                .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(len),
+                }))
+                .finish(),
+        ),
+    }
+}
+
+/// The full `len`-byte body, or the `range` of it chosen by [`raw_range`],
+/// streamed as `body` yields it.
+fn raw_response(
+    etag: EntityTag,
+    content_type: &'static str,
+    len: u64,
+    range: Option<(u64, u64)>,
+    body: ByteStream,
+) -> HttpResponse {
+    let mut builder = match range {
+        Some((start, end)) => {
+            let mut builder = raw_headers(HttpResponse::PartialContent(), etag);
+            builder.insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((start, end)),
+                instance_length: Some(len),
+            }));
+            builder
+        }
+        None => raw_headers(HttpResponse::Ok(), etag),
+    };
+    let sent = range.map_or(len, |(start, end)| end - start + 1);
+    builder
+        .content_type(content_type)
+        .no_chunking(sent)
+        .streaming(body.map_err(|e| actix_web::Error::from(ProjectServiceError::Storage(e))))
+}
+
+/// The single byte range to serve, if any. Multi-range requests, other units
+/// and a stale `If-Range` all fall back to the full body, which RFC 9110
+/// permits.
+fn requested_range(req: &HttpRequest, etag: &EntityTag) -> Option<ByteRangeSpec> {
+    if req.headers().contains_key(header::IF_RANGE) {
+        match IfRange::parse(req) {
+            Ok(IfRange::EntityTag(tag)) if tag.strong_eq(etag) => {}
+            _ => return None,
+        }
+    }
+    match Range::parse(req) {
+        Ok(Range::Bytes(mut specs)) if specs.len() == 1 => specs.pop(),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UploadFileQuery {
+    pub path: Option<String>,
+}
+
+/// Upload a binary asset. The body is either the raw bytes, with the target
+/// given as `?path=`, or `multipart/form-data` with a `file` part whose
+/// filename is the path unless `?path=` overrides it. Access, path rules and
+/// content sniffing live in `ProjectService::upload_file`.
+pub async fn upload_file(
+    id: web::Path<String>,
+    query: web::Query<UploadFileQuery>,
+    req: HttpRequest,
+    payload: web::Payload,
+    data: web::Data<crate::AppState>,
+    (upload_config, project_server): (web::Data<UploadConfig>, web::Data<ProjectServer>),
+    user: UserClaims,
+) -> Result<HttpResponse, ProjectServiceError> {
+    let project_id =
+        ObjectId::parse_str(id.into_inner()).map_err(|_| ProjectServiceError::ProjectNotFound)?;
+    let limit = upload_config.max_bytes;
+    let path = query.into_inner().path;
+
+    let is_multipart = req
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+    let (path, bytes) = if is_multipart {
+        let (filename, bytes) =
+            read_multipart_file(Multipart::new(req.headers(), payload), limit).await?;
+        (path.or(filename), bytes)
+    } else {
+        (path, read_limited(payload, limit).await?)
+    };
+    let path =
+        path.ok_or_else(|| ProjectServiceError::InvalidUpload("missing path".to_string()))?;
+
+    match data
+        .project_service
+        .upload_file(project_id, user.sub, path, bytes, &project_server)
+        .await
+    {
+        Ok(payload) => {
+            let response = ApiResponse::success("File uploaded successfully", payload);
+            Ok(HttpResponse::Created().json(response))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+impl From<UploadError> for ProjectServiceError {
+    fn from(e: UploadError) -> Self {
+        match e {
+            UploadError::Invalid(reason) => ProjectServiceError::InvalidUpload(reason),
+            UploadError::TooLarge(limit) => ProjectServiceError::PayloadTooLarge(limit),
+        }
+    }
+}
+
 #[cfg(test)]
 #[cfg_attr(coverage_nightly, coverage(off))]
 mod tests {
     use super::*;
+    use crate::compile::{Diagnostic, Severity};
+    use crate::storage::slice_stream;
     use actix_web::body::to_bytes;
 
     #[test]
@@ -193,6 +825,10 @@ mod tests {
             ProjectServiceError::ProjectNotFound.status_code(),
             StatusCode::NOT_FOUND
         );
+        assert_eq!(
+            ProjectServiceError::FileNotFound.status_code(),
+            StatusCode::NOT_FOUND
+        );
         assert_eq!(
             ProjectServiceError::AccessDenied.status_code(),
             StatusCode::FORBIDDEN
@@ -209,12 +845,206 @@ mod tests {
             ProjectServiceError::InvalidOwnerType.status_code(),
             StatusCode::BAD_REQUEST
         );
+        assert_eq!(
+            ProjectServiceError::InvalidPath("..".to_string()).status_code(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            ProjectServiceError::PathConflict("main.typ".to_string()).status_code(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            ProjectServiceError::EntryRequired.status_code(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            ProjectServiceError::InvalidEntry("x".to_string()).status_code(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            ProjectServiceError::Compile(CompileError::NoEntry).status_code(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            ProjectServiceError::Compile(CompileError::Failed(vec![])).status_code(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            ProjectServiceError::Compile(CompileError::Panicked).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            ProjectServiceError::Compile(CompileError::Busy).status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            ProjectServiceError::Compile(CompileError::Cancelled).status_code(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            ProjectServiceError::Compile(CompileError::TimedOut(30)).status_code(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(
+            ProjectServiceError::Compile(CompileError::Overrunning).status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            ProjectServiceError::InvalidRender("x".to_string()).status_code(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            ProjectServiceError::PageNotFound(2).status_code(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            ProjectServiceError::PayloadTooLarge(1).status_code(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            ProjectServiceError::UnsupportedMediaType("x".to_string()).status_code(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            ProjectServiceError::StorageUnavailable.status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
         assert_eq!(
             ProjectServiceError::Database(mongodb::error::Error::custom("boom")).status_code(),
             StatusCode::INTERNAL_SERVER_ERROR
         );
     }
 
+    fn etag() -> EntityTag {
+        EntityTag::new_strong("abc123".to_string())
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        let req = |value: &str| {
+            actix_web::test::TestRequest::default()
+                .insert_header((header::IF_NONE_MATCH, value))
+                .to_http_request()
+        };
+        assert!(is_not_modified(&req("\"abc123\""), &etag()));
+        assert!(is_not_modified(&req("\"old\", W/\"abc123\""), &etag()));
+        assert!(is_not_modified(&req("*"), &etag()));
+        assert!(!is_not_modified(&req("\"old\""), &etag()));
+        let bare = actix_web::test::TestRequest::default().to_http_request();
+        assert!(!is_not_modified(&bare, &etag()));
+    }
+
+    #[test]
+    fn test_expected_version() {
+        let req = |value: Option<&str>| {
+            let mut req = actix_web::test::TestRequest::default();
+            if let Some(value) = value {
+                req = req.insert_header((header::IF_MATCH, value));
+            }
+            req.to_http_request()
+        };
+        assert_eq!(expected_version(&req(None), None), Ok(None));
+        assert_eq!(expected_version(&req(None), Some(3)), Ok(Some(3)));
+        assert_eq!(expected_version(&req(Some("\"4\"")), None), Ok(Some(4)));
+        assert_eq!(expected_version(&req(Some("\"4\"")), Some(3)), Ok(Some(3)));
+        assert_eq!(expected_version(&req(Some("*")), None), Ok(None));
+        for value in ["W/\"4\"", "\"abc\"", "\"1\", \"2\"", "4"] {
+            assert_eq!(
+                expected_version(&req(Some(value)), None),
+                Err(()),
+                "{value}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_version_conflict_responds_with_the_current_version() {
+        let error = ProjectServiceError::VersionConflict(7);
+        assert_eq!(error.status_code(), StatusCode::CONFLICT);
+        let body = to_bytes(error.error_response().into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["payload"]["version"], 7);
+    }
+
+    #[actix_web::test]
+    async fn test_failed_compile_responds_with_its_errors() {
+        let error = ProjectServiceError::Compile(CompileError::Failed(vec![Diagnostic {
+            severity: Severity::Error,
+            message: "unknown variable: x".to_string(),
+            hints: vec![],
+            span: None,
+        }]));
+        let body = to_bytes(error.error_response().into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["message"], "Compilation failed: unknown variable: x");
+        assert_eq!(body["payload"][0]["severity"], "error");
+        assert_eq!(body["payload"][0]["span"], serde_json::Value::Null);
+    }
+
+    #[actix_web::test]
+    async fn test_raw_response_serves_byte_ranges() {
+        let respond = |headers: &[(header::HeaderName, &str)]| {
+            let mut req = actix_web::test::TestRequest::default();
+            for (name, value) in headers {
+                req = req.insert_header((name.clone(), *value));
+            }
+            let bytes = web::Bytes::from_static(b"0123456789");
+            match raw_range(&req.to_http_request(), &etag(), bytes.len() as u64) {
+                Ok(range) => raw_response(
+                    etag(),
+                    "application/pdf",
+                    bytes.len() as u64,
+                    range,
+                    slice_stream(bytes, range),
+                ),
+                Err(unsatisfiable) => unsatisfiable,
+            }
+        };
+
+        let full = respond(&[]);
+        assert_eq!(full.status(), StatusCode::OK);
+        assert_eq!(full.headers().get(header::ETAG).unwrap(), "\"abc123\"");
+        assert_eq!(full.headers().get(header::ACCEPT_RANGES).unwrap(), "bytes");
+        assert_eq!(
+            full.headers().get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+            "nosniff"
+        );
+        assert_eq!(to_bytes(full.into_body()).await.unwrap(), "0123456789");
+
+        for (range, content_range, body) in [
+            ("bytes=2-4", "bytes 2-4/10", "234"),
+            ("bytes=7-", "bytes 7-9/10", "789"),
+            ("bytes=-3", "bytes 7-9/10", "789"),
+            ("bytes=8-100", "bytes 8-9/10", "89"),
+        ] {
+            let partial = respond(&[(header::RANGE, range)]);
+            assert_eq!(partial.status(), StatusCode::PARTIAL_CONTENT, "{range}");
+            assert_eq!(
+                partial.headers().get(header::CONTENT_RANGE).unwrap(),
+                content_range
+            );
+            assert_eq!(to_bytes(partial.into_body()).await.unwrap(), body);
+        }
+
+        let unsatisfiable = respond(&[(header::RANGE, "bytes=10-")]);
+        assert_eq!(unsatisfiable.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            unsatisfiable.headers().get(header::CONTENT_RANGE).unwrap(),
+            "bytes */10"
+        );
+
+        // Multiple ranges, or an `If-Range` naming other content: whole body.
+        let multi = respond(&[(header::RANGE, "bytes=0-1,4-5")]);
+        assert_eq!(multi.status(), StatusCode::OK);
+        let stale = respond(&[(header::RANGE, "bytes=0-1"), (header::IF_RANGE, "\"old\"")]);
+        assert_eq!(stale.status(), StatusCode::OK);
+        let current = respond(&[
+            (header::RANGE, "bytes=0-1"),
+            (header::IF_RANGE, "\"abc123\""),
+        ]);
+        assert_eq!(current.status(), StatusCode::PARTIAL_CONTENT);
+    }
+
     #[actix_web::test]
     async fn test_project_service_error_response_body() {
         let resp = ProjectServiceError::AccessDenied.error_response();
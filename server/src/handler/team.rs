@@ -1,8 +1,11 @@
-use actix_web::{http::StatusCode, web, HttpResponse, ResponseError};
+use actix_multipart::Multipart;
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, ResponseError};
 use bson::oid::ObjectId;
 use serde::Deserialize;
 
 use crate::{
+    config::UploadConfig,
+    handler::upload::{read_limited, read_multipart_file, UploadError},
     models::{response::ApiResponse, user::UserClaims},
     services::team::TeamServiceError,
 };
@@ -17,8 +20,25 @@ impl ResponseError for TeamServiceError {
         match *self {
             TeamServiceError::UserNotFound => StatusCode::NOT_FOUND,
             TeamServiceError::TeamNotFound => StatusCode::NOT_FOUND,
+            TeamServiceError::FontNotFound => StatusCode::NOT_FOUND,
             TeamServiceError::AccessDenied => StatusCode::FORBIDDEN,
-            TeamServiceError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            TeamServiceError::InvalidFont(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            TeamServiceError::InvalidUpload(_) => StatusCode::BAD_REQUEST,
+            TeamServiceError::DuplicateFont(_) => StatusCode::CONFLICT,
+            TeamServiceError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            TeamServiceError::StorageUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            TeamServiceError::Database(_) | TeamServiceError::Storage(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
+
+impl From<UploadError> for TeamServiceError {
+    fn from(e: UploadError) -> Self {
+        match e {
+            UploadError::Invalid(reason) => TeamServiceError::InvalidUpload(reason),
+            UploadError::TooLarge(limit) => TeamServiceError::PayloadTooLarge(limit),
         }
     }
 }
@@ -61,6 +81,92 @@ pub async fn projects(
     }
 }
 
+#[derive(Deserialize)]
+pub struct UploadFontQuery {
+    pub filename: Option<String>,
+}
+
+/// Upload a font to a team's library, as a multipart `file` part or as the
+/// raw body with `?filename=`. The rules live in `TeamService::upload_font`.
+pub async fn upload_font(
+    id: web::Path<String>,
+    query: web::Query<UploadFontQuery>,
+    req: HttpRequest,
+    payload: web::Payload,
+    data: web::Data<crate::AppState>,
+    upload_config: web::Data<UploadConfig>,
+    user: UserClaims,
+) -> Result<HttpResponse, TeamServiceError> {
+    let team_id =
+        ObjectId::parse_str(id.into_inner()).map_err(|_| TeamServiceError::TeamNotFound)?;
+    let limit = upload_config.max_bytes;
+    let filename = query.into_inner().filename;
+
+    let is_multipart = req
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+    let (filename, bytes) = if is_multipart {
+        let (part_filename, bytes) =
+            read_multipart_file(Multipart::new(req.headers(), payload), limit).await?;
+        (filename.or(part_filename), bytes)
+    } else {
+        (filename, read_limited(payload, limit).await?)
+    };
+    let filename =
+        filename.ok_or_else(|| TeamServiceError::InvalidUpload("missing filename".to_string()))?;
+
+    match data
+        .team_service
+        .upload_font(team_id, user.sub, filename, bytes)
+        .await
+    {
+        Ok(font) => {
+            let response = ApiResponse::success("Font uploaded successfully", font);
+            Ok(HttpResponse::Created().json(response))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn fonts(
+    id: web::Path<String>,
+    data: web::Data<crate::AppState>,
+    user: UserClaims,
+) -> Result<HttpResponse, TeamServiceError> {
+    let team_id =
+        ObjectId::parse_str(id.into_inner()).map_err(|_| TeamServiceError::TeamNotFound)?;
+    match data.team_service.list_fonts(team_id, user.sub).await {
+        Ok(fonts) => {
+            let response = ApiResponse::success("Fonts fetched successfully", fonts);
+            Ok(HttpResponse::Ok().json(response))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn delete_font(
+    path: web::Path<(String, String)>,
+    data: web::Data<crate::AppState>,
+    user: UserClaims,
+) -> Result<HttpResponse, TeamServiceError> {
+    let (team_id, font_id) = path.into_inner();
+    let team_id = ObjectId::parse_str(team_id).map_err(|_| TeamServiceError::TeamNotFound)?;
+    let font_id = ObjectId::parse_str(font_id).map_err(|_| TeamServiceError::FontNotFound)?;
+    match data
+        .team_service
+        .delete_font(team_id, user.sub, font_id)
+        .await
+    {
+        Ok(()) => {
+            let response = ApiResponse::success_no_payload("Font deleted successfully");
+            Ok(HttpResponse::Ok().json(response))
+        }
+        Err(e) => Err(e),
+    }
+}
+
 #[cfg(test)]
 #[cfg_attr(coverage_nightly, coverage(off))]
 mod tests {
@@ -81,6 +187,26 @@ mod tests {
             TeamServiceError::AccessDenied.status_code(),
             StatusCode::FORBIDDEN
         );
+        assert_eq!(
+            TeamServiceError::FontNotFound.status_code(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            TeamServiceError::InvalidFont("a.txt".to_string()).status_code(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            TeamServiceError::DuplicateFont("a.ttf".to_string()).status_code(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            TeamServiceError::PayloadTooLarge(1).status_code(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            TeamServiceError::StorageUnavailable.status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
         assert_eq!(
             TeamServiceError::Database(mongodb::error::Error::custom("boom")).status_code(),
             StatusCode::INTERNAL_SERVER_ERROR
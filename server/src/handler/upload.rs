@@ -0,0 +1,71 @@
+//! Reading an upload's bytes from a request body, shared by the handlers
+//! that take file uploads.
+
+use actix_multipart::Multipart;
+use actix_web::web;
+use futures_util::{Stream, StreamExt as _};
+
+/// Why an upload body couldn't be read. Each handler maps it onto its own
+/// error type.
+#[derive(Debug)]
+pub enum UploadError {
+    Invalid(String),
+    TooLarge(usize),
+}
+
+/// The `file` part of a multipart body: its filename (if any) and bytes.
+/// Other parts are skipped.
+pub async fn read_multipart_file(
+    mut multipart: Multipart,
+    limit: usize,
+) -> Result<(Option<String>, Vec<u8>), UploadError> {
+    while let Some(field) = multipart.next().await {
+        let field = field.map_err(|e| UploadError::Invalid(e.to_string()))?;
+        if field.name() != Some("file") {
+            continue;
+        }
+        let filename = field
+            .content_disposition()
+            .and_then(|cd| cd.get_filename())
+            .map(str::to_string);
+        return Ok((filename, read_limited(field, limit).await?));
+    }
+    Err(UploadError::Invalid("missing `file` part".to_string()))
+}
+
+/// Collect a body stream, failing with `TooLarge` as soon as it grows past
+/// `limit` bytes.
+pub async fn read_limited<E: std::fmt::Display>(
+    mut stream: impl Stream<Item = Result<web::Bytes, E>> + Unpin,
+    limit: usize,
+) -> Result<Vec<u8>, UploadError> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| UploadError::Invalid(e.to_string()))?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(UploadError::TooLarge(limit));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_read_limited_stops_past_the_limit() {
+        let chunks = || {
+            futures_util::stream::iter(
+                [&b"abc"[..], b"def"].map(|c| Ok::<_, String>(web::Bytes::from_static(c))),
+            )
+        };
+        assert_eq!(read_limited(chunks(), 6).await.unwrap(), b"abcdef");
+        assert!(matches!(
+            read_limited(chunks(), 5).await,
+            Err(UploadError::TooLarge(5))
+        ));
+    }
+}
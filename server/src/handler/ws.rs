@@ -1,30 +1,64 @@
-use std::{collections::HashMap, thread, time::Duration};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    rc::Rc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
 
 use actix_web::ResponseError;
 use actix_web::http::StatusCode;
 use actix_web::{HttpRequest, HttpResponse, rt, web};
-use actix_ws::AggregatedMessage;
+use actix_ws::{AggregatedMessage, CloseCode};
 use bson::oid::ObjectId;
 use derive_more::Display;
-use futures_util::StreamExt as _;
+use futures_util::{StreamExt as _, future::join_all};
 use tokio::{
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    sync::{
+        Notify,
+        mpsc::{self, UnboundedReceiver, UnboundedSender, WeakUnboundedSender},
+        oneshot,
+    },
     task::LocalSet,
-    time::{Instant, interval},
+    time::{Instant, interval, sleep_until},
 };
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 use yrs::{
-    ClientID, Doc, GetString, ReadTxn, Text, Transact,
+    ClientID, DeepObservable, Doc, GetString, MapRef, ReadTxn, Subscription, Text, TextRef,
+    Transact, TransactionMut,
+    encoding::{
+        read::{Cursor, Read as _},
+        write::Write as _,
+    },
     sync::{Awareness, DefaultProtocol, Message as YMessage, Protocol, SyncMessage},
     updates::decoder::Decode as _,
     updates::encoder::{Encode, Encoder, EncoderV1},
 };
 
+use crate::compile::{
+    CompileError, CompileQueue, FontCache, ImageFormat, JobKind, PackageStore, ProjectWorld,
+    ide::{self, IdeRequest},
+    preview::{PageUpdate, PreviewPages, render_preview},
+};
 use crate::config::WsConfig;
-use crate::models::project::FileContent;
+use crate::crdt::{
+    self,
+    snapshot::{self, Snapshot},
+};
+use crate::models::project::{FileContent, Project, ProjectFile};
 use crate::models::response::ApiResponse;
+use crate::models::tree::ProjectTree;
 use crate::models::user::UserClaims;
 use crate::repo::project::{MongoProjectRepo, ProjectRepo};
+use crate::repo::team::{MongoTeamRepo, TeamRepo};
+use crate::services::project::{ProjectServiceError, owner_fonts, project_world};
+use crate::services::tree::{self, sync_nodes};
+use crate::storage::ObjectStore;
 
 #[derive(Debug, Display)]
 pub enum WebSocketError {
@@ -59,10 +93,39 @@ impl ResponseError for WebSocketError {
 /// single lib0 varint byte for values < 128, so the first byte identifies it.
 const MSG_AWARENESS: u8 = 1;
 
-/// A `(file_id, text)` pair used to hydrate a room from stored files. The CRDT
-/// text root is keyed by the file's **id** (stable across renames), not its
-/// path, so renaming a file never detaches its buffer from its edit history.
-type FileSeed = (ObjectId, String);
+/// Message-type tag for the server-rendered live preview, our own extension
+/// next to sync and awareness (y-protocols claims 0–3). The byte after it is
+/// one of the `PREVIEW_*` kinds below; the rest is lib0-encoded:
+///
+/// - `SUBSCRIBE` (client → server), then the format: `0` SVG, `1` PNG.
+/// - `UNSUBSCRIBE` (client → server).
+/// - `PAGES` (server → client): the page count, the number of pages that
+///   follow, and for each its index and image. Pages not listed are
+///   unchanged; pages past the count are gone. Sent after every render, so
+///   even an empty one tells a client showing an error that it's fixed.
+/// - `ERROR` (server → client): a JSON `{ message, diagnostics }`, leaving the
+///   last pages in place.
+const MSG_PREVIEW: u8 = 100;
+const PREVIEW_SUBSCRIBE: u8 = 0;
+const PREVIEW_UNSUBSCRIBE: u8 = 1;
+const PREVIEW_PAGES: u8 = 2;
+const PREVIEW_ERROR: u8 = 3;
+
+/// Message-type tag for language analysis, answered from the room's live
+/// text. A frame is the tag and a lib0 string of JSON, both ways:
+///
+/// - request (client → server): `{ id, method, params }`, see [`IdeRequest`].
+///   The `id` is the client's, any JSON value, to match the reply with.
+/// - reply (server → client): `{ id, result }`, or `{ id, error }` with a
+///   message. Replies come as answers are ready, not necessarily in order; a
+///   request overtaken by a change to the text may be answered with an error.
+const MSG_IDE: u8 = 101;
+
+/// A `(file_id, text, version)` triple used to hydrate a room from stored
+/// files. The CRDT text root is keyed by the file's **id** (stable across
+/// renames), not its path, so renaming a file never detaches its buffer from
+/// its edit history. The version is the one the text was stored at.
+type FileSeed = (ObjectId, String, i32);
 
 /// Handshake and start WebSocket handler with heartbeats.
 pub async fn ws(
@@ -84,27 +147,18 @@ pub async fn ws(
         Err(_) => return Err(WebSocketError::ProjectNotFound),
     };
 
-    // Seed data to hydrate the room's CRDT document from the stored text files.
-    // Only the *first* connection to a project uses it; later joiners sync
-    // against the already-live document.
-    let project = match data
+    // The room manager reads the project's text itself when it loads the
+    // room; only make sure here that there is a project to join.
+    match data
         .project_service
         .project_repo
         .find_by_id(project_id)
         .await
     {
-        Ok(Some(project)) => project,
+        Ok(Some(_)) => {}
         Ok(None) => return Err(WebSocketError::ProjectNotFound),
         Err(_) => return Err(WebSocketError::ProjectNotFound),
     };
-    let seed: Vec<FileSeed> = project
-        .files
-        .into_iter()
-        .filter_map(|file| match file.content {
-            FileContent::Text { text } => Some((file.id, text)),
-            FileContent::Binary { .. } => None,
-        })
-        .collect();
 
     let (res, session, stream) = match actix_ws::handle(&req, stream) {
         Ok(tuple) => tuple,
@@ -114,7 +168,6 @@ pub async fn ws(
     rt::spawn(handle_ws(
         project_server.as_ref().clone(),
         project_id,
-        seed,
         session,
         stream,
         ws_config.as_ref().clone(),
@@ -123,14 +176,13 @@ pub async fn ws(
     Ok(res)
 }
 
-/// Per-connection loop. Bridges this WebSocket to the single-threaded room
-/// manager: client frames are forwarded as [`Command::Data`], and messages the
+/// Per-connection loop. Bridges this WebSocket to its project's single-threaded
+/// room manager: client frames are forwarded as [`Command::Data`], and messages the
 /// manager routes back (initial sync, peers' updates, awareness) arrive on
 /// `out_rx` and are written to the socket.
 async fn handle_ws(
     project_server: ProjectServer,
     project_id: ObjectId,
-    seed: Vec<FileSeed>,
     mut session: actix_ws::Session,
     msg_stream: actix_ws::MessageStream,
     ws_config: WsConfig,
@@ -142,7 +194,7 @@ async fn handle_ws(
 
     let conn_id = ObjectId::new();
     let (out_tx, mut out_rx) = mpsc::unbounded_channel::<Vec<u8>>();
-    project_server.join(project_id, seed, conn_id, out_tx);
+    project_server.join(project_id, conn_id, out_tx);
     info!("WS handler: joined project {}", project_id.to_hex());
 
     let mut msg_stream = msg_stream
@@ -177,7 +229,9 @@ async fn handle_ws(
                     Some(bytes) => {
                         if session.binary(bytes).await.is_err() { break None; }
                     }
-                    None => break None,
+                    // The room manager let go of this connection: the server
+                    // is shutting down, or the room failed to load.
+                    None => break Some(CloseCode::Away.into()),
                 }
             }
 
@@ -198,12 +252,11 @@ async fn handle_ws(
 }
 
 /// Commands sent from connection handlers (any worker thread) to the
-/// single-threaded room manager. Everything here is `Send`; the `yrs` document
-/// itself never leaves the manager thread.
+/// single-threaded room managers. Everything here is `Send`; the `yrs` document
+/// itself never leaves its manager's thread.
 enum Command {
     Join {
         project_id: ObjectId,
-        seed: Vec<FileSeed>,
         conn_id: ObjectId,
         out: UnboundedSender<Vec<u8>>,
     },
@@ -216,6 +269,105 @@ enum Command {
         project_id: ObjectId,
         conn_id: ObjectId,
     },
+    /// Reply with every blob hash referenced by a live room's accepted tree.
+    LiveBlobs {
+        out: oneshot::Sender<HashSet<String>>,
+    },
+    /// Reply with the current text of each file in a project's room, by file
+    /// id; empty if the project has no room in memory.
+    LiveTexts {
+        project_id: ObjectId,
+        out: oneshot::Sender<HashMap<ObjectId, String>>,
+    },
+    /// Reply with the rooms holding edits that haven't reached MongoDB for
+    /// longer than `ws.unsaved_alert_secs`.
+    UnsavedRooms {
+        out: oneshot::Sender<Vec<UnsavedRoom>>,
+    },
+    /// Save a file through its project's live room (see `Shard::edit_text`).
+    /// Answers `None` if no room in memory holds the file.
+    EditText {
+        project_id: ObjectId,
+        file_id: ObjectId,
+        text: String,
+        expected_version: Option<i32>,
+        out: oneshot::Sender<Option<Result<ProjectFile, ProjectServiceError>>>,
+    },
+    /// A file of the project was created, moved or deleted outside its room
+    /// (over REST): re-read the project and bring the room's files and tree in
+    /// step (see `sync_files`). Answers once done, or at once without a room.
+    FilesChanged {
+        project_id: ObjectId,
+        out: oneshot::Sender<()>,
+    },
+    /// Close every connection, flush every room and stop (see
+    /// `shutdown_rooms`). Answers once done.
+    Shutdown { out: oneshot::Sender<()> },
+    /// A room's write of a file lost its version check to a save made
+    /// outside the room; this is the stored text it lost to (sent by the
+    /// manager's own task, see `persist_room`).
+    StoredText {
+        project_id: ObjectId,
+        key: String,
+        text: String,
+        version: i32,
+    },
+    /// A room's project was re-read after `FilesChanged`, the `seq`th time
+    /// (sent by the manager's own task, see `reread_files`).
+    FilesRead {
+        project_id: ObjectId,
+        seq: u64,
+        result: Result<Option<Box<Project>>, String>,
+        out: oneshot::Sender<()>,
+    },
+    /// A room's stored text and snapshot were read on cold start, or failed
+    /// to be (sent by the manager's own task, see `Shard::load`).
+    Loaded {
+        project_id: ObjectId,
+        result: Result<(Vec<FileSeed>, Option<Snapshot>), String>,
+    },
+    /// A room's analysis world finished loading (sent by the manager's own
+    /// task, see `handle_ide`).
+    AnalysisLoaded {
+        project_id: ObjectId,
+        result: Result<Box<ProjectWorld>, ProjectServiceError>,
+    },
+    /// A room's preview render finished (sent by the manager's own task, see
+    /// `start_preview`). Hands back the room's blob cache and the project it
+    /// rendered with the result.
+    Previewed {
+        project_id: ObjectId,
+        project: Option<Box<Project>>,
+        blobs: HashMap<String, Vec<u8>>,
+        result: Result<Vec<PageUpdate>, ProjectServiceError>,
+    },
+}
+
+impl Command {
+    /// The project whose room the command is for, if it must wait while that
+    /// room loads.
+    fn room(&self) -> Option<ObjectId> {
+        match self {
+            Command::Join { project_id, .. }
+            | Command::Data { project_id, .. }
+            | Command::Leave { project_id, .. }
+            | Command::LiveTexts { project_id, .. }
+            | Command::EditText { project_id, .. }
+            | Command::FilesChanged { project_id, .. } => Some(*project_id),
+            _ => None,
+        }
+    }
+}
+
+/// A room whose edits have been waiting to reach MongoDB for too long (see
+/// [`ProjectServer::unsaved_rooms`]).
+#[derive(Debug, Clone)]
+pub struct UnsavedRoom {
+    pub project_id: ObjectId,
+    /// How long the oldest unsaved edit has been waiting.
+    pub unsaved_for: Duration,
+    /// Failed writes in a row, summed over the room's files.
+    pub failed_writes: u32,
 }
 
 /// Handle to the collaboration subsystem, stored in actix app data. Cheap to
@@ -223,43 +375,92 @@ enum Command {
 /// types it fronts.
 #[derive(Clone)]
 pub struct ProjectServer {
-    cmd_tx: UnboundedSender<Command>,
+    /// One room manager per shard; a project's room lives on the one its id
+    /// hashes to (see [`shard_of`]).
+    shards: Arc<[UnboundedSender<Command>]>,
 }
 
 impl ProjectServer {
-    pub fn new(project_repo: MongoProjectRepo, ws_config: WsConfig) -> Self {
-        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-        // The room manager owns all `yrs` state on a dedicated thread running a
-        // current-thread runtime + LocalSet, so the `!Send` documents never have
-        // to cross threads.
-        thread::spawn(move || {
-            let rt = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .expect("build room-manager runtime");
-            let local = LocalSet::new();
-            local.block_on(&rt, room_manager(cmd_rx, project_repo, ws_config));
-        });
-        ProjectServer { cmd_tx }
+    /// `store`, when configured, holds each room's Y.Doc snapshot so a room
+    /// survives a restart with its CRDT history; without it rooms seed from
+    /// stored text on every cold start. `team_repo`, `packages` and
+    /// `font_cache` serve the team fonts and Typst packages live previews and
+    /// language analysis compile with, and both wait in `compiler` like every
+    /// other compilation.
+    pub fn new(
+        project_repo: MongoProjectRepo,
+        team_repo: MongoTeamRepo,
+        store: Option<Arc<dyn ObjectStore>>,
+        packages: Arc<PackageStore>,
+        font_cache: Arc<FontCache>,
+        compiler: Arc<CompileQueue>,
+        ws_config: WsConfig,
+    ) -> Self {
+        let compiler = RoomCompiler {
+            repo: project_repo,
+            team_repo,
+            store,
+            packages,
+            font_cache,
+            compiler,
+        };
+        let shards = (0..ws_config.room_shards.max(1))
+            .map(|shard| {
+                let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+                // Weak, so the manager still stops once every handle is dropped.
+                let preview_tx = cmd_tx.downgrade();
+                let (compiler, ws_config) = (compiler.clone(), ws_config.clone());
+                // Each room manager owns its rooms' `yrs` state on a dedicated
+                // thread running a current-thread runtime + LocalSet, so the
+                // `!Send` documents never have to cross threads.
+                thread::Builder::new()
+                    .name(format!("room-manager-{shard}"))
+                    .spawn(move || {
+                        let rt = tokio::runtime::Builder::new_current_thread()
+                            .enable_all()
+                            .build()
+                            .expect("build room-manager runtime");
+                        let local = LocalSet::new();
+                        local.block_on(&rt, room_manager(cmd_rx, preview_tx, compiler, ws_config));
+                    })
+                    .expect("spawn room-manager thread");
+                cmd_tx
+            })
+            .collect();
+        ProjectServer { shards }
     }
 
-    fn join(
-        &self,
-        project_id: ObjectId,
-        seed: Vec<FileSeed>,
-        conn_id: ObjectId,
-        out: UnboundedSender<Vec<u8>>,
-    ) {
-        let _ = self.cmd_tx.send(Command::Join {
+    /// The room manager of the project's shard.
+    fn shard(&self, project_id: ObjectId) -> &UnboundedSender<Command> {
+        &self.shards[shard_of(project_id, self.shards.len())]
+    }
+
+    /// Send every shard the command `ask` makes, and collect the replies, one
+    /// per shard: `None` from a shard whose manager is gone.
+    async fn ask_all<T>(&self, ask: impl Fn(oneshot::Sender<T>) -> Command) -> Vec<Option<T>> {
+        // Every shard is asked before any reply is awaited, so they work at once.
+        let replies: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| {
+                let (out, rx) = oneshot::channel();
+                let sent = shard.send(ask(out)).is_ok();
+                async move { if sent { rx.await.ok() } else { None } }
+            })
+            .collect();
+        join_all(replies).await
+    }
+
+    fn join(&self, project_id: ObjectId, conn_id: ObjectId, out: UnboundedSender<Vec<u8>>) {
+        let _ = self.shard(project_id).send(Command::Join {
             project_id,
-            seed,
             conn_id,
             out,
         });
     }
 
     fn data(&self, project_id: ObjectId, conn_id: ObjectId, data: Vec<u8>) {
-        let _ = self.cmd_tx.send(Command::Data {
+        let _ = self.shard(project_id).send(Command::Data {
             project_id,
             conn_id,
             data,
@@ -267,11 +468,112 @@ impl ProjectServer {
     }
 
     fn leave(&self, project_id: ObjectId, conn_id: ObjectId) {
-        let _ = self.cmd_tx.send(Command::Leave {
+        let _ = self.shard(project_id).send(Command::Leave {
             project_id,
             conn_id,
         });
     }
+
+    /// Blob hashes referenced by rooms currently in memory, including changes
+    /// not yet snapshotted — the live part of the GC's mark phase. `None` if
+    /// any room manager is gone.
+    pub async fn live_blobs(&self) -> Option<HashSet<String>> {
+        let replies = self.ask_all(|out| Command::LiveBlobs { out }).await;
+        replies
+            .into_iter()
+            .try_fold(HashSet::new(), |mut all, blobs| {
+                all.extend(blobs?);
+                Some(all)
+            })
+    }
+
+    /// The text of each file in the project's live room, by file id, which is
+    /// newer than MongoDB's until the next flush. Empty when the project has
+    /// no room in memory or the room manager is gone.
+    pub async fn live_texts(&self, project_id: ObjectId) -> HashMap<ObjectId, String> {
+        let (out, rx) = oneshot::channel();
+        if self
+            .shard(project_id)
+            .send(Command::LiveTexts { project_id, out })
+            .is_err()
+        {
+            return HashMap::new();
+        }
+        rx.await.unwrap_or_default()
+    }
+
+    /// The rooms whose edits have been waiting to reach MongoDB for longer than
+    /// `ws.unsaved_alert_secs`, for the health check. A room manager that is
+    /// gone has none.
+    pub async fn unsaved_rooms(&self) -> Vec<UnsavedRoom> {
+        let replies = self.ask_all(|out| Command::UnsavedRooms { out }).await;
+        replies.into_iter().flatten().flatten().collect()
+    }
+
+    /// Save `text` to a file through its project's live room, so connected
+    /// editors see the edit and the room's next flush doesn't undo it. `None`
+    /// when no room in memory holds the file (or the room manager is gone), and
+    /// the caller writes the file itself.
+    pub async fn edit_text(
+        &self,
+        project_id: ObjectId,
+        file_id: ObjectId,
+        text: String,
+        expected_version: Option<i32>,
+    ) -> Option<Result<ProjectFile, ProjectServiceError>> {
+        let (out, rx) = oneshot::channel();
+        self.shard(project_id)
+            .send(Command::EditText {
+                project_id,
+                file_id,
+                text,
+                expected_version,
+                out,
+            })
+            .ok()?;
+        rx.await.ok().flatten()
+    }
+
+    /// Bring the project's live room, if it has one in memory, in step with
+    /// the project's files as stored, after a file was created, moved or
+    /// deleted outside the room, so its editors see the change. Returns once
+    /// the room has taken it in.
+    pub async fn files_changed(&self, project_id: ObjectId) {
+        let (out, rx) = oneshot::channel();
+        if self
+            .shard(project_id)
+            .send(Command::FilesChanged { project_id, out })
+            .is_ok()
+        {
+            let _ = rx.await;
+        }
+    }
+
+    /// Close every collaboration connection, write every room's text and
+    /// snapshot, and stop the room managers, for a graceful shutdown. Returns
+    /// once all of it is stored (or failed and logged). Joins after that are
+    /// refused, and the other methods answer as if no room were in memory.
+    pub async fn shutdown(&self) {
+        self.ask_all(|out| Command::Shutdown { out }).await;
+    }
+
+    /// A handle to no room manager at all: every project is without a room.
+    #[cfg(test)]
+    pub(crate) fn detached() -> Self {
+        let (cmd_tx, _) = mpsc::unbounded_channel();
+        ProjectServer {
+            shards: Arc::new([cmd_tx]),
+        }
+    }
+}
+
+/// The shard, out of `shards`, whose room manager holds the project's room.
+/// Stable for the life of the process, which is all a room needs: rooms don't
+/// outlive it.
+fn shard_of(project_id: ObjectId, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    project_id.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
 }
 
 /// One live collaboration room: the shared CRDT document plus its connections.
@@ -286,11 +588,222 @@ struct RoomState {
     /// text-root key (file id hex) -> file id, for writing snapshots back to
     /// the right file.
     files: HashMap<String, ObjectId>,
-    /// Last text persisted per text-root key, to skip unchanged files.
-    last: HashMap<String, String>,
+    /// How each text-root key's file stands against MongoDB. Shared with the
+    /// room's background writes, which record their outcome here.
+    saves: Rc<RefCell<HashMap<String, FileSave>>>,
+    /// Whether the room's unsaved text was last reported as overdue, so the
+    /// warning is logged once per episode rather than on every flush.
+    unsaved_reported: bool,
+    /// The document's structural `nodes` map (see [`crate::crdt`]).
+    nodes: MapRef,
+    /// The last file tree the authority accepted. Always valid: an incoming
+    /// change that would break a tree rule is rolled back to this.
+    tree: ProjectTree,
+    /// Raised by `_nodes_sub` whenever a transaction touches `nodes`, so the
+    /// tree is only decoded and validated after a structural change rather
+    /// than on every keystroke.
+    nodes_touched: Arc<AtomicBool>,
+    _nodes_sub: Subscription,
+    /// Raised by `_update_sub` on every document change (including deletions,
+    /// which don't advance the state vector), so a flush only re-snapshots a
+    /// room that actually changed.
+    dirty: Arc<AtomicBool>,
+    _update_sub: Subscription,
+    /// When the last connection left, or `None` while anyone is connected.
+    /// A room idle for `room_idle_timeout_secs` is evicted (see `evictable`).
+    idle_since: Option<Instant>,
+    /// Background writes (text or snapshot) spawned for this room that haven't
+    /// finished. A room is only evicted once this drains, so no older write
+    /// can land after its final flush.
+    in_flight: Rc<InFlight>,
+    /// A snapshot write is in flight. One at a time, so an older snapshot
+    /// never lands over a newer one (see `persist_snapshot`).
+    snapshotting: Rc<Cell<bool>>,
+    /// Re-reads of the project started after `FilesChanged`, and the last one
+    /// taken in. A read that finishes after a later one was taken in is
+    /// older, and dropped.
+    files_read: u64,
+    files_synced: u64,
+    preview: RoomPreview,
+    analysis: RoomAnalysis,
+}
+
+/// A room's background writes that haven't finished.
+#[derive(Default)]
+struct InFlight {
+    count: Cell<usize>,
+    /// Woken whenever the count drops to zero.
+    drained: Notify,
+}
+
+impl InFlight {
+    fn get(&self) -> usize {
+        self.count.get()
+    }
+
+    fn start(&self) {
+        self.count.set(self.count.get() + 1);
+    }
+
+    fn end(&self) {
+        let count = self.count.get() - 1;
+        self.count.set(count);
+        if count == 0 {
+            self.drained.notify_waiters();
+        }
+    }
+
+    /// Resolves once no write is in flight. The writes are tasks on the same
+    /// thread, so none can end between the check and the wait starting.
+    async fn drained(&self) {
+        while self.get() > 0 {
+            self.drained.notified().await;
+        }
+    }
+}
+
+/// How one file's text in a room stands against MongoDB (see
+/// [`persist_room`]).
+struct FileSave {
+    /// The text last stored, to skip unchanged files.
+    stored: String,
+    /// The stored version, as the room last read or wrote it. The room's
+    /// writes only land at that version, so it never overwrites a save made
+    /// outside it. `None` while a write is in flight, so writes of one file
+    /// never race, and after one lost to such a save, until the room adopts it.
+    version: Option<i32>,
+    /// Failed writes in a row.
+    failures: u32,
+    /// When the next write may start, after a failed one.
+    retry_at: Option<Instant>,
+    /// Since when (as of a flush) the room has held text that isn't stored.
+    dirty_since: Option<Instant>,
+    /// Wakes the edits waiting for the file to have a version again (see
+    /// [`Shard::edit_text`]), and when it leaves the room.
+    settled: Arc<Notify>,
+}
+
+impl FileSave {
+    fn new(stored: String, version: i32) -> Self {
+        FileSave {
+            stored,
+            version: Some(version),
+            failures: 0,
+            retry_at: None,
+            dirty_since: None,
+            settled: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Record a successful write of `text`, now at `version`.
+    fn saved(&mut self, text: String, version: i32) {
+        self.stored = text;
+        self.failures = 0;
+        self.retry_at = None;
+        self.dirty_since = None;
+        self.restore(version);
+    }
+
+    /// Record a failed write made at `version`, and hold off the next one.
+    /// Returns how long.
+    fn failed(&mut self, version: i32, backoff: Backoff) -> Duration {
+        self.restore(version);
+        self.failures += 1;
+        let delay = backoff.delay(self.failures);
+        self.retry_at = Some(Instant::now() + delay);
+        delay
+    }
+
+    /// Give the file back its `version` after a write that didn't land.
+    fn restore(&mut self, version: i32) {
+        self.version = Some(version);
+        self.settled.notify_waiters();
+    }
+}
+
+impl Drop for FileSave {
+    fn drop(&mut self) {
+        self.settled.notify_waiters();
+    }
+}
+
+/// Waits between retries of a failed write: `base`, doubling with each
+/// further failure, up to `max`.
+#[derive(Clone, Copy)]
+struct Backoff {
+    base: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn delay(self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(16);
+        self.base.saturating_mul(1 << doublings).min(self.max)
+    }
+}
+
+/// The live preview of a room, rendered for the connections that subscribed
+/// to it (see [`MSG_PREVIEW`]). Nothing is compiled while nobody subscribes.
+#[derive(Default)]
+struct RoomPreview {
+    /// Subscribed connections, and the format each wants.
+    subscribers: HashMap<ObjectId, ImageFormat>,
+    /// The pages last sent, to diff the next render against and to catch a
+    /// new subscriber up.
+    pages: PreviewPages,
+    /// When the next render is due. Each change pushes it back, so a room is
+    /// only rendered once its document has settled.
+    due: Option<Instant>,
+    /// A render is running. One at a time per room; a change meanwhile is
+    /// rendered after it.
+    running: bool,
+    /// Binary files' bytes by storage key, so they aren't re-read from
+    /// storage on every render. Lent to the running render.
+    blobs: HashMap<String, Vec<u8>>,
+    /// The project as stored, so it isn't re-read from MongoDB on every
+    /// render: read by the first one, and replaced whenever the room re-reads
+    /// its files. Lent to the running render.
+    project: Option<Box<Project>>,
+}
+
+/// Language analysis for a room (see [`MSG_IDE`]): a world built on the first
+/// request and kept up to date with the room's text from then on.
+#[derive(Default)]
+struct RoomAnalysis {
+    /// `None` until the first request, and again after a change to the file
+    /// tree, which the world can't follow.
+    world: Option<ProjectWorld>,
+    /// While the world loads, the requests waiting for it.
+    pending: Option<Vec<PendingRequest>>,
+}
+
+/// A request waiting for the room's analysis world: who asked, and the id
+/// to answer with.
+struct PendingRequest {
+    conn_id: ObjectId,
+    id: serde_json::Value,
+    request: IdeRequest,
+}
+
+impl RoomPreview {
+    /// Schedule a render at `at`, if anyone is watching.
+    fn schedule(&mut self, at: Instant) {
+        if !self.subscribers.is_empty() {
+            self.due = Some(at);
+        }
+    }
+
+    /// The formats subscribers want, each once.
+    fn formats(&self) -> Vec<ImageFormat> {
+        let mut formats: Vec<_> = self.subscribers.values().copied().collect();
+        formats.sort_by_key(|format| *format as u8);
+        formats.dedup();
+        formats
+    }
 }
 
 impl RoomState {
+    /// A room for a project with no snapshot yet, seeded from stored text.
     fn new(seed: Vec<FileSeed>) -> RoomState {
         let doc = Doc::new();
         // Seed from stored text. The server is authoritative on cold start;
@@ -298,7 +811,8 @@ impl RoomState {
         // parties both inserting the initial text (CRDT would merge those into
         // duplicated content).
         let mut files = HashMap::new();
-        for (id, text) in seed {
+        let mut saves = HashMap::new();
+        for (id, text, version) in seed {
             // Key the text root by the file's id (hex) — stable across renames.
             let key = id.to_hex();
             let root = doc.get_or_insert_text(key.as_str());
@@ -306,14 +820,251 @@ impl RoomState {
                 let mut txn = doc.transact_mut();
                 root.insert(&mut txn, 0, &text);
             }
-            files.insert(key, id);
+            files.insert(key.clone(), id);
+            saves.insert(key, FileSave::new(text, version));
+        }
+        let room = RoomState::with_doc(doc, files, saves)
+            .expect("a doc seeded from text has no file tree to reject");
+        // The seeding ops must reach a snapshot before the next cold start, or
+        // re-seeding would mint a second copy of them for clients to merge.
+        room.dirty.store(true, Ordering::Relaxed);
+        room
+    }
+
+    /// A room rehydrated from its `ydoc/{project_id}` snapshot. The snapshot
+    /// already holds every file's text *with its CRDT history*, so stored text
+    /// is never re-inserted (that is what duplicated content on rejoin).
+    /// A file still at the version the snapshot recorded for it keeps the
+    /// snapshot's text, edits not yet stored included; the next flush stores
+    /// them. Any other file was saved outside the room after the snapshot was
+    /// taken, or is newer than it, and is reconciled to its stored text with a
+    /// minimal diff. Fails if the snapshot's file tree doesn't validate.
+    fn rehydrate(snapshot: Snapshot, seed: &[FileSeed]) -> Result<RoomState, String> {
+        let Snapshot { doc, versions } = snapshot;
+        let roots: Vec<_> = seed
+            .iter()
+            .map(|(id, _, version)| {
+                let key = id.to_hex();
+                let root = doc.get_or_insert_text(key.as_str());
+                (versions.get(&key) != Some(version)).then_some(root)
+            })
+            .collect();
+        let files = seed.iter().map(|(id, ..)| (id.to_hex(), *id)).collect();
+        let saves = seed
+            .iter()
+            .map(|(id, text, version)| (id.to_hex(), FileSave::new(text.clone(), *version)))
+            .collect();
+
+        // Reconcile after the observers are in place, so any correction marks
+        // the room dirty and is snapshotted on the next flush. Re-deriving the
+        // same correction on a later cold start would mint duplicate ops.
+        let room = RoomState::with_doc(doc, files, saves)?;
+        for ((_, text, _), root) in seed.iter().zip(roots) {
+            if let Some(root) = root {
+                let mut txn = room.awareness.doc().transact_mut();
+                replace_text(&mut txn, &root, text);
+            }
+        }
+        Ok(room)
+    }
+
+    /// A room on cold start: from its snapshot if it has one, otherwise by
+    /// seeding from stored text. A snapshot whose file tree doesn't validate
+    /// is set aside like a corrupt one (see `read_snapshot`): a room starting
+    /// from no accepted tree would roll every node back out of the document
+    /// on the first illegal structural edit.
+    fn load(seed: Vec<FileSeed>, snapshot: Option<Snapshot>) -> RoomState {
+        match snapshot.map(|snapshot| RoomState::rehydrate(snapshot, &seed)) {
+            Some(Ok(room)) => room,
+            Some(Err(reason)) => {
+                warn!("WS snapshot has an invalid file tree, seeding from text: {reason}");
+                RoomState::new(seed)
+            }
+            None => RoomState::new(seed),
         }
-        RoomState {
+    }
+
+    fn with_doc(
+        doc: Doc,
+        files: HashMap<String, ObjectId>,
+        saves: HashMap<String, FileSave>,
+    ) -> Result<RoomState, String> {
+        let nodes = crdt::nodes_map(&doc);
+        let tree = accepted_tree(&doc, &nodes)?;
+        let nodes_touched = Arc::new(AtomicBool::new(false));
+        let flag = nodes_touched.clone();
+        let nodes_sub = nodes.observe_deep(move |_, _| flag.store(true, Ordering::Relaxed));
+
+        let dirty = Arc::new(AtomicBool::new(false));
+        let flag = dirty.clone();
+        let update_sub = doc
+            .observe_update_v1(move |_, _| flag.store(true, Ordering::Relaxed))
+            .expect("no transaction is open on a new room's doc");
+
+        Ok(RoomState {
             awareness: Awareness::new(doc),
             conns: HashMap::new(),
             client_owner: HashMap::new(),
             files,
-            last: HashMap::new(),
+            saves: Rc::new(RefCell::new(saves)),
+            unsaved_reported: false,
+            nodes,
+            tree,
+            nodes_touched,
+            _nodes_sub: nodes_sub,
+            dirty,
+            _update_sub: update_sub,
+            idle_since: None,
+            in_flight: Rc::default(),
+            snapshotting: Rc::new(Cell::new(false)),
+            files_read: 0,
+            files_synced: 0,
+            preview: RoomPreview::default(),
+            analysis: RoomAnalysis::default(),
+        })
+    }
+
+    /// How long the room has held text that isn't stored, as of `now` and
+    /// counted from the flush that first saw it, and how many writes of it
+    /// failed in a row. `None` when all is stored.
+    fn unsaved(&self, now: Instant) -> Option<(Duration, u32)> {
+        let saves = self.saves.borrow();
+        let since = saves.values().filter_map(|save| save.dirty_since).min()?;
+        let failures = saves.values().map(|save| save.failures).sum();
+        Some((now.saturating_duration_since(since), failures))
+    }
+
+    /// The room's document as a snapshot, with the version each file's text
+    /// stands on. A file being written has none yet, so it is reconciled to
+    /// its stored text on rehydrate, whichever way its write went.
+    fn snapshot(&self) -> Vec<u8> {
+        let versions = self
+            .saves
+            .borrow()
+            .iter()
+            .filter_map(|(key, save)| Some((key.clone(), save.version?)))
+            .collect();
+        snapshot::encode_snapshot(self.awareness.doc(), &versions)
+    }
+
+    /// Whether every file's text in the room is stored as it stands.
+    fn all_stored(&self) -> bool {
+        let saves = self.saves.borrow();
+        self.texts().iter().all(|(key, _, text)| {
+            saves
+                .get(key)
+                .is_none_or(|save| save.version.is_some() && save.stored == *text)
+        })
+    }
+
+    /// The current text of every file, as `(text-root key, file id, text)`.
+    fn texts(&self) -> Vec<(String, ObjectId, String)> {
+        let txn = self.awareness.doc().transact();
+        self.files
+            .iter()
+            .filter_map(|(key, id)| {
+                txn.get_text(key.as_str())
+                    .map(|text| (key.clone(), *id, text.get_string(&txn)))
+            })
+            .collect()
+    }
+}
+
+/// Read every text file's stored body, to hydrate a room on cold start. The
+/// manager reads this itself when it loads a room, not at handshake time, so it
+/// always sees the writes of an earlier, since-evicted incarnation of the room.
+async fn load_seed(repo: &impl ProjectRepo, project_id: ObjectId) -> Result<Vec<FileSeed>, String> {
+    let project = repo
+        .find_by_id(project_id)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| "project not found".to_string())?;
+    Ok(project
+        .files
+        .into_iter()
+        .filter_map(|file| match file.content {
+            FileContent::Text { text } => Some((file.id, text, file.version)),
+            FileContent::Binary { .. } => None,
+        })
+        .collect())
+}
+
+/// Read a room's snapshot on cold start, when storage is configured and one
+/// exists. A corrupt snapshot is logged and treated as absent rather than
+/// locking everyone out of the project.
+async fn read_snapshot(project_id: ObjectId, store: Option<&dyn ObjectStore>) -> Option<Snapshot> {
+    match snapshot::load_snapshot(store?, &project_id.to_hex()).await {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            warn!("WS snapshot load failed in {}: {}", project_id.to_hex(), e);
+            None
+        }
+    }
+}
+
+/// Rewrite `root` to hold `target` as one minimal edit: keep the longest common
+/// prefix and suffix, and replace only what lies between. Returns whether
+/// anything changed. Offsets are UTF-8 bytes (the doc's default offset kind),
+/// cut on char boundaries.
+fn replace_text(txn: &mut TransactionMut, root: &TextRef, target: &str) -> bool {
+    let current = root.get_string(txn);
+    if current == target {
+        return false;
+    }
+    let (a, b) = (current.as_bytes(), target.as_bytes());
+    let mut prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    while !current.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    let max_suffix = a.len().min(b.len()) - prefix;
+    let mut suffix = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take(max_suffix)
+        .take_while(|(x, y)| x == y)
+        .count();
+    while !current.is_char_boundary(a.len() - suffix) {
+        suffix -= 1;
+    }
+
+    let removed = a.len() - prefix - suffix;
+    if removed > 0 {
+        root.remove_range(txn, prefix as u32, removed as u32);
+    }
+    let inserted = &target[prefix..b.len() - suffix];
+    if !inserted.is_empty() {
+        root.insert(txn, prefix as u32, inserted);
+    }
+    true
+}
+
+/// Decode the `nodes` map and validate the result, i.e. the tree the document
+/// currently describes if the authority may accept it.
+fn accepted_tree(doc: &Doc, nodes: &MapRef) -> Result<ProjectTree, String> {
+    let tree = crdt::read_tree(&doc.transact(), nodes).map_err(|e| e.to_string())?;
+    tree.validate().map_err(|e| e.to_string())?;
+    Ok(tree)
+}
+
+/// The room authority's structural check, run after a frame touched `nodes`.
+/// A legal tree becomes the new accepted tree; an illegal one (a cycle, a
+/// duplicate sibling name, a file used as a parent, a malformed node, …) is
+/// rolled back to the last accepted tree with compensating CRDT writes. Returns
+/// whether a rollback was written.
+fn enforce_tree(room: &mut RoomState) -> bool {
+    match accepted_tree(room.awareness.doc(), &room.nodes) {
+        Ok(tree) => {
+            if tree != room.tree {
+                room.analysis.world = None;
+            }
+            room.tree = tree;
+            false
+        }
+        Err(reason) => {
+            warn!("WS rejected structural change: {reason}");
+            let mut txn = room.awareness.doc().transact_mut();
+            crdt::restore_tree(&mut txn, &room.nodes, &room.tree)
         }
     }
 }
@@ -342,113 +1093,972 @@ fn retract_connection(room: &mut RoomState, conn_id: ObjectId) -> Option<Vec<u8>
         .map(|update| YMessage::Awareness(update).encode_v1())
 }
 
-/// Single-threaded owner of every room. Serves commands and periodically
-/// flushes text to MongoDB.
+/// Single-threaded owner of the rooms of one shard. Serves commands and
+/// periodically flushes text to MongoDB.
 async fn room_manager(
     mut cmd_rx: UnboundedReceiver<Command>,
-    repo: MongoProjectRepo,
+    preview_tx: WeakUnboundedSender<Command>,
+    compiler: RoomCompiler,
     ws_config: WsConfig,
 ) {
-    let mut rooms: HashMap<ObjectId, RoomState> = HashMap::new();
     let mut persist_tick = interval(Duration::from_secs(ws_config.persist_interval_secs));
+    let idle_timeout = Duration::from_secs(ws_config.room_idle_timeout_secs);
+    let mut shard = Shard {
+        rooms: HashMap::new(),
+        loading: HashMap::new(),
+        replay: VecDeque::new(),
+        compiler,
+        preview_tx,
+        preview_debounce: Duration::from_millis(ws_config.preview_debounce_ms),
+        backoff: Backoff {
+            base: Duration::from_secs(ws_config.persist_interval_secs),
+            max: Duration::from_secs(ws_config.persist_retry_max_secs),
+        },
+        unsaved_alert: Duration::from_secs(ws_config.unsaved_alert_secs),
+    };
+    let (repo, store) = (shard.compiler.repo.clone(), shard.compiler.store.clone());
 
     loop {
+        let next_preview = shard
+            .rooms
+            .values()
+            .filter(|room| !room.preview.running)
+            .filter_map(|room| room.preview.due)
+            .min();
         tokio::select! {
             cmd = cmd_rx.recv() => {
                 match cmd {
-                    Some(Command::Join { project_id, seed, conn_id, out }) => {
-                        let room = rooms.entry(project_id).or_insert_with(|| RoomState::new(seed));
-                        // Send the initial sync step 1 + awareness state.
-                        let mut encoder = EncoderV1::new();
-                        if DefaultProtocol.start(&room.awareness, &mut encoder).is_ok() {
-                            let _ = out.send(encoder.to_vec());
-                        }
-                        room.conns.insert(conn_id, out);
+                    Some(Command::Shutdown { out }) => {
+                        shutdown_rooms(&mut shard.rooms, &repo, store.as_deref()).await;
+                        let _ = out.send(());
+                        // Dropping the receiver refuses whatever is still queued.
+                        break;
                     }
-                    Some(Command::Data { project_id, conn_id, data }) => {
-                        if let Some(room) = rooms.get_mut(&project_id) {
-                            handle_data(room, conn_id, data);
-                        }
-                    }
-                    Some(Command::Leave { project_id, conn_id }) => {
-                        if let Some(room) = rooms.get_mut(&project_id) {
-                            room.conns.remove(&conn_id);
-
-                            // Retract this connection's awareness state (cursor,
-                            // presence) so peers drop it immediately, rather than
-                            // leaving a ghost participant until the process
-                            // restarts (the room itself is kept alive with no
-                            // connections, see below).
-                            if let Some(msg) = retract_connection(room, conn_id) {
-                                broadcast(room, conn_id, &msg);
-                            }
-
-                            if room.conns.is_empty() {
-                                // Keep the room (and its CRDT document) in memory
-                                // even with no connections. Re-deriving the doc
-                                // from text on every (re)join produces independent
-                                // insertions of the same characters, which the CRDT
-                                // merges into DUPLICATED content. A reconnecting
-                                // client must re-sync against the SAME document.
-                                // Just flush its text now.
-                                persist_room(project_id, room, &repo);
-                            }
+                    Some(cmd) => {
+                        shard.serve(cmd).await;
+                        while let Some(cmd) = shard.replay.pop_front() {
+                            shard.serve(cmd).await;
                         }
                     }
                     None => break,
                 }
             }
+            _ = async {
+                match next_preview {
+                    Some(at) => sleep_until(at).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let now = Instant::now();
+                for (project_id, room) in shard.rooms.iter_mut() {
+                    if !room.preview.running && room.preview.due.is_some_and(|due| due <= now) {
+                        start_preview(*project_id, room, &shard.compiler, &shard.preview_tx);
+                    }
+                }
+            }
             _ = persist_tick.tick() => {
-                for (project_id, room) in rooms.iter_mut() {
-                    persist_room(*project_id, room, &repo);
+                for (project_id, room) in shard.rooms.iter_mut() {
+                    persist_room(*project_id, room, &repo, store.as_ref(), &shard.preview_tx, shard.backoff);
+                    report_unsaved(*project_id, room, shard.unsaved_alert);
+                }
+
+                // Evicting needs a snapshot to come back from; without storage,
+                // rooms stay in memory for the life of the process. A room
+                // only goes once the flushes above have stored all of it, so
+                // a rejoin loads exactly what it held.
+                if store.is_some() {
+                    let evicted: Vec<ObjectId> = shard
+                        .rooms
+                        .iter()
+                        .filter(|(_, room)| evictable(room, idle_timeout))
+                        .map(|(project_id, _)| *project_id)
+                        .collect();
+                    for project_id in evicted {
+                        shard.rooms.remove(&project_id);
+                        let (repo, store) = (repo.clone(), store.clone());
+                        tokio::task::spawn_local(async move {
+                            project_room_nodes(project_id, &repo, store.as_deref()).await;
+                        });
+                    }
                 }
             }
         }
     }
 }
 
-/// Apply one client frame to the room's document and fan the result out.
-fn handle_data(room: &mut RoomState, conn_id: ObjectId, data: Vec<u8>) {
-    let is_awareness = data.first() == Some(&MSG_AWARENESS);
+/// The rooms of one room manager, and what it serves their commands with.
+struct Shard {
+    rooms: HashMap<ObjectId, RoomState>,
+    /// Rooms loading on cold start, each with the commands for it that
+    /// arrived meanwhile, to serve in order once it is in (see `load`).
+    loading: HashMap<ObjectId, Vec<Command>>,
+    /// Commands taken back from `loading`, served before the next one received.
+    replay: VecDeque<Command>,
+    compiler: RoomCompiler,
+    /// The manager's own channel, which its tasks report back on.
+    preview_tx: WeakUnboundedSender<Command>,
+    preview_debounce: Duration,
+    backoff: Backoff,
+    unsaved_alert: Duration,
+}
 
-    // Run the protocol against the shared document, and diff the state before /
-    // after to capture exactly what this frame changed.
-    let before = room.awareness.doc().transact().state_vector();
-    let replies = DefaultProtocol.handle(&mut room.awareness, &data);
-    let doc_update = {
-        let txn = room.awareness.doc().transact();
-        (txn.state_vector() != before).then(|| txn.encode_state_as_update_v1(&before))
-    };
+impl Shard {
+    /// Serve one command other than `Shutdown`.
+    async fn serve(&mut self, cmd: Command) {
+        if let Some(queued) = cmd
+            .room()
+            .and_then(|project_id| self.loading.get_mut(&project_id))
+        {
+            queued.push(cmd);
+            return;
+        }
+        let (repo, store) = (&self.compiler.repo, &self.compiler.store);
+        match cmd {
+            Command::Join {
+                project_id,
+                conn_id,
+                out,
+            } => {
+                let Some(room) = self.rooms.get_mut(&project_id) else {
+                    self.load(
+                        project_id,
+                        Command::Join {
+                            project_id,
+                            conn_id,
+                            out,
+                        },
+                    );
+                    return;
+                };
+                room.idle_since = None;
+                // Send the initial sync step 1 + awareness state.
+                let mut encoder = EncoderV1::new();
+                if DefaultProtocol.start(&room.awareness, &mut encoder).is_ok() {
+                    let _ = out.send(encoder.to_vec());
+                }
+                room.conns.insert(conn_id, out);
+            }
+            Command::Data {
+                project_id,
+                conn_id,
+                data,
+            } => {
+                if let Some(room) = self.rooms.get_mut(&project_id) {
+                    if data.first() == Some(&MSG_PREVIEW) {
+                        handle_preview(room, conn_id, &data);
+                    } else if data.first() == Some(&MSG_IDE) {
+                        handle_ide(
+                            project_id,
+                            room,
+                            conn_id,
+                            &data,
+                            &self.compiler,
+                            &self.preview_tx,
+                        );
+                    } else if handle_data(room, conn_id, data) {
+                        room.preview
+                            .schedule(Instant::now() + self.preview_debounce);
+                    }
+                }
+            }
+            Command::Leave {
+                project_id,
+                conn_id,
+            } => {
+                if let Some(room) = self.rooms.get_mut(&project_id) {
+                    room.conns.remove(&conn_id);
+                    room.preview.subscribers.remove(&conn_id);
+
+                    // Retract this connection's awareness state (cursor,
+                    // presence) so peers drop it immediately, rather than
+                    // leaving a ghost participant until the process
+                    // restarts (the room itself is kept alive with no
+                    // connections, see below).
+                    if let Some(msg) = retract_connection(room, conn_id) {
+                        broadcast(room, conn_id, &msg);
+                    }
 
-    // Sync replies (e.g. the sync step 2 carrying current content) go back to
-    // the sender only.
-    match replies {
-        Ok(replies) => {
-            if let Some(origin) = room.conns.get(&conn_id) {
-                for reply in replies {
-                    let _ = origin.send(reply.encode_v1());
+                    if room.conns.is_empty() {
+                        // Keep the room (and its CRDT document) in memory
+                        // for now. Re-deriving the doc from text on every
+                        // (re)join produces independent insertions of the
+                        // same characters, which the CRDT merges into
+                        // DUPLICATED content; a reconnecting client must
+                        // re-sync against the SAME document. Only once it
+                        // is safely snapshotted may it go (see the manager's
+                        // persist tick). Just flush its text and snapshot now.
+                        room.idle_since = Some(Instant::now());
+                        persist_room(
+                            project_id,
+                            room,
+                            repo,
+                            store.as_ref(),
+                            &self.preview_tx,
+                            self.backoff,
+                        );
+                    }
+                }
+            }
+            Command::LiveBlobs { out } => {
+                let _ = out.send(live_blobs(&self.rooms));
+            }
+            Command::LiveTexts { project_id, out } => {
+                let texts = self
+                    .rooms
+                    .get(&project_id)
+                    .map(|room| {
+                        room.texts()
+                            .into_iter()
+                            .map(|(_, id, text)| (id, text))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let _ = out.send(texts);
+            }
+            Command::UnsavedRooms { out } => {
+                let _ = out.send(unsaved_rooms(&self.rooms, self.unsaved_alert));
+            }
+            Command::EditText {
+                project_id,
+                file_id,
+                text,
+                expected_version,
+                out,
+            } => self.edit_text(project_id, file_id, text, expected_version, out),
+            Command::FilesChanged { project_id, out } => match self.rooms.get_mut(&project_id) {
+                Some(room) => reread_files(project_id, room, repo, &self.preview_tx, out),
+                None => {
+                    let _ = out.send(());
+                }
+            },
+            Command::FilesRead {
+                project_id,
+                seq,
+                result,
+                out,
+            } => {
+                if let Some(room) = self.rooms.get_mut(&project_id)
+                    && seq > room.files_synced
+                {
+                    match result {
+                        Ok(Some(project)) => {
+                            room.files_synced = seq;
+                            sync_files(room, &project);
+                            room.preview.project = Some(project);
+                            room.preview
+                                .schedule(Instant::now() + self.preview_debounce);
+                        }
+                        // The project is gone, and its files with it; the
+                        // room goes once idle.
+                        Ok(None) => {
+                            room.files_synced = seq;
+                            room.files.clear();
+                            room.saves.borrow_mut().clear();
+                        }
+                        Err(e) => warn!("WS files not re-read in {}: {}", project_id.to_hex(), e),
+                    }
+                }
+                let _ = out.send(());
+            }
+            // Handled by the manager itself.
+            Command::Shutdown { .. } => {}
+            Command::Loaded { project_id, result } => {
+                let queued = self.loading.remove(&project_id).unwrap_or_default();
+                match result {
+                    Ok((seed, snapshot)) => {
+                        self.rooms
+                            .insert(project_id, RoomState::load(seed, snapshot));
+                        self.replay.extend(queued);
+                    }
+                    Err(e) => {
+                        // Dropping a join's `out` ends its connection; the
+                        // rest find no room.
+                        warn!("WS room load failed in {}: {}", project_id.to_hex(), e);
+                        self.replay.extend(
+                            queued
+                                .into_iter()
+                                .filter(|cmd| !matches!(cmd, Command::Join { .. })),
+                        );
+                    }
+                }
+            }
+            Command::StoredText {
+                project_id,
+                key,
+                text,
+                version,
+            } => {
+                if let Some(room) = self.rooms.get_mut(&project_id)
+                    && adopt_stored_text(room, key, text, version)
+                {
+                    room.preview
+                        .schedule(Instant::now() + self.preview_debounce);
+                }
+            }
+            Command::AnalysisLoaded { project_id, result } => {
+                if let Some(room) = self.rooms.get_mut(&project_id) {
+                    finish_analysis_load(project_id, room, result, &self.compiler);
+                }
+            }
+            Command::Previewed {
+                project_id,
+                project,
+                blobs,
+                result,
+            } => {
+                // The room may have been evicted meanwhile.
+                if let Some(room) = self.rooms.get_mut(&project_id) {
+                    // Unless the room re-read its files meanwhile.
+                    if room.preview.project.is_none() {
+                        room.preview.project = project;
+                    }
+                    finish_preview(project_id, room, blobs, result);
                 }
             }
         }
-        Err(e) => debug!("WS protocol error: {:?}", e),
     }
 
-    // Applied document changes and awareness frames go to everyone else.
-    if let Some(update) = doc_update {
-        let msg = YMessage::Sync(SyncMessage::Update(update)).encode_v1();
-        broadcast(room, conn_id, &msg);
-    }
-    if is_awareness {
-        // Track which connection last reported each awareness client id, so
-        // it can be retracted if this connection disconnects without
-        // reporting a `null` state itself (e.g. a crash or dropped socket).
-        if let Ok(YMessage::Awareness(update)) = YMessage::decode_v1(&data) {
-            for client_id in update.clients.keys() {
-                room.client_owner.insert(*client_id, conn_id);
+    /// A save of a file into its live room, e.g. over REST. The room's text
+    /// becomes `text` by a minimal edit sent to every connection, so it merges
+    /// with the edits collaborators make meanwhile, and a task writes it to
+    /// MongoDB at once, conditional on the version the room holds, and answers
+    /// `out`. `expected_version`, if any, must be that version. While a write
+    /// of the file is in flight, or one lost to a save made outside the room
+    /// that the room hasn't adopted yet, the edit waits for the file to
+    /// settle. A save the edit's own write loses to is adopted as in
+    /// [`persist_room`], and the edit answered with `VersionConflict`. If the
+    /// write fails, the room keeps the text and its next flush retries it.
+    fn edit_text(
+        &mut self,
+        project_id: ObjectId,
+        file_id: ObjectId,
+        text: String,
+        expected_version: Option<i32>,
+        out: oneshot::Sender<Option<Result<ProjectFile, ProjectServiceError>>>,
+    ) {
+        let key = file_id.to_hex();
+        let Some(room) = self
+            .rooms
+            .get_mut(&project_id)
+            .filter(|room| room.files.contains_key(&key))
+        else {
+            let _ = out.send(None);
+            return;
+        };
+        let version = match begin_room_edit(room, &key, &text, expected_version) {
+            Ok(Some(version)) => version,
+            Ok(None) => {
+                // Served again once the file settles, or leaves the room.
+                let Some(settled) = room.saves.borrow().get(&key).map(|s| s.settled.clone()) else {
+                    let _ = out.send(None);
+                    return;
+                };
+                let mut settled = Box::pin(settled.notified_owned());
+                settled.as_mut().enable();
+                let done = self.preview_tx.clone();
+                tokio::task::spawn_local(async move {
+                    settled.await;
+                    if let Some(done) = done.upgrade() {
+                        let _ = done.send(Command::EditText {
+                            project_id,
+                            file_id,
+                            text,
+                            expected_version,
+                            out,
+                        });
+                    }
+                });
+                return;
+            }
+            Err(e) => {
+                let _ = out.send(Some(Err(e)));
+                return;
+            }
+        };
+        room.preview
+            .schedule(Instant::now() + self.preview_debounce);
+
+        let (repo, saves) = (self.compiler.repo.clone(), room.saves.clone());
+        let done = self.preview_tx.clone();
+        let in_flight = room.in_flight.clone();
+        in_flight.start();
+        tokio::task::spawn_local(async move {
+            let result =
+                write_room_text(project_id, file_id, text, version, &repo, &saves, &done).await;
+            in_flight.end();
+            let _ = out.send(Some(result));
+        });
+    }
+
+    /// Load a project's room on cold start, without holding up the rooms
+    /// already in: a task reads its stored text and snapshot, and hands them
+    /// back as [`Command::Loaded`]. Until then `join`, and every other command
+    /// for the room, waits in `loading`.
+    fn load(&mut self, project_id: ObjectId, join: Command) {
+        self.loading.insert(project_id, vec![join]);
+        let (repo, store) = (self.compiler.repo.clone(), self.compiler.store.clone());
+        let done = self.preview_tx.clone();
+        tokio::task::spawn_local(async move {
+            let result = match load_seed(&repo, project_id).await {
+                Ok(seed) => Ok((seed, read_snapshot(project_id, store.as_deref()).await)),
+                Err(e) => Err(e),
+            };
+            if let Some(done) = done.upgrade() {
+                let _ = done.send(Command::Loaded { project_id, result });
+            }
+        });
+    }
+}
+
+/// Re-read a room's project after `FilesChanged`, without holding up the
+/// manager: a task reads it and hands it back as [`Command::FilesRead`],
+/// which answers `out`. The read counts as in flight until then, so the
+/// room isn't evicted and reloaded under it.
+fn reread_files(
+    project_id: ObjectId,
+    room: &mut RoomState,
+    repo: &MongoProjectRepo,
+    done: &WeakUnboundedSender<Command>,
+    out: oneshot::Sender<()>,
+) {
+    room.files_read += 1;
+    let seq = room.files_read;
+    let (repo, done) = (repo.clone(), done.clone());
+    let in_flight = room.in_flight.clone();
+    in_flight.start();
+    tokio::task::spawn_local(async move {
+        let result = repo
+            .find_by_id(project_id)
+            .await
+            .map(|project| project.map(Box::new))
+            .map_err(|e| e.to_string());
+        if let Some(done) = done.upgrade() {
+            let _ = done.send(Command::FilesRead {
+                project_id,
+                seq,
+                result,
+                out,
+            });
+        }
+        in_flight.end();
+    });
+}
+
+/// The rooms whose unsaved edits are older than `alert`.
+fn unsaved_rooms(rooms: &HashMap<ObjectId, RoomState>, alert: Duration) -> Vec<UnsavedRoom> {
+    let now = Instant::now();
+    rooms
+        .iter()
+        .filter_map(|(project_id, room)| {
+            let (unsaved_for, failed_writes) = room.unsaved(now)?;
+            (unsaved_for > alert).then_some(UnsavedRoom {
+                project_id: *project_id,
+                unsaved_for,
+                failed_writes,
+            })
+        })
+        .collect()
+}
+
+/// Log once when a room's unsaved edits grow older than `alert`, and once
+/// more when they are saved.
+fn report_unsaved(project_id: ObjectId, room: &mut RoomState, alert: Duration) {
+    match room.unsaved(Instant::now()) {
+        Some((unsaved_for, failures)) if unsaved_for > alert => {
+            if !room.unsaved_reported {
+                room.unsaved_reported = true;
+                error!(
+                    "WS room {} has edits unsaved for {:?} ({} failed writes)",
+                    project_id.to_hex(),
+                    unsaved_for,
+                    failures
+                );
+            }
+        }
+        Some(_) => {}
+        None => {
+            if room.unsaved_reported {
+                room.unsaved_reported = false;
+                info!("WS room {} saved its overdue edits", project_id.to_hex());
+            }
+        }
+    }
+}
+
+/// Apply one client frame to the room's document and fan the result out.
+/// Returns whether the document changed.
+fn handle_data(room: &mut RoomState, conn_id: ObjectId, data: Vec<u8>) -> bool {
+    let is_awareness = data.first() == Some(&MSG_AWARENESS);
+
+    // Run the protocol against the shared document, and diff the state before /
+    // after to capture exactly what this frame changed.
+    let before = room.awareness.doc().transact().state_vector();
+    let replies = DefaultProtocol.handle(&mut room.awareness, &data);
+
+    // A structural change is validated before anyone else sees it. If it's
+    // rolled back, the sender (which already applied its own change locally)
+    // gets the compensating ops; everyone else gets the net result below.
+    let mut rollback = None;
+    if room.nodes_touched.swap(false, Ordering::Relaxed) {
+        let applied = room.awareness.doc().transact().state_vector();
+        if enforce_tree(room) {
+            let txn = room.awareness.doc().transact();
+            rollback = Some(txn.encode_state_as_update_v1(&applied));
+        }
+        // The rollback itself touched `nodes`; it needs no re-check.
+        room.nodes_touched.store(false, Ordering::Relaxed);
+    }
+
+    let doc_update = {
+        let txn = room.awareness.doc().transact();
+        (txn.state_vector() != before).then(|| txn.encode_state_as_update_v1(&before))
+    };
+
+    // Sync replies (e.g. the sync step 2 carrying current content) go back to
+    // the sender only.
+    match replies {
+        Ok(replies) => {
+            if let Some(origin) = room.conns.get(&conn_id) {
+                for reply in replies {
+                    let _ = origin.send(reply.encode_v1());
+                }
+            }
+        }
+        Err(e) => debug!("WS protocol error: {:?}", e),
+    }
+    if let Some(update) = rollback
+        && let Some(origin) = room.conns.get(&conn_id)
+    {
+        let _ = origin.send(YMessage::Sync(SyncMessage::Update(update)).encode_v1());
+    }
+
+    // Applied document changes and awareness frames go to everyone else.
+    let changed = doc_update.is_some();
+    if let Some(update) = doc_update {
+        let msg = YMessage::Sync(SyncMessage::Update(update)).encode_v1();
+        broadcast(room, conn_id, &msg);
+    }
+    if is_awareness {
+        // Track which connection last reported each awareness client id, so
+        // it can be retracted if this connection disconnects without
+        // reporting a `null` state itself (e.g. a crash or dropped socket).
+        if let Ok(YMessage::Awareness(update)) = YMessage::decode_v1(&data) {
+            for client_id in update.clients.keys() {
+                room.client_owner.insert(*client_id, conn_id);
             }
         }
         broadcast(room, conn_id, &data);
     }
+    changed
+}
+
+/// A client's preview request.
+#[derive(Debug, PartialEq, Eq)]
+enum PreviewRequest {
+    Subscribe(ImageFormat),
+    Unsubscribe,
+}
+
+fn decode_preview_request(data: &[u8]) -> Option<PreviewRequest> {
+    let mut cursor = Cursor::new(data);
+    if cursor.read_u8().ok()? != MSG_PREVIEW {
+        return None;
+    }
+    match cursor.read_u8().ok()? {
+        PREVIEW_SUBSCRIBE => match cursor.read_u8().ok()? {
+            0 => Some(PreviewRequest::Subscribe(ImageFormat::Svg)),
+            1 => Some(PreviewRequest::Subscribe(ImageFormat::Png)),
+            _ => None,
+        },
+        PREVIEW_UNSUBSCRIBE => Some(PreviewRequest::Unsubscribe),
+        _ => None,
+    }
+}
+
+/// A `PAGES` frame: the document's page count, then the pages that changed.
+fn pages_frame<'a>(
+    page_count: usize,
+    pages: impl ExactSizeIterator<Item = (usize, &'a [u8])>,
+) -> Vec<u8> {
+    let mut frame = vec![MSG_PREVIEW, PREVIEW_PAGES];
+    frame.write_var(page_count);
+    frame.write_var(pages.len());
+    for (index, image) in pages {
+        frame.write_var(index);
+        frame.write_buf(image);
+    }
+    frame
+}
+
+/// An `ERROR` frame. A document that doesn't compile carries its errors.
+fn error_frame(error: &ProjectServiceError) -> Vec<u8> {
+    let diagnostics = match error {
+        ProjectServiceError::Compile(CompileError::Failed(errors)) => errors.as_slice(),
+        _ => &[],
+    };
+    let body = serde_json::json!({
+        "message": error.to_string(),
+        "diagnostics": diagnostics,
+    });
+    let mut frame = vec![MSG_PREVIEW, PREVIEW_ERROR];
+    frame.write_string(&body.to_string());
+    frame
+}
+
+/// Serve a preview frame. A new subscriber is sent the pages already rendered
+/// in its format at once; if there are none yet, a render is due now.
+fn handle_preview(room: &mut RoomState, conn_id: ObjectId, data: &[u8]) {
+    match decode_preview_request(data) {
+        Some(PreviewRequest::Subscribe(format)) => {
+            room.preview.subscribers.insert(conn_id, format);
+            match room.preview.pages.get(format) {
+                Some(pages) => {
+                    let frame = pages_frame(
+                        pages.len(),
+                        pages
+                            .iter()
+                            .enumerate()
+                            .map(|(i, (_, image))| (i, image.as_slice())),
+                    );
+                    if let Some(out) = room.conns.get(&conn_id) {
+                        let _ = out.send(frame);
+                    }
+                }
+                None => room.preview.schedule(Instant::now()),
+            }
+        }
+        Some(PreviewRequest::Unsubscribe) => {
+            room.preview.subscribers.remove(&conn_id);
+        }
+        None => debug!("WS malformed preview frame"),
+    }
+}
+
+/// What live previews and language analysis build their worlds from — the
+/// project as stored, its team's fonts, the packages it imports — and the
+/// queue their compilations wait in.
+#[derive(Clone)]
+struct RoomCompiler {
+    repo: MongoProjectRepo,
+    team_repo: MongoTeamRepo,
+    store: Option<Arc<dyn ObjectStore>>,
+    packages: Arc<PackageStore>,
+    font_cache: Arc<FontCache>,
+    compiler: Arc<CompileQueue>,
+}
+
+/// Start rendering a room's preview: its live text, plus paths, entry and
+/// binaries from MongoDB and storage. The result comes back to the manager as
+/// [`Command::Previewed`].
+fn start_preview(
+    project_id: ObjectId,
+    room: &mut RoomState,
+    compiler: &RoomCompiler,
+    done: &WeakUnboundedSender<Command>,
+) {
+    let Some(done) = done.upgrade() else { return };
+    let preview = &mut room.preview;
+    preview.due = None;
+    let formats = preview.formats();
+    preview.pages.retain(&formats);
+    if formats.is_empty() {
+        return;
+    }
+    let previous: Vec<_> = formats
+        .iter()
+        .map(|format| (*format, preview.pages.hashes(*format)))
+        .collect();
+    preview.running = true;
+    let mut blobs = std::mem::take(&mut preview.blobs);
+    let project = preview.project.take();
+    let texts = room
+        .texts()
+        .into_iter()
+        .map(|(_, id, text)| (id, text))
+        .collect();
+    let compiler = compiler.clone();
+    tokio::task::spawn_local(async move {
+        let project = match project {
+            Some(project) => Ok(project),
+            None => read_project(&compiler.repo, project_id).await,
+        };
+        let (project, world) = match project {
+            Ok(project) => {
+                let world = preview_world(
+                    &compiler.team_repo,
+                    compiler.store.as_deref(),
+                    &compiler.packages,
+                    &compiler.font_cache,
+                    Project::clone(&project),
+                    texts,
+                    &mut blobs,
+                )
+                .await;
+                (Some(project), world)
+            }
+            Err(e) => (None, Err(e)),
+        };
+        let result = match world {
+            Ok(world) => compiler
+                .compiler
+                .run(project_id, JobKind::Preview, world, move |world| {
+                    render_preview(world, &previous)
+                })
+                .await
+                .map_err(ProjectServiceError::Compile),
+            Err(e) => Err(e),
+        };
+        let _ = done.send(Command::Previewed {
+            project_id,
+            project,
+            blobs,
+            result,
+        });
+    });
+}
+
+/// A room's project as stored, for its preview and analysis worlds.
+async fn read_project(
+    repo: &impl ProjectRepo,
+    project_id: ObjectId,
+) -> Result<Box<Project>, ProjectServiceError> {
+    repo.find_by_id(project_id)
+        .await
+        .map_err(ProjectServiceError::Database)?
+        .map(Box::new)
+        .ok_or(ProjectServiceError::ProjectNotFound)
+}
+
+/// The world a room's preview renders: the project as stored, with the room's
+/// live text in place of each file's stored text.
+async fn preview_world(
+    team_repo: &impl TeamRepo,
+    store: Option<&dyn ObjectStore>,
+    packages: &PackageStore,
+    font_cache: &FontCache,
+    mut project: Project,
+    mut texts: HashMap<ObjectId, String>,
+    blobs: &mut HashMap<String, Vec<u8>>,
+) -> Result<ProjectWorld, ProjectServiceError> {
+    for file in &mut project.files {
+        if let FileContent::Text { text } = &mut file.content
+            && let Some(live) = texts.remove(&file.id)
+        {
+            *text = live;
+        }
+    }
+    let fonts = owner_fonts(team_repo, &project).await?;
+    project_world(project, &fonts, store, packages, font_cache, blobs).await
+}
+
+/// Take in a finished render: remember the pages and send each subscriber
+/// the ones that changed in its format, or the error.
+fn finish_preview(
+    project_id: ObjectId,
+    room: &mut RoomState,
+    blobs: HashMap<String, Vec<u8>>,
+    result: Result<Vec<PageUpdate>, ProjectServiceError>,
+) {
+    let preview = &mut room.preview;
+    preview.running = false;
+    preview.blobs = blobs;
+    match result {
+        Ok(updates) => {
+            for update in updates {
+                preview.pages.apply(&update);
+                let frame = pages_frame(
+                    update.page_count,
+                    update
+                        .changed
+                        .iter()
+                        .map(|(index, _, image)| (*index, image.as_slice())),
+                );
+                for (conn_id, format) in &preview.subscribers {
+                    if *format == update.format
+                        && let Some(out) = room.conns.get(conn_id)
+                    {
+                        let _ = out.send(frame.clone());
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            debug!("WS preview failed in {}: {}", project_id.to_hex(), e);
+            let frame = error_frame(&e);
+            for conn_id in preview.subscribers.keys() {
+                if let Some(out) = room.conns.get(conn_id) {
+                    let _ = out.send(frame.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Decode a language-analysis request: the client's id, and the request or
+/// why it's malformed. `None` if there's no id to answer to.
+fn decode_ide_request(data: &[u8]) -> Option<(serde_json::Value, Result<IdeRequest, String>)> {
+    let mut cursor = Cursor::new(data);
+    if cursor.read_u8().ok()? != MSG_IDE {
+        return None;
+    }
+    let mut frame: serde_json::Value = serde_json::from_str(cursor.read_string().ok()?).ok()?;
+    let id = frame.get_mut("id")?.take();
+    let request = serde_json::from_value(frame).map_err(|e| e.to_string());
+    Some((id, request))
+}
+
+/// A reply frame: the answer to request `id`, or why there's none.
+fn ide_frame(id: serde_json::Value, result: Result<serde_json::Value, String>) -> Vec<u8> {
+    let body = match result {
+        Ok(result) => serde_json::json!({ "id": id, "result": result }),
+        Err(error) => serde_json::json!({ "id": id, "error": error }),
+    };
+    let mut frame = vec![MSG_IDE];
+    frame.write_string(&body.to_string());
+    frame
+}
+
+/// Serve a language-analysis frame. The room's world is brought up to date
+/// with its text first; if there's none (or it can't follow the text), the
+/// request waits while one loads.
+fn handle_ide(
+    project_id: ObjectId,
+    room: &mut RoomState,
+    conn_id: ObjectId,
+    data: &[u8],
+    compiler: &RoomCompiler,
+    done: &WeakUnboundedSender<Command>,
+) {
+    let Some((id, request)) = decode_ide_request(data) else {
+        debug!("WS malformed analysis frame");
+        return;
+    };
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            if let Some(out) = room.conns.get(&conn_id) {
+                let _ = out.send(ide_frame(id, Err(e)));
+            }
+            return;
+        }
+    };
+    let pending = PendingRequest {
+        conn_id,
+        id,
+        request,
+    };
+    if let Some(waiting) = &mut room.analysis.pending {
+        waiting.push(pending);
+        return;
+    }
+    let texts: Vec<_> = room
+        .texts()
+        .into_iter()
+        .map(|(_, id, text)| (id, text))
+        .collect();
+    if let Some(world) = &mut room.analysis.world
+        && world.update_sources(texts.clone())
+    {
+        answer(project_id, room, pending, compiler);
+        return;
+    }
+
+    let Some(done) = done.upgrade() else { return };
+    room.analysis.world = None;
+    room.analysis.pending = Some(vec![pending]);
+    let project = room.preview.project.clone();
+    let compiler = compiler.clone();
+    tokio::task::spawn_local(async move {
+        let project = match project {
+            Some(project) => project,
+            None => match read_project(&compiler.repo, project_id).await {
+                Ok(project) => project,
+                Err(e) => {
+                    let _ = done.send(Command::AnalysisLoaded {
+                        project_id,
+                        result: Err(e),
+                    });
+                    return;
+                }
+            },
+        };
+        let result = preview_world(
+            &compiler.team_repo,
+            compiler.store.as_deref(),
+            &compiler.packages,
+            &compiler.font_cache,
+            *project,
+            texts.into_iter().collect(),
+            &mut HashMap::new(),
+        )
+        .await
+        .map(Box::new);
+        let _ = done.send(Command::AnalysisLoaded { project_id, result });
+    });
+}
+
+/// Take in a loaded analysis world and answer the requests waiting for it.
+/// The text may have moved on while it loaded, so it's brought up to date
+/// first; if it can't be, or didn't load, they're answered with the error.
+fn finish_analysis_load(
+    project_id: ObjectId,
+    room: &mut RoomState,
+    result: Result<Box<ProjectWorld>, ProjectServiceError>,
+    compiler: &RoomCompiler,
+) {
+    let pending = room.analysis.pending.take().unwrap_or_default();
+    let texts = room.texts().into_iter().map(|(_, id, text)| (id, text));
+    let error = match result {
+        Ok(mut world) => {
+            if world.update_sources(texts) {
+                room.analysis.world = Some(*world);
+                for request in pending {
+                    answer(project_id, room, request, compiler);
+                }
+                return;
+            }
+            "The project changed while it loaded".to_string()
+        }
+        Err(e) => {
+            debug!(
+                "WS analysis failed to load in {}: {}",
+                project_id.to_hex(),
+                e
+            );
+            e.to_string()
+        }
+    };
+    for request in pending {
+        if let Some(out) = room.conns.get(&request.conn_id) {
+            let _ = out.send(ide_frame(request.id, Err(error.clone())));
+        }
+    }
+}
+
+/// Answer a request against the room's world, in the compile queue, and
+/// reply to the connection that asked.
+fn answer(
+    project_id: ObjectId,
+    room: &RoomState,
+    pending: PendingRequest,
+    compiler: &RoomCompiler,
+) {
+    let (Some(world), Some(out)) = (&room.analysis.world, room.conns.get(&pending.conn_id)) else {
+        return;
+    };
+    let (world, out) = (world.clone(), out.clone());
+    let queue = compiler.compiler.clone();
+    let PendingRequest { id, request, .. } = pending;
+    tokio::task::spawn_local(async move {
+        let result = queue
+            .run(project_id, JobKind::Analysis, world, move |world| {
+                Ok(ide::respond(world, request))
+            })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|answer| answer.map_err(|e| e.to_string()));
+        let _ = out.send(ide_frame(id, result));
+    });
 }
 
 /// Send a frame to every connection in the room except `origin`.
@@ -460,38 +2070,501 @@ fn broadcast(room: &RoomState, origin: ObjectId, msg: &[u8]) {
     }
 }
 
-/// Flush each changed file's current CRDT text back to MongoDB. Whole-text
-/// snapshot (not a delta), so the at-rest store stays plain text and REST loads,
-/// preview, and PDF export never need to understand the CRDT.
-fn persist_room(project_id: ObjectId, room: &mut RoomState, repo: &MongoProjectRepo) {
-    let snapshot: Vec<(String, ObjectId, String)> = {
-        let txn = room.awareness.doc().transact();
-        room.files
-            .iter()
-            .filter_map(|(key, id)| {
-                txn.get_text(key.as_str())
-                    .map(|text| (key.clone(), *id, text.get_string(&txn)))
-            })
-            .collect()
+/// Flush each changed file's current CRDT text back to MongoDB, and the room's
+/// Y.Doc snapshot to object storage. The text is a whole-text copy (not a
+/// delta), so the at-rest store stays plain text and REST loads, preview, and
+/// PDF export never need to understand the CRDT; the snapshot is what lets the
+/// room come back with its history after a restart.
+///
+/// Each write is conditional on the file's version (see [`FileSave`]). One
+/// that finds the file saved outside the room meanwhile (over REST, say)
+/// leaves that save in place and sends its text back to `done` as
+/// [`Command::StoredText`], for the room to adopt; one that finds the file
+/// deleted has the room drop it (see [`file_gone`]). A file only counts as
+/// stored once its write succeeds; a failed write is retried by a later
+/// flush, after `backoff`.
+fn persist_room(
+    project_id: ObjectId,
+    room: &mut RoomState,
+    repo: &MongoProjectRepo,
+    store: Option<&Arc<dyn ObjectStore>>,
+    done: &WeakUnboundedSender<Command>,
+    backoff: Backoff,
+) {
+    if let Some(store) = store {
+        persist_snapshot(project_id, room, store);
+    }
+
+    let now = Instant::now();
+    for (key, id, text) in room.texts() {
+        let mut saves = room.saves.borrow_mut();
+        let Some(save) = saves.get_mut(&key) else {
+            continue;
+        };
+        if save.stored == text {
+            save.dirty_since = None;
+            continue;
+        }
+        save.dirty_since.get_or_insert(now);
+        // Backing off after a failed write, or still writing this file: the
+        // text waits for a later flush.
+        if save.retry_at.is_some_and(|at| at > now) {
+            continue;
+        }
+        let Some(version) = save.version.take() else {
+            continue;
+        };
+        drop(saves);
+        let repo = repo.clone();
+        let saves = room.saves.clone();
+        let done = done.clone();
+        let in_flight = room.in_flight.clone();
+        in_flight.start();
+        // Snapshot is already taken (no document borrow held across the await),
+        // so the write can run as its own task on this thread's LocalSet.
+        tokio::task::spawn_local(async move {
+            let size = text.len() as i64;
+            let content = FileContent::Text { text: text.clone() };
+            match repo
+                .update_file_content(project_id, id, content, size, Some(version))
+                .await
+            {
+                Ok(Some(project)) => {
+                    if let Some(file) = project.files.iter().find(|file| file.id == id)
+                        && let Some(save) = saves.borrow_mut().get_mut(&key)
+                    {
+                        save.saved(text, file.version);
+                    }
+                }
+                Ok(None) => match stored_text(&repo, project_id, id).await {
+                    Ok(Some((text, version))) => {
+                        if let Some(done) = done.upgrade() {
+                            let _ = done.send(Command::StoredText {
+                                project_id,
+                                key,
+                                text,
+                                version,
+                            });
+                        }
+                    }
+                    Ok(None) => file_gone(project_id, &done),
+                    Err(e) => {
+                        if let Some(save) = saves.borrow_mut().get_mut(&key) {
+                            save.failed(version, backoff);
+                        }
+                        warn!("WS stored text not read in {}: {}", project_id.to_hex(), e);
+                    }
+                },
+                Err(e) => {
+                    let retry = saves
+                        .borrow_mut()
+                        .get_mut(&key)
+                        .map(|save| save.failed(version, backoff));
+                    warn!(
+                        "WS persist failed in {} (retrying in {:?}): {:?}",
+                        project_id.to_hex(),
+                        retry.unwrap_or_default(),
+                        e
+                    );
+                }
+            }
+            in_flight.end();
+        });
+    }
+}
+
+/// A room's write found its file deleted outside the room: have the room
+/// re-read its project and drop the file, as after `FilesChanged`, so it stops
+/// counting the file's text as unsaved.
+fn file_gone(project_id: ObjectId, done: &WeakUnboundedSender<Command>) {
+    if let Some(done) = done.upgrade() {
+        let (out, _) = oneshot::channel();
+        let _ = done.send(Command::FilesChanged { project_id, out });
+    }
+}
+
+/// A text file's stored text and version; `None` if the file is gone.
+async fn stored_text(
+    repo: &impl ProjectRepo,
+    project_id: ObjectId,
+    file_id: ObjectId,
+) -> Result<Option<(String, i32)>, mongodb::error::Error> {
+    let project = repo.find_by_id(project_id).await?;
+    Ok(project
+        .and_then(|project| project.files.into_iter().find(|file| file.id == file_id))
+        .and_then(|file| match file.content {
+            FileContent::Text { text } => Some((text, file.version)),
+            FileContent::Binary { .. } => None,
+        }))
+}
+
+/// Take in a file's stored text after it was saved outside the room, over the
+/// room's own write (see [`persist_room`]). The stored save is the newer one,
+/// so it wins: the room's text becomes the stored text by a minimal edit, sent
+/// to every connection, and edits the room hadn't saved yet are undone. Only a
+/// room waiting on that file's write takes it; a room loaded since already
+/// holds the stored text. Returns whether the text changed.
+fn adopt_stored_text(room: &mut RoomState, key: String, text: String, version: i32) -> bool {
+    if room
+        .saves
+        .borrow()
+        .get(&key)
+        .is_none_or(|save| save.version.is_some())
+    {
+        return false;
+    }
+    let changed = set_room_text(room, &key, &text);
+    if let Some(save) = room.saves.borrow_mut().get_mut(&key) {
+        save.saved(text, version);
+    }
+    changed
+}
+
+/// Bring a room's files and tree in step with its project as stored, after a
+/// file was created, moved or deleted outside the room. A new text file gets
+/// its stored text, a deleted one leaves the room (its text root stays in the
+/// document, unused), and the `nodes` map becomes the tree the stored files
+/// describe. Every connection is sent the changes. The text of files the room
+/// already holds is its own, and left alone.
+fn sync_files(room: &mut RoomState, project: &Project) {
+    let stored: HashMap<String, (&str, i32)> = project
+        .files
+        .iter()
+        .filter_map(|file| match &file.content {
+            FileContent::Text { text } => Some((file.id.to_hex(), (text.as_str(), file.version))),
+            FileContent::Binary { .. } => None,
+        })
+        .collect();
+    room.files.retain(|key, _| stored.contains_key(key));
+    room.saves
+        .borrow_mut()
+        .retain(|key, _| stored.contains_key(key));
+    for (key, (text, version)) in &stored {
+        if room.files.contains_key(key) {
+            continue;
+        }
+        set_room_text(room, key, text);
+        room.files.insert(
+            key.clone(),
+            ObjectId::parse_str(key).expect("a hex file id"),
+        );
+        room.saves
+            .borrow_mut()
+            .insert(key.clone(), FileSave::new(text.to_string(), *version));
+    }
+
+    let previous = project.nodes.as_deref().unwrap_or_default();
+    match tree::project_nodes(&project.files, previous) {
+        Ok(nodes) => set_room_tree(
+            room,
+            ProjectTree::from_nodes(nodes.into_iter().map(|n| n.node)),
+        ),
+        Err(e) => warn!(
+            "WS stored files form no tree in {}: {}",
+            project.id.to_hex(),
+            e
+        ),
+    }
+}
+
+/// Make `tree` the room's accepted tree, writing it to the `nodes` map and
+/// sending the change to every connection.
+fn set_room_tree(room: &mut RoomState, tree: ProjectTree) {
+    if tree == room.tree {
+        return;
+    }
+    let doc = room.awareness.doc();
+    let before = doc.transact().state_vector();
+    crdt::restore_tree(&mut doc.transact_mut(), &room.nodes, &tree);
+    // The server's own write needs no structural check.
+    room.nodes_touched.store(false, Ordering::Relaxed);
+    let update = doc.transact().encode_state_as_update_v1(&before);
+    let msg = YMessage::Sync(SyncMessage::Update(update)).encode_v1();
+    for out in room.conns.values() {
+        let _ = out.send(msg.clone());
+    }
+    room.tree = tree;
+    room.analysis.world = None;
+}
+
+/// Rewrite one file's text in the room by a minimal edit (see
+/// [`replace_text`]) made by the server, and send it to every connection.
+/// Returns whether the text changed.
+fn set_room_text(room: &RoomState, key: &str, text: &str) -> bool {
+    let doc = room.awareness.doc();
+    let before = doc.transact().state_vector();
+    let root = doc.get_or_insert_text(key);
+    if !replace_text(&mut doc.transact_mut(), &root, text) {
+        return false;
+    }
+    let update = doc.transact().encode_state_as_update_v1(&before);
+    let msg = YMessage::Sync(SyncMessage::Update(update)).encode_v1();
+    for out in room.conns.values() {
+        let _ = out.send(msg.clone());
+    }
+    true
+}
+
+/// Start an edit of a file in its room (see [`Shard::edit_text`]): check
+/// `expected_version` against the version the room holds, apply `text`, and
+/// take the version, so no flush writes the file until the edit's own write
+/// ends. `None`, with the room untouched, while the file has no version.
+fn begin_room_edit(
+    room: &mut RoomState,
+    key: &str,
+    text: &str,
+    expected_version: Option<i32>,
+) -> Result<Option<i32>, ProjectServiceError> {
+    let mut saves = room.saves.borrow_mut();
+    let save = saves
+        .get_mut(key)
+        .ok_or(ProjectServiceError::FileNotFound)?;
+    let Some(version) = save.version else {
+        return Ok(None);
+    };
+    if expected_version.is_some_and(|expected| expected != version) {
+        return Err(ProjectServiceError::VersionConflict(version));
+    }
+    save.version = None;
+    drop(saves);
+    set_room_text(room, key, text);
+    Ok(Some(version))
+}
+
+/// Write an edit begun by [`begin_room_edit`] at `version`, and record how it
+/// went in `saves`. A save made outside the room that the write loses to is
+/// sent back to `done` as [`Command::StoredText`], for the room to adopt.
+async fn write_room_text(
+    project_id: ObjectId,
+    file_id: ObjectId,
+    text: String,
+    version: i32,
+    repo: &impl ProjectRepo,
+    saves: &RefCell<HashMap<String, FileSave>>,
+    done: &WeakUnboundedSender<Command>,
+) -> Result<ProjectFile, ProjectServiceError> {
+    let key = file_id.to_hex();
+    // The room keeps the text, for a later flush to write.
+    let restore = || {
+        if let Some(save) = saves.borrow_mut().get_mut(&key) {
+            save.restore(version);
+        }
     };
+    let size = text.len() as i64;
+    let updated = repo
+        .update_file_content(
+            project_id,
+            file_id,
+            FileContent::Text { text: text.clone() },
+            size,
+            Some(version),
+        )
+        .await;
+    let project = match updated {
+        Ok(Some(project)) => project,
+        Ok(None) => {
+            let (stored, version) = match stored_text(repo, project_id, file_id).await {
+                Ok(Some(stored)) => stored,
+                Ok(None) => {
+                    file_gone(project_id, done);
+                    return Err(ProjectServiceError::FileNotFound);
+                }
+                Err(e) => {
+                    restore();
+                    return Err(ProjectServiceError::Database(e));
+                }
+            };
+            if let Some(done) = done.upgrade() {
+                let _ = done.send(Command::StoredText {
+                    project_id,
+                    key,
+                    text: stored,
+                    version,
+                });
+            }
+            return Err(ProjectServiceError::VersionConflict(version));
+        }
+        Err(e) => {
+            restore();
+            return Err(ProjectServiceError::Database(e));
+        }
+    };
+    // Gone from the project the write returned: deleted meanwhile.
+    let Some(file) = project.files.into_iter().find(|file| file.id == file_id) else {
+        file_gone(project_id, done);
+        return Err(ProjectServiceError::FileNotFound);
+    };
+    if let Some(save) = saves.borrow_mut().get_mut(&key) {
+        save.saved(text, file.version);
+    }
+    Ok(file)
+}
+
+/// Save the room's Y.Doc to `ydoc/{project_id}` if it changed since the last
+/// save. The snapshot is encoded here, together with the file versions it
+/// stands on, and written by a task; while one is writing, the next waits for
+/// a later flush. A failed write re-marks the room dirty so the next flush
+/// retries it.
+fn persist_snapshot(project_id: ObjectId, room: &RoomState, store: &Arc<dyn ObjectStore>) {
+    if room.snapshotting.get() || !room.dirty.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let bytes = room.snapshot();
+    let dirty = room.dirty.clone();
+    let store = store.clone();
+    let (in_flight, snapshotting) = (room.in_flight.clone(), room.snapshotting.clone());
+    in_flight.start();
+    snapshotting.set(true);
+    tokio::task::spawn_local(async move {
+        if let Err(e) = snapshot::save_snapshot(store.as_ref(), &project_id.to_hex(), &bytes).await
+        {
+            dirty.store(true, Ordering::Relaxed);
+            warn!("WS snapshot failed in {}: {}", project_id.to_hex(), e);
+        }
+        snapshotting.set(false);
+        in_flight.end();
+    });
+}
+
+/// Every blob hash referenced by a file node in any room's accepted tree. The
+/// accepted tree is exactly what the doc holds: the authority rolls back
+/// anything else before the next command is served.
+fn live_blobs(rooms: &HashMap<ObjectId, RoomState>) -> HashSet<String> {
+    rooms
+        .values()
+        .flat_map(|room| room.tree.iter())
+        .filter_map(|node| node.blob())
+        .map(|blob| blob.sha256.clone())
+        .collect()
+}
+
+/// Whether an idle room may be evicted: nobody connected for at least
+/// `timeout`, none of its background writes still running, and all of it
+/// stored — every file's text in MongoDB, the document in its snapshot. Until
+/// then, the room's flushes keep storing it.
+fn evictable(room: &RoomState, timeout: Duration) -> bool {
+    room.idle_since
+        .is_some_and(|since| since.elapsed() >= timeout)
+        && room.in_flight.get() == 0
+        && !room.dirty.load(Ordering::Relaxed)
+        && room.all_stored()
+}
+
+/// Bring the project's node projection in step with the text a room stored.
+/// Room flushes leave it behind (each revision would otherwise be uploaded as
+/// a blob of its own); it is caught up once, when the room is evicted or shut
+/// down. A failure leaves it to the next one, and is logged.
+async fn project_room_nodes(
+    project_id: ObjectId,
+    repo: &impl ProjectRepo,
+    store: Option<&dyn ObjectStore>,
+) {
+    let result = match repo.find_by_id(project_id).await {
+        Ok(Some(project)) => sync_nodes(repo, store, &project)
+            .await
+            .map_err(|e| e.to_string()),
+        Ok(None) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        warn!(
+            "WS node projection not updated in {}: {}",
+            project_id.to_hex(),
+            e
+        );
+    }
+}
+
+/// Final flush of a room being shut down: any text that changed since the
+/// last flush, then the snapshot (with `store`). Unlike [`persist_room`] the
+/// writes are awaited, so once this returns `Ok` the stored text and
+/// `ydoc/{project_id}` hold exactly this document and a restart restores it
+/// as-is. A file that fails to be written is logged, and the rest of the
+/// room, snapshot included, is still flushed.
+async fn flush_room(
+    project_id: ObjectId,
+    room: &mut RoomState,
+    repo: &impl ProjectRepo,
+    store: Option<&dyn ObjectStore>,
+) -> Result<(), String> {
+    let mut failed = 0;
+    for (key, id, text) in room.texts() {
+        // A file whose write lost to a save outside the room keeps that save.
+        let Some(version) = room
+            .saves
+            .borrow()
+            .get(&key)
+            .filter(|save| save.stored != text)
+            .and_then(|save| save.version)
+        else {
+            continue;
+        };
+        let size = text.len() as i64;
+        let updated = repo
+            .update_file_content(
+                project_id,
+                id,
+                FileContent::Text { text: text.clone() },
+                size,
+                Some(version),
+            )
+            .await;
+        // Saved outside the room meanwhile: that save stands, and the next
+        // cold start reconciles the room to it.
+        let project = match updated {
+            Ok(Some(project)) => project,
+            Ok(None) => continue,
+            Err(e) => {
+                error!(
+                    "WS flush of file {} failed in {}: {}",
+                    key,
+                    project_id.to_hex(),
+                    e
+                );
+                failed += 1;
+                continue;
+            }
+        };
+        if let Some(file) = project.files.iter().find(|file| file.id == id)
+            && let Some(save) = room.saves.borrow_mut().get_mut(&key)
+        {
+            save.saved(text, file.version);
+        }
+    }
+    project_room_nodes(project_id, repo, store).await;
+    if let Some(store) = store
+        && room.dirty.swap(false, Ordering::Relaxed)
+        && let Err(e) = snapshot::save_snapshot(store, &project_id.to_hex(), &room.snapshot()).await
+    {
+        room.dirty.store(true, Ordering::Relaxed);
+        return Err(e.to_string());
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(format!("{failed} file(s) not written")),
+    }
+}
 
-    for (key, id, text) in snapshot {
-        if room.last.get(&key).is_some_and(|prev| prev == &text) {
-            continue;
+/// Shut every room down: close its connections, let its background writes
+/// finish, and [`flush_room`] it. A room that fails to flush loses the edits it
+/// hadn't stored, and is logged.
+async fn shutdown_rooms(
+    rooms: &mut HashMap<ObjectId, RoomState>,
+    repo: &impl ProjectRepo,
+    store: Option<&dyn ObjectStore>,
+) {
+    for room in rooms.values_mut() {
+        // Dropping a connection's sender closes its socket (see `handle_ws`).
+        room.conns.clear();
+        room.preview.subscribers.clear();
+    }
+    for room in rooms.values() {
+        room.in_flight.drained().await;
+    }
+    for (project_id, room) in rooms.iter_mut() {
+        if let Err(e) = flush_room(*project_id, room, repo, store).await {
+            error!("WS shutdown flush failed in {}: {}", project_id.to_hex(), e);
         }
-        room.last.insert(key, text.clone());
-        let repo = repo.clone();
-        // Snapshot is already taken (no document borrow held across the await),
-        // so the write can run as its own task on this thread's LocalSet.
-        tokio::task::spawn_local(async move {
-            let size = text.len() as i64;
-            if let Err(e) = repo
-                .update_file_content(project_id, id, FileContent::Text { text }, size)
-                .await
-            {
-                warn!("WS persist failed in {}: {:?}", project_id.to_hex(), e);
-            }
-        });
     }
 }
 
@@ -499,6 +2572,14 @@ fn persist_room(project_id: ObjectId, room: &mut RoomState, repo: &MongoProjectR
 #[cfg_attr(coverage_nightly, coverage(off))]
 mod tests {
     use super::*;
+    use crate::models::project::{OwnerType, Project, ProjectFile};
+    use crate::models::tree::{Node, NodeContent};
+    use crate::repo::project::tests::MockProjectRepo;
+    use crate::repo::team::tests::MockTeamRepo;
+    use crate::storage::{Blob, InMemoryObjectStore};
+    use std::sync::Mutex;
+    use time::OffsetDateTime;
+    use yrs::Map as _;
 
     fn insert_conn(room: &mut RoomState) -> (ObjectId, UnboundedReceiver<Vec<u8>>) {
         let conn_id = ObjectId::new();
@@ -533,13 +2614,125 @@ mod tests {
         (client_id, YMessage::Awareness(update).encode_v1())
     }
 
+    /// A client doc already synced with the room, as a connected peer would
+    /// hold it.
+    fn synced_client(room: &RoomState) -> Doc {
+        let client = Doc::new();
+        let state = room
+            .awareness
+            .doc()
+            .transact()
+            .encode_state_as_update_v1(&yrs::StateVector::default());
+        client
+            .transact_mut()
+            .apply_update(yrs::Update::decode_v1(&state).unwrap())
+            .unwrap();
+        client
+    }
+
+    /// Apply a structural edit to `client` and encode just that edit as a
+    /// `Sync(Update(..))` frame.
+    fn structural_frame(
+        client: &Doc,
+        edit: impl FnOnce(&mut yrs::TransactionMut, &MapRef),
+    ) -> Vec<u8> {
+        let nodes = crdt::nodes_map(client);
+        let before = client.transact().state_vector();
+        {
+            let mut txn = client.transact_mut();
+            edit(&mut txn, &nodes);
+        }
+        let update = client.transact().encode_state_as_update_v1(&before);
+        YMessage::Sync(SyncMessage::Update(update)).encode_v1()
+    }
+
+    fn tree_of(doc: &Doc) -> ProjectTree {
+        let nodes = crdt::nodes_map(doc);
+        crdt::read_tree(&doc.transact(), &nodes).unwrap()
+    }
+
+    fn folder(id: &str, parent: Option<&str>, name: &str) -> Node {
+        Node {
+            id: id.to_string(),
+            parent: parent.map(String::from),
+            name: name.to_string(),
+            content: NodeContent::Folder,
+        }
+    }
+
+    fn file(id: &str, parent: Option<&str>, name: &str) -> Node {
+        Node {
+            id: id.to_string(),
+            parent: parent.map(String::from),
+            name: name.to_string(),
+            content: NodeContent::File {
+                blob: Blob {
+                    sha256: "a".repeat(64),
+                    size: 1,
+                },
+            },
+        }
+    }
+
+    /// A room whose accepted tree is `chapters/` + `chapters/intro.typ` +
+    /// `main.typ`, established through the authority like any client edit.
+    fn room_with_tree() -> RoomState {
+        let mut room = RoomState::new(vec![]);
+        let (conn, _rx) = insert_conn(&mut room);
+        let client = synced_client(&room);
+        let frame = structural_frame(&client, |txn, nodes| {
+            crdt::write_node(txn, nodes, &folder("d", None, "chapters"));
+            crdt::write_node(txn, nodes, &file("f", Some("d"), "intro.typ"));
+            crdt::write_node(txn, nodes, &file("m", None, "main.typ"));
+        });
+        handle_data(&mut room, conn, frame);
+        room.conns.remove(&conn);
+        assert_eq!(room.tree.len(), 3);
+        room
+    }
+
+    /// Send an illegal structural edit from one peer and assert the authority
+    /// rolled it back: the room, the other peer, and the sender itself all end
+    /// up on the previously accepted tree.
+    fn assert_rolled_back(edit: impl FnOnce(&mut yrs::TransactionMut, &MapRef)) {
+        let mut room = room_with_tree();
+        let accepted = room.tree.clone();
+        let (conn_a, mut rx_a) = insert_conn(&mut room);
+        let (_conn_b, mut rx_b) = insert_conn(&mut room);
+        let client_a = synced_client(&room);
+        let client_b = synced_client(&room);
+
+        let frame = structural_frame(&client_a, edit);
+        let nodes_a = crdt::nodes_map(&client_a);
+        assert_ne!(
+            crdt::read_tree(&client_a.transact(), &nodes_a),
+            Ok(accepted.clone())
+        );
+        handle_data(&mut room, conn_a, frame);
+
+        assert_eq!(room.tree, accepted);
+        assert_eq!(tree_of(room.awareness.doc()), accepted);
+
+        for (client, rx) in [(&client_a, &mut rx_a), (&client_b, &mut rx_b)] {
+            while let Ok(msg) = rx.try_recv() {
+                if let Ok(YMessage::Sync(SyncMessage::Update(update))) = YMessage::decode_v1(&msg) {
+                    client
+                        .transact_mut()
+                        .apply_update(yrs::Update::decode_v1(&update).unwrap())
+                        .unwrap();
+                }
+            }
+            assert_eq!(tree_of(client), accepted);
+        }
+    }
+
     #[test]
     fn test_room_state_new_seeds_text_and_files_map() {
         let id_a = ObjectId::new();
         let id_b = ObjectId::new();
         let room = RoomState::new(vec![
-            (id_a, "hello".to_string()),
-            (id_b, String::new()),
+            (id_a, "hello".to_string(), 0),
+            (id_b, String::new(), 0),
         ]);
 
         // Text roots are keyed by the file id (hex), not the path.
@@ -560,6 +2753,817 @@ mod tests {
         assert_eq!(room.files.get(&id_b.to_hex()), Some(&id_b));
     }
 
+    fn text_of(doc: &Doc, key: &str) -> String {
+        let txn = doc.transact();
+        txn.get_text(key).unwrap().get_string(&txn)
+    }
+
+    #[test]
+    fn test_replace_text_is_a_minimal_edit() {
+        let doc = Doc::new();
+        let root = doc.get_or_insert_text("t");
+        root.insert(&mut doc.transact_mut(), 0, "héllo wörld");
+        let clock = |doc: &Doc| doc.transact().state_vector().get(&doc.client_id());
+        let before = clock(&doc);
+
+        assert!(replace_text(&mut doc.transact_mut(), &root, "héllo, wörld"));
+        assert_eq!(text_of(&doc, "t"), "héllo, wörld");
+        // Only the inserted comma is a new op; the shared text is untouched.
+        assert_eq!(clock(&doc) - before, 1);
+
+        // Multi-byte characters on both sides of the edit stay intact.
+        assert!(replace_text(&mut doc.transact_mut(), &root, "hällo"));
+        assert_eq!(text_of(&doc, "t"), "hällo");
+        assert!(replace_text(&mut doc.transact_mut(), &root, ""));
+        assert_eq!(text_of(&doc, "t"), "");
+        assert!(!replace_text(&mut doc.transact_mut(), &root, ""));
+    }
+
+    #[test]
+    fn test_rehydrate_does_not_duplicate_text() {
+        let id = ObjectId::new();
+        let seed = vec![(id, "hello".to_string(), 0)];
+        let room = RoomState::new(seed.clone());
+        // A client that synced before the restart keeps its copy.
+        let client = synced_client(&room);
+
+        let snapshot = snapshot::decode_snapshot(&room.snapshot()).unwrap();
+        let room = RoomState::rehydrate(snapshot, &seed).unwrap();
+
+        assert_eq!(text_of(room.awareness.doc(), &id.to_hex()), "hello");
+        // Nothing to reconcile, so nothing new to snapshot.
+        assert!(!room.dirty.load(Ordering::Relaxed));
+        assert_eq!(room.files.get(&id.to_hex()), Some(&id));
+
+        // Re-syncing the old client merges to the same text, not a second copy.
+        let state = snapshot::encode_doc(room.awareness.doc());
+        client
+            .transact_mut()
+            .apply_update(yrs::Update::decode_v1(&state).unwrap())
+            .unwrap();
+        assert_eq!(text_of(&client, &id.to_hex()), "hello");
+    }
+
+    #[test]
+    fn test_rehydrate_reconciles_text_changed_since_snapshot() {
+        let id = ObjectId::new();
+        let added = ObjectId::new();
+        let room = RoomState::new(vec![(id, "hello".to_string(), 0)]);
+        let client = synced_client(&room);
+
+        let snapshot = snapshot::decode_snapshot(&room.snapshot()).unwrap();
+        // Stored text moved on after the snapshot, and a new file appeared.
+        let room = RoomState::rehydrate(
+            snapshot,
+            &[
+                (id, "hello world".to_string(), 1),
+                (added, "new".to_string(), 0),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(text_of(room.awareness.doc(), &id.to_hex()), "hello world");
+        assert_eq!(text_of(room.awareness.doc(), &added.to_hex()), "new");
+        assert!(room.dirty.load(Ordering::Relaxed));
+
+        let state = snapshot::encode_doc(room.awareness.doc());
+        client
+            .transact_mut()
+            .apply_update(yrs::Update::decode_v1(&state).unwrap())
+            .unwrap();
+        assert_eq!(text_of(&client, &id.to_hex()), "hello world");
+    }
+
+    #[test]
+    fn test_rehydrate_keeps_unstored_text_at_the_snapshot_version() {
+        let id = ObjectId::new();
+        let room = RoomState::new(vec![(id, "hello".to_string(), 3)]);
+        let root = room.awareness.doc().get_or_insert_text(id.to_hex());
+        root.insert(&mut room.awareness.doc().transact_mut(), 5, " world");
+        let snapshot = snapshot::decode_snapshot(&room.snapshot()).unwrap();
+
+        // Nobody saved the file since: its text in the snapshot is the newer.
+        let room = RoomState::rehydrate(snapshot, &[(id, "hello".to_string(), 3)]).unwrap();
+        assert_eq!(text_of(room.awareness.doc(), &id.to_hex()), "hello world");
+        assert!(!room.dirty.load(Ordering::Relaxed));
+        // And the next flush stores it.
+        assert_eq!(room.saves.borrow()[&id.to_hex()].stored, "hello");
+    }
+
+    #[test]
+    fn test_load_sets_aside_a_snapshot_with_an_invalid_tree() {
+        let id = ObjectId::new();
+        let invalid = || {
+            let doc = Doc::new();
+            doc.get_or_insert_text(id.to_hex().as_str()).insert(
+                &mut doc.transact_mut(),
+                0,
+                "from the snapshot",
+            );
+            // A file used as a parent.
+            let nodes = crdt::nodes_map(&doc);
+            crdt::write_node(&mut doc.transact_mut(), &nodes, &file("a", None, "a.typ"));
+            crdt::write_node(
+                &mut doc.transact_mut(),
+                &nodes,
+                &file("b", Some("a"), "b.typ"),
+            );
+            Snapshot {
+                doc,
+                versions: HashMap::from([(id.to_hex(), 0)]),
+            }
+        };
+        let seed = vec![(id, "stored".to_string(), 0)];
+        assert!(RoomState::rehydrate(invalid(), &seed).is_err());
+
+        // Seeded from stored text instead, with no tree to roll back to.
+        let room = RoomState::load(seed, Some(invalid()));
+        assert_eq!(text_of(room.awareness.doc(), &id.to_hex()), "stored");
+        assert!(room.tree.is_empty());
+        assert!(room.dirty.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_snapshot_omits_the_version_of_a_file_being_written() {
+        let (id, other) = (ObjectId::new(), ObjectId::new());
+        let room = RoomState::new(vec![(id, "a".to_string(), 3), (other, "b".to_string(), 7)]);
+        room.saves
+            .borrow_mut()
+            .get_mut(&id.to_hex())
+            .unwrap()
+            .version = None;
+
+        let snapshot = snapshot::decode_snapshot(&room.snapshot()).unwrap();
+        assert_eq!(snapshot.versions, HashMap::from([(other.to_hex(), 7)]));
+    }
+
+    #[tokio::test]
+    async fn test_persist_snapshot_waits_for_the_one_in_flight() {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemoryObjectStore::new());
+        let project_id = ObjectId::new();
+        let room = RoomState::new(vec![(ObjectId::new(), "hi".to_string(), 0)]);
+        room.snapshotting.set(true);
+
+        LocalSet::new()
+            .run_until(async {
+                persist_snapshot(project_id, &room, &store);
+                tokio::task::yield_now().await;
+            })
+            .await;
+        // Nothing written, and still dirty for the next flush.
+        assert!(room.dirty.load(Ordering::Relaxed));
+        assert_eq!(room.in_flight.get(), 0);
+        assert!(
+            store
+                .get_object(&format!("ydoc/{}", project_id.to_hex()))
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    /// Build a room as its manager does on cold start.
+    async fn load_room(
+        project_id: ObjectId,
+        seed: Vec<FileSeed>,
+        store: Option<&dyn ObjectStore>,
+    ) -> RoomState {
+        RoomState::load(seed, read_snapshot(project_id, store).await)
+    }
+
+    #[tokio::test]
+    async fn test_persist_snapshot_then_load_room_restores_the_doc() {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemoryObjectStore::new());
+        let project_id = ObjectId::new();
+        let id = ObjectId::new();
+        let seed = vec![(id, "hello".to_string(), 0)];
+
+        // No snapshot yet: seeded from text.
+        let room = load_room(project_id, seed.clone(), Some(store.as_ref())).await;
+        assert_eq!(text_of(room.awareness.doc(), &id.to_hex()), "hello");
+        let client = synced_client(&room);
+
+        let local = LocalSet::new();
+        local
+            .run_until(async {
+                persist_snapshot(project_id, &room, &store);
+                while store
+                    .get_object(&format!("ydoc/{}", project_id.to_hex()))
+                    .await
+                    .unwrap()
+                    .is_none()
+                {
+                    tokio::task::yield_now().await;
+                }
+            })
+            .await;
+        assert!(!room.dirty.load(Ordering::Relaxed));
+
+        // Cold start again: the same document comes back, history included.
+        let reloaded = load_room(project_id, seed, Some(store.as_ref())).await;
+        assert_eq!(
+            snapshot::encode_doc(reloaded.awareness.doc()),
+            snapshot::encode_doc(room.awareness.doc())
+        );
+        let state = snapshot::encode_doc(reloaded.awareness.doc());
+        client
+            .transact_mut()
+            .apply_update(yrs::Update::decode_v1(&state).unwrap())
+            .unwrap();
+        assert_eq!(text_of(&client, &id.to_hex()), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_load_room_falls_back_to_seed_on_corrupt_snapshot() {
+        let store = InMemoryObjectStore::new();
+        let project_id = ObjectId::new();
+        let id = ObjectId::new();
+        store
+            .put_object(&format!("ydoc/{}", project_id.to_hex()), b"garbage")
+            .await
+            .unwrap();
+
+        let room = load_room(project_id, vec![(id, "hi".to_string(), 0)], Some(&store)).await;
+        assert_eq!(text_of(room.awareness.doc(), &id.to_hex()), "hi");
+    }
+
+    fn project_with(files: Vec<ProjectFile>) -> Project {
+        Project {
+            id: ObjectId::new(),
+            name: "test".to_string(),
+            owner_id: ObjectId::new(),
+            owner_type: OwnerType::User,
+            creator_id: ObjectId::new(),
+            files,
+            created_at: OffsetDateTime::now_utc(),
+            updated_at: OffsetDateTime::now_utc(),
+            entry: None,
+            pinned_version: None,
+            nodes: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_seed_reads_text_files_only() {
+        let text = ProjectFile::default();
+        let binary = ProjectFile {
+            id: ObjectId::new(),
+            path: "logo.png".to_string(),
+            content: FileContent::Binary {
+                storage_key: crate::storage::sha256_hex(b"png"),
+            },
+            ..ProjectFile::default()
+        };
+        let project = project_with(vec![text.clone(), binary]);
+        let project_id = project.id;
+        let repo = MockProjectRepo {
+            projects: Mutex::new(vec![project]),
+        };
+
+        let FileContent::Text { text: body } = text.content else {
+            unreachable!()
+        };
+        assert_eq!(
+            load_seed(&repo, project_id).await.unwrap(),
+            vec![(text.id, body, text.version)]
+        );
+        assert!(load_seed(&repo, ObjectId::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_flush_room_flushes_text_and_snapshot() {
+        let file = ProjectFile::default();
+        let project = project_with(vec![file.clone()]);
+        let project_id = project.id;
+        let repo = MockProjectRepo {
+            projects: Mutex::new(vec![project]),
+        };
+        let store = InMemoryObjectStore::new();
+
+        let seed = load_seed(&repo, project_id).await.unwrap();
+        let mut room = load_room(project_id, seed, Some(&store)).await;
+        let root = room.awareness.doc().get_or_insert_text(file.id.to_hex());
+        root.insert(&mut room.awareness.doc().transact_mut(), 0, "edited ");
+
+        flush_room(project_id, &mut room, &repo, Some(&store))
+            .await
+            .unwrap();
+        assert!(!room.dirty.load(Ordering::Relaxed));
+
+        // A later cold start sees the flushed text and the very same document.
+        let seed = load_seed(&repo, project_id).await.unwrap();
+        assert!(seed[0].1.starts_with("edited "));
+        let reloaded = load_room(project_id, seed, Some(&store)).await;
+        assert_eq!(
+            snapshot::encode_doc(reloaded.awareness.doc()),
+            snapshot::encode_doc(room.awareness.doc())
+        );
+        assert!(!reloaded.dirty.load(Ordering::Relaxed));
+    }
+
+    /// Save `text` over REST, as if from another tab, bumping the version.
+    async fn save_outside(repo: &MockProjectRepo, project_id: ObjectId, id: ObjectId, text: &str) {
+        let content = FileContent::Text {
+            text: text.to_string(),
+        };
+        repo.update_file_content(project_id, id, content, text.len() as i64, None)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flush_room_keeps_a_newer_save() {
+        let file = ProjectFile::default();
+        let project = project_with(vec![file.clone()]);
+        let project_id = project.id;
+        let repo = MockProjectRepo {
+            projects: Mutex::new(vec![project]),
+        };
+        let store = InMemoryObjectStore::new();
+
+        let seed = load_seed(&repo, project_id).await.unwrap();
+        let mut room = load_room(project_id, seed, Some(&store)).await;
+        save_outside(&repo, project_id, file.id, "saved over REST").await;
+        let root = room.awareness.doc().get_or_insert_text(file.id.to_hex());
+        root.insert(&mut room.awareness.doc().transact_mut(), 0, "edited ");
+
+        flush_room(project_id, &mut room, &repo, Some(&store))
+            .await
+            .unwrap();
+        let seed = load_seed(&repo, project_id).await.unwrap();
+        assert_eq!(seed[0].1, "saved over REST");
+        assert_eq!(seed[0].2, file.version + 1);
+
+        // The next cold start reconciles to the save.
+        let reloaded = load_room(project_id, seed, Some(&store)).await;
+        assert_eq!(
+            text_of(reloaded.awareness.doc(), &file.id.to_hex()),
+            "saved over REST"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_rooms_closes_connections_and_flushes() {
+        let file = ProjectFile::default();
+        let project = project_with(vec![file.clone()]);
+        let project_id = project.id;
+        let repo = MockProjectRepo {
+            projects: Mutex::new(vec![project]),
+        };
+        let store = InMemoryObjectStore::new();
+
+        let seed = load_seed(&repo, project_id).await.unwrap();
+        let mut room = load_room(project_id, seed, Some(&store)).await;
+        let (_, mut rx) = insert_conn(&mut room);
+        let root = room.awareness.doc().get_or_insert_text(file.id.to_hex());
+        root.insert(&mut room.awareness.doc().transact_mut(), 0, "edited ");
+        let edited = text_of(room.awareness.doc(), &file.id.to_hex());
+        let mut rooms = HashMap::from([(project_id, room)]);
+
+        shutdown_rooms(&mut rooms, &repo, Some(&store)).await;
+        assert!(matches!(
+            rx.try_recv(),
+            Err(mpsc::error::TryRecvError::Disconnected)
+        ));
+        let seed = load_seed(&repo, project_id).await.unwrap();
+        assert_eq!(seed[0].1, edited);
+        let restored = load_room(project_id, seed, Some(&store)).await;
+        assert_eq!(text_of(restored.awareness.doc(), &file.id.to_hex()), edited);
+    }
+
+    #[tokio::test]
+    async fn test_room_edits_save_through_the_room() {
+        let file = ProjectFile::default();
+        let project = project_with(vec![file.clone()]);
+        let project_id = project.id;
+        let repo = MockProjectRepo {
+            projects: Mutex::new(vec![project]),
+        };
+        let key = file.id.to_hex();
+        let mut room = RoomState::new(load_seed(&repo, project_id).await.unwrap());
+        let (_, mut rx) = insert_conn(&mut room);
+        let client = synced_client(&room);
+        let (tx, mut stored_rx) = mpsc::unbounded_channel();
+        let done = tx.downgrade();
+        let stored = |repo: &MockProjectRepo| {
+            let projects = repo.projects.lock().unwrap();
+            projects[0].files[0].clone()
+        };
+
+        // Applied to the room and sent to its editors at once, then written.
+        let version = begin_room_edit(&mut room, &key, "= REST", Some(0)).unwrap();
+        assert_eq!(version, Some(0));
+        assert_eq!(text_of(room.awareness.doc(), &key), "= REST");
+        assert_eq!(room.saves.borrow()[&key].version, None);
+        let Ok(YMessage::Sync(SyncMessage::Update(update))) =
+            YMessage::decode_v1(&rx.try_recv().unwrap())
+        else {
+            panic!("expected a sync update");
+        };
+        client
+            .transact_mut()
+            .apply_update(yrs::Update::decode_v1(&update).unwrap())
+            .unwrap();
+        assert_eq!(text_of(&client, &key), "= REST");
+        let saved = write_room_text(
+            project_id,
+            file.id,
+            "= REST".to_string(),
+            0,
+            &repo,
+            &room.saves,
+            &done,
+        )
+        .await
+        .unwrap();
+        assert_eq!(saved.version, 1);
+        assert_eq!(room.saves.borrow()[&key].version, Some(1));
+        assert_eq!(room.saves.borrow()[&key].stored, "= REST");
+
+        // A save made on an older version is refused, and the room untouched.
+        let res = begin_room_edit(&mut room, &key, "stale", Some(0));
+        assert!(matches!(res, Err(ProjectServiceError::VersionConflict(1))));
+        assert_eq!(text_of(room.awareness.doc(), &key), "= REST");
+
+        // Saved outside the room meanwhile: the write loses, and the room is
+        // sent that save to take instead.
+        save_outside(&repo, project_id, file.id, "= Outside").await;
+        let version = begin_room_edit(&mut room, &key, "= Mine", None).unwrap();
+        let res = write_room_text(
+            project_id,
+            file.id,
+            "= Mine".to_string(),
+            version.unwrap(),
+            &repo,
+            &room.saves,
+            &done,
+        )
+        .await;
+        assert!(matches!(res, Err(ProjectServiceError::VersionConflict(2))));
+        let Ok(Command::StoredText {
+            key: stored_key,
+            text,
+            version,
+            ..
+        }) = stored_rx.try_recv()
+        else {
+            panic!("expected the stored text");
+        };
+
+        // Until the room takes it in, edits wait for the file to settle.
+        assert_eq!(
+            begin_room_edit(&mut room, &key, "= Later", Some(2)).unwrap(),
+            None
+        );
+        let settled = room.saves.borrow()[&key].settled.clone();
+        let mut settled = Box::pin(settled.notified_owned());
+        settled.as_mut().enable();
+        assert!(adopt_stored_text(&mut room, stored_key, text, version));
+        tokio::time::timeout(Duration::from_secs(1), settled)
+            .await
+            .unwrap();
+        assert_eq!(text_of(room.awareness.doc(), &key), "= Outside");
+        assert_eq!(room.saves.borrow()[&key].version, Some(2));
+
+        let version = begin_room_edit(&mut room, &key, "= Later", Some(2)).unwrap();
+        write_room_text(
+            project_id,
+            file.id,
+            "= Later".to_string(),
+            version.unwrap(),
+            &repo,
+            &room.saves,
+            &done,
+        )
+        .await
+        .unwrap();
+        match stored(&repo).content {
+            FileContent::Text { text } => assert_eq!(text, "= Later"),
+            FileContent::Binary { .. } => panic!("expected text content"),
+        }
+
+        // Deleted outside the room: the save is answered `FileNotFound`, as a
+        // PUT to a deleted file is, and the room is told to drop the file,
+        // which wakes the edits waiting on it.
+        repo.projects.lock().unwrap()[0].files.clear();
+        let version = begin_room_edit(&mut room, &key, "= Gone", None).unwrap();
+        let res = write_room_text(
+            project_id,
+            file.id,
+            "= Gone".to_string(),
+            version.unwrap(),
+            &repo,
+            &room.saves,
+            &done,
+        )
+        .await;
+        assert!(matches!(res, Err(ProjectServiceError::FileNotFound)));
+        assert_eq!(res.unwrap_err().status_code(), StatusCode::NOT_FOUND);
+        assert!(matches!(
+            stored_rx.try_recv(),
+            Ok(Command::FilesChanged { .. })
+        ));
+        let settled = room.saves.borrow()[&key].settled.clone();
+        let mut settled = Box::pin(settled.notified_owned());
+        settled.as_mut().enable();
+        let project = repo.projects.lock().unwrap()[0].clone();
+        sync_files(&mut room, &project);
+        tokio::time::timeout(Duration::from_secs(1), settled)
+            .await
+            .unwrap();
+        assert!(room.saves.borrow().is_empty());
+        assert_eq!(room.unsaved(Instant::now()), None);
+    }
+
+    #[test]
+    fn test_adopt_stored_text_after_a_lost_write() {
+        let id = ObjectId::new();
+        let key = id.to_hex();
+        let mut room = RoomState::new(vec![(id, "hello".to_string(), 0)]);
+        let (_, mut rx) = insert_conn(&mut room);
+        let client = synced_client(&room);
+
+        // Not waiting on a write of the file: a stale reply is ignored.
+        assert!(!adopt_stored_text(
+            &mut room,
+            key.clone(),
+            "old".to_string(),
+            0
+        ));
+        assert_eq!(text_of(room.awareness.doc(), &key), "hello");
+
+        // The room's write (from version 0) lost to a save at version 1.
+        room.saves.borrow_mut().get_mut(&key).unwrap().version = None;
+        assert!(adopt_stored_text(
+            &mut room,
+            key.clone(),
+            "hello, REST".to_string(),
+            1
+        ));
+        assert_eq!(text_of(room.awareness.doc(), &key), "hello, REST");
+        assert_eq!(room.saves.borrow()[&key].version, Some(1));
+        assert_eq!(room.saves.borrow()[&key].stored, "hello, REST");
+
+        // Connections get the edit.
+        let Ok(YMessage::Sync(SyncMessage::Update(update))) =
+            YMessage::decode_v1(&rx.try_recv().unwrap())
+        else {
+            panic!("expected a sync update");
+        };
+        client
+            .transact_mut()
+            .apply_update(yrs::Update::decode_v1(&update).unwrap())
+            .unwrap();
+        assert_eq!(text_of(&client, &key), "hello, REST");
+    }
+
+    #[test]
+    fn test_sync_files_follows_files_changed_outside_the_room() {
+        let (main_id, old_id) = (ObjectId::new(), ObjectId::new());
+        let mut room = RoomState::new(vec![
+            (main_id, "= Main, edited".to_string(), 0),
+            (old_id, "bye".to_string(), 0),
+        ]);
+        let (_, mut rx) = insert_conn(&mut room);
+        let client = synced_client(&room);
+        let text_file = |id, path: &str, text: &str| ProjectFile {
+            id,
+            path: path.to_string(),
+            content: FileContent::Text {
+                text: text.to_string(),
+            },
+            ..ProjectFile::default()
+        };
+
+        // `old.typ` was deleted and `chapters/intro.typ` created over REST.
+        let intro_id = ObjectId::new();
+        let mut project = project_with(vec![
+            text_file(main_id, "main.typ", "= Main"),
+            text_file(intro_id, "chapters/intro.typ", "= Intro"),
+        ]);
+        sync_files(&mut room, &project);
+
+        let mut keys: Vec<_> = room.files.keys().cloned().collect();
+        keys.sort();
+        let mut expected = vec![main_id.to_hex(), intro_id.to_hex()];
+        expected.sort();
+        assert_eq!(keys, expected);
+        assert!(!room.saves.borrow().contains_key(&old_id.to_hex()));
+        assert_eq!(room.saves.borrow()[&intro_id.to_hex()].version, Some(0));
+        // The room's own text of a file it holds stands.
+        assert_eq!(
+            text_of(room.awareness.doc(), &main_id.to_hex()),
+            "= Main, edited"
+        );
+        let paths = |room: &RoomState| {
+            let mut paths: Vec<_> = room.tree.paths().unwrap().into_values().collect();
+            paths.sort();
+            paths
+        };
+        assert_eq!(paths(&room), ["chapters", "chapters/intro.typ", "main.typ"]);
+
+        // Then `chapters/intro.typ` is moved to `intro.typ`.
+        project.nodes = Some(tree::project_nodes(&project.files, &[]).unwrap());
+        project.files[1].path = "intro.typ".to_string();
+        sync_files(&mut room, &project);
+        assert_eq!(paths(&room), ["intro.typ", "main.typ"]);
+        assert_eq!(tree_of(room.awareness.doc()), room.tree);
+
+        // Connections get every change.
+        while let Ok(msg) = rx.try_recv() {
+            if let Ok(YMessage::Sync(SyncMessage::Update(update))) = YMessage::decode_v1(&msg) {
+                client
+                    .transact_mut()
+                    .apply_update(yrs::Update::decode_v1(&update).unwrap())
+                    .unwrap();
+            }
+        }
+        assert_eq!(tree_of(&client), room.tree);
+        assert_eq!(text_of(&client, &intro_id.to_hex()), "= Intro");
+    }
+
+    #[test]
+    fn test_shard_of_is_stable_and_spreads_projects() {
+        let ids: Vec<_> = (0..1000).map(|_| ObjectId::new()).collect();
+        let mut counts = [0; 4];
+        for id in &ids {
+            let shard = shard_of(*id, 4);
+            assert_eq!(shard_of(*id, 4), shard);
+            counts[shard] += 1;
+        }
+        assert!(counts.iter().all(|&count| count > 150), "{counts:?}");
+        assert!(ids.iter().all(|id| shard_of(*id, 1) == 0));
+    }
+
+    #[tokio::test]
+    async fn test_project_server_routes_to_one_shard_and_asks_them_all() {
+        let (txs, mut rxs): (Vec<_>, Vec<_>) = (0..4).map(|_| mpsc::unbounded_channel()).unzip();
+        let server = ProjectServer { shards: txs.into() };
+
+        // A project's commands reach its own shard only.
+        let project_id = ObjectId::new();
+        server.leave(project_id, ObjectId::new());
+        for (shard, rx) in rxs.iter_mut().enumerate() {
+            assert_eq!(rx.try_recv().is_ok(), shard == shard_of(project_id, 4));
+        }
+
+        // Every shard answers for its own rooms.
+        async fn serve_blobs(rxs: &mut [UnboundedReceiver<Command>]) {
+            for (shard, rx) in rxs.iter_mut().enumerate() {
+                let Some(Command::LiveBlobs { out }) = rx.recv().await else {
+                    panic!("expected LiveBlobs");
+                };
+                let _ = out.send(HashSet::from([shard.to_string()]));
+            }
+        }
+        let (blobs, ()) = tokio::join!(server.live_blobs(), serve_blobs(&mut rxs));
+        assert_eq!(blobs.unwrap().len(), 4);
+
+        // Without one of them, nothing can be said.
+        rxs.pop();
+        let (blobs, ()) = tokio::join!(server.live_blobs(), serve_blobs(&mut rxs));
+        assert!(blobs.is_none());
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_max() {
+        let backoff = Backoff {
+            base: Duration::from_secs(3),
+            max: Duration::from_secs(20),
+        };
+        let delays: Vec<_> = (1..=5).map(|n| backoff.delay(n).as_secs()).collect();
+        assert_eq!(delays, [3, 6, 12, 20, 20]);
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(20));
+    }
+
+    #[test]
+    fn test_failed_writes_keep_the_room_unsaved_until_one_succeeds() {
+        let id = ObjectId::new();
+        let key = id.to_hex();
+        let room = RoomState::new(vec![(id, "hello".to_string(), 0)]);
+        let backoff = Backoff {
+            base: Duration::from_secs(3),
+            max: Duration::from_secs(300),
+        };
+        let now = Instant::now();
+        assert!(room.unsaved(now).is_none());
+
+        // A flush saw unsaved text, and its write failed twice.
+        let mut saves = room.saves.borrow_mut();
+        let save = saves.get_mut(&key).unwrap();
+        save.dirty_since = Some(now - Duration::from_secs(90));
+        save.version = None;
+        assert_eq!(save.failed(0, backoff), Duration::from_secs(3));
+        save.version = None;
+        assert_eq!(save.failed(0, backoff), Duration::from_secs(6));
+        assert_eq!(save.version, Some(0));
+        assert!(save.retry_at.is_some_and(|at| at > now));
+        assert_eq!(save.stored, "hello");
+        drop(saves);
+
+        let (unsaved_for, failures) = room.unsaved(now).unwrap();
+        assert_eq!(unsaved_for, Duration::from_secs(90));
+        assert_eq!(failures, 2);
+        let rooms = HashMap::from([(ObjectId::new(), room)]);
+        assert_eq!(unsaved_rooms(&rooms, Duration::from_secs(60)).len(), 1);
+        assert!(unsaved_rooms(&rooms, Duration::from_secs(120)).is_empty());
+
+        // The write goes through: stored, and nothing left to report.
+        let room = rooms.values().next().unwrap();
+        room.saves
+            .borrow_mut()
+            .get_mut(&key)
+            .unwrap()
+            .saved("hello, world".to_string(), 1);
+        let save = &room.saves.borrow()[&key];
+        assert_eq!((save.failures, save.retry_at), (0, None));
+        assert_eq!(save.version, Some(1));
+        assert!(room.unsaved(now).is_none());
+        assert!(unsaved_rooms(&rooms, Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn test_live_blobs_collects_file_blobs_across_rooms() {
+        let rooms = HashMap::from([
+            (ObjectId::new(), room_with_tree()),
+            (ObjectId::new(), RoomState::new(vec![])),
+        ]);
+        assert_eq!(live_blobs(&rooms), HashSet::from(["a".repeat(64)]));
+    }
+
+    #[test]
+    fn test_evictable_waits_for_idle_timeout_and_in_flight_writes() {
+        let timeout = Duration::from_secs(60);
+        let id = ObjectId::new();
+        let mut room = RoomState::new(vec![(id, "hi".to_string(), 0)]);
+        room.dirty.store(false, Ordering::Relaxed);
+        assert!(!evictable(&room, timeout));
+
+        room.idle_since = Some(Instant::now());
+        assert!(!evictable(&room, timeout));
+
+        room.idle_since = Some(Instant::now() - timeout);
+        assert!(evictable(&room, timeout));
+
+        room.in_flight.start();
+        assert!(!evictable(&room, timeout));
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_drained_waits_for_the_last_write() {
+        let in_flight = Rc::new(InFlight::default());
+        in_flight.drained().await;
+
+        in_flight.start();
+        in_flight.start();
+        let local = LocalSet::new();
+        local
+            .run_until(async {
+                let writes = in_flight.clone();
+                tokio::task::spawn_local(async move {
+                    writes.end();
+                    tokio::task::yield_now().await;
+                    writes.end();
+                });
+                in_flight.drained().await;
+            })
+            .await;
+        assert_eq!(in_flight.get(), 0);
+    }
+
+    #[test]
+    fn test_evictable_waits_until_the_room_is_stored() {
+        let timeout = Duration::from_secs(60);
+        let id = ObjectId::new();
+        let mut room = RoomState::new(vec![(id, "hi".to_string(), 0)]);
+        room.idle_since = Some(Instant::now() - timeout);
+        // The seeded document isn't snapshotted yet.
+        assert!(!evictable(&room, timeout));
+        room.dirty.store(false, Ordering::Relaxed);
+        assert!(evictable(&room, timeout));
+
+        // Text the room holds but hasn't stored.
+        let root = room.awareness.doc().get_or_insert_text(id.to_hex());
+        root.insert(&mut room.awareness.doc().transact_mut(), 2, "!");
+        room.dirty.store(false, Ordering::Relaxed);
+        assert!(!evictable(&room, timeout));
+        room.saves
+            .borrow_mut()
+            .get_mut(&id.to_hex())
+            .unwrap()
+            .saved("hi!".to_string(), 1);
+        assert!(evictable(&room, timeout));
+
+        // A write that lost to a save outside, not yet adopted.
+        room.saves
+            .borrow_mut()
+            .get_mut(&id.to_hex())
+            .unwrap()
+            .version = None;
+        assert!(!evictable(&room, timeout));
+    }
+
     #[test]
     fn test_handle_data_broadcasts_doc_update_to_others_not_sender() {
         let mut room = RoomState::new(vec![]);
@@ -582,7 +3586,7 @@ mod tests {
 
     #[test]
     fn test_handle_data_sync_reply_goes_to_sender_only() {
-        let mut room = RoomState::new(vec![(ObjectId::new(), "hi".to_string())]);
+        let mut room = RoomState::new(vec![(ObjectId::new(), "hi".to_string(), 0)]);
         let (conn_a, mut rx_a) = insert_conn(&mut room);
         let (_conn_b, mut rx_b) = insert_conn(&mut room);
 
@@ -630,6 +3634,80 @@ mod tests {
         assert!(rx_b.try_recv().is_err());
     }
 
+    #[test]
+    fn test_handle_data_accepts_valid_structural_change() {
+        let mut room = room_with_tree();
+        let (conn_a, mut rx_a) = insert_conn(&mut room);
+        let (_conn_b, mut rx_b) = insert_conn(&mut room);
+        let client = synced_client(&room);
+
+        // Move `intro.typ` to the root: legal, so it's accepted and broadcast.
+        let frame = structural_frame(&client, |txn, nodes| {
+            crdt::write_node(txn, nodes, &file("f", None, "intro.typ"));
+        });
+        handle_data(&mut room, conn_a, frame);
+
+        assert_eq!(room.tree, tree_of(&client));
+        assert_eq!(room.tree.path_of("f").unwrap(), "intro.typ");
+        assert!(rx_a.try_recv().is_err());
+        rx_b.try_recv().expect("broadcast to other connection");
+    }
+
+    #[test]
+    fn test_handle_data_rolls_back_cycle() {
+        assert_rolled_back(|txn, nodes| {
+            crdt::write_node(txn, nodes, &folder("e", Some("d"), "part1"));
+            crdt::write_node(txn, nodes, &folder("d", Some("e"), "chapters"));
+        });
+    }
+
+    #[test]
+    fn test_handle_data_rolls_back_duplicate_sibling_name() {
+        assert_rolled_back(|txn, nodes| {
+            crdt::write_node(txn, nodes, &file("x", None, "main.typ"));
+        });
+    }
+
+    #[test]
+    fn test_handle_data_rolls_back_file_used_as_parent() {
+        assert_rolled_back(|txn, nodes| {
+            crdt::write_node(txn, nodes, &file("x", Some("m"), "child.typ"));
+        });
+    }
+
+    #[test]
+    fn test_handle_data_rolls_back_malformed_node() {
+        assert_rolled_back(|txn, nodes| {
+            let m = nodes.insert(txn, "x", yrs::MapPrelim::default());
+            m.insert(txn, "kind", "symlink");
+            m.insert(txn, "name", "link");
+        });
+    }
+
+    #[test]
+    fn test_handle_data_text_edit_does_not_decode_tree() {
+        let mut room = room_with_tree();
+        let accepted = room.tree.clone();
+        // A malformed node the authority wasn't told about: decoding the tree
+        // now would reject it and roll it back.
+        {
+            let mut txn = room.awareness.doc().transact_mut();
+            let m = room.nodes.insert(&mut txn, "x", yrs::MapPrelim::default());
+            m.insert(&mut txn, "kind", "symlink");
+        }
+        room.nodes_touched.store(false, Ordering::Relaxed);
+        let (conn_a, mut rx_a) = insert_conn(&mut room);
+
+        handle_data(&mut room, conn_a, doc_update_frame("a.typ", "hello"));
+        assert_eq!(room.tree, accepted);
+        assert!(
+            room.nodes
+                .get(&room.awareness.doc().transact(), "x")
+                .is_some()
+        );
+        assert!(rx_a.try_recv().is_err());
+    }
+
     #[test]
     fn test_retract_connection_removes_owned_awareness_and_returns_retraction() {
         let mut room = RoomState::new(vec![]);
@@ -672,4 +3750,228 @@ mod tests {
         assert!(result.is_none());
         assert_eq!(room.client_owner.get(&client_id), Some(&conn_b));
     }
+
+    /// Decode a `PAGES` frame into its page count and `(index, image)` pages.
+    fn decode_pages(frame: &[u8]) -> (usize, Vec<(usize, Vec<u8>)>) {
+        let mut cursor = Cursor::new(frame);
+        assert_eq!(cursor.read_u8().unwrap(), MSG_PREVIEW);
+        assert_eq!(cursor.read_u8().unwrap(), PREVIEW_PAGES);
+        let page_count = cursor.read_var().unwrap();
+        let len: usize = cursor.read_var().unwrap();
+        let pages = (0..len)
+            .map(|_| {
+                let index = cursor.read_var().unwrap();
+                (index, cursor.read_buf().unwrap().to_vec())
+            })
+            .collect();
+        (page_count, pages)
+    }
+
+    #[test]
+    fn test_decode_preview_request() {
+        assert_eq!(
+            decode_preview_request(&[MSG_PREVIEW, PREVIEW_SUBSCRIBE, 0]),
+            Some(PreviewRequest::Subscribe(ImageFormat::Svg))
+        );
+        assert_eq!(
+            decode_preview_request(&[MSG_PREVIEW, PREVIEW_SUBSCRIBE, 1]),
+            Some(PreviewRequest::Subscribe(ImageFormat::Png))
+        );
+        assert_eq!(
+            decode_preview_request(&[MSG_PREVIEW, PREVIEW_UNSUBSCRIBE]),
+            Some(PreviewRequest::Unsubscribe)
+        );
+        assert_eq!(
+            decode_preview_request(&[MSG_PREVIEW, PREVIEW_SUBSCRIBE, 7]),
+            None
+        );
+        assert_eq!(decode_preview_request(&[MSG_PREVIEW]), None);
+        assert_eq!(decode_preview_request(&[MSG_AWARENESS, 0]), None);
+    }
+
+    #[test]
+    fn test_preview_renders_only_for_subscribers_after_a_change() {
+        let mut room = RoomState::new(vec![]);
+        let (conn_id, _rx) = insert_conn(&mut room);
+
+        // Nobody watches: a change schedules nothing.
+        assert!(handle_data(&mut room, conn_id, doc_update_frame("a", "x")));
+        room.preview.schedule(Instant::now());
+        assert_eq!(room.preview.due, None);
+
+        // A first subscriber has nothing rendered to catch up on: due now.
+        handle_preview(&mut room, conn_id, &[MSG_PREVIEW, PREVIEW_SUBSCRIBE, 1]);
+        assert!(room.preview.due.is_some());
+        assert_eq!(room.preview.formats(), [ImageFormat::Png]);
+
+        handle_preview(&mut room, conn_id, &[MSG_PREVIEW, PREVIEW_UNSUBSCRIBE]);
+        assert!(room.preview.formats().is_empty());
+    }
+
+    #[test]
+    fn test_finish_preview_sends_changed_pages_per_format() {
+        let mut room = RoomState::new(vec![]);
+        let (svg_conn, mut svg_rx) = insert_conn(&mut room);
+        let (png_conn, mut png_rx) = insert_conn(&mut room);
+        handle_preview(&mut room, svg_conn, &[MSG_PREVIEW, PREVIEW_SUBSCRIBE, 0]);
+        handle_preview(&mut room, png_conn, &[MSG_PREVIEW, PREVIEW_SUBSCRIBE, 1]);
+        room.preview.running = true;
+
+        let update = PageUpdate {
+            format: ImageFormat::Svg,
+            page_count: 2,
+            changed: vec![(0, 1, b"<svg/>".to_vec()), (1, 2, b"<svg></svg>".to_vec())],
+        };
+        finish_preview(ObjectId::new(), &mut room, HashMap::new(), Ok(vec![update]));
+        assert!(!room.preview.running);
+        let (page_count, pages) = decode_pages(&svg_rx.try_recv().unwrap());
+        assert_eq!(page_count, 2);
+        assert_eq!(
+            pages,
+            [(0, b"<svg/>".to_vec()), (1, b"<svg></svg>".to_vec())]
+        );
+        assert!(png_rx.try_recv().is_err());
+
+        // A later subscriber is caught up with every page at once.
+        let (late_conn, mut late_rx) = insert_conn(&mut room);
+        handle_preview(&mut room, late_conn, &[MSG_PREVIEW, PREVIEW_SUBSCRIBE, 0]);
+        assert_eq!(decode_pages(&late_rx.try_recv().unwrap()).1.len(), 2);
+    }
+
+    #[test]
+    fn test_finish_preview_sends_errors_to_every_subscriber() {
+        let mut room = RoomState::new(vec![]);
+        let (conn_id, mut rx) = insert_conn(&mut room);
+        let (_, mut bystander_rx) = insert_conn(&mut room);
+        handle_preview(&mut room, conn_id, &[MSG_PREVIEW, PREVIEW_SUBSCRIBE, 0]);
+
+        let result = Err(ProjectServiceError::Compile(CompileError::NoEntry));
+        finish_preview(ObjectId::new(), &mut room, HashMap::new(), result);
+
+        let frame = rx.try_recv().unwrap();
+        assert_eq!(frame[..2], [MSG_PREVIEW, PREVIEW_ERROR]);
+        let mut cursor = Cursor::new(&frame[2..]);
+        let body: serde_json::Value = serde_json::from_str(cursor.read_string().unwrap()).unwrap();
+        assert_eq!(body["message"], "The project has no entry file");
+        assert!(bystander_rx.try_recv().is_err());
+    }
+
+    fn ide_request_frame(body: &str) -> Vec<u8> {
+        let mut frame = vec![MSG_IDE];
+        frame.write_string(body);
+        frame
+    }
+
+    #[test]
+    fn test_decode_ide_request() {
+        let file_id = ObjectId::new();
+        let body = serde_json::json!({
+            "id": 7,
+            "method": "hover",
+            "params": {"file_id": file_id, "position": {"line": 1, "column": 2}},
+        });
+        let (id, request) = decode_ide_request(&ide_request_frame(&body.to_string())).unwrap();
+        assert_eq!(id, 7);
+        assert!(matches!(
+            request,
+            Ok(IdeRequest::Hover { file_id: f, position }) if f == file_id && position.column == 2
+        ));
+
+        // Malformed, but with an id to answer.
+        let (id, request) =
+            decode_ide_request(&ide_request_frame(r#"{"id": "a", "method": "format"}"#)).unwrap();
+        assert_eq!(id, "a");
+        assert!(request.is_err());
+
+        assert!(decode_ide_request(&ide_request_frame(r#"{"method": "hover"}"#)).is_none());
+        assert!(decode_ide_request(&ide_request_frame("not json")).is_none());
+        assert!(decode_ide_request(&[MSG_PREVIEW, PREVIEW_UNSUBSCRIBE]).is_none());
+    }
+
+    #[test]
+    fn test_ide_frame_carries_result_or_error() {
+        let decode = |frame: Vec<u8>| {
+            let mut cursor = Cursor::new(&frame);
+            assert_eq!(cursor.read_u8().unwrap(), MSG_IDE);
+            serde_json::from_str::<serde_json::Value>(cursor.read_string().unwrap()).unwrap()
+        };
+        assert_eq!(
+            decode(ide_frame(serde_json::json!(1), Ok(serde_json::Value::Null))),
+            serde_json::json!({"id": 1, "result": null})
+        );
+        assert_eq!(
+            decode(ide_frame(
+                serde_json::json!(2),
+                Err("File not found".to_string())
+            )),
+            serde_json::json!({"id": 2, "error": "File not found"})
+        );
+    }
+
+    #[test]
+    fn test_tree_change_drops_the_analysis_world() {
+        let mut room = room_with_tree();
+        let main = ObjectId::new();
+        room.analysis.world = Some(
+            ProjectWorld::new(
+                main,
+                vec![crate::compile::WorldFile {
+                    id: main,
+                    path: "main.typ".to_string(),
+                    content: crate::compile::WorldContent::Text(String::new()),
+                }],
+            )
+            .unwrap(),
+        );
+        // An unchanged tree keeps it.
+        assert!(!enforce_tree(&mut room));
+        assert!(room.analysis.world.is_some());
+
+        {
+            let mut txn = room.awareness.doc().transact_mut();
+            crdt::write_node(&mut txn, &room.nodes, &folder("f2", None, "more"));
+        }
+        assert!(!enforce_tree(&mut room));
+        assert!(room.analysis.world.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_preview_world_uses_live_text_and_caches_blobs() {
+        let store = InMemoryObjectStore::new();
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="1" height="1"/>"#;
+        let blob = store.put(svg).await.unwrap();
+        let main = ProjectFile {
+            content: FileContent::Text {
+                text: "#panic()".to_string(),
+            },
+            ..ProjectFile::default()
+        };
+        let image = ProjectFile {
+            id: ObjectId::new(),
+            path: "dot.svg".to_string(),
+            content: FileContent::Binary {
+                storage_key: blob.sha256.clone(),
+            },
+            ..ProjectFile::default()
+        };
+        let mut project = project_with(vec![main.clone(), image]);
+        project.entry = Some(main.id);
+
+        // The stored text panics; the room's live text compiles.
+        let texts = HashMap::from([(main.id, "#image(\"dot.svg\")".to_string())]);
+        let mut blobs = HashMap::new();
+        let world = preview_world(
+            &MockTeamRepo::default(),
+            Some(&store),
+            &PackageStore::default(),
+            &FontCache::default(),
+            project,
+            texts,
+            &mut blobs,
+        )
+        .await
+        .unwrap();
+        assert!(crate::compile::compile_document(&world).is_ok());
+        assert_eq!(blobs.get(&blob.sha256).map(Vec::as_slice), Some(&svg[..]));
+    }
 }
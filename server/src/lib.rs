@@ -1,5 +1,6 @@
 #![cfg_attr(coverage_nightly, feature(coverage_attribute))]
 
+pub mod compile;
 pub mod config;
 pub mod crdt;
 pub mod database;
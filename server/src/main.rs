@@ -3,13 +3,20 @@
 use actix_web::{App, HttpServer, web};
 use server::{
     AppState,
+    compile::{CompileQueue, FontCache, PackageStore, RenderCache},
     config::Config,
     database::Database,
     handler::ws::ProjectServer,
     repo::{project::MongoProjectRepo, team::MongoTeamRepo, user::MongoUserRepo},
-    services::{project::ProjectService, team::TeamService, user::UserService},
+    services::{
+        gc::GcService, project::ProjectService, team::TeamService, tree::MigrationService,
+        user::UserService,
+    },
+    storage,
 };
-use std::{env, io};
+use std::{env, io, sync::Arc, time::Duration};
+use tokio::time::{Instant, interval_at};
+use tracing::{error, info, warn};
 use tracing_subscriber::fmt;
 
 #[cfg_attr(coverage_nightly, coverage(off))]
@@ -35,6 +42,15 @@ async fn main() -> io::Result<()> {
         collection: database.db.collection("projects"),
     };
 
+    let store = config
+        .storage
+        .as_ref()
+        .map(|storage| storage::from_config(storage).expect("Failed to configure object storage"));
+
+    let packages = Arc::new(PackageStore::new(&config.packages, store.clone()));
+    let font_cache = Arc::new(FontCache::new(config.compile.font_cache_bytes));
+    let compiler = Arc::new(CompileQueue::new(&config.compile));
+
     let data = web::Data::new(AppState {
         user_service: UserService {
             user_repo: user_repo.clone(),
@@ -46,21 +62,115 @@ async fn main() -> io::Result<()> {
             team_repo: team_repo.clone(),
             user_repo: user_repo.clone(),
             project_repo: project_repo.clone(),
+            store: store.clone(),
         },
         project_service: ProjectService {
             project_repo: project_repo.clone(),
             user_repo: user_repo.clone(),
             team_repo: team_repo.clone(),
+            store: store.clone(),
+            packages: packages.clone(),
+            font_cache: font_cache.clone(),
+            compiler: compiler.clone(),
         },
     });
 
     // Create ProjectServer instance (actor-less implementation). It owns a repo
-    // handle so collaboration rooms can persist live CRDT text back to MongoDB.
+    // handle so collaboration rooms can persist live CRDT text back to MongoDB,
+    // and the object store (if any) for their Y.Doc snapshots.
     let ws_config = config.ws.clone();
-    let project_server = ProjectServer::new(project_repo.clone(), ws_config.clone());
+    let project_server = ProjectServer::new(
+        project_repo.clone(),
+        team_repo.clone(),
+        store.clone(),
+        packages,
+        font_cache,
+        compiler,
+        ws_config.clone(),
+    );
+
+    // Migrate projects onto the node projection in the background, then verify
+    // every projection. Both are safe to repeat, so each startup resumes
+    // whatever an earlier run left unfinished.
+    if let Some(store) = store.clone() {
+        let migration = MigrationService {
+            project_repo: project_repo.clone(),
+            store,
+        };
+        actix_web::rt::spawn(async move {
+            match migration.migrate().await {
+                Ok(report) => {
+                    info!(
+                        "Tree migration: migrated {}, deferred {}, failed {}, rekeyed {}",
+                        report.migrated,
+                        report.deferred,
+                        report.failed.len(),
+                        report.rekeyed
+                    );
+                    for (id, reason) in report.failed {
+                        warn!("Tree migration failed for {}: {}", id.to_hex(), reason);
+                    }
+                }
+                Err(e) => warn!("Tree migration aborted: {}", e),
+            }
+            match migration.verify().await {
+                Ok(report) => {
+                    info!(
+                        "Tree verification: checked {}, unmigrated {}, problems {}",
+                        report.checked,
+                        report.unmigrated,
+                        report.problems.len()
+                    );
+                    for (id, problem) in report.problems {
+                        warn!("Tree verification in {}: {}", id.to_hex(), problem);
+                    }
+                }
+                Err(e) => warn!("Tree verification aborted: {}", e),
+            }
+        });
+    }
+
+    // Blob GC runs in the background on its own interval, first one full
+    // interval after startup so restarts don't each trigger a pass.
+    if let (Some(gc_config), Some(store)) = (config.gc.clone(), store) {
+        let gc = GcService {
+            project_repo: project_repo.clone(),
+            team_repo: team_repo.clone(),
+            store,
+        };
+        let project_server = project_server.clone();
+        actix_web::rt::spawn(async move {
+            let period = Duration::from_secs(gc_config.interval_secs);
+            let grace = Duration::from_secs(gc_config.grace_secs);
+            let mut tick = interval_at(Instant::now() + period, period);
+            loop {
+                tick.tick().await;
+                match gc
+                    .collect(project_server.live_blobs(), grace, gc_config.dry_run)
+                    .await
+                {
+                    Ok(report) => info!(
+                        "Blob GC{}: scanned {}, referenced {}, kept {} young, swept {} ({} bytes)",
+                        if report.dry_run { " (dry run)" } else { "" },
+                        report.scanned,
+                        report.referenced,
+                        report.young,
+                        report.swept.len(),
+                        report.swept_bytes
+                    ),
+                    Err(e) => warn!("Blob GC aborted: {}", e),
+                }
+            }
+        });
+    }
+
+    // One cache shared by every worker.
+    let render_cache = web::Data::new(RenderCache::new(config.render.cache_bytes));
 
+    let shutdown_timeout = Duration::from_secs(ws_config.shutdown_timeout_secs);
     let jwt_secret = config.jwt_secret.clone();
     let address = config.address.clone();
+    let rooms = project_server.clone();
 
     let factory = move || {
         let cors = config.cors();
@@ -70,17 +180,60 @@ async fn main() -> io::Result<()> {
             .app_data(data.clone())
             .app_data(web::Data::new(project_server.clone()))
             .app_data(web::Data::new(ws_config.clone()))
+            .app_data(web::Data::new(config.upload.clone()))
+            .app_data(render_cache.clone())
             .configure(|cfg| server::routes::configure(cfg, jwt_secret.clone()))
             .wrap(actix_web::middleware::Logger::default())
     };
 
-    let mut server = HttpServer::new(factory);
+    // Signals are handled here rather than by actix, so collaboration rooms
+    // are flushed and their sockets closed before the server drains: open
+    // WebSockets would otherwise hold its graceful stop until the timeout.
+    let mut server = HttpServer::new(factory).disable_signals();
 
     for addr in address {
         server = server.bind(addr)?;
     }
 
-    server.run().await?;
+    let server = server.run();
+    let handle = server.handle();
+    actix_web::rt::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutting down: flushing collaboration rooms");
+        // A flush stuck on its storage mustn't keep the server up.
+        tokio::select! {
+            flushed = tokio::time::timeout(shutdown_timeout, rooms.shutdown()) => {
+                if flushed.is_err() {
+                    error!(
+                        "Collaboration rooms not flushed within {:?}; stopping anyway",
+                        shutdown_timeout
+                    );
+                }
+            }
+            () = shutdown_signal() => {
+                warn!("Second signal: stopping without waiting for the rooms to flush");
+            }
+        }
+        handle.stop(true).await;
+    });
+
+    server.await?;
 
     Ok(())
 }
+
+/// Resolves on Ctrl-C, or on SIGTERM where there is one.
+#[cfg_attr(coverage_nightly, coverage(off))]
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use bson::oid::ObjectId;
 use bson::serde_helpers::time_0_3_offsetdatetime_as_bson_datetime;
 use derive_more::Display;
@@ -7,6 +9,8 @@ use time::OffsetDateTime;
 
 use time::serde::rfc3339;
 
+use crate::models::tree::NodeProjection;
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Display)]
 pub enum OwnerType {
     #[serde(rename = "user")]
@@ -36,6 +40,42 @@ pub struct Project {
     /// awareness channel, never in this document.
     pub entry: Option<ObjectId>,
     pub pinned_version: Option<Version>,
+    /// The id-based file tree (see [`crate::models::tree`]) as a projection:
+    /// folder nodes for every path prefix plus one node per file, keyed by the
+    /// file's id (hex), each with its derived path. Kept in step with `files`
+    /// by every file write, and what listings take paths from. `None` until the
+    /// project is migrated (see [`crate::services::tree`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<Vec<NodeProjection>>,
+}
+
+impl Project {
+    /// Take `files` in listing order. Once migrated, paths and order come from
+    /// the node projection. A file the projection doesn't know yet (a write
+    /// whose projection update hasn't landed) is still listed, after the
+    /// others and under its own path.
+    pub fn take_listed_files(&mut self) -> Vec<ProjectFile> {
+        let mut files = std::mem::take(&mut self.files);
+        let Some(nodes) = &self.nodes else {
+            return files;
+        };
+        let paths: HashMap<&str, &str> = nodes
+            .iter()
+            .filter(|n| n.node.is_file())
+            .map(|n| (n.node.id.as_str(), n.path.as_str()))
+            .collect();
+        let mut projected = Vec::with_capacity(files.len());
+        for file in &mut files {
+            let path = paths.get(file.id.to_hex().as_str());
+            if let Some(path) = path {
+                file.path = path.to_string();
+            }
+            projected.push(path.is_some());
+        }
+        let mut keyed: Vec<_> = projected.into_iter().zip(files).collect();
+        keyed.sort_by(|(a, x), (b, y)| b.cmp(a).then_with(|| x.path.cmp(&y.path)));
+        keyed.into_iter().map(|(_, file)| file).collect()
+    }
 }
 
 /// A single node in the project's virtual file system.
@@ -92,9 +132,31 @@ impl Default for ProjectFile {
 pub enum FileContent {
     /// UTF-8 source stored inline in the document (`.typ`, `.bib`, `.csl`, …).
     Text { text: String },
-    /// A binary asset (image, font, …) stored outside the document — in GridFS
-    /// or object storage — and referenced here by its storage key.
-    Binary { storage_key: ObjectId },
+    /// A binary asset (image, font, …) stored outside the document, in object
+    /// storage. `storage_key` is the SHA-256 of its bytes, i.e. the blob at
+    /// `blobs/{storage_key}` (see [`crate::storage`]).
+    Binary {
+        #[serde(deserialize_with = "storage_key")]
+        storage_key: String,
+    },
+}
+
+/// Read a binary's `storage_key`: a blob hash, or the `ObjectId` binaries were
+/// keyed by before object storage. That one is read as its hex, which names no
+/// blob, so the file's bytes are missing until it is uploaded again; the tree
+/// migration rewrites such keys as strings (see
+/// `ProjectRepo::rekey_legacy_binaries`).
+fn storage_key<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StorageKey {
+        Hash(String),
+        Legacy(ObjectId),
+    }
+    Ok(match StorageKey::deserialize(deserializer)? {
+        StorageKey::Hash(key) => key,
+        StorageKey::Legacy(id) => id.to_hex(),
+    })
 }
 
 impl FileContent {
@@ -146,17 +208,14 @@ pub struct ProjectFilePayload {
 }
 
 impl From<Project> for ProjectPayload {
-    fn from(project: Project) -> Self {
+    fn from(mut project: Project) -> Self {
+        let files = project.take_listed_files();
         ProjectPayload {
             id: project.id.to_hex(),
             name: project.name,
             owner_id: project.owner_id.to_hex(),
             owner_type: project.owner_type,
-            files: project
-                .files
-                .into_iter()
-                .map(ProjectFilePayload::from)
-                .collect(),
+            files: files.into_iter().map(ProjectFilePayload::from).collect(),
             creator_id: project.creator_id.to_hex(),
             created_at: project.created_at,
             updated_at: project.updated_at,
@@ -233,9 +292,7 @@ impl From<FileContent> for FileContentPayload {
     fn from(content: FileContent) -> Self {
         match content {
             FileContent::Text { text } => FileContentPayload::Text { text },
-            FileContent::Binary { storage_key } => FileContentPayload::Binary {
-                storage_key: storage_key.to_hex(),
-            },
+            FileContent::Binary { storage_key } => FileContentPayload::Binary { storage_key },
         }
     }
 }
@@ -275,17 +332,14 @@ impl From<ProjectFile> for UpdateFilePayload {
 }
 
 impl From<Project> for ProjectDetailPayload {
-    fn from(project: Project) -> Self {
+    fn from(mut project: Project) -> Self {
+        let files = project.take_listed_files();
         ProjectDetailPayload {
             id: project.id.to_hex(),
             name: project.name,
             owner_id: project.owner_id.to_hex(),
             owner_type: project.owner_type,
-            files: project
-                .files
-                .into_iter()
-                .map(ProjectFileDetailPayload::from)
-                .collect(),
+            files: files.into_iter().map(ProjectFileDetailPayload::from).collect(),
             creator_id: project.creator_id.to_hex(),
             created_at: project.created_at,
             updated_at: project.updated_at,
@@ -294,3 +348,24 @@ impl From<Project> for ProjectDetailPayload {
         }
     }
 }
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_storage_key_reads_a_hash_or_a_legacy_object_id() {
+        let hash = "a".repeat(64);
+        let content: FileContent =
+            bson::from_document(bson::doc! { "kind": "binary", "storage_key": &hash }).unwrap();
+        assert!(matches!(content, FileContent::Binary { storage_key } if storage_key == hash));
+
+        let legacy = ObjectId::new();
+        let content: FileContent =
+            bson::from_document(bson::doc! { "kind": "binary", "storage_key": legacy }).unwrap();
+        assert!(
+            matches!(content, FileContent::Binary { storage_key } if storage_key == legacy.to_hex())
+        );
+    }
+}
@@ -14,6 +14,14 @@ impl<T> ApiResponse<T> {
             payload: Some(payload),
         }
     }
+
+    /// An error that carries details for the client to act on.
+    pub fn error_with_payload(message: &str, payload: T) -> Self {
+        Self {
+            message: message.to_string(),
+            payload: Some(payload),
+        }
+    }
 }
 
 impl ApiResponse<()> {
@@ -57,4 +65,11 @@ mod tests {
         assert_eq!(response.message, "Error occurred");
         assert_eq!(response.payload, None);
     }
+
+    #[test]
+    fn test_error_response_with_payload() {
+        let response = ApiResponse::error_with_payload("Conflict", 3);
+        assert_eq!(response.message, "Conflict");
+        assert_eq!(response.payload, Some(3));
+    }
 }
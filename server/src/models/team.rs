@@ -12,12 +12,41 @@ pub struct Team {
     pub avatar_uri: Option<String>,
     pub creator_id: ObjectId,
     pub member_ids: Vec<ObjectId>,
+    /// Fonts available to every project the team owns.
+    #[serde(default)]
+    pub fonts: Vec<TeamFont>,
     #[serde(with = "time_0_3_offsetdatetime_as_bson_datetime")]
     pub created_at: OffsetDateTime,
     #[serde(with = "time_0_3_offsetdatetime_as_bson_datetime")]
     pub updated_at: OffsetDateTime,
 }
 
+/// A font file uploaded to a team. The bytes are a blob in object storage;
+/// the faces are parsed from them on upload, so listing never reads the blob.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TeamFont {
+    pub id: ObjectId,
+    pub filename: String,
+    /// SHA-256 of the font file, its key in object storage.
+    pub storage_key: String,
+    pub size: u64,
+    /// One face per font in the file; a collection (`.ttc`) has several.
+    pub faces: Vec<FontFace>,
+    #[serde(with = "time_0_3_offsetdatetime_as_bson_datetime")]
+    pub uploaded_at: OffsetDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FontFace {
+    pub family: String,
+    /// `normal`, `italic` or `oblique`.
+    pub style: String,
+    /// From 100 (thin) to 900 (black).
+    pub weight: u16,
+    /// Width relative to normal, from 0.5 to 2.0.
+    pub stretch: f64,
+}
+
 #[derive(Serialize)]
 pub struct TeamPayload {
     pub id: String,
@@ -31,6 +60,30 @@ pub struct TeamPayload {
     pub updated_at: OffsetDateTime,
 }
 
+#[derive(Serialize)]
+pub struct TeamFontPayload {
+    pub id: String,
+    pub filename: String,
+    pub storage_key: String,
+    pub size: u64,
+    pub faces: Vec<FontFace>,
+    #[serde(with = "rfc3339")]
+    pub uploaded_at: OffsetDateTime,
+}
+
+impl From<TeamFont> for TeamFontPayload {
+    fn from(font: TeamFont) -> Self {
+        TeamFontPayload {
+            id: font.id.to_hex(),
+            filename: font.filename,
+            storage_key: font.storage_key,
+            size: font.size,
+            faces: font.faces,
+            uploaded_at: font.uploaded_at,
+        }
+    }
+}
+
 impl From<Team> for TeamPayload {
     fn from(team: Team) -> Self {
         TeamPayload {
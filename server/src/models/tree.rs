@@ -138,7 +138,7 @@ pub fn is_valid_segment(name: &str) -> bool {
 /// `path`, serialized flat (`{ id, parent, name, kind, blob?, path }`). This is
 /// the *projection* the metadata store keeps so listings and access checks
 /// don't need to load the Y.Doc; it can be rebuilt from the tree at any time.
-#[derive(Debug, Clone, PartialEq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NodeProjection {
     #[serde(flatten)]
     pub node: Node,
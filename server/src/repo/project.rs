@@ -2,8 +2,10 @@ use bson::oid::ObjectId;
 use futures_util::TryStreamExt;
 use mongodb::error::Result;
 use mongodb::options::ReturnDocument;
+use time::OffsetDateTime;
 
-use crate::models::project::{FileContent, OwnerType, Project};
+use crate::models::project::{FileContent, OwnerType, Project, ProjectFile};
+use crate::models::tree::NodeProjection;
 
 #[async_trait::async_trait]
 pub trait ProjectRepo {
@@ -16,14 +18,47 @@ pub trait ProjectRepo {
     ) -> Result<Vec<Project>>;
     /// Replace one file's content, bump its version and `updated_at` (and the
     /// project's), and return the updated project. `None` if the project or the
-    /// file does not exist. The file is addressed by its stable id, not path,
-    /// so a concurrent rename does not misroute the write.
+    /// file does not exist, or if `expected_version` is given and the file is
+    /// at another version — the check and the write are one atomic update, so
+    /// of two saves made from the same version only the first lands. The file
+    /// is addressed by its stable id, not path, so a concurrent rename does not
+    /// misroute the write.
     async fn update_file_content(
         &self,
         project_id: ObjectId,
         file_id: ObjectId,
         content: FileContent,
         size: i64,
+        expected_version: Option<i32>,
+    ) -> Result<Option<Project>>;
+    /// Append `file` to a project's files unless one already exists at its
+    /// path, bump the project's `updated_at`, and return the updated project.
+    /// `None` if the project does not exist *or* the path is taken — the path
+    /// check and the insert are one atomic update, so two concurrent creates of
+    /// the same path can't both succeed.
+    async fn insert_file(&self, project_id: ObjectId, file: ProjectFile)
+    -> Result<Option<Project>>;
+    /// Move one file to `path` (a rename, a move between folders, or both),
+    /// bump its `updated_at` (and the project's), and return the updated
+    /// project. `None` if the project or the file does not exist, or `path` is
+    /// taken. Content and version are untouched, so an edit racing the move
+    /// still lands on the same file.
+    async fn move_file(
+        &self,
+        project_id: ObjectId,
+        file_id: ObjectId,
+        path: String,
+    ) -> Result<Option<Project>>;
+    /// Remove one file, optionally making `entry` the project's entry file in
+    /// the same write, and return the updated project. `None` if the project or
+    /// the file does not exist, if `entry` is given but missing, or if no
+    /// `entry` is given and the file *is* the current entry — so a project can
+    /// never be left pointing at a deleted entry.
+    async fn delete_file(
+        &self,
+        project_id: ObjectId,
+        file_id: ObjectId,
+        entry: Option<ObjectId>,
     ) -> Result<Option<Project>>;
     /// Update a project's metadata (name + ownership), bump `updated_at`, and
     /// return the updated project. `None` if the project does not exist.
@@ -34,6 +69,38 @@ pub trait ProjectRepo {
         owner_id: ObjectId,
         owner_type: OwnerType,
     ) -> Result<Option<Project>>;
+    /// Every distinct blob hash referenced from MongoDB — a binary file's
+    /// `storage_key` or a projected file node's blob, in any project. The Mongo
+    /// side of the blob GC's mark phase.
+    async fn referenced_blobs(&self) -> Result<Vec<String>>;
+    /// Rewrite every binary file's `storage_key` still stored as the
+    /// `ObjectId` binaries were keyed by before object storage, as its hex
+    /// string, and return how many projects changed. Projects load either way
+    /// (see `FileContent::Binary`); this keeps every key in MongoDB a string.
+    async fn rekey_legacy_binaries(&self) -> Result<u64>;
+    /// Ids of every project (`None`), or only of those that do (`Some(true)`)
+    /// or don't (`Some(false)`) have a node projection yet.
+    async fn project_ids(&self, migrated: Option<bool>) -> Result<Vec<ObjectId>>;
+    /// Give a not-yet-migrated project its first node projection, and return
+    /// the updated project. `None` if the project is gone, already has one, or
+    /// changed since `updated_at` (its projection would be stale).
+    async fn init_nodes(
+        &self,
+        project_id: ObjectId,
+        nodes: Vec<NodeProjection>,
+        updated_at: OffsetDateTime,
+    ) -> Result<Option<Project>>;
+    /// Replace a migrated project's node projection, and return the updated
+    /// project. `None` if the project is gone, unmigrated, or changed since
+    /// `updated_at` — a later write then owns bringing the projection up to
+    /// date, so an older one can never overwrite it. Doesn't bump
+    /// `updated_at`: the projection is derived, not an edit.
+    async fn set_nodes(
+        &self,
+        project_id: ObjectId,
+        nodes: Vec<NodeProjection>,
+        updated_at: OffsetDateTime,
+    ) -> Result<Option<Project>>;
 }
 
 #[derive(Clone)]
@@ -79,7 +146,15 @@ impl ProjectRepo for MongoProjectRepo {
         file_id: ObjectId,
         content: FileContent,
         size: i64,
+        expected_version: Option<i32>,
     ) -> Result<Option<Project>> {
+        let mut filter = bson::doc! { "_id": project_id };
+        if let Some(version) = expected_version {
+            filter.insert(
+                "files",
+                bson::doc! { "$elemMatch": { "_id": file_id, "version": version } },
+            );
+        }
         let content_bson = bson::to_bson(&content)?;
         let now = bson::DateTime::now();
         let update = bson::doc! {
@@ -94,7 +169,7 @@ impl ProjectRepo for MongoProjectRepo {
 
         let updated = self
             .collection
-            .find_one_and_update(bson::doc! { "_id": project_id }, update)
+            .find_one_and_update(filter, update)
             .array_filters(vec![bson::doc! { "f._id": file_id }])
             .return_document(ReturnDocument::After)
             .await?;
@@ -108,6 +183,79 @@ impl ProjectRepo for MongoProjectRepo {
         Ok(updated.filter(|project| project.files.iter().any(|f| f.id == file_id)))
     }
 
+    async fn insert_file(
+        &self,
+        project_id: ObjectId,
+        file: ProjectFile,
+    ) -> Result<Option<Project>> {
+        let filter = bson::doc! { "_id": project_id, "files.path": { "$ne": &file.path } };
+        let update = bson::doc! {
+            "$push": { "files": bson::to_bson(&file)? },
+            "$set": { "updated_at": bson::DateTime::now() },
+        };
+
+        self.collection
+            .find_one_and_update(filter, update)
+            .return_document(ReturnDocument::After)
+            .await
+    }
+
+    async fn move_file(
+        &self,
+        project_id: ObjectId,
+        file_id: ObjectId,
+        path: String,
+    ) -> Result<Option<Project>> {
+        let filter = bson::doc! {
+            "_id": project_id,
+            "files._id": file_id,
+            "files.path": { "$ne": &path },
+        };
+        let now = bson::DateTime::now();
+        let update = bson::doc! {
+            "$set": {
+                "files.$[f].path": path,
+                "files.$[f].updated_at": now,
+                "updated_at": now,
+            },
+        };
+
+        self.collection
+            .find_one_and_update(filter, update)
+            .array_filters(vec![bson::doc! { "f._id": file_id }])
+            .return_document(ReturnDocument::After)
+            .await
+    }
+
+    async fn delete_file(
+        &self,
+        project_id: ObjectId,
+        file_id: ObjectId,
+        entry: Option<ObjectId>,
+    ) -> Result<Option<Project>> {
+        let mut filter = bson::doc! { "_id": project_id };
+        let mut set = bson::doc! { "updated_at": bson::DateTime::now() };
+        match entry {
+            Some(entry) => {
+                filter.insert("files._id", bson::doc! { "$all": [file_id, entry] });
+                set.insert("entry", entry);
+            }
+            None => {
+                filter.insert("files._id", file_id);
+                filter.insert("entry", bson::doc! { "$ne": file_id });
+            }
+        }
+        let update = bson::doc! {
+            "$pull": { "files": { "_id": file_id } },
+            "$set": set,
+        };
+
+        self.collection
+            .find_one_and_update(filter, update)
+            .return_document(ReturnDocument::After)
+            .await
+    }
+
     async fn update_metadata(
         &self,
         project_id: ObjectId,
@@ -129,6 +277,103 @@ impl ProjectRepo for MongoProjectRepo {
             .return_document(ReturnDocument::After)
             .await
     }
+
+    async fn referenced_blobs(&self) -> Result<Vec<String>> {
+        // `distinct` over an array path collects across every file (or node) of
+        // every project server-side, so no project document is ever loaded.
+        let mut keys = self
+            .collection
+            .distinct(
+                "files.content.storage_key",
+                bson::doc! { "files.content.kind": "binary" },
+            )
+            .await?;
+        keys.extend(
+            self.collection
+                .distinct("nodes.blob.sha256", bson::doc! {})
+                .await?,
+        );
+        let mut keys: Vec<String> = keys
+            .into_iter()
+            .filter_map(|key| key.as_str().map(str::to_string))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    async fn rekey_legacy_binaries(&self) -> Result<u64> {
+        let legacy = bson::doc! { "$eq": [{ "$type": "$$file.content.storage_key" }, "objectId"] };
+        let rekeyed = bson::doc! {
+            "$mergeObjects": ["$$file", {
+                "content": { "$mergeObjects": ["$$file.content", {
+                    "storage_key": { "$toString": "$$file.content.storage_key" },
+                }] },
+            }],
+        };
+        let result = self
+            .collection
+            .update_many(
+                bson::doc! { "files.content.storage_key": { "$type": "objectId" } },
+                vec![bson::doc! {
+                    "$set": { "files": { "$map": {
+                        "input": "$files",
+                        "as": "file",
+                        "in": { "$cond": [legacy, rekeyed, "$$file"] },
+                    } } },
+                }],
+            )
+            .await?;
+        Ok(result.modified_count)
+    }
+
+    async fn project_ids(&self, migrated: Option<bool>) -> Result<Vec<ObjectId>> {
+        let filter = match migrated {
+            None => bson::doc! {},
+            Some(true) => bson::doc! { "nodes": { "$ne": null } },
+            Some(false) => bson::doc! { "nodes": null },
+        };
+        let ids = self.collection.distinct("_id", filter).await?;
+        Ok(ids.into_iter().filter_map(|id| id.as_object_id()).collect())
+    }
+
+    async fn init_nodes(
+        &self,
+        project_id: ObjectId,
+        nodes: Vec<NodeProjection>,
+        updated_at: OffsetDateTime,
+    ) -> Result<Option<Project>> {
+        let filter = bson::doc! {
+            "_id": project_id,
+            "nodes": null,
+            "updated_at": bson::DateTime::from_time_0_3(updated_at),
+        };
+        let update = bson::doc! { "$set": { "nodes": bson::to_bson(&nodes)? } };
+
+        self.collection
+            .find_one_and_update(filter, update)
+            .return_document(ReturnDocument::After)
+            .await
+    }
+
+    async fn set_nodes(
+        &self,
+        project_id: ObjectId,
+        nodes: Vec<NodeProjection>,
+        updated_at: OffsetDateTime,
+    ) -> Result<Option<Project>> {
+        let filter = bson::doc! {
+            "_id": project_id,
+            "nodes": { "$ne": null },
+            "updated_at": bson::DateTime::from_time_0_3(updated_at),
+        };
+        let update = bson::doc! { "$set": { "nodes": bson::to_bson(&nodes)? } };
+
+        self.collection
+            .find_one_and_update(filter, update)
+            .return_document(ReturnDocument::After)
+            .await
+    }
 }
 
 #[cfg(test)]
@@ -177,6 +422,7 @@ pub mod tests {
             file_id: ObjectId,
             content: FileContent,
             size: i64,
+            expected_version: Option<i32>,
         ) -> Result<Option<Project>> {
             let mut projects = self.projects.lock().unwrap();
             let Some(project) = projects.iter_mut().find(|p| p.id == project_id) else {
@@ -185,6 +431,9 @@ pub mod tests {
             let Some(file) = project.files.iter_mut().find(|f| f.id == file_id) else {
                 return Ok(None);
             };
+            if expected_version.is_some_and(|version| version != file.version) {
+                return Ok(None);
+            }
             file.content = content;
             file.size = size;
             file.version += 1;
@@ -193,6 +442,71 @@ pub mod tests {
             Ok(Some(project.clone()))
         }
 
+        async fn insert_file(
+            &self,
+            project_id: ObjectId,
+            file: ProjectFile,
+        ) -> Result<Option<Project>> {
+            let mut projects = self.projects.lock().unwrap();
+            let Some(project) = projects.iter_mut().find(|p| p.id == project_id) else {
+                return Ok(None);
+            };
+            if project.files.iter().any(|f| f.path == file.path) {
+                return Ok(None);
+            }
+            project.files.push(file);
+            project.updated_at = OffsetDateTime::now_utc();
+            Ok(Some(project.clone()))
+        }
+
+        async fn move_file(
+            &self,
+            project_id: ObjectId,
+            file_id: ObjectId,
+            path: String,
+        ) -> Result<Option<Project>> {
+            let mut projects = self.projects.lock().unwrap();
+            let Some(project) = projects.iter_mut().find(|p| p.id == project_id) else {
+                return Ok(None);
+            };
+            if project.files.iter().any(|f| f.path == path) {
+                return Ok(None);
+            }
+            let Some(file) = project.files.iter_mut().find(|f| f.id == file_id) else {
+                return Ok(None);
+            };
+            file.path = path;
+            file.updated_at = OffsetDateTime::now_utc();
+            project.updated_at = OffsetDateTime::now_utc();
+            Ok(Some(project.clone()))
+        }
+
+        async fn delete_file(
+            &self,
+            project_id: ObjectId,
+            file_id: ObjectId,
+            entry: Option<ObjectId>,
+        ) -> Result<Option<Project>> {
+            let mut projects = self.projects.lock().unwrap();
+            let Some(project) = projects.iter_mut().find(|p| p.id == project_id) else {
+                return Ok(None);
+            };
+            let has = |id: ObjectId| project.files.iter().any(|f| f.id == id);
+            let allowed = match entry {
+                Some(entry) => has(file_id) && has(entry),
+                None => has(file_id) && project.entry != Some(file_id),
+            };
+            if !allowed {
+                return Ok(None);
+            }
+            project.files.retain(|f| f.id != file_id);
+            if entry.is_some() {
+                project.entry = entry;
+            }
+            project.updated_at = OffsetDateTime::now_utc();
+            Ok(Some(project.clone()))
+        }
+
         async fn update_metadata(
             &self,
             project_id: ObjectId,
@@ -210,6 +524,73 @@ pub mod tests {
             project.updated_at = OffsetDateTime::now_utc();
             Ok(Some(project.clone()))
         }
+
+        async fn referenced_blobs(&self) -> Result<Vec<String>> {
+            let projects = self.projects.lock().unwrap();
+            let files = projects
+                .iter()
+                .flat_map(|p| &p.files)
+                .filter_map(|f| match &f.content {
+                    FileContent::Binary { storage_key } => Some(storage_key.clone()),
+                    FileContent::Text { .. } => None,
+                });
+            let nodes = projects
+                .iter()
+                .flat_map(|p| p.nodes.iter().flatten())
+                .filter_map(|n| n.node.blob().map(|blob| blob.sha256.clone()));
+            let mut keys: Vec<String> = files.chain(nodes).collect();
+            keys.sort();
+            keys.dedup();
+            Ok(keys)
+        }
+
+        async fn rekey_legacy_binaries(&self) -> Result<u64> {
+            // Keys are typed here; there is nothing legacy to rewrite.
+            Ok(0)
+        }
+
+        async fn project_ids(&self, migrated: Option<bool>) -> Result<Vec<ObjectId>> {
+            let projects = self.projects.lock().unwrap();
+            Ok(projects
+                .iter()
+                .filter(|p| migrated.is_none_or(|migrated| p.nodes.is_some() == migrated))
+                .map(|p| p.id)
+                .collect())
+        }
+
+        async fn init_nodes(
+            &self,
+            project_id: ObjectId,
+            nodes: Vec<NodeProjection>,
+            updated_at: OffsetDateTime,
+        ) -> Result<Option<Project>> {
+            let mut projects = self.projects.lock().unwrap();
+            let Some(project) = projects.iter_mut().find(|p| p.id == project_id) else {
+                return Ok(None);
+            };
+            if project.nodes.is_some() || project.updated_at != updated_at {
+                return Ok(None);
+            }
+            project.nodes = Some(nodes);
+            Ok(Some(project.clone()))
+        }
+
+        async fn set_nodes(
+            &self,
+            project_id: ObjectId,
+            nodes: Vec<NodeProjection>,
+            updated_at: OffsetDateTime,
+        ) -> Result<Option<Project>> {
+            let mut projects = self.projects.lock().unwrap();
+            let Some(project) = projects.iter_mut().find(|p| p.id == project_id) else {
+                return Ok(None);
+            };
+            if project.nodes.is_none() || project.updated_at != updated_at {
+                return Ok(None);
+            }
+            project.nodes = Some(nodes);
+            Ok(Some(project.clone()))
+        }
     }
 
     use crate::models::project::ProjectFile;
@@ -238,6 +619,7 @@ pub mod tests {
             updated_at: OffsetDateTime::now_utc(),
             entry: None,
             pinned_version: None,
+            nodes: None,
         }
     }
 
@@ -337,7 +719,7 @@ pub mod tests {
             text: "updated content".to_string(),
         };
         let updated = repo
-            .update_file_content(project.id, file.id, new_content, 16)
+            .update_file_content(project.id, file.id, new_content, 16, None)
             .await
             .unwrap();
 
@@ -369,6 +751,7 @@ pub mod tests {
                     text: "x".to_string(),
                 },
                 1,
+                None,
             )
             .await
             .unwrap();
@@ -436,6 +819,7 @@ pub mod tests {
                     text: "x".to_string(),
                 },
                 1,
+                None,
             )
             .await
             .unwrap();
@@ -443,4 +827,223 @@ pub mod tests {
 
         cleanup(&repo, project.id).await;
     }
+
+    #[tokio::test]
+    #[ignore = "requires a live MongoDB (provisioned in CI; run locally with cargo test -- --ignored)"]
+    async fn test_update_file_content_checks_the_expected_version() {
+        let repo = test_repo().await;
+        let file = ProjectFile::default();
+        let project = new_project(ObjectId::new(), OwnerType::User, vec![file.clone()]);
+        repo.create(project.clone()).await.unwrap();
+        let save = |text: &str, version| {
+            repo.update_file_content(
+                project.id,
+                file.id,
+                FileContent::Text {
+                    text: text.to_string(),
+                },
+                text.len() as i64,
+                Some(version),
+            )
+        };
+
+        // Two saves from version 0: the first lands, the second is refused.
+        assert!(save("first", 0).await.unwrap().is_some());
+        assert!(save("second", 0).await.unwrap().is_none());
+        let stored = repo.find_by_id(project.id).await.unwrap().unwrap();
+        assert_eq!(stored.files[0].version, 1);
+        match &stored.files[0].content {
+            FileContent::Text { text } => assert_eq!(text, "first"),
+            FileContent::Binary { .. } => panic!("expected text content"),
+        }
+        assert!(save("second", 1).await.unwrap().is_some());
+
+        cleanup(&repo, project.id).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a live MongoDB (provisioned in CI; run locally with cargo test -- --ignored)"]
+    async fn test_referenced_blobs_lists_binary_references() {
+        let repo = test_repo().await;
+        let storage_key = crate::storage::sha256_hex(ObjectId::new().to_hex().as_bytes());
+        let binary = ProjectFile {
+            path: "logo.png".to_string(),
+            content: FileContent::Binary {
+                storage_key: storage_key.clone(),
+            },
+            ..ProjectFile::default()
+        };
+        let project = new_project(
+            ObjectId::new(),
+            OwnerType::User,
+            vec![ProjectFile::default(), binary],
+        );
+        repo.create(project.clone()).await.unwrap();
+
+        let keys = repo.referenced_blobs().await.unwrap();
+        assert!(keys.contains(&storage_key));
+
+        cleanup(&repo, project.id).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a live MongoDB (provisioned in CI; run locally with cargo test -- --ignored)"]
+    async fn test_rekey_legacy_binaries_stores_keys_as_strings() {
+        let repo = test_repo().await;
+        let project = new_project(
+            ObjectId::new(),
+            OwnerType::User,
+            vec![ProjectFile::default()],
+        );
+        repo.create(project.clone()).await.unwrap();
+        // A binary as stored before object storage.
+        let legacy = ObjectId::new();
+        repo.collection
+            .update_one(
+                bson::doc! { "_id": project.id },
+                bson::doc! { "$push": { "files": {
+                    "_id": ObjectId::new(),
+                    "path": "logo.png",
+                    "content": { "kind": "binary", "storage_key": legacy },
+                    "size": 3_i64,
+                    "version": 0,
+                    "updated_at": bson::DateTime::now(),
+                } } },
+            )
+            .await
+            .unwrap();
+
+        // Readable as is, then rewritten once.
+        let stored = repo.find_by_id(project.id).await.unwrap().unwrap();
+        let FileContent::Binary { storage_key } = &stored.files[1].content else {
+            panic!("expected binary content");
+        };
+        assert_eq!(*storage_key, legacy.to_hex());
+        assert!(repo.rekey_legacy_binaries().await.unwrap() >= 1);
+        let raw = repo
+            .collection
+            .clone_with_type::<bson::Document>()
+            .find_one(bson::doc! { "_id": project.id })
+            .await
+            .unwrap()
+            .unwrap();
+        let files = raw.get_array("files").unwrap();
+        let content = |i: usize| {
+            files[i]
+                .as_document()
+                .unwrap()
+                .get_document("content")
+                .unwrap()
+        };
+        assert_eq!(content(1).get_str("storage_key").unwrap(), legacy.to_hex());
+        // Other files are left as they were.
+        assert!(content(0).get_str("text").is_ok());
+
+        cleanup(&repo, project.id).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a live MongoDB (provisioned in CI; run locally with cargo test -- --ignored)"]
+    async fn test_insert_file_refuses_a_taken_path() {
+        let repo = test_repo().await;
+        let project = new_project(
+            ObjectId::new(),
+            OwnerType::User,
+            vec![ProjectFile::default()],
+        );
+        repo.create(project.clone()).await.unwrap();
+
+        let taken = ProjectFile::default();
+        assert!(repo.insert_file(project.id, taken).await.unwrap().is_none());
+
+        let fresh = ProjectFile {
+            path: "other.typ".to_string(),
+            ..ProjectFile::default()
+        };
+        let updated = repo
+            .insert_file(project.id, fresh.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(updated.files.iter().any(|f| f.id == fresh.id));
+
+        cleanup(&repo, project.id).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a live MongoDB (provisioned in CI; run locally with cargo test -- --ignored)"]
+    async fn test_move_file_refuses_a_taken_path() {
+        let repo = test_repo().await;
+        let main = ProjectFile::default();
+        let other = ProjectFile {
+            path: "other.typ".to_string(),
+            ..ProjectFile::default()
+        };
+        let project = new_project(
+            ObjectId::new(),
+            OwnerType::User,
+            vec![main.clone(), other.clone()],
+        );
+        repo.create(project.clone()).await.unwrap();
+
+        let taken = repo
+            .move_file(project.id, other.id, "main.typ".to_string())
+            .await
+            .unwrap();
+        assert!(taken.is_none());
+
+        let updated = repo
+            .move_file(project.id, other.id, "chapters/other.typ".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        let moved = updated.files.iter().find(|f| f.id == other.id).unwrap();
+        assert_eq!(moved.path, "chapters/other.typ");
+        assert_eq!(moved.version, other.version);
+
+        cleanup(&repo, project.id).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a live MongoDB (provisioned in CI; run locally with cargo test -- --ignored)"]
+    async fn test_delete_file_guards_the_entry() {
+        let repo = test_repo().await;
+        let main = ProjectFile::default();
+        let other = ProjectFile {
+            path: "other.typ".to_string(),
+            ..ProjectFile::default()
+        };
+        let mut project = new_project(
+            ObjectId::new(),
+            OwnerType::User,
+            vec![main.clone(), other.clone()],
+        );
+        project.entry = Some(main.id);
+        repo.create(project.clone()).await.unwrap();
+
+        // The entry can't go without a replacement, and the replacement must
+        // exist.
+        assert!(
+            repo.delete_file(project.id, main.id, None)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            repo.delete_file(project.id, main.id, Some(ObjectId::new()))
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let updated = repo
+            .delete_file(project.id, main.id, Some(other.id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.entry, Some(other.id));
+        assert_eq!(updated.files.len(), 1);
+
+        cleanup(&repo, project.id).await;
+    }
 }
@@ -2,13 +2,20 @@ use bson::{doc, oid::ObjectId};
 use futures_util::TryStreamExt;
 use mongodb::error::Result;
 
-use crate::models::team::Team;
+use crate::models::team::{Team, TeamFont};
 
 #[async_trait::async_trait]
 pub trait TeamRepo {
     async fn create(&self, team: Team) -> Result<Team>;
     async fn find_by_id(&self, id: ObjectId) -> Result<Option<Team>>;
     async fn list_by_member_id(&self, member_id: ObjectId) -> Result<Vec<Team>>;
+    /// Add a font to a team's library. `false` if the team doesn't exist.
+    async fn add_font(&self, team_id: ObjectId, font: TeamFont) -> Result<bool>;
+    /// Remove a font from a team's library. `false` if the team or the font
+    /// doesn't exist.
+    async fn remove_font(&self, team_id: ObjectId, font_id: ObjectId) -> Result<bool>;
+    /// Every blob hash a team font refers to, for GC.
+    async fn referenced_blobs(&self) -> Result<Vec<String>>;
 }
 
 #[derive(Clone)]
@@ -37,6 +44,41 @@ impl TeamRepo for MongoTeamRepo {
         let teams: Vec<Team> = cursor.try_collect().await?;
         Ok(teams)
     }
+
+    async fn add_font(&self, team_id: ObjectId, font: TeamFont) -> Result<bool> {
+        let update = doc! {
+            "$push": { "fonts": bson::to_bson(&font)? },
+            "$set": { "updated_at": bson::DateTime::now() },
+        };
+        let result = self
+            .collection
+            .update_one(doc! { "_id": team_id }, update)
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn remove_font(&self, team_id: ObjectId, font_id: ObjectId) -> Result<bool> {
+        let update = doc! {
+            "$pull": { "fonts": { "id": font_id } },
+            "$set": { "updated_at": bson::DateTime::now() },
+        };
+        let result = self
+            .collection
+            .update_one(doc! { "_id": team_id, "fonts.id": font_id }, update)
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn referenced_blobs(&self) -> Result<Vec<String>> {
+        let keys = self
+            .collection
+            .distinct("fonts.storage_key", doc! {})
+            .await?;
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| key.as_str().map(str::to_string))
+            .collect())
+    }
 }
 
 #[cfg(test)]
@@ -78,6 +120,33 @@ pub mod tests {
                 .collect();
             Ok(filtered_teams)
         }
+
+        async fn add_font(&self, team_id: ObjectId, font: TeamFont) -> Result<bool> {
+            let mut teams = self.teams.lock().unwrap();
+            let Some(team) = teams.iter_mut().find(|team| team.id == team_id) else {
+                return Ok(false);
+            };
+            team.fonts.push(font);
+            Ok(true)
+        }
+
+        async fn remove_font(&self, team_id: ObjectId, font_id: ObjectId) -> Result<bool> {
+            let mut teams = self.teams.lock().unwrap();
+            let Some(team) = teams.iter_mut().find(|team| team.id == team_id) else {
+                return Ok(false);
+            };
+            let before = team.fonts.len();
+            team.fonts.retain(|font| font.id != font_id);
+            Ok(team.fonts.len() < before)
+        }
+
+        async fn referenced_blobs(&self) -> Result<Vec<String>> {
+            let teams = self.teams.lock().unwrap();
+            Ok(teams
+                .iter()
+                .flat_map(|team| team.fonts.iter().map(|font| font.storage_key.clone()))
+                .collect())
+        }
     }
 
     async fn test_repo() -> MongoTeamRepo {
@@ -99,6 +168,7 @@ pub mod tests {
             avatar_uri: None,
             creator_id: ObjectId::new(),
             member_ids,
+            fonts: vec![],
             created_at: time::OffsetDateTime::now_utc(),
             updated_at: time::OffsetDateTime::now_utc(),
         }
@@ -1,4 +1,4 @@
-use actix_web::web;
+use actix_web::{guard, web};
 
 use crate::{handler, middleware::jwt::JwtMiddleware};
 
@@ -16,15 +16,54 @@ pub fn configure(cfg: &mut web::ServiceConfig, jwt_secret: String) {
                 .wrap(JwtMiddleware::new(jwt_secret.clone()))
                 .route("/team", web::post().to(handler::team::create))
                 .route("/team/projects", web::get().to(handler::team::projects))
+                .route("/team/{id}/fonts", web::get().to(handler::team::fonts))
+                .route(
+                    "/team/{id}/fonts",
+                    web::post().to(handler::team::upload_font),
+                )
+                .route(
+                    "/team/{id}/fonts/{font_id}",
+                    web::delete().to(handler::team::delete_font),
+                )
                 .route("/project", web::post().to(handler::project::create))
+                .route("/project/import", web::post().to(handler::project::import))
+                .route("/compile/status", web::get().to(handler::compile::status))
+                .route(
+                    "/package/{namespace}/{name}/{version}",
+                    web::put().to(handler::package::upload),
+                )
                 .service(
                     web::scope("/project/{id}")
                         .route("", web::get().to(handler::project::find_by_id))
                         .route("", web::put().to(handler::project::update))
+                        .route(
+                            "/file",
+                            web::post()
+                                .guard(guard::fn_guard(handler::project::is_json))
+                                .to(handler::project::create_file),
+                        )
+                        .route("/file", web::post().to(handler::project::upload_file))
                         .route(
                             "/file/{file_id}",
                             web::put().to(handler::project::update_file),
                         )
+                        .route(
+                            "/file/{file_id}",
+                            web::patch().to(handler::project::move_file),
+                        )
+                        .route(
+                            "/file/{file_id}",
+                            web::delete().to(handler::project::delete_file),
+                        )
+                        .route(
+                            "/file/{file_id}/raw",
+                            web::get().to(handler::project::raw_file),
+                        )
+                        .route("/compile", web::post().to(handler::project::compile))
+                        .route("/render", web::get().to(handler::project::render))
+                        .route("/diagnostics", web::get().to(handler::project::diagnostics))
+                        .route("/outline", web::get().to(handler::project::outline))
+                        .route("/export.zip", web::get().to(handler::project::export))
                         .route("/duplicate", web::post().to(handler::project::duplicate)),
                 )
                 .service(
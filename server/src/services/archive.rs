@@ -0,0 +1,486 @@
+//! Projects as zip archives.
+//!
+//! An export is written entry by entry as the response is sent: a binary's
+//! bytes are only read from storage when its turn comes, and each entry is
+//! handed on as soon as it's written, so at most one file is held in memory.
+//! Text is deflated; binaries (images, fonts, PDFs) are stored as they are,
+//! being compressed already.
+//!
+//! An import is unpacked whole, within [`UnpackLimits`] that are enforced on
+//! the bytes actually inflated, never on the sizes an archive declares.
+
+use std::collections::HashSet;
+use std::io::{Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use futures_util::Stream;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use time::{OffsetDateTime, PrimitiveDateTime};
+use zip::write::{SimpleFileOptions, StreamWriter};
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::models::tree::{MAX_DEPTH, is_valid_segment};
+use crate::services::project::ProjectServiceError;
+use crate::storage::ObjectStore;
+
+/// Where an export puts its manifest.
+pub const MANIFEST_PATH: &str = "manifest.json";
+
+/// What a project's files don't say about it: which file is the entry, by
+/// path, and the Typst version it's pinned to.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub entry: Option<String>,
+    pub pinned_version: Option<Version>,
+}
+
+/// What an imported archive may unpack to.
+pub struct UnpackLimits {
+    /// Largest file, in bytes.
+    pub max_file_bytes: usize,
+    /// All files together, in bytes.
+    pub max_total_bytes: usize,
+    /// Entries in the archive, folders included.
+    pub max_files: usize,
+}
+
+/// An unpacked archive: its files by path, in archive order, and the
+/// manifest at its root, if it has one.
+#[derive(Debug, Default)]
+pub struct Unpacked {
+    pub files: Vec<(String, Vec<u8>)>,
+    pub manifest: Option<Manifest>,
+}
+
+/// Unpack a zip archive. Folders are implied by file paths and skipped, and
+/// so is what macOS adds to archives (`__MACOSX/`, `.DS_Store`). When every
+/// file sits in one top-level folder, as when a folder itself was zipped,
+/// that folder is stripped. Every path must then be a legal project path,
+/// which also rules out `..`, absolute paths and backslashes; symbolic links
+/// and encrypted entries are refused. A `manifest.json` at the root that reads
+/// as a [`Manifest`] is taken as one rather than as a file.
+pub fn unpack(bytes: &[u8], limits: &UnpackLimits) -> Result<Unpacked, ProjectServiceError> {
+    let mut archive =
+        zip::ZipArchive::new(Cursor::new(bytes)).map_err(ProjectServiceError::Archive)?;
+    if archive.len() > limits.max_files {
+        return Err(ProjectServiceError::InvalidUpload(format!(
+            "the archive has more than {} entries",
+            limits.max_files
+        )));
+    }
+
+    let mut files = Vec::new();
+    let mut total = 0;
+    for index in 0..archive.len() {
+        let file = archive
+            .by_index(index)
+            .map_err(ProjectServiceError::Archive)?;
+        let name = file
+            .name()
+            .map_err(ProjectServiceError::Archive)?
+            .into_owned();
+        if file.is_dir() || is_junk(&name) {
+            continue;
+        }
+        if file.is_symlink() {
+            return Err(ProjectServiceError::InvalidUpload(format!(
+                "{name} is a symbolic link"
+            )));
+        }
+        // Read one byte past the limit to tell a file that's too big.
+        let limit = limits.max_file_bytes.min(limits.max_total_bytes - total);
+        let mut contents = Vec::new();
+        file.take(limit as u64 + 1)
+            .read_to_end(&mut contents)
+            .map_err(|e| ProjectServiceError::Archive(e.into()))?;
+        if contents.len() > limit {
+            return Err(if limit == limits.max_file_bytes {
+                ProjectServiceError::PayloadTooLarge(limits.max_file_bytes)
+            } else {
+                ProjectServiceError::InvalidUpload(format!(
+                    "the archive unpacks to more than {} bytes",
+                    limits.max_total_bytes
+                ))
+            });
+        }
+        total += contents.len();
+        files.push((name, contents));
+    }
+
+    let root = files
+        .first()
+        .and_then(|(path, _)| path.split_once('/'))
+        .map(|(root, _)| format!("{root}/"));
+    if let Some(root) = root
+        && files.iter().all(|(path, _)| path.starts_with(&root))
+    {
+        for (path, _) in &mut files {
+            path.drain(..root.len());
+        }
+    }
+
+    let mut unpacked = Unpacked::default();
+    let mut paths = HashSet::new();
+    for (path, contents) in files {
+        let segments: Vec<&str> = path.split('/').collect();
+        if segments.len() > MAX_DEPTH || !segments.iter().all(|s| is_valid_segment(s)) {
+            return Err(ProjectServiceError::InvalidPath(path));
+        }
+        if path == MANIFEST_PATH
+            && let Ok(manifest) = serde_json::from_slice(&contents)
+        {
+            unpacked.manifest = Some(manifest);
+            continue;
+        }
+        if !paths.insert(path.clone()) {
+            return Err(ProjectServiceError::PathConflict(path));
+        }
+        unpacked.files.push((path, contents));
+    }
+    Ok(unpacked)
+}
+
+/// What macOS's Archive Utility adds to every archive it makes.
+fn is_junk(path: &str) -> bool {
+    path.starts_with("__MACOSX/") || path == ".DS_Store" || path.ends_with("/.DS_Store")
+}
+
+/// An archive entry's bytes, or where to find them.
+pub enum EntryContent {
+    Text(String),
+    /// A blob in object storage, by storage key.
+    Blob(String),
+    /// Bytes made for the export, such as the compiled PDF.
+    Bytes(Vec<u8>),
+}
+
+pub struct Entry {
+    pub path: String,
+    pub content: EntryContent,
+    pub modified: OffsetDateTime,
+}
+
+/// A project ready to be written out as a zip archive; see
+/// [`Export::into_stream`].
+pub struct Export {
+    /// The project's name, for the archive's file name.
+    pub name: String,
+    entries: Vec<Entry>,
+    store: Option<Arc<dyn ObjectStore>>,
+}
+
+impl Export {
+    /// An export of `entries`, which must have distinct paths. Fails up front
+    /// if a blob needs reading and there's no storage to read it from.
+    pub fn new(
+        name: String,
+        entries: Vec<Entry>,
+        store: Option<Arc<dyn ObjectStore>>,
+    ) -> Result<Export, ProjectServiceError> {
+        let has_blobs = entries
+            .iter()
+            .any(|entry| matches!(entry.content, EntryContent::Blob(_)));
+        if has_blobs && store.is_none() {
+            return Err(ProjectServiceError::StorageUnavailable);
+        }
+        Ok(Export {
+            name,
+            entries,
+            store,
+        })
+    }
+
+    /// The archive, a chunk per entry and a last one for the central
+    /// directory. Storage failing midway ends the stream with the error, by
+    /// which time the response has started: the client is left with a
+    /// truncated archive.
+    pub fn into_stream(self) -> impl Stream<Item = Result<Vec<u8>, ProjectServiceError>> {
+        let chunk = Chunk::default();
+        let state = Writing {
+            zip: Some(ZipWriter::new_stream(chunk.clone())),
+            chunk,
+            entries: self.entries.into_iter(),
+            store: self.store,
+        };
+        futures_util::stream::try_unfold(state, |mut state| async move {
+            let chunk = state.next_chunk().await?;
+            Ok(chunk.map(|chunk| (chunk, state)))
+        })
+    }
+}
+
+struct Writing {
+    /// `None` once the archive is finished.
+    zip: Option<ZipWriter<StreamWriter<Chunk>>>,
+    chunk: Chunk,
+    entries: std::vec::IntoIter<Entry>,
+    store: Option<Arc<dyn ObjectStore>>,
+}
+
+impl Writing {
+    /// Write the next entry, or the central directory after the last, and
+    /// take what was written. `None` once the archive is finished.
+    async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, ProjectServiceError> {
+        let Some(zip) = self.zip.as_mut() else {
+            return Ok(None);
+        };
+        let Some(entry) = self.entries.next() else {
+            if let Some(zip) = self.zip.take() {
+                zip.finish().map_err(ProjectServiceError::Archive)?;
+            }
+            return Ok(Some(self.chunk.take()));
+        };
+        let (method, bytes) = match entry.content {
+            EntryContent::Text(text) => (CompressionMethod::Deflated, text.into_bytes()),
+            EntryContent::Blob(storage_key) => {
+                let store = self.store.as_ref().expect("checked by Export::new");
+                let bytes = store
+                    .get(&storage_key)
+                    .await
+                    .map_err(ProjectServiceError::Storage)?;
+                (CompressionMethod::Stored, bytes)
+            }
+            EntryContent::Bytes(bytes) => (CompressionMethod::Stored, bytes),
+        };
+        let mut options = SimpleFileOptions::default()
+            .compression_method(method)
+            .large_file(bytes.len() as u64 >= u32::MAX as u64);
+        let modified = entry.modified;
+        if let Ok(modified) = PrimitiveDateTime::new(modified.date(), modified.time()).try_into() {
+            options = options.last_modified_time(modified);
+        }
+        zip.start_file(entry.path, options)
+            .map_err(ProjectServiceError::Archive)?;
+        zip.write_all(&bytes)
+            .map_err(|e| ProjectServiceError::Archive(e.into()))?;
+        Ok(Some(self.chunk.take()))
+    }
+}
+
+/// What the zip writer wrote since the last chunk was taken. The writer owns
+/// its output, so the stream keeps a second handle to drain it.
+#[derive(Clone, Default)]
+struct Chunk(Arc<Mutex<Vec<u8>>>);
+
+impl Chunk {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for Chunk {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use futures_util::TryStreamExt as _;
+
+    use super::*;
+    use crate::storage::InMemoryObjectStore;
+
+    const LIMITS: UnpackLimits = UnpackLimits {
+        max_file_bytes: 1024,
+        max_total_bytes: 1536,
+        max_files: 8,
+    };
+
+    /// A zip of `files`; a path ending in `/` is a folder.
+    fn zip_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        for (path, contents) in files {
+            if path.ends_with('/') {
+                zip.add_directory(*path, options).unwrap();
+            } else {
+                zip.start_file(*path, options).unwrap();
+                zip.write_all(contents).unwrap();
+            }
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_unpack_strips_a_shared_folder_and_reads_the_manifest() {
+        let bytes = zip_of(&[
+            ("thesis/", b""),
+            ("thesis/main.typ", b"= Hi"),
+            ("thesis/img/logo.png", b"\x89PNG"),
+            (
+                "thesis/manifest.json",
+                br#"{"entry": "main.typ", "pinned_version": "0.14.2"}"#,
+            ),
+            ("thesis/.DS_Store", b"junk"),
+            ("__MACOSX/thesis/._main.typ", b"junk"),
+        ]);
+        let unpacked = unpack(&bytes, &LIMITS).unwrap();
+        let paths: Vec<_> = unpacked
+            .files
+            .iter()
+            .map(|(path, _)| path.as_str())
+            .collect();
+        assert_eq!(paths, ["main.typ", "img/logo.png"]);
+        assert_eq!(unpacked.files[0].1, b"= Hi");
+        assert_eq!(
+            unpacked.manifest,
+            Some(Manifest {
+                entry: Some("main.typ".to_string()),
+                pinned_version: Some(Version::new(0, 14, 2)),
+            })
+        );
+
+        // Two top-level folders stay; a manifest.json of another kind is a file.
+        let bytes = zip_of(&[
+            ("a/main.typ", b""),
+            ("b/x.typ", b""),
+            ("manifest.json", br#"{"name": "x"}"#),
+        ]);
+        let paths: Vec<_> = unpack(&bytes, &LIMITS)
+            .unwrap()
+            .files
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(paths, ["a/main.typ", "b/x.typ", "manifest.json"]);
+    }
+
+    #[test]
+    fn test_unpack_rejects_traversal_bombs_and_malformed_archives() {
+        for path in [
+            "../etc/passwd",
+            "a/../../b.typ",
+            "/abs.typ",
+            "a\\b.typ",
+            "a//b.typ",
+        ] {
+            let bytes = zip_of(&[(path, b""), ("main.typ", b"")]);
+            assert!(
+                matches!(
+                    unpack(&bytes, &LIMITS),
+                    Err(ProjectServiceError::InvalidPath(_))
+                ),
+                "{path}"
+            );
+        }
+
+        // Zeros deflate to almost nothing; what's inflated is what counts.
+        let zeros = vec![0; 2048];
+        let bomb = zip_of(&[("big.typ", &zeros)]);
+        assert!(bomb.len() < 200);
+        assert!(matches!(
+            unpack(&bomb, &LIMITS),
+            Err(ProjectServiceError::PayloadTooLarge(1024))
+        ));
+        let total = zip_of(&[("a.typ", &zeros[..1000]), ("b.typ", &zeros[..1000])]);
+        assert!(matches!(
+            unpack(&total, &LIMITS),
+            Err(ProjectServiceError::InvalidUpload(_))
+        ));
+        let many: Vec<_> = (0..9).map(|i| format!("{i}.typ")).collect();
+        let many = zip_of(
+            &many
+                .iter()
+                .map(|p| (p.as_str(), &b""[..]))
+                .collect::<Vec<_>>(),
+        );
+        assert!(matches!(
+            unpack(&many, &LIMITS),
+            Err(ProjectServiceError::InvalidUpload(_))
+        ));
+
+        assert!(matches!(
+            unpack(b"not a zip", &LIMITS),
+            Err(ProjectServiceError::Archive(_))
+        ));
+    }
+
+    fn entry(path: &str, content: EntryContent) -> Entry {
+        Entry {
+            path: path.to_string(),
+            content,
+            modified: OffsetDateTime::now_utc(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_streams_every_entry() {
+        let store = Arc::new(InMemoryObjectStore::new());
+        let blob = store.put(b"\x89PNG").await.unwrap();
+        let export = Export::new(
+            "Thesis".to_string(),
+            vec![
+                entry("main.typ", EntryContent::Text("= Hello".repeat(100))),
+                entry("img/logo.png", EntryContent::Blob(blob.sha256)),
+                entry("main.pdf", EntryContent::Bytes(b"%PDF-".to_vec())),
+            ],
+            Some(store),
+        )
+        .unwrap();
+
+        let chunks: Vec<_> = export.into_stream().try_collect().await.unwrap();
+        // One per entry, then the central directory.
+        assert_eq!(chunks.len(), 4);
+        let mut archive = zip::ZipArchive::new(Cursor::new(chunks.concat())).unwrap();
+        let mut read = |path: &str| {
+            let mut file = archive.by_name(path).unwrap();
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes).unwrap();
+            (file.compression(), bytes)
+        };
+        assert_eq!(
+            read("main.typ"),
+            (
+                CompressionMethod::Deflated,
+                "= Hello".repeat(100).into_bytes()
+            )
+        );
+        assert_eq!(
+            read("img/logo.png"),
+            (CompressionMethod::Stored, b"\x89PNG".to_vec())
+        );
+        assert_eq!(
+            read("main.pdf"),
+            (CompressionMethod::Stored, b"%PDF-".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_needs_storage_for_blobs_only() {
+        assert!(matches!(
+            Export::new(
+                "p".to_string(),
+                vec![entry("a.png", EntryContent::Blob("a".repeat(64)))],
+                None,
+            ),
+            Err(ProjectServiceError::StorageUnavailable)
+        ));
+
+        // A blob missing from storage fails the stream where it is.
+        let store = Arc::new(InMemoryObjectStore::new());
+        let export = Export::new(
+            "p".to_string(),
+            vec![
+                entry("main.typ", EntryContent::Text(String::new())),
+                entry("a.png", EntryContent::Blob("a".repeat(64))),
+            ],
+            Some(store),
+        )
+        .unwrap();
+        let mut stream = std::pin::pin!(export.into_stream());
+        assert!(stream.try_next().await.unwrap().is_some());
+        assert!(matches!(
+            stream.try_next().await,
+            Err(ProjectServiceError::Storage(_))
+        ));
+    }
+}
@@ -0,0 +1,391 @@
+//! Mark-and-sweep garbage collection for content-addressed blobs.
+//!
+//! Blobs are never deleted when a file stops using them: other files (in any
+//! project) may share the same bytes. Instead this pass periodically
+//!
+//! 1. **lists** every `blobs/{sha256}` object,
+//! 2. **marks** every hash still referenced — by a live room's tree, by a
+//!    stored `ydoc/*` snapshot, or by a `FileContent::Binary` or team font in
+//!    MongoDB — and
+//! 3. **sweeps** listed blobs that are unreferenced *and* older than the grace
+//!    period.
+//!
+//! Listing happens before marking, so any reference recorded before the mark
+//! phase is seen. The grace period covers the rest: a writer uploads a blob
+//! first and records its hash afterwards (write-before-reference), and a blob
+//! that young is never swept even if its reference hasn't landed yet. Storing
+//! a blob that already exists refreshes it too, and the sweep re-reads each
+//! candidate's age just before deleting it, so a blob reused after the listing
+//! isn't swept either.
+//!
+//! Marking is conservative: if any source can't be read (a corrupt snapshot,
+//! the room manager gone, a database error) the pass aborts without deleting
+//! anything.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use derive_more::Display;
+use time::OffsetDateTime;
+use yrs::Transact;
+
+use crate::{
+    crdt::{self, snapshot},
+    repo::{project::ProjectRepo, team::TeamRepo},
+    storage::{BLOB_PREFIX, ObjectStore, StorageError, is_valid_sha256},
+};
+
+/// Key prefix of stored Y.Doc snapshots (see [`crate::crdt::snapshot`]).
+const SNAPSHOT_PREFIX: &str = "ydoc/";
+
+#[derive(Debug, Display)]
+pub enum GcError {
+    #[display("Storage error: {_0}")]
+    Storage(StorageError),
+    #[display("Database error: {_0}")]
+    Database(mongodb::error::Error),
+    /// A snapshot exists but its tree can't be read, so its references are
+    /// unknown.
+    #[display("Unreadable snapshot {key}: {reason}")]
+    Snapshot { key: String, reason: String },
+    /// The room manager didn't answer, so live references are unknown.
+    #[display("Live rooms unavailable")]
+    LiveRoomsUnavailable,
+}
+
+/// Outcome of one GC pass.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Nothing was deleted; `swept` lists what would have been.
+    pub dry_run: bool,
+    /// Blobs found in storage.
+    pub scanned: usize,
+    /// Distinct hashes referenced by rooms, snapshots or MongoDB.
+    pub referenced: usize,
+    /// Unreferenced blobs kept because they're younger than the grace period.
+    pub young: usize,
+    /// Unreferenced blobs past the grace period, deleted (or, in a dry run,
+    /// deletable).
+    pub swept: Vec<String>,
+    /// Total size of `swept`.
+    pub swept_bytes: u64,
+}
+
+pub struct GcService<P: ProjectRepo, T: TeamRepo> {
+    pub project_repo: P,
+    pub team_repo: T,
+    pub store: Arc<dyn ObjectStore>,
+}
+
+impl<P: ProjectRepo, T: TeamRepo> GcService<P, T> {
+    /// Run one mark-and-sweep pass. `live` resolves to the hashes referenced by
+    /// rooms currently in memory (see `ProjectServer::live_blobs`); it's
+    /// awaited only after the blob listing, as part of the mark phase.
+    pub async fn collect(
+        &self,
+        live: impl Future<Output = Option<HashSet<String>>>,
+        grace: Duration,
+        dry_run: bool,
+    ) -> Result<GcReport, GcError> {
+        let blobs = self
+            .store
+            .list(BLOB_PREFIX)
+            .await
+            .map_err(GcError::Storage)?;
+
+        let mut marked = live.await.ok_or(GcError::LiveRoomsUnavailable)?;
+        marked.extend(self.snapshot_blobs().await?);
+        marked.extend(
+            self.project_repo
+                .referenced_blobs()
+                .await
+                .map_err(GcError::Database)?,
+        );
+        marked.extend(
+            self.team_repo
+                .referenced_blobs()
+                .await
+                .map_err(GcError::Database)?,
+        );
+
+        let cutoff = OffsetDateTime::now_utc() - grace;
+        let mut report = GcReport {
+            dry_run,
+            scanned: blobs.len(),
+            referenced: marked.len(),
+            ..GcReport::default()
+        };
+        for blob in blobs {
+            let Some(sha256) = blob.key.strip_prefix(BLOB_PREFIX) else {
+                continue;
+            };
+            // Never touch something that isn't shaped like a blob key.
+            if !is_valid_sha256(sha256) || marked.contains(sha256) {
+                continue;
+            }
+            if blob.last_modified > cutoff {
+                report.young += 1;
+                continue;
+            }
+            // The listing may be stale by now: a writer reusing the blob since
+            // has refreshed it (see `ObjectStore::put`), and its reference may
+            // not have landed yet. Look again right before sweeping.
+            match self
+                .store
+                .modified(sha256)
+                .await
+                .map_err(GcError::Storage)?
+            {
+                None => continue,
+                Some(modified) if modified > cutoff => {
+                    report.young += 1;
+                    continue;
+                }
+                Some(_) => {}
+            }
+            if !dry_run {
+                self.store.delete(sha256).await.map_err(GcError::Storage)?;
+            }
+            report.swept_bytes += blob.size;
+            report.swept.push(sha256.to_string());
+        }
+        Ok(report)
+    }
+
+    /// Hashes referenced by file nodes in any stored snapshot.
+    async fn snapshot_blobs(&self) -> Result<HashSet<String>, GcError> {
+        let snapshots = self
+            .store
+            .list(SNAPSHOT_PREFIX)
+            .await
+            .map_err(GcError::Storage)?;
+        let mut marked = HashSet::new();
+        for meta in snapshots {
+            let unreadable = |reason: String| GcError::Snapshot {
+                key: meta.key.clone(),
+                reason,
+            };
+            let project_id = &meta.key[SNAPSHOT_PREFIX.len()..];
+            let Some(snapshot) = snapshot::load_snapshot(self.store.as_ref(), project_id)
+                .await
+                .map_err(|e| unreadable(e.to_string()))?
+            else {
+                // Overwritten or removed since the listing.
+                continue;
+            };
+            let nodes = crdt::nodes_map(&snapshot.doc);
+            let tree = crdt::read_tree(&snapshot.doc.transact(), &nodes)
+                .map_err(|e| unreadable(e.to_string()))?;
+            marked.extend(
+                tree.iter()
+                    .filter_map(|node| node.blob())
+                    .map(|blob| blob.sha256.clone()),
+            );
+        }
+        Ok(marked)
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::models::project::{FileContent, OwnerType, Project, ProjectFile};
+    use crate::models::team::{Team, TeamFont};
+    use crate::models::tree::{Node, NodeContent, ProjectTree};
+    use crate::repo::project::tests::MockProjectRepo;
+    use crate::repo::team::tests::MockTeamRepo;
+    use crate::storage::{Blob, InMemoryObjectStore};
+    use bson::oid::ObjectId;
+    use std::collections::HashMap;
+    use std::future::ready;
+    use std::sync::Mutex;
+    use yrs::Doc;
+
+    /// A store holding four blobs — one referenced from each source plus an
+    /// orphan — and a service whose repo references the `mongo` one. Returns
+    /// the service and the hashes `(live, snapshot, mongo, orphan)`.
+    async fn fixture() -> (
+        GcService<MockProjectRepo, MockTeamRepo>,
+        Arc<InMemoryObjectStore>,
+        [String; 4],
+    ) {
+        let store = Arc::new(InMemoryObjectStore::new());
+        let mut hashes = Vec::new();
+        for bytes in [&b"live"[..], b"snapshot", b"mongo", b"orphan"] {
+            hashes.push(store.put(bytes).await.unwrap().sha256);
+        }
+        let [live, snap, mongo, orphan]: [String; 4] = hashes.try_into().unwrap();
+
+        let doc = Doc::new();
+        let nodes = crdt::nodes_map(&doc);
+        let tree = ProjectTree::from_nodes([Node {
+            id: "n".to_string(),
+            parent: None,
+            name: "logo.png".to_string(),
+            content: NodeContent::File {
+                blob: Blob {
+                    sha256: snap.clone(),
+                    size: 8,
+                },
+            },
+        }]);
+        crdt::write_tree(&mut doc.transact_mut(), &nodes, &tree);
+        let bytes = snapshot::encode_snapshot(&doc, &HashMap::new());
+        snapshot::save_snapshot(store.as_ref(), "p1", &bytes)
+            .await
+            .unwrap();
+
+        let project = Project {
+            id: ObjectId::new(),
+            name: "test".to_string(),
+            owner_id: ObjectId::new(),
+            owner_type: OwnerType::User,
+            creator_id: ObjectId::new(),
+            files: vec![ProjectFile {
+                path: "font.otf".to_string(),
+                content: FileContent::Binary {
+                    storage_key: mongo.clone(),
+                },
+                ..ProjectFile::default()
+            }],
+            created_at: OffsetDateTime::now_utc(),
+            updated_at: OffsetDateTime::now_utc(),
+            entry: None,
+            pinned_version: None,
+            nodes: None,
+        };
+        let service = GcService {
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project]),
+            },
+            team_repo: MockTeamRepo::default(),
+            store: store.clone(),
+        };
+        (service, store, [live, snap, mongo, orphan])
+    }
+
+    #[tokio::test]
+    async fn test_collect_sweeps_only_unreferenced_blobs() {
+        let (service, store, [live, snap, mongo, orphan]) = fixture().await;
+
+        let report = service
+            .collect(
+                ready(Some(HashSet::from([live.clone()]))),
+                Duration::ZERO,
+                false,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(report.scanned, 4);
+        assert_eq!(report.referenced, 3);
+        assert_eq!(report.swept, vec![orphan.clone()]);
+        assert_eq!(report.swept_bytes, 6);
+        assert!(!store.exists(&orphan).await.unwrap());
+        for kept in [live, snap, mongo] {
+            assert!(store.exists(&kept).await.unwrap());
+        }
+        // The snapshot itself is a named object, never a sweep candidate.
+        assert!(store.get_object("ydoc/p1").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_collect_keeps_team_fonts() {
+        let (service, store, [live, _, _, orphan]) = fixture().await;
+        service.team_repo.teams.lock().unwrap().push(Team {
+            id: ObjectId::new(),
+            name: "test".to_string(),
+            avatar_uri: None,
+            creator_id: ObjectId::new(),
+            member_ids: vec![],
+            fonts: vec![TeamFont {
+                id: ObjectId::new(),
+                filename: "corporate.otf".to_string(),
+                storage_key: orphan.clone(),
+                size: 6,
+                faces: vec![],
+                uploaded_at: OffsetDateTime::now_utc(),
+            }],
+            created_at: OffsetDateTime::now_utc(),
+            updated_at: OffsetDateTime::now_utc(),
+        });
+
+        let report = service
+            .collect(ready(Some(HashSet::from([live]))), Duration::ZERO, false)
+            .await
+            .unwrap();
+
+        assert!(report.swept.is_empty());
+        assert!(store.exists(&orphan).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_collect_dry_run_reports_without_deleting() {
+        let (service, store, [_, _, _, orphan]) = fixture().await;
+
+        let report = service
+            .collect(ready(Some(HashSet::new())), Duration::ZERO, true)
+            .await
+            .unwrap();
+
+        assert!(report.dry_run);
+        assert_eq!(report.swept.len(), 2);
+        assert!(report.swept.contains(&orphan));
+        assert_eq!(store.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_collect_keeps_a_blob_reused_after_the_listing() {
+        let (service, store, [live, _, _, orphan]) = fixture().await;
+        let grace = Duration::from_millis(50);
+        tokio::time::sleep(grace * 2).await;
+
+        // Uploaded again (deduplicated) between the listing and the sweep, its
+        // reference not recorded yet.
+        let reuse = async {
+            store.put(b"orphan").await.unwrap();
+            Some(HashSet::from([live]))
+        };
+        let report = service.collect(reuse, grace, false).await.unwrap();
+
+        assert!(report.swept.is_empty());
+        assert_eq!(report.young, 1);
+        assert!(store.exists(&orphan).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_collect_keeps_blobs_within_grace_period() {
+        let (service, store, _) = fixture().await;
+
+        let report = service
+            .collect(
+                ready(Some(HashSet::new())),
+                Duration::from_secs(3600),
+                false,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(report.young, 2);
+        assert!(report.swept.is_empty());
+        assert_eq!(store.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_collect_aborts_when_a_source_is_unreadable() {
+        let (service, store, _) = fixture().await;
+
+        let result = service.collect(ready(None), Duration::ZERO, false).await;
+        assert!(matches!(result, Err(GcError::LiveRoomsUnavailable)));
+
+        store.put_object("ydoc/p2", b"garbage").await.unwrap();
+        let result = service
+            .collect(ready(Some(HashSet::new())), Duration::ZERO, false)
+            .await;
+        assert!(matches!(result, Err(GcError::Snapshot { key, .. }) if key == "ydoc/p2"));
+
+        assert_eq!(store.len(), 4);
+    }
+}
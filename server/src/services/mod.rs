@@ -1,3 +1,6 @@
+pub mod archive;
+pub mod gc;
 pub mod project;
 pub mod team;
+pub mod tree;
 pub mod user;
@@ -1,15 +1,36 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use bson::oid::ObjectId;
 use derive_more::Display;
 use time::OffsetDateTime;
+use tracing::warn;
+use typst::foundations::Bytes;
+use typst::text::Font;
 
 use crate::{
-    models::project::{
-        FileContent, OwnerType, Project, ProjectDetailPayload, ProjectFile, ProjectPayload,
-        UpdateFilePayload,
+    compile::{
+        self, CompileError, CompileQueue, Diagnostic, FontCache, ImageFormat, JobKind,
+        PackageStore, ProjectWorld, RenderCache, WorldContent, WorldFile,
+        cache::inputs_key,
+        outline::{self, Outline},
+        render::{MAX_PIXELS, MAX_PPI, PageError, render_document_page},
+    },
+    handler::ws::ProjectServer,
+    models::{
+        project::{
+            FileContent, OwnerType, Project, ProjectDetailPayload, ProjectFile, ProjectFilePayload,
+            ProjectPayload, UpdateFilePayload,
+        },
+        team::TeamFont,
+        tree::{MAX_DEPTH, NodeProjection, ProjectTree, is_valid_segment},
     },
     repo::{project::ProjectRepo, team::TeamRepo, user::UserRepo},
+    services::{
+        archive::{self, Entry, EntryContent, Export, Manifest, UnpackLimits},
+        tree,
+    },
+    storage::{ByteStream, ObjectStore, StorageError, mime, sha256_hex, slice_stream},
 };
 
 #[derive(Debug, Display)]
@@ -20,6 +41,12 @@ pub enum ProjectServiceError {
     OwnerNotFound(OwnerType),
     #[display("Project not found")]
     ProjectNotFound,
+    #[display("File not found")]
+    FileNotFound,
+    #[display("The entry file can't be deleted without choosing a new entry")]
+    EntryRequired,
+    #[display("Invalid entry: {_0}")]
+    InvalidEntry(String),
     #[display("Access denied: You do not have permission to access this project")]
     AccessDenied,
     #[display("Creator does not match owner")]
@@ -28,14 +55,173 @@ pub enum ProjectServiceError {
     CreatorNotMemberOfTeam,
     #[display("Invalid owner type")]
     InvalidOwnerType,
+    #[display("Invalid file path: {_0}")]
+    InvalidPath(String),
+    #[display("Path already in use: {_0}")]
+    PathConflict(String),
+    #[display("File was changed meanwhile; it is now at version {_0}")]
+    VersionConflict(i32),
+    #[display("Invalid render request: {_0}")]
+    InvalidRender(String),
+    #[display("Page {_0} not found")]
+    PageNotFound(usize),
+    #[display("Invalid upload: {_0}")]
+    InvalidUpload(String),
+    #[display("File exceeds the {_0}-byte upload limit")]
+    PayloadTooLarge(usize),
+    #[display("Unsupported file type: {_0}")]
+    UnsupportedMediaType(String),
+    #[display("Object storage is not configured")]
+    StorageUnavailable,
+    #[display("{_0}")]
+    Compile(CompileError),
+    #[display("Storage error: {_0}")]
+    Storage(StorageError),
+    #[display("Archive error: {_0}")]
+    Archive(zip::result::ZipError),
     #[display("Database error: {_0}")]
     Database(mongodb::error::Error),
 }
 
+/// Content type text files are served as; Typst sources are UTF-8.
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// A file located for raw download: its SHA-256 (the download's ETag),
+/// content type and size, with the bytes left where they are until
+/// [`ProjectService::raw_body`] streams them — so a revalidation that ends in
+/// `304 Not Modified` never touches object storage.
+pub struct RawFile {
+    pub path: String,
+    pub sha256: String,
+    pub content_type: &'static str,
+    pub size: u64,
+    content: FileContent,
+}
+
+/// The Typst world over a project's files, with `fonts` (its team's) besides
+/// the bundled ones. Binary files and fonts are read from object storage and
+/// imported packages loaded here, so the compilation itself does no I/O.
+/// `blobs` caches binaries' bytes by storage key across calls: a blob never
+/// changes, so a cached copy is never stale. Afterwards it holds exactly this
+/// project's binaries. Fonts are parsed once and kept in `font_cache`.
+pub async fn project_world(
+    project: Project,
+    fonts: &[TeamFont],
+    store: Option<&dyn ObjectStore>,
+    packages: &PackageStore,
+    font_cache: &FontCache,
+    blobs: &mut HashMap<String, Vec<u8>>,
+) -> Result<ProjectWorld, ProjectServiceError> {
+    compile::check_version(project.pinned_version.as_ref())
+        .map_err(ProjectServiceError::Compile)?;
+    let entry = project
+        .entry
+        .ok_or(ProjectServiceError::Compile(CompileError::NoEntry))?;
+
+    let packages = packages
+        .resolve(project.files.iter().filter_map(|file| match &file.content {
+            FileContent::Text { text } => Some(text.as_str()),
+            FileContent::Binary { .. } => None,
+        }))
+        .await;
+
+    let mut cached = std::mem::take(blobs);
+    let mut files = Vec::with_capacity(project.files.len());
+    for file in project.files {
+        let content = match file.content {
+            FileContent::Text { text } => WorldContent::Text(text),
+            FileContent::Binary { storage_key } => {
+                WorldContent::Binary(world_blob(storage_key, store, &mut cached, blobs).await?)
+            }
+        };
+        files.push(WorldFile {
+            id: file.id,
+            path: file.path,
+            content,
+        });
+    }
+    let mut faces = Vec::new();
+    for font in fonts {
+        if let Some(parsed) = font_cache.get(&font.storage_key) {
+            faces.extend(parsed);
+            continue;
+        }
+        let bytes = store
+            .ok_or(ProjectServiceError::StorageUnavailable)?
+            .get(&font.storage_key)
+            .await
+            .map_err(ProjectServiceError::Storage)?;
+        let size = bytes.len();
+        let parsed: Vec<Font> = Font::iter(Bytes::new(bytes)).collect();
+        font_cache.insert(font.storage_key.clone(), parsed.clone(), size);
+        faces.extend(parsed);
+    }
+    ProjectWorld::new(entry, files)
+        .map(|world| world.with_packages(packages).with_fonts(faces))
+        .ok_or(ProjectServiceError::Compile(CompileError::NoEntry))
+}
+
+/// A blob's bytes for a world, taken from `cached` or storage, and kept in
+/// `blobs` for the next one.
+async fn world_blob(
+    storage_key: String,
+    store: Option<&dyn ObjectStore>,
+    cached: &mut HashMap<String, Vec<u8>>,
+    blobs: &mut HashMap<String, Vec<u8>>,
+) -> Result<Vec<u8>, ProjectServiceError> {
+    let bytes = match cached.remove(&storage_key) {
+        Some(bytes) => bytes,
+        None => store
+            .ok_or(ProjectServiceError::StorageUnavailable)?
+            .get(&storage_key)
+            .await
+            .map_err(ProjectServiceError::Storage)?,
+    };
+    blobs.insert(storage_key, bytes.clone());
+    Ok(bytes)
+}
+
+/// The fonts a project compiles with besides the bundled ones: its team's,
+/// if a team owns it.
+pub async fn owner_fonts(
+    team_repo: &impl TeamRepo,
+    project: &Project,
+) -> Result<Vec<TeamFont>, ProjectServiceError> {
+    match project.owner_type {
+        OwnerType::User => Ok(Vec::new()),
+        OwnerType::Team => match team_repo.find_by_id(project.owner_id).await {
+            Ok(Some(team)) => Ok(team.fonts),
+            Ok(None) => Err(ProjectServiceError::OwnerNotFound(OwnerType::Team)),
+            Err(e) => Err(ProjectServiceError::Database(e)),
+        },
+    }
+}
+
+/// A page located for rendering. `key` names the image by the compile's
+/// inputs, so it is both the render cache's key and the response's ETag, known
+/// before anything is compiled.
+pub struct PageRender {
+    pub key: String,
+    pub format: ImageFormat,
+    page: usize,
+    ppi: f32,
+    project: Project,
+    fonts: Vec<TeamFont>,
+}
+
 pub struct ProjectService<P: ProjectRepo, U: UserRepo, T: TeamRepo> {
     pub project_repo: P,
     pub user_repo: U,
     pub team_repo: T,
+    /// Where binary assets live. `None` when no storage backend is configured,
+    /// in which case asset operations fail with `StorageUnavailable`.
+    pub store: Option<Arc<dyn ObjectStore>>,
+    /// Team fonts, kept parsed between compilations.
+    pub font_cache: Arc<FontCache>,
+    /// Where the Typst packages projects import are found.
+    pub packages: Arc<PackageStore>,
+    /// Where compilations wait for a worker, shared with live previews.
+    pub compiler: Arc<CompileQueue>,
 }
 
 impl<P: ProjectRepo, U: UserRepo, T: TeamRepo> ProjectService<P, U, T> {
@@ -46,6 +232,42 @@ impl<P: ProjectRepo, U: UserRepo, T: TeamRepo> ProjectService<P, U, T> {
         owner_type: OwnerType,
         name: String,
     ) -> Result<ProjectPayload, ProjectServiceError> {
+        let owner_id = self.new_owner(creator_id, owner_id, &owner_type).await?;
+
+        // Seed an entry file so the project is editable/compilable immediately.
+        let entry_file = ProjectFile::default();
+        let now = entry_file.updated_at;
+        let entry_id = entry_file.id;
+
+        let project = self
+            .project_repo
+            .create(Project {
+                id: ObjectId::new(),
+                name,
+                owner_id,
+                owner_type,
+                creator_id,
+                nodes: self.new_nodes(std::slice::from_ref(&entry_file)).await,
+                files: vec![entry_file],
+                created_at: now,
+                updated_at: now,
+                entry: Some(entry_id),
+                pinned_version: None,
+            })
+            .await
+            .map_err(ProjectServiceError::Database)?;
+
+        Ok(project.into())
+    }
+
+    /// Check that `creator_id` may create a project owned by `owner_id`,
+    /// returning the owner's id.
+    async fn new_owner(
+        &self,
+        creator_id: ObjectId,
+        owner_id: ObjectId,
+        owner_type: &OwnerType,
+    ) -> Result<ObjectId, ProjectServiceError> {
         // Validate creator exists, creator must be a user
         let creator = match self.user_repo.find_by_id(creator_id).await {
             Ok(Some(user)) => user,
@@ -79,30 +301,7 @@ impl<P: ProjectRepo, U: UserRepo, T: TeamRepo> ProjectService<P, U, T> {
                 Err(e) => return Err(ProjectServiceError::Database(e)),
             },
         };
-
-        // Seed an entry file so the project is editable/compilable immediately.
-        let entry_file = ProjectFile::default();
-        let now = entry_file.updated_at;
-        let entry_id = entry_file.id;
-
-        let project = self
-            .project_repo
-            .create(Project {
-                id: ObjectId::new(),
-                name,
-                owner_id,
-                owner_type,
-                creator_id: creator.id,
-                files: vec![entry_file],
-                created_at: now,
-                updated_at: now,
-                entry: Some(entry_id),
-                pinned_version: None,
-            })
-            .await
-            .map_err(ProjectServiceError::Database)?;
-
-        Ok(project.into())
+        Ok(owner_id)
     }
 
     pub async fn find_by_id(
@@ -120,12 +319,20 @@ impl<P: ProjectRepo, U: UserRepo, T: TeamRepo> ProjectService<P, U, T> {
     /// the file's new version/timestamp. Whole-buffer save (not a delta) — this
     /// is the at-rest store, orthogonal to how edits are *synced* between
     /// collaborators (that becomes CRDT in M5).
+    ///
+    /// With `expected_version`, the save only lands if the file is still at
+    /// that version; otherwise it fails with `VersionConflict` carrying the
+    /// current one, so a client saving over a newer edit finds out. A file in
+    /// a project with a live room is saved through the room
+    /// ([`ProjectServer::edit_text`]).
     pub async fn update_file(
         &self,
         project_id: ObjectId,
         user_id: ObjectId,
         file_id: ObjectId,
         text: String,
+        expected_version: Option<i32>,
+        live: &ProjectServer,
     ) -> Result<UpdateFilePayload, ProjectServiceError> {
         match self.accessible(project_id, user_id).await {
             Ok(true) => {}
@@ -133,187 +340,1123 @@ impl<P: ProjectRepo, U: UserRepo, T: TeamRepo> ProjectService<P, U, T> {
             Err(e) => return Err(e),
         };
 
+        // A file open in a live room is saved through it, so collaborators see
+        // the edit and the room's next flush doesn't overwrite it.
+        if let Some(saved) = live
+            .edit_text(project_id, file_id, text.clone(), expected_version)
+            .await
+        {
+            return saved.map(UpdateFilePayload::from);
+        }
+
         let size = text.len() as i64;
         let content = FileContent::Text { text };
 
         match self
             .project_repo
-            .update_file_content(project_id, file_id, content, size)
+            .update_file_content(project_id, file_id, content, size, expected_version)
             .await
         {
-            Ok(Some(project)) => project
-                .files
-                .into_iter()
-                .find(|file| file.id == file_id)
-                .map(UpdateFilePayload::from)
-                .ok_or(ProjectServiceError::ProjectNotFound),
-            Ok(None) => Err(ProjectServiceError::ProjectNotFound),
+            Ok(Some(project)) => {
+                self.sync_nodes(&project).await;
+                project
+                    .files
+                    .into_iter()
+                    .find(|file| file.id == file_id)
+                    .map(UpdateFilePayload::from)
+                    .ok_or(ProjectServiceError::FileNotFound)
+            }
+            // Either the file is gone or, given a version, it moved on.
+            Ok(None) if expected_version.is_some() => {
+                match self.project_repo.find_by_id(project_id).await {
+                    Ok(project) => Err(project
+                        .and_then(|p| p.files.into_iter().find(|file| file.id == file_id))
+                        .map_or(ProjectServiceError::FileNotFound, |file| {
+                            ProjectServiceError::VersionConflict(file.version)
+                        })),
+                    Err(e) => Err(ProjectServiceError::Database(e)),
+                }
+            }
+            Ok(None) => Err(ProjectServiceError::FileNotFound),
             Err(e) => Err(ProjectServiceError::Database(e)),
         }
     }
 
-    /// Update a project's metadata: rename it and/or move it between owners
-    /// (personal space ↔ team). The caller must have access to the project,
-    /// and the *target* owner is validated with the same rules as `create` —
-    /// a user owner must be the requester themselves, a team owner must be a
-    /// team the requester belongs to — so a project can never be pushed into
-    /// someone else's space.
-    pub async fn update(
+    /// Store an uploaded binary asset (image, font, PDF) at `path`: a new file,
+    /// or new bytes for the binary file already there. The content type is
+    /// sniffed from the bytes, and must be a supported asset whose type agrees
+    /// with the path's extension.
+    ///
+    /// Write-before-reference: the blob is written to object storage first and
+    /// only then recorded on the project. A failure in between leaves an
+    /// unreferenced blob for GC, never a file pointing at missing bytes.
+    ///
+    /// Like every file created, moved or deleted, the change reaches a live
+    /// room of the project ([`ProjectServer::files_changed`]).
+    pub async fn upload_file(
         &self,
         project_id: ObjectId,
         user_id: ObjectId,
-        name: String,
-        owner_id: ObjectId,
-        owner_type: OwnerType,
-    ) -> Result<ProjectPayload, ProjectServiceError> {
+        path: String,
+        bytes: Vec<u8>,
+        live: &ProjectServer,
+    ) -> Result<ProjectFilePayload, ProjectServiceError> {
         match self.accessible(project_id, user_id).await {
             Ok(true) => {}
             Ok(false) => return Err(ProjectServiceError::AccessDenied),
             Err(e) => return Err(e),
         };
+        let store = self
+            .store
+            .as_ref()
+            .ok_or(ProjectServiceError::StorageUnavailable)?;
 
-        let owner_id = match owner_type {
-            OwnerType::User => match self.user_repo.find_by_id(owner_id).await {
-                Ok(Some(owner)) => {
-                    if owner.id != user_id {
-                        return Err(ProjectServiceError::CreatorNotMatchOwner);
-                    }
-                    owner.id
-                }
-                Ok(None) => return Err(ProjectServiceError::OwnerNotFound(OwnerType::User)),
-                Err(e) => return Err(ProjectServiceError::Database(e)),
-            },
-            OwnerType::Team => match self.team_repo.find_by_id(owner_id).await {
-                Ok(Some(team)) => {
-                    if !team.member_ids.contains(&user_id) {
-                        return Err(ProjectServiceError::CreatorNotMemberOfTeam);
-                    }
-                    team.id
-                }
-                Ok(None) => return Err(ProjectServiceError::OwnerNotFound(OwnerType::Team)),
-                Err(e) => return Err(ProjectServiceError::Database(e)),
-            },
+        let project = match self.project_repo.find_by_id(project_id).await {
+            Ok(Some(project)) => project,
+            Ok(None) => return Err(ProjectServiceError::ProjectNotFound),
+            Err(e) => return Err(ProjectServiceError::Database(e)),
         };
+        check_file_path(&project.files, &path)?;
+        let existing = project.files.iter().find(|file| file.path == path);
+        if let Some(ProjectFile {
+            content: FileContent::Text { .. },
+            ..
+        }) = existing
+        {
+            return Err(ProjectServiceError::PathConflict(path));
+        }
 
-        match self
-            .project_repo
-            .update_metadata(project_id, name, owner_id, owner_type)
+        let content_type = mime::sniff(&bytes).ok_or_else(|| {
+            ProjectServiceError::UnsupportedMediaType("unrecognized content".to_string())
+        })?;
+        if !mime::matches_extension(content_type, &path) {
+            return Err(ProjectServiceError::UnsupportedMediaType(format!(
+                "{content_type} content can't be stored as {path}"
+            )));
+        }
+
+        let blob = store
+            .put(&bytes)
             .await
-        {
-            Ok(Some(project)) => Ok(project.into()),
-            Ok(None) => Err(ProjectServiceError::ProjectNotFound),
+            .map_err(ProjectServiceError::Storage)?;
+        let content = FileContent::Binary {
+            storage_key: blob.sha256,
+        };
+        let size = blob.size as i64;
+
+        let (file_id, updated) = match existing {
+            Some(file) => (
+                file.id,
+                self.project_repo
+                    .update_file_content(project_id, file.id, content, size, None)
+                    .await,
+            ),
+            None => {
+                let file = ProjectFile {
+                    id: ObjectId::new(),
+                    path: path.clone(),
+                    content,
+                    size,
+                    version: 0,
+                    updated_at: OffsetDateTime::now_utc(),
+                };
+                (
+                    file.id,
+                    self.project_repo.insert_file(project_id, file).await,
+                )
+            }
+        };
+
+        match updated {
+            Ok(Some(project)) => {
+                self.sync_nodes(&project).await;
+                live.files_changed(project_id).await;
+                project
+                    .files
+                    .into_iter()
+                    .find(|file| file.id == file_id)
+                    .map(ProjectFilePayload::from)
+                    .ok_or(ProjectServiceError::ProjectNotFound)
+            }
+            // The project existed a moment ago, so the path was taken meanwhile
+            // (or the file replaced was removed).
+            Ok(None) => Err(ProjectServiceError::PathConflict(path)),
             Err(e) => Err(ProjectServiceError::Database(e)),
         }
     }
 
-    /// Clone a project the caller can access into a brand-new, independent
-    /// project owned the same way (same `owner_id`/`owner_type`), with the
-    /// requester recorded as the new project's `creator_id`. Every file gets a
-    /// fresh id — the copy must not alias the source's file rows — and `entry`
-    /// is remapped through that id swap so the duplicate still opens on the
-    /// same logical file.
-    pub async fn duplicate(
+    /// Create a text file at `path`. Asset paths (an image, font or PDF
+    /// extension) are refused: those files are created by uploading their
+    /// bytes (`upload_file`).
+    pub async fn create_file(
         &self,
         project_id: ObjectId,
         user_id: ObjectId,
-    ) -> Result<ProjectPayload, ProjectServiceError> {
+        path: String,
+        text: String,
+        live: &ProjectServer,
+    ) -> Result<ProjectFilePayload, ProjectServiceError> {
         match self.accessible(project_id, user_id).await {
             Ok(true) => {}
             Ok(false) => return Err(ProjectServiceError::AccessDenied),
             Err(e) => return Err(e),
         };
 
-        let source = match self.project_repo.find_by_id(project_id).await {
+        let project = match self.project_repo.find_by_id(project_id).await {
             Ok(Some(project)) => project,
             Ok(None) => return Err(ProjectServiceError::ProjectNotFound),
             Err(e) => return Err(ProjectServiceError::Database(e)),
         };
+        check_file_path(&project.files, &path)?;
+        if project.files.iter().any(|file| file.path == path) {
+            return Err(ProjectServiceError::PathConflict(path));
+        }
+        if let Some(content_type) = mime::for_extension(&path) {
+            return Err(ProjectServiceError::UnsupportedMediaType(format!(
+                "{path} is a {content_type} path; upload its bytes instead"
+            )));
+        }
 
-        let now = OffsetDateTime::now_utc();
+        let file = ProjectFile {
+            id: ObjectId::new(),
+            path: path.clone(),
+            size: text.len() as i64,
+            content: FileContent::Text { text },
+            version: 0,
+            updated_at: OffsetDateTime::now_utc(),
+        };
+        let file_id = file.id;
+        match self.project_repo.insert_file(project_id, file).await {
+            Ok(Some(project)) => {
+                self.sync_nodes(&project).await;
+                live.files_changed(project_id).await;
+                project
+                    .files
+                    .into_iter()
+                    .find(|file| file.id == file_id)
+                    .map(ProjectFilePayload::from)
+                    .ok_or(ProjectServiceError::ProjectNotFound)
+            }
+            Ok(None) => Err(ProjectServiceError::PathConflict(path)),
+            Err(e) => Err(ProjectServiceError::Database(e)),
+        }
+    }
 
-        let mut id_map = HashMap::with_capacity(source.files.len());
-        let files: Vec<ProjectFile> = source
+    /// Move a file to `path` — a rename, a move between folders, or both.
+    /// Folders are implied by paths, so moving a file out of a folder leaves
+    /// no empty folder behind. A binary file keeps its type: the new extension
+    /// must name the same asset type as the old one. The file keeps its id, so
+    /// `entry` needs no remapping.
+    pub async fn move_file(
+        &self,
+        project_id: ObjectId,
+        user_id: ObjectId,
+        file_id: ObjectId,
+        path: String,
+        live: &ProjectServer,
+    ) -> Result<ProjectFilePayload, ProjectServiceError> {
+        match self.accessible(project_id, user_id).await {
+            Ok(true) => {}
+            Ok(false) => return Err(ProjectServiceError::AccessDenied),
+            Err(e) => return Err(e),
+        };
+
+        let project = match self.project_repo.find_by_id(project_id).await {
+            Ok(Some(project)) => project,
+            Ok(None) => return Err(ProjectServiceError::ProjectNotFound),
+            Err(e) => return Err(ProjectServiceError::Database(e)),
+        };
+        let (moving, others): (Vec<_>, Vec<_>) = project
             .files
             .into_iter()
-            .map(|file| {
-                let new_id = ObjectId::new();
-                id_map.insert(file.id, new_id);
-                ProjectFile {
-                    id: new_id,
-                    updated_at: now,
-                    ..file
-                }
-            })
-            .collect();
-        let entry = source.entry.and_then(|old_id| id_map.get(&old_id).copied());
+            .partition(|file| file.id == file_id);
+        let file = moving
+            .into_iter()
+            .next()
+            .ok_or(ProjectServiceError::FileNotFound)?;
+        if file.path == path {
+            return Ok(file.into());
+        }
+        check_file_path(&others, &path)?;
+        if others.iter().any(|other| other.path == path) {
+            return Err(ProjectServiceError::PathConflict(path));
+        }
+        if let FileContent::Binary { .. } = file.content {
+            let content_type = mime::for_extension(&file.path);
+            if mime::for_extension(&path) != content_type {
+                return Err(ProjectServiceError::UnsupportedMediaType(format!(
+                    "{} content can't be stored as {path}",
+                    content_type.unwrap_or("binary")
+                )));
+            }
+        }
 
-        let project = self
+        match self
             .project_repo
-            .create(Project {
-                id: ObjectId::new(),
-                name: format!("{} copy", source.name),
-                owner_id: source.owner_id,
-                owner_type: source.owner_type,
-                creator_id: user_id,
-                files,
-                created_at: now,
-                updated_at: now,
-                entry,
-                pinned_version: source.pinned_version,
-            })
+            .move_file(project_id, file_id, path.clone())
             .await
-            .map_err(ProjectServiceError::Database)?;
-
-        Ok(project.into())
+        {
+            Ok(Some(project)) => {
+                self.sync_nodes(&project).await;
+                live.files_changed(project_id).await;
+                project
+                    .files
+                    .into_iter()
+                    .find(|file| file.id == file_id)
+                    .map(ProjectFilePayload::from)
+                    .ok_or(ProjectServiceError::FileNotFound)
+            }
+            // Checked above, so the path was taken (or the file removed)
+            // meanwhile.
+            Ok(None) => Err(ProjectServiceError::PathConflict(path)),
+            Err(e) => Err(ProjectServiceError::Database(e)),
+        }
     }
-}
 
-impl<P: ProjectRepo, U: UserRepo, T: TeamRepo> ProjectService<P, U, T> {
-    pub async fn accessible(
+    /// Delete a file. The project's entry file can only be deleted together
+    /// with naming a new `entry` — another text file in the project — which is
+    /// switched to in the same write. `entry` may also be given when deleting
+    /// any other file.
+    pub async fn delete_file(
         &self,
         project_id: ObjectId,
         user_id: ObjectId,
-    ) -> Result<bool, ProjectServiceError> {
+        file_id: ObjectId,
+        entry: Option<ObjectId>,
+        live: &ProjectServer,
+    ) -> Result<(), ProjectServiceError> {
+        match self.accessible(project_id, user_id).await {
+            Ok(true) => {}
+            Ok(false) => return Err(ProjectServiceError::AccessDenied),
+            Err(e) => return Err(e),
+        };
+
         let project = match self.project_repo.find_by_id(project_id).await {
             Ok(Some(project)) => project,
             Ok(None) => return Err(ProjectServiceError::ProjectNotFound),
             Err(e) => return Err(ProjectServiceError::Database(e)),
         };
-
-        // Check if user is the creator
-        if project.creator_id == user_id {
-            return Ok(true);
+        if !project.files.iter().any(|file| file.id == file_id) {
+            return Err(ProjectServiceError::FileNotFound);
         }
-
-        // Check based on owner type
-        match project.owner_type {
-            OwnerType::User => {
-                // If owner is a user, check if it's the same user
-                Ok(project.owner_id == user_id)
+        match entry {
+            Some(entry) if entry == file_id => {
+                return Err(ProjectServiceError::InvalidEntry(
+                    "the new entry is the file being deleted".to_string(),
+                ));
             }
-            OwnerType::Team => {
-                // If owner is a team, check if user is a team member
-                match self.team_repo.find_by_id(project.owner_id).await {
-                    Ok(Some(team)) => Ok(team.member_ids.contains(&user_id)),
-                    Ok(None) => Err(ProjectServiceError::OwnerNotFound(OwnerType::Team)),
-                    Err(e) => Err(ProjectServiceError::Database(e)),
+            Some(entry) => match project.files.iter().find(|file| file.id == entry) {
+                Some(ProjectFile {
+                    content: FileContent::Text { .. },
+                    ..
+                }) => {}
+                Some(file) => {
+                    return Err(ProjectServiceError::InvalidEntry(format!(
+                        "{} is not a text file",
+                        file.path
+                    )));
                 }
+                None => {
+                    return Err(ProjectServiceError::InvalidEntry(format!(
+                        "no file {entry} in this project"
+                    )));
+                }
+            },
+            None if project.entry == Some(file_id) => {
+                return Err(ProjectServiceError::EntryRequired);
             }
+            None => {}
         }
-    }
-}
 
-#[cfg(test)]
-#[cfg_attr(coverage_nightly, coverage(off))]
-mod tests {
-    use super::*;
-    use crate::models::{project::OwnerType, team::Team, user::User};
-    use crate::repo::project::tests::MockProjectRepo;
-    use crate::repo::team::tests::MockTeamRepo;
-    use crate::repo::user::tests::MockUserRepo;
-    use bson::oid::ObjectId;
-    use std::sync::Mutex;
-    use time::OffsetDateTime;
+        match self
+            .project_repo
+            .delete_file(project_id, file_id, entry)
+            .await
+        {
+            Ok(Some(project)) => {
+                self.sync_nodes(&project).await;
+                live.files_changed(project_id).await;
+                Ok(())
+            }
+            // Checked above, so the file, the new entry, or which file is the
+            // entry changed meanwhile.
+            Ok(None) => Err(ProjectServiceError::FileNotFound),
+            Err(e) => Err(ProjectServiceError::Database(e)),
+        }
+    }
+
+    /// Locate a file for raw download. Caller must have access. A binary
+    /// file's hash is its storage key; a text file's is computed here, from
+    /// its text in `live` (the project's collaboration room) if it has one.
+    pub async fn raw_file(
+        &self,
+        project_id: ObjectId,
+        user_id: ObjectId,
+        file_id: ObjectId,
+        mut live: HashMap<ObjectId, String>,
+    ) -> Result<RawFile, ProjectServiceError> {
+        match self.accessible(project_id, user_id).await {
+            Ok(true) => {}
+            Ok(false) => return Err(ProjectServiceError::AccessDenied),
+            Err(e) => return Err(e),
+        };
+
+        let project = match self.project_repo.find_by_id(project_id).await {
+            Ok(Some(project)) => project,
+            Ok(None) => return Err(ProjectServiceError::ProjectNotFound),
+            Err(e) => return Err(ProjectServiceError::Database(e)),
+        };
+        let mut file = project
+            .files
+            .into_iter()
+            .find(|file| file.id == file_id)
+            .ok_or(ProjectServiceError::FileNotFound)?;
+        if let FileContent::Text { text } = &mut file.content
+            && let Some(live) = live.remove(&file.id)
+        {
+            *text = live;
+        }
+
+        let (sha256, content_type, size) = match &file.content {
+            FileContent::Text { text } => (
+                sha256_hex(text.as_bytes()),
+                TEXT_CONTENT_TYPE,
+                text.len() as u64,
+            ),
+            FileContent::Binary { storage_key } => (
+                storage_key.clone(),
+                mime::for_extension(&file.path).unwrap_or("application/octet-stream"),
+                file.size.max(0) as u64,
+            ),
+        };
+        Ok(RawFile {
+            path: file.path,
+            sha256,
+            content_type,
+            size,
+            content: file.content,
+        })
+    }
+
+    /// The bytes of a file located by [`Self::raw_file`], or only the
+    /// inclusive byte `range` of them, streamed from object storage for a
+    /// binary file. The range must lie within the file's `size`.
+    pub async fn raw_body(
+        &self,
+        file: RawFile,
+        range: Option<(u64, u64)>,
+    ) -> Result<ByteStream, ProjectServiceError> {
+        match file.content {
+            FileContent::Text { text } => Ok(slice_stream(bytes::Bytes::from(text), range)),
+            FileContent::Binary { storage_key } => self
+                .store
+                .as_ref()
+                .ok_or(ProjectServiceError::StorageUnavailable)?
+                .get_range(&storage_key, range)
+                .await
+                .map_err(ProjectServiceError::Storage),
+        }
+    }
+
+    /// Compile the project to PDF, starting from its entry file. Caller must
+    /// have access.
+    pub async fn compile_pdf(
+        &self,
+        project_id: ObjectId,
+        user_id: ObjectId,
+    ) -> Result<Vec<u8>, ProjectServiceError> {
+        let world = self.world(project_id, user_id).await?;
+        self.compiler
+            .run(project_id, JobKind::Pdf, world, compile::compile_pdf)
+            .await
+            .map_err(ProjectServiceError::Compile)
+    }
+
+    /// The headings, labels, references and citation keys in the project's
+    /// sources and bibliographies. `live` holds the text of files open in a
+    /// collaboration room, newer than the stored text it replaces. Caller must
+    /// have access.
+    pub async fn outline(
+        &self,
+        project_id: ObjectId,
+        user_id: ObjectId,
+        mut live: HashMap<ObjectId, String>,
+    ) -> Result<Outline, ProjectServiceError> {
+        match self.accessible(project_id, user_id).await {
+            Ok(true) => {}
+            Ok(false) => return Err(ProjectServiceError::AccessDenied),
+            Err(e) => return Err(e),
+        };
+        let project = match self.project_repo.find_by_id(project_id).await {
+            Ok(Some(project)) => project,
+            Ok(None) => return Err(ProjectServiceError::ProjectNotFound),
+            Err(e) => return Err(ProjectServiceError::Database(e)),
+        };
+
+        // Sources and bibliographies are text files; uploads are only ever
+        // images, fonts and PDFs.
+        let mut index = Outline::default();
+        for file in project.files {
+            if let FileContent::Text { text } = file.content
+                && outline::is_indexed(&file.path)
+            {
+                let text = live.remove(&file.id).unwrap_or(text);
+                index.add(file.id, &file.path, &text);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Everything in the project, for [`Export::into_stream`] to write out as a
+    /// zip archive. Paths come from the file tree; `live` holds the text of
+    /// files open in a collaboration room, as for [`Self::outline`]. With
+    /// `pdf`, the compiled document is added next to the entry (`main.typ`
+    /// gives `main.pdf`); it's compiled before anything is sent, so a failure
+    /// is an error rather than a truncated archive. With `manifest`, a
+    /// [`Manifest`] is added at [`archive::MANIFEST_PATH`]. Either clashing
+    /// with a project file is a `PathConflict`. Caller must have access.
+    pub async fn export(
+        &self,
+        project_id: ObjectId,
+        user_id: ObjectId,
+        mut live: HashMap<ObjectId, String>,
+        pdf: bool,
+        manifest: bool,
+    ) -> Result<Export, ProjectServiceError> {
+        match self.accessible(project_id, user_id).await {
+            Ok(true) => {}
+            Ok(false) => return Err(ProjectServiceError::AccessDenied),
+            Err(e) => return Err(e),
+        };
+        let mut project = match self.project_repo.find_by_id(project_id).await {
+            Ok(Some(project)) => project,
+            Ok(None) => return Err(ProjectServiceError::ProjectNotFound),
+            Err(e) => return Err(ProjectServiceError::Database(e)),
+        };
+
+        // A project not migrated yet derives its tree here. A file the
+        // projection doesn't know yet keeps its own path, as in listings.
+        let nodes = match &project.nodes {
+            Some(nodes) => nodes.clone(),
+            None => tree::project_nodes(&project.files, &[]).unwrap_or_default(),
+        };
+        let file_tree = ProjectTree::from_nodes(nodes.into_iter().map(|n| n.node));
+        for file in &mut project.files {
+            if let Ok(path) = file_tree.path_of(&file.id.to_hex()) {
+                file.path = path;
+            }
+            if let FileContent::Text { text } = &mut file.content
+                && let Some(text_live) = live.remove(&file.id)
+            {
+                *text = text_live;
+            }
+        }
+        let entry_path = project
+            .entry
+            .and_then(|entry| project.files.iter().find(|file| file.id == entry))
+            .map(|file| file.path.clone());
+
+        let mut extras = Vec::new();
+        if pdf {
+            let path = match &entry_path {
+                Some(path) => format!("{}.pdf", path.strip_suffix(".typ").unwrap_or(path)),
+                None => return Err(ProjectServiceError::Compile(CompileError::NoEntry)),
+            };
+            let fonts = owner_fonts(&self.team_repo, &project).await?;
+            let world = project_world(
+                project.clone(),
+                &fonts,
+                self.store.as_deref(),
+                &self.packages,
+                &self.font_cache,
+                &mut HashMap::new(),
+            )
+            .await?;
+            let bytes = self
+                .compiler
+                .run(project_id, JobKind::Pdf, world, compile::compile_pdf)
+                .await
+                .map_err(ProjectServiceError::Compile)?;
+            extras.push((path, EntryContent::Bytes(bytes)));
+        }
+        if manifest {
+            let manifest = Manifest {
+                entry: entry_path,
+                pinned_version: project.pinned_version.clone(),
+            };
+            let json = serde_json::to_string_pretty(&manifest).expect("a manifest serializes");
+            extras.push((archive::MANIFEST_PATH.to_string(), EntryContent::Text(json)));
+        }
+
+        let now = OffsetDateTime::now_utc();
+        let mut entries: Vec<_> = project
+            .files
+            .into_iter()
+            .map(|file| Entry {
+                path: file.path,
+                content: match file.content {
+                    FileContent::Text { text } => EntryContent::Text(text),
+                    FileContent::Binary { storage_key } => EntryContent::Blob(storage_key),
+                },
+                modified: file.updated_at,
+            })
+            .collect();
+        for (path, content) in extras {
+            if entries.iter().any(|entry| entry.path == path) {
+                return Err(ProjectServiceError::PathConflict(path));
+            }
+            entries.push(Entry {
+                path,
+                content,
+                modified: now,
+            });
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Export::new(project.name, entries, self.store.clone())
+    }
+
+    /// Create a project from a zip archive (see [`archive::unpack`]). Files
+    /// with an asset extension must hold that kind of asset and are stored as
+    /// blobs; every other file must be UTF-8 text and is stored inline. The
+    /// entry file is the one a root `manifest.json` names, else `main.typ` at
+    /// the root, else the only `.typ` file at the root or, failing that, in
+    /// the whole archive. The manifest's pinned version is kept too.
+    ///
+    /// Write-before-reference, as for uploads: every blob is stored before
+    /// the project is created.
+    pub async fn import(
+        &self,
+        creator_id: ObjectId,
+        owner_id: ObjectId,
+        owner_type: OwnerType,
+        name: String,
+        bytes: Vec<u8>,
+        limits: UnpackLimits,
+    ) -> Result<ProjectPayload, ProjectServiceError> {
+        let owner_id = self.new_owner(creator_id, owner_id, &owner_type).await?;
+
+        // Inflating is CPU-bound; keep it off the async workers.
+        let unpacked = tokio::task::spawn_blocking(move || archive::unpack(&bytes, &limits))
+            .await
+            .map_err(|_| ProjectServiceError::InvalidUpload("unreadable archive".to_string()))??;
+        if unpacked.files.is_empty() {
+            return Err(ProjectServiceError::InvalidUpload(
+                "the archive holds no files".to_string(),
+            ));
+        }
+
+        let now = OffsetDateTime::now_utc();
+        let mut files: Vec<ProjectFile> = Vec::with_capacity(unpacked.files.len());
+        for (path, bytes) in unpacked.files {
+            check_file_path(&files, &path)?;
+            let size = bytes.len() as i64;
+            let content = match mime::for_extension(&path) {
+                Some(_) => {
+                    let content_type = mime::sniff(&bytes).ok_or_else(|| {
+                        ProjectServiceError::UnsupportedMediaType(format!(
+                            "unrecognized content in {path}"
+                        ))
+                    })?;
+                    if !mime::matches_extension(content_type, &path) {
+                        return Err(ProjectServiceError::UnsupportedMediaType(format!(
+                            "{content_type} content can't be stored as {path}"
+                        )));
+                    }
+                    let store = self
+                        .store
+                        .as_ref()
+                        .ok_or(ProjectServiceError::StorageUnavailable)?;
+                    let blob = store
+                        .put(&bytes)
+                        .await
+                        .map_err(ProjectServiceError::Storage)?;
+                    FileContent::Binary {
+                        storage_key: blob.sha256,
+                    }
+                }
+                None => match String::from_utf8(bytes) {
+                    Ok(text) => FileContent::Text { text },
+                    Err(_) => {
+                        return Err(ProjectServiceError::UnsupportedMediaType(format!(
+                            "{path} is neither text nor a supported asset"
+                        )));
+                    }
+                },
+            };
+            files.push(ProjectFile {
+                id: ObjectId::new(),
+                path,
+                content,
+                size,
+                version: 0,
+                updated_at: now,
+            });
+        }
+
+        let manifest = unpacked.manifest.unwrap_or_default();
+        let entry = guess_entry(&files, manifest.entry.as_deref())?;
+        let nodes = self.new_nodes(&files).await;
+
+        let project = self
+            .project_repo
+            .create(Project {
+                id: ObjectId::new(),
+                name,
+                owner_id,
+                owner_type,
+                creator_id,
+                files,
+                created_at: now,
+                updated_at: now,
+                entry: Some(entry),
+                pinned_version: manifest.pinned_version,
+                nodes,
+            })
+            .await
+            .map_err(ProjectServiceError::Database)?;
+
+        Ok(project.into())
+    }
+
+    /// The errors and warnings compiling the project produces, located by
+    /// file id. Caller must have access.
+    pub async fn diagnostics(
+        &self,
+        project_id: ObjectId,
+        user_id: ObjectId,
+    ) -> Result<Vec<Diagnostic>, ProjectServiceError> {
+        let world = self.world(project_id, user_id).await?;
+        self.compiler
+            .run(project_id, JobKind::Diagnostics, world, |world| {
+                Ok(compile::diagnose(world))
+            })
+            .await
+            .map_err(ProjectServiceError::Compile)
+    }
+
+    /// Locate one page of the compiled project (`page` counts from 1) for
+    /// rendering. Caller must have access. Nothing is compiled yet; see
+    /// [`Self::render_bytes`].
+    pub async fn page_render(
+        &self,
+        project_id: ObjectId,
+        user_id: ObjectId,
+        page: usize,
+        format: ImageFormat,
+        ppi: f32,
+    ) -> Result<PageRender, ProjectServiceError> {
+        if page == 0 {
+            return Err(ProjectServiceError::InvalidRender(
+                "pages count from 1".to_string(),
+            ));
+        }
+        if !(ppi > 0.0 && ppi <= MAX_PPI) {
+            return Err(ProjectServiceError::InvalidRender(format!(
+                "ppi must be above 0 and at most {MAX_PPI}"
+            )));
+        }
+        match self.accessible(project_id, user_id).await {
+            Ok(true) => {}
+            Ok(false) => return Err(ProjectServiceError::AccessDenied),
+            Err(e) => return Err(e),
+        };
+
+        let project = match self.project_repo.find_by_id(project_id).await {
+            Ok(Some(project)) => project,
+            Ok(None) => return Err(ProjectServiceError::ProjectNotFound),
+            Err(e) => return Err(ProjectServiceError::Database(e)),
+        };
+        compile::check_version(project.pinned_version.as_ref())
+            .map_err(ProjectServiceError::Compile)?;
+        let entry = project
+            .entry
+            .and_then(|entry| project.files.iter().find(|file| file.id == entry))
+            .ok_or(ProjectServiceError::Compile(CompileError::NoEntry))?;
+
+        let hashes: Vec<_> = project
+            .files
+            .iter()
+            .map(|file| match &file.content {
+                FileContent::Text { text } => (file.path.as_str(), sha256_hex(text.as_bytes())),
+                FileContent::Binary { storage_key } => (file.path.as_str(), storage_key.clone()),
+            })
+            .collect();
+        let fonts = owner_fonts(&self.team_repo, &project).await?;
+        // Only a package that was found can have been compiled in; a missing
+        // one fails the compile, which isn't cached.
+        let packages: Vec<String> = self
+            .packages
+            .resolve(project.files.iter().filter_map(|file| match &file.content {
+                FileContent::Text { text } => Some(text.as_str()),
+                FileContent::Binary { .. } => None,
+            }))
+            .await
+            .into_iter()
+            .filter(|(_, package)| package.is_ok())
+            .map(|(spec, _)| spec.to_string())
+            .collect();
+        let inputs = inputs_key(
+            &entry.path,
+            hashes.iter().map(|(path, hash)| (*path, hash.as_str())),
+            fonts.iter().map(|font| font.storage_key.as_str()),
+            packages.iter().map(String::as_str),
+        );
+        // An SVG is the same at any resolution.
+        let output = match format {
+            ImageFormat::Svg => format!("{page}/svg"),
+            ImageFormat::Png => format!("{page}/png/{ppi}"),
+        };
+        let key = sha256_hex(format!("{inputs}/{output}").as_bytes());
+
+        Ok(PageRender {
+            key,
+            format,
+            page,
+            ppi,
+            project,
+            fonts,
+        })
+    }
+
+    /// The image of a page located by [`Self::page_render`], from `cache` or
+    /// by compiling the project.
+    pub async fn render_bytes(
+        &self,
+        render: PageRender,
+        cache: &RenderCache,
+    ) -> Result<Arc<[u8]>, ProjectServiceError> {
+        if let Some(image) = cache.get(&render.key) {
+            return Ok(image);
+        }
+        let PageRender {
+            key,
+            format,
+            page,
+            ppi,
+            project,
+            fonts,
+        } = render;
+        let project_id = project.id;
+        let world = project_world(
+            project,
+            &fonts,
+            self.store.as_deref(),
+            &self.packages,
+            &self.font_cache,
+            &mut HashMap::new(),
+        )
+        .await?;
+        let image: Arc<[u8]> = self
+            .compiler
+            .run(project_id, JobKind::Render, world, move |world| {
+                render_document_page(world, page - 1, format, ppi)
+            })
+            .await
+            .map_err(ProjectServiceError::Compile)?
+            .map_err(|e| match e {
+                PageError::Missing => ProjectServiceError::PageNotFound(page),
+                PageError::TooLarge(pixels) => ProjectServiceError::InvalidRender(format!(
+                    "the page would be {pixels} pixels at {ppi} ppi, over the {MAX_PIXELS}-pixel limit"
+                )),
+            })?
+            .into();
+        cache.insert(key, image.clone());
+        Ok(image)
+    }
+
+    /// The Typst world over a project the caller has access to.
+    async fn world(
+        &self,
+        project_id: ObjectId,
+        user_id: ObjectId,
+    ) -> Result<ProjectWorld, ProjectServiceError> {
+        match self.accessible(project_id, user_id).await {
+            Ok(true) => {}
+            Ok(false) => return Err(ProjectServiceError::AccessDenied),
+            Err(e) => return Err(e),
+        };
+
+        let project = match self.project_repo.find_by_id(project_id).await {
+            Ok(Some(project)) => project,
+            Ok(None) => return Err(ProjectServiceError::ProjectNotFound),
+            Err(e) => return Err(ProjectServiceError::Database(e)),
+        };
+        let fonts = owner_fonts(&self.team_repo, &project).await?;
+        project_world(
+            project,
+            &fonts,
+            self.store.as_deref(),
+            &self.packages,
+            &self.font_cache,
+            &mut HashMap::new(),
+        )
+        .await
+    }
+
+    /// Update a project's metadata: rename it and/or move it between owners
+    /// (personal space ↔ team). The caller must have access to the project,
+    /// and the *target* owner is validated with the same rules as `create` —
+    /// a user owner must be the requester themselves, a team owner must be a
+    /// team the requester belongs to — so a project can never be pushed into
+    /// someone else's space.
+    pub async fn update(
+        &self,
+        project_id: ObjectId,
+        user_id: ObjectId,
+        name: String,
+        owner_id: ObjectId,
+        owner_type: OwnerType,
+    ) -> Result<ProjectPayload, ProjectServiceError> {
+        match self.accessible(project_id, user_id).await {
+            Ok(true) => {}
+            Ok(false) => return Err(ProjectServiceError::AccessDenied),
+            Err(e) => return Err(e),
+        };
+
+        let owner_id = match owner_type {
+            OwnerType::User => match self.user_repo.find_by_id(owner_id).await {
+                Ok(Some(owner)) => {
+                    if owner.id != user_id {
+                        return Err(ProjectServiceError::CreatorNotMatchOwner);
+                    }
+                    owner.id
+                }
+                Ok(None) => return Err(ProjectServiceError::OwnerNotFound(OwnerType::User)),
+                Err(e) => return Err(ProjectServiceError::Database(e)),
+            },
+            OwnerType::Team => match self.team_repo.find_by_id(owner_id).await {
+                Ok(Some(team)) => {
+                    if !team.member_ids.contains(&user_id) {
+                        return Err(ProjectServiceError::CreatorNotMemberOfTeam);
+                    }
+                    team.id
+                }
+                Ok(None) => return Err(ProjectServiceError::OwnerNotFound(OwnerType::Team)),
+                Err(e) => return Err(ProjectServiceError::Database(e)),
+            },
+        };
+
+        match self
+            .project_repo
+            .update_metadata(project_id, name, owner_id, owner_type)
+            .await
+        {
+            Ok(Some(project)) => Ok(project.into()),
+            Ok(None) => Err(ProjectServiceError::ProjectNotFound),
+            Err(e) => Err(ProjectServiceError::Database(e)),
+        }
+    }
+
+    /// Clone a project the caller can access into a brand-new, independent
+    /// project owned the same way (same `owner_id`/`owner_type`), with the
+    /// requester recorded as the new project's `creator_id`. Every file gets a
+    /// fresh id — the copy must not alias the source's file rows — and `entry`
+    /// is remapped through that id swap so the duplicate still opens on the
+    /// same logical file.
+    pub async fn duplicate(
+        &self,
+        project_id: ObjectId,
+        user_id: ObjectId,
+    ) -> Result<ProjectPayload, ProjectServiceError> {
+        match self.accessible(project_id, user_id).await {
+            Ok(true) => {}
+            Ok(false) => return Err(ProjectServiceError::AccessDenied),
+            Err(e) => return Err(e),
+        };
+
+        let source = match self.project_repo.find_by_id(project_id).await {
+            Ok(Some(project)) => project,
+            Ok(None) => return Err(ProjectServiceError::ProjectNotFound),
+            Err(e) => return Err(ProjectServiceError::Database(e)),
+        };
+
+        let now = OffsetDateTime::now_utc();
+
+        let mut id_map = HashMap::with_capacity(source.files.len());
+        let files: Vec<ProjectFile> = source
+            .files
+            .into_iter()
+            .map(|file| {
+                let new_id = ObjectId::new();
+                id_map.insert(file.id, new_id);
+                ProjectFile {
+                    id: new_id,
+                    updated_at: now,
+                    ..file
+                }
+            })
+            .collect();
+        let entry = source.entry.and_then(|old_id| id_map.get(&old_id).copied());
+        let nodes = self.new_nodes(&files).await;
+
+        let project = self
+            .project_repo
+            .create(Project {
+                id: ObjectId::new(),
+                name: format!("{} copy", source.name),
+                owner_id: source.owner_id,
+                owner_type: source.owner_type,
+                creator_id: user_id,
+                files,
+                created_at: now,
+                updated_at: now,
+                entry,
+                pinned_version: source.pinned_version,
+                nodes,
+            })
+            .await
+            .map_err(ProjectServiceError::Database)?;
+
+        Ok(project.into())
+    }
+}
+
+/// Check that `path` is a legal file path in a project holding `files`: every
+/// segment valid (see [`is_valid_segment`]), no deeper than [`MAX_DEPTH`], and
+/// not crossing an existing file — a file can't also be a folder, so neither
+/// `path` nor an existing file may lie "inside" the other. An existing file *at*
+/// `path` is not a conflict here; callers decide whether to replace it.
+fn check_file_path(files: &[ProjectFile], path: &str) -> Result<(), ProjectServiceError> {
+    let invalid = || ProjectServiceError::InvalidPath(path.to_string());
+    let segments: Vec<&str> = path.split('/').collect();
+    if segments.len() > MAX_DEPTH || !segments.iter().all(|s| is_valid_segment(s)) {
+        return Err(invalid());
+    }
+    let crosses = files.iter().any(|file| {
+        path.strip_prefix(file.path.as_str())
+            .is_some_and(|rest| rest.starts_with('/'))
+            || file
+                .path
+                .strip_prefix(path)
+                .is_some_and(|rest| rest.starts_with('/'))
+    });
+    if crosses {
+        return Err(ProjectServiceError::PathConflict(path.to_string()));
+    }
+    Ok(())
+}
+
+/// The entry file of an imported project: `named` by its manifest, or else
+/// the likeliest `.typ` file (see [`ProjectService::import`]).
+fn guess_entry(
+    files: &[ProjectFile],
+    named: Option<&str>,
+) -> Result<ObjectId, ProjectServiceError> {
+    let typ = |file: &&ProjectFile| {
+        file.path.ends_with(".typ") && matches!(file.content, FileContent::Text { .. })
+    };
+    if let Some(named) = named {
+        return files
+            .iter()
+            .filter(typ)
+            .find(|file| file.path == named)
+            .map(|file| file.id)
+            .ok_or_else(|| {
+                ProjectServiceError::InvalidEntry(format!(
+                    "the manifest's entry {named} is not a Typst file in the archive"
+                ))
+            });
+    }
+    let sole = |mut candidates: Vec<&ProjectFile>| match candidates.len() {
+        1 => candidates.pop().map(|file| file.id),
+        _ => None,
+    };
+    let typ_files: Vec<_> = files.iter().filter(typ).collect();
+    let root = typ_files
+        .iter()
+        .copied()
+        .filter(|file| !file.path.contains('/'))
+        .collect::<Vec<_>>();
+    root.iter()
+        .find(|file| file.path == "main.typ")
+        .map(|file| file.id)
+        .or_else(|| sole(root))
+        .or_else(|| sole(typ_files))
+        .ok_or_else(|| {
+            ProjectServiceError::InvalidEntry(
+                "no entry file; add main.typ or a manifest.json naming one".to_string(),
+            )
+        })
+}
+
+impl<P: ProjectRepo, U: UserRepo, T: TeamRepo> ProjectService<P, U, T> {
+    /// The first node projection for a new project's `files`, so new projects
+    /// start out migrated. `None` (left for the migration) without a store, or
+    /// if uploading the text bodies fails.
+    async fn new_nodes(&self, files: &[ProjectFile]) -> Option<Vec<NodeProjection>> {
+        let store = self.store.as_ref()?;
+        match tree::initial_nodes(store.as_ref(), files).await {
+            Ok(nodes) => Some(nodes),
+            Err(e) => {
+                warn!("Project created without a node projection: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Bring a project's node projection in step after a file write. Best
+    /// effort: the write itself has landed, and a lagging projection is caught
+    /// up by the next write and reported by the migration's verify pass.
+    async fn sync_nodes(&self, project: &Project) {
+        if let Err(e) = tree::sync_nodes(&self.project_repo, self.store.as_deref(), project).await {
+            warn!(
+                "Node projection of {} not updated: {}",
+                project.id.to_hex(),
+                e
+            );
+        }
+    }
+
+    pub async fn accessible(
+        &self,
+        project_id: ObjectId,
+        user_id: ObjectId,
+    ) -> Result<bool, ProjectServiceError> {
+        let project = match self.project_repo.find_by_id(project_id).await {
+            Ok(Some(project)) => project,
+            Ok(None) => return Err(ProjectServiceError::ProjectNotFound),
+            Err(e) => return Err(ProjectServiceError::Database(e)),
+        };
+
+        // Check if user is the creator
+        if project.creator_id == user_id {
+            return Ok(true);
+        }
+
+        // Check based on owner type
+        match project.owner_type {
+            OwnerType::User => {
+                // If owner is a user, check if it's the same user
+                Ok(project.owner_id == user_id)
+            }
+            OwnerType::Team => {
+                // If owner is a team, check if user is a team member
+                match self.team_repo.find_by_id(project.owner_id).await {
+                    Ok(Some(team)) => Ok(team.member_ids.contains(&user_id)),
+                    Ok(None) => Err(ProjectServiceError::OwnerNotFound(OwnerType::Team)),
+                    Err(e) => Err(ProjectServiceError::Database(e)),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::models::{
+        project::{FileKind, OwnerType},
+        team::Team,
+        user::User,
+    };
+    use crate::repo::project::tests::MockProjectRepo;
+    use crate::repo::team::tests::MockTeamRepo;
+    use crate::repo::user::tests::MockUserRepo;
+    use crate::storage::InMemoryObjectStore;
+    use bson::oid::ObjectId;
+    use std::sync::Mutex;
+    use time::OffsetDateTime;
 
     fn dummy_user(id: ObjectId) -> User {
         User {
@@ -324,90 +1467,719 @@ mod tests {
             avatar_uri: None,
             created_at: OffsetDateTime::now_utc(),
             updated_at: OffsetDateTime::now_utc(),
-        }
+        }
+    }
+
+    fn dummy_team(id: ObjectId, member_ids: Vec<ObjectId>) -> Team {
+        Team {
+            id,
+            name: format!("team_{}", id),
+            avatar_uri: None,
+            creator_id: member_ids[0],
+            member_ids,
+            fonts: vec![],
+            created_at: OffsetDateTime::now_utc(),
+            updated_at: OffsetDateTime::now_utc(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_project_invalid_creator() {
+        let service = ProjectService {
+            project_repo: MockProjectRepo::default(),
+            user_repo: MockUserRepo::default(),
+            team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+        let creator_id = ObjectId::new();
+        let owner_id = creator_id;
+        let res = service
+            .create(creator_id, owner_id, OwnerType::User, "p1".to_string())
+            .await;
+        assert!(matches!(res, Err(ProjectServiceError::UserNotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_create_project_user_owner_success() {
+        let creator_id = ObjectId::new();
+        let user = dummy_user(creator_id);
+        let service = ProjectService {
+            project_repo: MockProjectRepo::default(),
+            user_repo: MockUserRepo {
+                users: Mutex::new(vec![user.clone()]),
+            },
+            team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+        let res = service
+            .create(creator_id, creator_id, OwnerType::User, "p3".to_string())
+            .await;
+        assert!(res.is_ok());
+        let payload = res.unwrap();
+        assert_eq!(payload.owner_id, creator_id.to_hex());
+        assert_eq!(payload.owner_type, OwnerType::User);
+    }
+
+    #[tokio::test]
+    async fn test_create_project_owner_user_not_found() {
+        let creator_id = ObjectId::new();
+        let user = dummy_user(creator_id);
+        let service = ProjectService {
+            project_repo: MockProjectRepo::default(),
+            user_repo: MockUserRepo {
+                users: Mutex::new(vec![user.clone()]),
+            },
+            team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+        let owner_id = ObjectId::new();
+        let res = service
+            .create(creator_id, owner_id, OwnerType::User, "p4".to_string())
+            .await;
+        assert!(matches!(
+            res,
+            Err(ProjectServiceError::OwnerNotFound(OwnerType::User))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_project_owner_user_not_match() {
+        let creator_id = ObjectId::new();
+        let owner_id = ObjectId::new();
+        let service = ProjectService {
+            project_repo: MockProjectRepo::default(),
+            user_repo: MockUserRepo {
+                users: Mutex::new(vec![dummy_user(creator_id), dummy_user(owner_id)]),
+            },
+            team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+        let res = service
+            .create(creator_id, owner_id, OwnerType::User, "p5".to_string())
+            .await;
+        assert!(matches!(
+            res,
+            Err(ProjectServiceError::CreatorNotMatchOwner)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_project_team_owner_success() {
+        let creator_id = ObjectId::new();
+        let team_id = ObjectId::new();
+        let user = dummy_user(creator_id);
+        let team = dummy_team(team_id, vec![creator_id]);
+        let service = ProjectService {
+            project_repo: MockProjectRepo::default(),
+            user_repo: MockUserRepo {
+                users: Mutex::new(vec![user.clone()]),
+            },
+            team_repo: MockTeamRepo {
+                teams: Mutex::new(vec![team.clone()]),
+            },
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+        let res = service
+            .create(creator_id, team_id, OwnerType::Team, "p7".to_string())
+            .await;
+        assert!(res.is_ok());
+        let payload = res.unwrap();
+        assert_eq!(payload.owner_id, team_id.to_hex());
+        assert_eq!(payload.owner_type, OwnerType::Team);
+    }
+
+    #[tokio::test]
+    async fn test_create_project_team_owner_not_member() {
+        let creator_id = ObjectId::new();
+        let team_id = ObjectId::new();
+        let user = dummy_user(creator_id);
+        let team = dummy_team(team_id, vec![ObjectId::new()]);
+        let service = ProjectService {
+            project_repo: MockProjectRepo::default(),
+            user_repo: MockUserRepo {
+                users: Mutex::new(vec![user.clone()]),
+            },
+            team_repo: MockTeamRepo {
+                teams: Mutex::new(vec![team.clone()]),
+            },
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+        let res = service
+            .create(creator_id, team_id, OwnerType::Team, "p8".to_string())
+            .await;
+        assert!(matches!(
+            res,
+            Err(ProjectServiceError::CreatorNotMemberOfTeam)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_project_team_owner_not_found() {
+        let creator_id = ObjectId::new();
+        let team_id = ObjectId::new();
+        let user = dummy_user(creator_id);
+        let service = ProjectService {
+            project_repo: MockProjectRepo::default(),
+            user_repo: MockUserRepo {
+                users: Mutex::new(vec![user.clone()]),
+            },
+            team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+        let res = service
+            .create(creator_id, team_id, OwnerType::Team, "p9".to_string())
+            .await;
+        assert!(matches!(
+            res,
+            Err(ProjectServiceError::OwnerNotFound(OwnerType::Team))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_check_access_creator() {
+        let creator_id = ObjectId::new();
+        let owner_id = ObjectId::new();
+        let project_id = ObjectId::new();
+
+        let project = Project {
+            id: project_id,
+            name: "test".to_string(),
+            owner_id,
+            owner_type: OwnerType::User,
+            creator_id,
+            files: vec![],
+            created_at: OffsetDateTime::now_utc(),
+            updated_at: OffsetDateTime::now_utc(),
+            entry: None,
+            pinned_version: None,
+            nodes: None,
+        };
+
+        let service = ProjectService {
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project]),
+            },
+            user_repo: MockUserRepo::default(),
+            team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+
+        let has_access = service.accessible(project_id, creator_id).await.unwrap();
+        assert!(has_access);
+    }
+
+    #[tokio::test]
+    async fn test_check_access_user_owner() {
+        let creator_id = ObjectId::new();
+        let owner_id = ObjectId::new();
+        let project_id = ObjectId::new();
+
+        let project = Project {
+            id: project_id,
+            name: "test".to_string(),
+            owner_id,
+            owner_type: OwnerType::User,
+            creator_id,
+            files: vec![],
+            created_at: OffsetDateTime::now_utc(),
+            updated_at: OffsetDateTime::now_utc(),
+            entry: None,
+            pinned_version: None,
+            nodes: None,
+        };
+
+        let service = ProjectService {
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project]),
+            },
+            user_repo: MockUserRepo::default(),
+            team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+
+        let has_access = service.accessible(project_id, owner_id).await.unwrap();
+        assert!(has_access);
+    }
+
+    #[tokio::test]
+    async fn test_check_access_team_member() {
+        let creator_id = ObjectId::new();
+        let team_id = ObjectId::new();
+        let member_id = ObjectId::new();
+        let project_id = ObjectId::new();
+
+        let project = Project {
+            id: project_id,
+            name: "test".to_string(),
+            owner_id: team_id,
+            owner_type: OwnerType::Team,
+            creator_id,
+            files: vec![],
+            created_at: OffsetDateTime::now_utc(),
+            updated_at: OffsetDateTime::now_utc(),
+            entry: None,
+            pinned_version: None,
+            nodes: None,
+        };
+
+        let team = dummy_team(team_id, vec![creator_id, member_id]);
+
+        let service = ProjectService {
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project]),
+            },
+            user_repo: MockUserRepo::default(),
+            team_repo: MockTeamRepo {
+                teams: Mutex::new(vec![team]),
+            },
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+
+        let has_access = service.accessible(project_id, member_id).await.unwrap();
+        assert!(has_access);
+    }
+
+    #[tokio::test]
+    async fn test_check_access_denied_not_member() {
+        let creator_id = ObjectId::new();
+        let team_id = ObjectId::new();
+        let other_user_id = ObjectId::new();
+        let project_id = ObjectId::new();
+
+        let project = Project {
+            id: project_id,
+            name: "test".to_string(),
+            owner_id: team_id,
+            owner_type: OwnerType::Team,
+            creator_id,
+            files: vec![],
+            created_at: OffsetDateTime::now_utc(),
+            updated_at: OffsetDateTime::now_utc(),
+            entry: None,
+            pinned_version: None,
+            nodes: None,
+        };
+
+        let team = dummy_team(team_id, vec![creator_id]);
+
+        let service = ProjectService {
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project]),
+            },
+            user_repo: MockUserRepo::default(),
+            team_repo: MockTeamRepo {
+                teams: Mutex::new(vec![team]),
+            },
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+
+        let has_access = service.accessible(project_id, other_user_id).await.unwrap();
+        assert!(!has_access);
+    }
+
+    #[tokio::test]
+    async fn test_check_access_denied_different_user() {
+        let creator_id = ObjectId::new();
+        let owner_id = ObjectId::new();
+        let other_user_id = ObjectId::new();
+        let project_id = ObjectId::new();
+
+        let project = Project {
+            id: project_id,
+            name: "test".to_string(),
+            owner_id,
+            owner_type: OwnerType::User,
+            creator_id,
+            files: vec![],
+            created_at: OffsetDateTime::now_utc(),
+            updated_at: OffsetDateTime::now_utc(),
+            entry: None,
+            pinned_version: None,
+            nodes: None,
+        };
+
+        let service = ProjectService {
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project]),
+            },
+            user_repo: MockUserRepo::default(),
+            team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+
+        let has_access = service.accessible(project_id, other_user_id).await.unwrap();
+        assert!(!has_access);
     }
 
-    fn dummy_team(id: ObjectId, member_ids: Vec<ObjectId>) -> Team {
-        Team {
-            id,
-            name: format!("team_{}", id),
-            avatar_uri: None,
-            creator_id: member_ids[0],
-            member_ids,
+    fn project_with_file(project_id: ObjectId, owner_id: ObjectId, file_id: ObjectId) -> Project {
+        Project {
+            id: project_id,
+            name: "test".to_string(),
+            owner_id,
+            owner_type: OwnerType::User,
+            creator_id: owner_id,
+            files: vec![ProjectFile {
+                id: file_id,
+                path: "main.typ".to_string(),
+                content: FileContent::Text {
+                    text: "old".to_string(),
+                },
+                size: 3,
+                version: 1,
+                updated_at: OffsetDateTime::now_utc(),
+            }],
             created_at: OffsetDateTime::now_utc(),
             updated_at: OffsetDateTime::now_utc(),
+            entry: Some(file_id),
+            pinned_version: None,
+            nodes: None,
         }
     }
 
     #[tokio::test]
-    async fn test_create_project_invalid_creator() {
+    async fn test_update_file_success() {
+        let owner_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let file_id = ObjectId::new();
         let service = ProjectService {
-            project_repo: MockProjectRepo::default(),
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
+            },
             user_repo: MockUserRepo::default(),
             team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
         };
-        let creator_id = ObjectId::new();
-        let owner_id = creator_id;
+
+        let payload = service
+            .update_file(
+                project_id,
+                owner_id,
+                file_id,
+                "new body".to_string(),
+                None,
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(payload.id, file_id.to_hex());
+        assert_eq!(payload.version, 2);
+    }
+
+    #[tokio::test]
+    async fn test_update_file_access_denied() {
+        let owner_id = ObjectId::new();
+        let other_user_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let file_id = ObjectId::new();
+        let service = ProjectService {
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
+            },
+            user_repo: MockUserRepo::default(),
+            team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+
         let res = service
-            .create(creator_id, owner_id, OwnerType::User, "p1".to_string())
+            .update_file(
+                project_id,
+                other_user_id,
+                file_id,
+                "x".to_string(),
+                None,
+                &ProjectServer::detached(),
+            )
             .await;
-        assert!(matches!(res, Err(ProjectServiceError::UserNotFound)));
+        assert!(matches!(res, Err(ProjectServiceError::AccessDenied)));
     }
 
     #[tokio::test]
-    async fn test_create_project_user_owner_success() {
-        let creator_id = ObjectId::new();
-        let user = dummy_user(creator_id);
+    async fn test_update_file_not_found() {
+        let owner_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let file_id = ObjectId::new();
         let service = ProjectService {
-            project_repo: MockProjectRepo::default(),
-            user_repo: MockUserRepo {
-                users: Mutex::new(vec![user.clone()]),
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
             },
+            user_repo: MockUserRepo::default(),
             team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
         };
+
+        // Access passes (owner) but the file id does not exist.
         let res = service
-            .create(creator_id, creator_id, OwnerType::User, "p3".to_string())
+            .update_file(
+                project_id,
+                owner_id,
+                ObjectId::new(),
+                "x".to_string(),
+                None,
+                &ProjectServer::detached(),
+            )
             .await;
-        assert!(res.is_ok());
-        let payload = res.unwrap();
-        assert_eq!(payload.owner_id, creator_id.to_hex());
-        assert_eq!(payload.owner_type, OwnerType::User);
+        assert!(matches!(res, Err(ProjectServiceError::FileNotFound)));
     }
 
     #[tokio::test]
-    async fn test_create_project_owner_user_not_found() {
-        let creator_id = ObjectId::new();
-        let user = dummy_user(creator_id);
+    async fn test_update_file_expected_version() {
+        let owner_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let file_id = ObjectId::new();
         let service = ProjectService {
-            project_repo: MockProjectRepo::default(),
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
+            },
+            user_repo: MockUserRepo::default(),
+            team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+
+        // Two tabs loaded version 1; the second save is refused, not applied.
+        let payload = service
+            .update_file(
+                project_id,
+                owner_id,
+                file_id,
+                "a".to_string(),
+                Some(1),
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(payload.version, 2);
+        let res = service
+            .update_file(
+                project_id,
+                owner_id,
+                file_id,
+                "b".to_string(),
+                Some(1),
+                &ProjectServer::detached(),
+            )
+            .await;
+        assert!(matches!(res, Err(ProjectServiceError::VersionConflict(2))));
+        let project = service.find_by_id(project_id).await.unwrap();
+        assert_eq!(project.files[0].version, 2);
+
+        let res = service
+            .update_file(
+                project_id,
+                owner_id,
+                ObjectId::new(),
+                "x".to_string(),
+                Some(1),
+                &ProjectServer::detached(),
+            )
+            .await;
+        assert!(matches!(res, Err(ProjectServiceError::FileNotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_update_project_rename_success() {
+        let owner_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let file_id = ObjectId::new();
+        let service = ProjectService {
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
+            },
             user_repo: MockUserRepo {
-                users: Mutex::new(vec![user.clone()]),
+                users: Mutex::new(vec![dummy_user(owner_id)]),
             },
             team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+
+        let payload = service
+            .update(
+                project_id,
+                owner_id,
+                "renamed".to_string(),
+                owner_id,
+                OwnerType::User,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(payload.name, "renamed");
+        assert_eq!(payload.owner_id, owner_id.to_hex());
+        assert_eq!(payload.owner_type, OwnerType::User);
+    }
+
+    #[tokio::test]
+    async fn test_update_project_move_to_team() {
+        let owner_id = ObjectId::new();
+        let team_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let file_id = ObjectId::new();
+        let service = ProjectService {
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
+            },
+            user_repo: MockUserRepo {
+                users: Mutex::new(vec![dummy_user(owner_id)]),
+            },
+            team_repo: MockTeamRepo {
+                teams: Mutex::new(vec![dummy_team(team_id, vec![owner_id])]),
+            },
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
         };
+
+        let payload = service
+            .update(
+                project_id,
+                owner_id,
+                "moved".to_string(),
+                team_id,
+                OwnerType::Team,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(payload.owner_id, team_id.to_hex());
+        assert_eq!(payload.owner_type, OwnerType::Team);
+    }
+
+    #[tokio::test]
+    async fn test_update_project_access_denied() {
         let owner_id = ObjectId::new();
+        let other_user_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let file_id = ObjectId::new();
+        let service = ProjectService {
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
+            },
+            user_repo: MockUserRepo::default(),
+            team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+
         let res = service
-            .create(creator_id, owner_id, OwnerType::User, "p4".to_string())
+            .update(
+                project_id,
+                other_user_id,
+                "x".to_string(),
+                other_user_id,
+                OwnerType::User,
+            )
             .await;
-        assert!(matches!(
-            res,
-            Err(ProjectServiceError::OwnerNotFound(OwnerType::User))
-        ));
+        assert!(matches!(res, Err(ProjectServiceError::AccessDenied)));
     }
 
     #[tokio::test]
-    async fn test_create_project_owner_user_not_match() {
-        let creator_id = ObjectId::new();
+    async fn test_update_project_not_found() {
+        let service = ProjectService {
+            project_repo: MockProjectRepo::default(),
+            user_repo: MockUserRepo::default(),
+            team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+
+        let user_id = ObjectId::new();
+        let res = service
+            .update(
+                ObjectId::new(),
+                user_id,
+                "x".to_string(),
+                user_id,
+                OwnerType::User,
+            )
+            .await;
+        assert!(matches!(res, Err(ProjectServiceError::ProjectNotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_update_project_target_user_not_self() {
         let owner_id = ObjectId::new();
+        let other_user_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let file_id = ObjectId::new();
         let service = ProjectService {
-            project_repo: MockProjectRepo::default(),
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
+            },
             user_repo: MockUserRepo {
-                users: Mutex::new(vec![dummy_user(creator_id), dummy_user(owner_id)]),
+                users: Mutex::new(vec![dummy_user(owner_id), dummy_user(other_user_id)]),
             },
             team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
         };
+
+        // The owner tries to hand the project to another user directly.
         let res = service
-            .create(creator_id, owner_id, OwnerType::User, "p5".to_string())
+            .update(
+                project_id,
+                owner_id,
+                "x".to_string(),
+                other_user_id,
+                OwnerType::User,
+            )
             .await;
         assert!(matches!(
             res,
@@ -416,46 +2188,35 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_create_project_team_owner_success() {
-        let creator_id = ObjectId::new();
+    async fn test_update_project_target_team_not_member() {
+        let owner_id = ObjectId::new();
         let team_id = ObjectId::new();
-        let user = dummy_user(creator_id);
-        let team = dummy_team(team_id, vec![creator_id]);
+        let project_id = ObjectId::new();
+        let file_id = ObjectId::new();
         let service = ProjectService {
-            project_repo: MockProjectRepo::default(),
-            user_repo: MockUserRepo {
-                users: Mutex::new(vec![user.clone()]),
-            },
-            team_repo: MockTeamRepo {
-                teams: Mutex::new(vec![team.clone()]),
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
             },
-        };
-        let res = service
-            .create(creator_id, team_id, OwnerType::Team, "p7".to_string())
-            .await;
-        assert!(res.is_ok());
-        let payload = res.unwrap();
-        assert_eq!(payload.owner_id, team_id.to_hex());
-        assert_eq!(payload.owner_type, OwnerType::Team);
-    }
-
-    #[tokio::test]
-    async fn test_create_project_team_owner_not_member() {
-        let creator_id = ObjectId::new();
-        let team_id = ObjectId::new();
-        let user = dummy_user(creator_id);
-        let team = dummy_team(team_id, vec![ObjectId::new()]);
-        let service = ProjectService {
-            project_repo: MockProjectRepo::default(),
             user_repo: MockUserRepo {
-                users: Mutex::new(vec![user.clone()]),
+                users: Mutex::new(vec![dummy_user(owner_id)]),
             },
             team_repo: MockTeamRepo {
-                teams: Mutex::new(vec![team.clone()]),
+                teams: Mutex::new(vec![dummy_team(team_id, vec![ObjectId::new()])]),
             },
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
         };
+
         let res = service
-            .create(creator_id, team_id, OwnerType::Team, "p8".to_string())
+            .update(
+                project_id,
+                owner_id,
+                "x".to_string(),
+                team_id,
+                OwnerType::Team,
+            )
             .await;
         assert!(matches!(
             res,
@@ -464,19 +2225,30 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_create_project_team_owner_not_found() {
-        let creator_id = ObjectId::new();
-        let team_id = ObjectId::new();
-        let user = dummy_user(creator_id);
+    async fn test_update_project_target_owner_not_found() {
+        let owner_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let file_id = ObjectId::new();
         let service = ProjectService {
-            project_repo: MockProjectRepo::default(),
-            user_repo: MockUserRepo {
-                users: Mutex::new(vec![user.clone()]),
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
             },
+            user_repo: MockUserRepo::default(),
             team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
         };
+
         let res = service
-            .create(creator_id, team_id, OwnerType::Team, "p9".to_string())
+            .update(
+                project_id,
+                owner_id,
+                "x".to_string(),
+                ObjectId::new(),
+                OwnerType::Team,
+            )
             .await;
         assert!(matches!(
             res,
@@ -485,124 +2257,59 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_check_access_creator() {
+    async fn test_duplicate_project_success() {
         let creator_id = ObjectId::new();
-        let owner_id = ObjectId::new();
         let project_id = ObjectId::new();
-
-        let project = Project {
-            id: project_id,
-            name: "test".to_string(),
-            owner_id,
-            owner_type: OwnerType::User,
-            creator_id,
-            files: vec![],
-            created_at: OffsetDateTime::now_utc(),
-            updated_at: OffsetDateTime::now_utc(),
-            entry: None,
-            pinned_version: None,
-        };
-
+        let file_id = ObjectId::new();
         let service = ProjectService {
             project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project]),
+                projects: Mutex::new(vec![project_with_file(project_id, creator_id, file_id)]),
             },
             user_repo: MockUserRepo::default(),
             team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
         };
 
-        let has_access = service.accessible(project_id, creator_id).await.unwrap();
-        assert!(has_access);
-    }
-
-    #[tokio::test]
-    async fn test_check_access_user_owner() {
-        let creator_id = ObjectId::new();
-        let owner_id = ObjectId::new();
-        let project_id = ObjectId::new();
-
-        let project = Project {
-            id: project_id,
-            name: "test".to_string(),
-            owner_id,
-            owner_type: OwnerType::User,
-            creator_id,
-            files: vec![],
-            created_at: OffsetDateTime::now_utc(),
-            updated_at: OffsetDateTime::now_utc(),
-            entry: None,
-            pinned_version: None,
-        };
+        let payload = service.duplicate(project_id, creator_id).await.unwrap();
 
-        let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project]),
-            },
-            user_repo: MockUserRepo::default(),
-            team_repo: MockTeamRepo::default(),
-        };
+        assert_eq!(payload.name, "test copy");
+        assert_eq!(payload.owner_id, creator_id.to_hex());
+        assert_eq!(payload.creator_id, creator_id.to_hex());
+        assert_ne!(payload.id, project_id.to_hex());
+        assert_eq!(payload.files.len(), 1);
 
-        let has_access = service.accessible(project_id, owner_id).await.unwrap();
-        assert!(has_access);
+        // The duplicated file gets a fresh id, distinct from the source
+        // file's, and `entry` is remapped to point at the new one.
+        let new_file_id = payload.files[0].id.clone();
+        assert_ne!(new_file_id, file_id.to_hex());
+        assert_eq!(payload.entry, Some(new_file_id));
     }
 
     #[tokio::test]
-    async fn test_check_access_team_member() {
-        let creator_id = ObjectId::new();
+    async fn test_duplicate_project_team_owned_sets_requester_as_creator() {
+        let original_creator_id = ObjectId::new();
         let team_id = ObjectId::new();
         let member_id = ObjectId::new();
         let project_id = ObjectId::new();
 
         let project = Project {
             id: project_id,
-            name: "test".to_string(),
-            owner_id: team_id,
-            owner_type: OwnerType::Team,
-            creator_id,
-            files: vec![],
-            created_at: OffsetDateTime::now_utc(),
-            updated_at: OffsetDateTime::now_utc(),
-            entry: None,
-            pinned_version: None,
-        };
-
-        let team = dummy_team(team_id, vec![creator_id, member_id]);
-
-        let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project]),
-            },
-            user_repo: MockUserRepo::default(),
-            team_repo: MockTeamRepo {
-                teams: Mutex::new(vec![team]),
-            },
-        };
-
-        let has_access = service.accessible(project_id, member_id).await.unwrap();
-        assert!(has_access);
-    }
-
-    #[tokio::test]
-    async fn test_check_access_denied_not_member() {
-        let creator_id = ObjectId::new();
-        let team_id = ObjectId::new();
-        let other_user_id = ObjectId::new();
-        let project_id = ObjectId::new();
-
-        let project = Project {
-            id: project_id,
-            name: "test".to_string(),
+            name: "team project".to_string(),
             owner_id: team_id,
             owner_type: OwnerType::Team,
-            creator_id,
+            creator_id: original_creator_id,
             files: vec![],
             created_at: OffsetDateTime::now_utc(),
             updated_at: OffsetDateTime::now_utc(),
             entry: None,
             pinned_version: None,
+            nodes: None,
         };
 
-        let team = dummy_team(team_id, vec![creator_id]);
+        let team = dummy_team(team_id, vec![original_creator_id, member_id]);
 
         let service = ProjectService {
             project_repo: MockProjectRepo {
@@ -612,71 +2319,42 @@ mod tests {
             team_repo: MockTeamRepo {
                 teams: Mutex::new(vec![team]),
             },
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
         };
 
-        let has_access = service.accessible(project_id, other_user_id).await.unwrap();
-        assert!(!has_access);
+        // A team member other than the original creator duplicates the
+        // project: ownership stays with the team, but the duplicate's creator
+        // is the requester, not the original creator.
+        let payload = service.duplicate(project_id, member_id).await.unwrap();
+        assert_eq!(payload.owner_id, team_id.to_hex());
+        assert_eq!(payload.owner_type, OwnerType::Team);
+        assert_eq!(payload.creator_id, member_id.to_hex());
+        assert_ne!(payload.creator_id, original_creator_id.to_hex());
     }
 
     #[tokio::test]
-    async fn test_check_access_denied_different_user() {
-        let creator_id = ObjectId::new();
-        let owner_id = ObjectId::new();
-        let other_user_id = ObjectId::new();
-        let project_id = ObjectId::new();
-
-        let project = Project {
-            id: project_id,
-            name: "test".to_string(),
-            owner_id,
-            owner_type: OwnerType::User,
-            creator_id,
-            files: vec![],
-            created_at: OffsetDateTime::now_utc(),
-            updated_at: OffsetDateTime::now_utc(),
-            entry: None,
-            pinned_version: None,
-        };
-
+    async fn test_duplicate_project_not_found() {
         let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project]),
-            },
+            project_repo: MockProjectRepo::default(),
             user_repo: MockUserRepo::default(),
             team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
         };
 
-        let has_access = service.accessible(project_id, other_user_id).await.unwrap();
-        assert!(!has_access);
-    }
-
-    fn project_with_file(project_id: ObjectId, owner_id: ObjectId, file_id: ObjectId) -> Project {
-        Project {
-            id: project_id,
-            name: "test".to_string(),
-            owner_id,
-            owner_type: OwnerType::User,
-            creator_id: owner_id,
-            files: vec![ProjectFile {
-                id: file_id,
-                path: "main.typ".to_string(),
-                content: FileContent::Text {
-                    text: "old".to_string(),
-                },
-                size: 3,
-                version: 1,
-                updated_at: OffsetDateTime::now_utc(),
-            }],
-            created_at: OffsetDateTime::now_utc(),
-            updated_at: OffsetDateTime::now_utc(),
-            entry: Some(file_id),
-            pinned_version: None,
-        }
+        let res = service.duplicate(ObjectId::new(), ObjectId::new()).await;
+        assert!(matches!(res, Err(ProjectServiceError::ProjectNotFound)));
     }
 
     #[tokio::test]
-    async fn test_update_file_success() {
+    async fn test_duplicate_project_access_denied() {
         let owner_id = ObjectId::new();
+        let other_user_id = ObjectId::new();
         let project_id = ObjectId::new();
         let file_id = ObjectId::new();
         let service = ProjectService {
@@ -685,358 +2363,1026 @@ mod tests {
             },
             user_repo: MockUserRepo::default(),
             team_repo: MockTeamRepo::default(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
         };
 
-        let payload = service
-            .update_file(project_id, owner_id, file_id, "new body".to_string())
+        let res = service.duplicate(project_id, other_user_id).await;
+        assert!(matches!(res, Err(ProjectServiceError::AccessDenied)));
+    }
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    /// A service over one project (`main.typ` owned by `owner_id`) with an
+    /// in-memory object store.
+    fn upload_service(
+        project_id: ObjectId,
+        owner_id: ObjectId,
+    ) -> (
+        ProjectService<MockProjectRepo, MockUserRepo, MockTeamRepo>,
+        Arc<InMemoryObjectStore>,
+    ) {
+        let store = Arc::new(InMemoryObjectStore::new());
+        let service = ProjectService {
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(vec![project_with_file(
+                    project_id,
+                    owner_id,
+                    ObjectId::new(),
+                )]),
+            },
+            user_repo: MockUserRepo::default(),
+            team_repo: MockTeamRepo::default(),
+            store: Some(store.clone()),
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
+        };
+        (service, store)
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_creates_then_replaces_binary_file() {
+        let owner_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let (service, store) = upload_service(project_id, owner_id);
+
+        let created = service
+            .upload_file(
+                project_id,
+                owner_id,
+                "img/logo.png".to_string(),
+                PNG.to_vec(),
+                &ProjectServer::detached(),
+            )
             .await
             .unwrap();
+        assert_eq!(created.path, "img/logo.png");
+        assert!(matches!(created.kind, FileKind::Binary));
+        assert_eq!(created.size, PNG.len() as i64);
+        assert_eq!(created.version, 0);
 
-        assert_eq!(payload.id, file_id.to_hex());
-        assert_eq!(payload.version, 2);
+        let project = service
+            .project_repo
+            .find_by_id(project_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let file = project
+            .files
+            .iter()
+            .find(|f| f.path == "img/logo.png")
+            .unwrap();
+        let FileContent::Binary { storage_key } = &file.content else {
+            panic!("expected binary content");
+        };
+        assert_eq!(store.get(storage_key).await.unwrap(), PNG);
+
+        // Same path again: new bytes for the same file, not a second file.
+        let mut bigger = PNG.to_vec();
+        bigger.extend_from_slice(b"more");
+        let replaced = service
+            .upload_file(
+                project_id,
+                owner_id,
+                "img/logo.png".to_string(),
+                bigger,
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(replaced.id, created.id);
+        assert_eq!(replaced.version, 1);
+        assert_eq!(store.len(), 2);
     }
 
     #[tokio::test]
-    async fn test_update_file_access_denied() {
+    async fn test_upload_file_rejects_before_writing_the_blob() {
         let owner_id = ObjectId::new();
-        let other_user_id = ObjectId::new();
         let project_id = ObjectId::new();
-        let file_id = ObjectId::new();
-        let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
-            },
-            user_repo: MockUserRepo::default(),
-            team_repo: MockTeamRepo::default(),
+        let (service, store) = upload_service(project_id, owner_id);
+        let live = ProjectServer::detached();
+        let upload = |path: &str, bytes: &[u8]| {
+            service.upload_file(
+                project_id,
+                owner_id,
+                path.to_string(),
+                bytes.to_vec(),
+                &live,
+            )
         };
 
-        let res = service
-            .update_file(project_id, other_user_id, file_id, "x".to_string())
-            .await;
-        assert!(matches!(res, Err(ProjectServiceError::AccessDenied)));
+        for path in ["../logo.png", "img//logo.png", " logo.png", ""] {
+            assert!(matches!(
+                upload(path, PNG).await,
+                Err(ProjectServiceError::InvalidPath(_))
+            ));
+        }
+        // A text file is there, or the path runs through a file.
+        for path in ["main.typ", "main.typ/logo.png"] {
+            assert!(matches!(
+                upload(path, PNG).await,
+                Err(ProjectServiceError::PathConflict(_))
+            ));
+        }
+        assert!(matches!(
+            upload("logo.png", b"<html></html>").await,
+            Err(ProjectServiceError::UnsupportedMediaType(_))
+        ));
+        assert!(matches!(
+            upload("logo.jpg", PNG).await,
+            Err(ProjectServiceError::UnsupportedMediaType(_))
+        ));
+        assert!(matches!(
+            service
+                .upload_file(
+                    project_id,
+                    ObjectId::new(),
+                    "logo.png".to_string(),
+                    PNG.to_vec(),
+                    &ProjectServer::detached()
+                )
+                .await,
+            Err(ProjectServiceError::AccessDenied)
+        ));
+        assert!(store.is_empty());
+    }
+
+    /// The id of the file at `path` in the service's project.
+    async fn file_id_at(
+        service: &ProjectService<MockProjectRepo, MockUserRepo, MockTeamRepo>,
+        project_id: ObjectId,
+        path: &str,
+    ) -> ObjectId {
+        let project = service
+            .project_repo
+            .find_by_id(project_id)
+            .await
+            .unwrap()
+            .unwrap();
+        project.files.iter().find(|f| f.path == path).unwrap().id
     }
 
     #[tokio::test]
-    async fn test_update_file_not_found() {
+    async fn test_create_file_enforces_tree_rules() {
         let owner_id = ObjectId::new();
         let project_id = ObjectId::new();
-        let file_id = ObjectId::new();
-        let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
-            },
-            user_repo: MockUserRepo::default(),
-            team_repo: MockTeamRepo::default(),
+        let (service, _) = upload_service(project_id, owner_id);
+        let live = ProjectServer::detached();
+        let create = |path: &str| {
+            service.create_file(
+                project_id,
+                owner_id,
+                path.to_string(),
+                "= Hi".to_string(),
+                &live,
+            )
         };
 
-        // Access passes (owner) but the file id does not exist.
-        let res = service
-            .update_file(project_id, owner_id, ObjectId::new(), "x".to_string())
-            .await;
-        assert!(matches!(res, Err(ProjectServiceError::ProjectNotFound)));
+        let created = create("chapters/intro.typ").await.unwrap();
+        assert_eq!(created.path, "chapters/intro.typ");
+        assert!(matches!(created.kind, FileKind::Text));
+        assert_eq!(created.size, 4);
+
+        for path in [
+            "main.typ",
+            "chapters/intro.typ",
+            "main.typ/x.typ",
+            "chapters",
+        ] {
+            assert!(
+                matches!(
+                    create(path).await,
+                    Err(ProjectServiceError::PathConflict(_))
+                ),
+                "{path}"
+            );
+        }
+        for path in ["../x.typ", "a//b.typ", "x.typ ", ""] {
+            assert!(
+                matches!(create(path).await, Err(ProjectServiceError::InvalidPath(_))),
+                "{path}"
+            );
+        }
+        let too_deep = vec!["d"; MAX_DEPTH + 1].join("/");
+        assert!(matches!(
+            create(&too_deep).await,
+            Err(ProjectServiceError::InvalidPath(_))
+        ));
+        assert!(matches!(
+            create("logo.png").await,
+            Err(ProjectServiceError::UnsupportedMediaType(_))
+        ));
+        assert!(matches!(
+            service
+                .create_file(
+                    project_id,
+                    ObjectId::new(),
+                    "x.typ".to_string(),
+                    String::new(),
+                    &ProjectServer::detached()
+                )
+                .await,
+            Err(ProjectServiceError::AccessDenied)
+        ));
     }
 
     #[tokio::test]
-    async fn test_update_project_rename_success() {
+    async fn test_move_file_renames_and_moves() {
         let owner_id = ObjectId::new();
         let project_id = ObjectId::new();
-        let file_id = ObjectId::new();
-        let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
-            },
-            user_repo: MockUserRepo {
-                users: Mutex::new(vec![dummy_user(owner_id)]),
-            },
-            team_repo: MockTeamRepo::default(),
+        let (service, _) = upload_service(project_id, owner_id);
+        let main_id = file_id_at(&service, project_id, "main.typ").await;
+        let logo = service
+            .upload_file(
+                project_id,
+                owner_id,
+                "logo.png".to_string(),
+                PNG.to_vec(),
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
+        let logo_id = ObjectId::parse_str(&logo.id).unwrap();
+        let live = ProjectServer::detached();
+        let move_to = |file_id: ObjectId, path: &str| {
+            service.move_file(project_id, owner_id, file_id, path.to_string(), &live)
         };
 
-        let payload = service
-            .update(
+        let moved = move_to(main_id, "src/main.typ").await.unwrap();
+        assert_eq!(moved.id, main_id.to_hex());
+        assert_eq!(moved.path, "src/main.typ");
+        assert_eq!(moved.version, 1);
+        // The entry follows the file by id.
+        let project = service
+            .project_repo
+            .find_by_id(project_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(project.entry, Some(main_id));
+
+        assert!(matches!(
+            move_to(main_id, "logo.png").await,
+            Err(ProjectServiceError::PathConflict(_))
+        ));
+        assert!(matches!(
+            move_to(logo_id, "src/main.typ/logo.png").await,
+            Err(ProjectServiceError::PathConflict(_))
+        ));
+        assert!(matches!(
+            move_to(main_id, "src/../main.typ").await,
+            Err(ProjectServiceError::InvalidPath(_))
+        ));
+        // An asset keeps its type.
+        assert!(matches!(
+            move_to(logo_id, "logo.jpg").await,
+            Err(ProjectServiceError::UnsupportedMediaType(_))
+        ));
+        assert_eq!(
+            move_to(logo_id, "img/Logo.PNG").await.unwrap().path,
+            "img/Logo.PNG"
+        );
+        assert!(matches!(
+            move_to(ObjectId::new(), "x.typ").await,
+            Err(ProjectServiceError::FileNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_delete_file_requires_a_new_entry_for_the_entry_file() {
+        let owner_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let (service, _) = upload_service(project_id, owner_id);
+        let main_id = file_id_at(&service, project_id, "main.typ").await;
+        let other = service
+            .create_file(
                 project_id,
                 owner_id,
-                "renamed".to_string(),
+                "other.typ".to_string(),
+                String::new(),
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
+        let other_id = ObjectId::parse_str(&other.id).unwrap();
+        let logo = service
+            .upload_file(
+                project_id,
                 owner_id,
-                OwnerType::User,
+                "logo.png".to_string(),
+                PNG.to_vec(),
+                &ProjectServer::detached(),
             )
             .await
             .unwrap();
+        let logo_id = ObjectId::parse_str(&logo.id).unwrap();
+        let live = ProjectServer::detached();
+        let delete = |file_id: ObjectId, entry: Option<ObjectId>| {
+            service.delete_file(project_id, owner_id, file_id, entry, &live)
+        };
 
-        assert_eq!(payload.name, "renamed");
-        assert_eq!(payload.owner_id, owner_id.to_hex());
-        assert_eq!(payload.owner_type, OwnerType::User);
+        assert!(matches!(
+            delete(main_id, None).await,
+            Err(ProjectServiceError::EntryRequired)
+        ));
+        for entry in [main_id, logo_id, ObjectId::new()] {
+            assert!(matches!(
+                delete(main_id, Some(entry)).await,
+                Err(ProjectServiceError::InvalidEntry(_))
+            ));
+        }
+
+        delete(main_id, Some(other_id)).await.unwrap();
+        delete(logo_id, None).await.unwrap();
+        let project = service
+            .project_repo
+            .find_by_id(project_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(project.entry, Some(other_id));
+        assert_eq!(project.files.len(), 1);
+
+        assert!(matches!(
+            delete(main_id, None).await,
+            Err(ProjectServiceError::FileNotFound)
+        ));
+        assert!(matches!(
+            service
+                .delete_file(
+                    project_id,
+                    ObjectId::new(),
+                    other_id,
+                    None,
+                    &ProjectServer::detached()
+                )
+                .await,
+            Err(ProjectServiceError::AccessDenied)
+        ));
     }
 
     #[tokio::test]
-    async fn test_update_project_move_to_team() {
+    async fn test_file_writes_keep_the_node_projection_in_step() {
         let owner_id = ObjectId::new();
-        let team_id = ObjectId::new();
         let project_id = ObjectId::new();
-        let file_id = ObjectId::new();
-        let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
-            },
-            user_repo: MockUserRepo {
-                users: Mutex::new(vec![dummy_user(owner_id)]),
-            },
-            team_repo: MockTeamRepo {
-                teams: Mutex::new(vec![dummy_team(team_id, vec![owner_id])]),
-            },
+        let (service, store) = upload_service(project_id, owner_id);
+        {
+            let mut projects = service.project_repo.projects.lock().unwrap();
+            let nodes = tree::project_nodes(&projects[0].files, &[]).unwrap();
+            projects[0].nodes = Some(nodes);
+        }
+        let paths = || async {
+            let project = service
+                .project_repo
+                .find_by_id(project_id)
+                .await
+                .unwrap()
+                .unwrap();
+            project
+                .nodes
+                .unwrap()
+                .into_iter()
+                .map(|n| n.path)
+                .collect::<Vec<_>>()
         };
 
-        let payload = service
-            .update(
+        let created = service
+            .create_file(
                 project_id,
                 owner_id,
-                "moved".to_string(),
-                team_id,
-                OwnerType::Team,
+                "chapters/intro.typ".to_string(),
+                "= Intro".to_string(),
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            paths().await,
+            ["chapters", "chapters/intro.typ", "main.typ"]
+        );
+        assert!(store.exists(&sha256_hex(b"= Intro")).await.unwrap());
+
+        let intro_id = ObjectId::parse_str(&created.id).unwrap();
+        service
+            .move_file(
+                project_id,
+                owner_id,
+                intro_id,
+                "intro.typ".to_string(),
+                &ProjectServer::detached(),
             )
             .await
             .unwrap();
+        assert_eq!(paths().await, ["intro.typ", "main.typ"]);
 
-        assert_eq!(payload.owner_id, team_id.to_hex());
-        assert_eq!(payload.owner_type, OwnerType::Team);
+        service
+            .delete_file(
+                project_id,
+                owner_id,
+                intro_id,
+                None,
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(paths().await, ["main.typ"]);
     }
 
     #[tokio::test]
-    async fn test_update_project_access_denied() {
+    async fn test_compile_pdf_builds_the_entry_with_stored_assets() {
         let owner_id = ObjectId::new();
-        let other_user_id = ObjectId::new();
         let project_id = ObjectId::new();
-        let file_id = ObjectId::new();
-        let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
-            },
-            user_repo: MockUserRepo::default(),
-            team_repo: MockTeamRepo::default(),
+        let (service, _) = upload_service(project_id, owner_id);
+        let entry = service.project_repo.projects.lock().unwrap()[0].files[0].id;
+
+        let pdf = service.compile_pdf(project_id, owner_id).await.unwrap();
+        assert!(pdf.starts_with(b"%PDF-"));
+
+        // The asset is fetched from the store and handed to Typst, which
+        // rejects the truncated PNG as an image.
+        service
+            .upload_file(
+                project_id,
+                owner_id,
+                "logo.png".to_string(),
+                PNG.to_vec(),
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
+        service
+            .update_file(
+                project_id,
+                owner_id,
+                entry,
+                "#image(\"logo.png\")".to_string(),
+                None,
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            service.compile_pdf(project_id, owner_id).await,
+            Err(ProjectServiceError::Compile(CompileError::Failed(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_outline_prefers_live_text() {
+        let owner_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let (service, _) = upload_service(project_id, owner_id);
+        let entry = service.project_repo.projects.lock().unwrap()[0].files[0].id;
+        service
+            .update_file(
+                project_id,
+                owner_id,
+                entry,
+                "= Stored".to_string(),
+                None,
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
+        let bib = service
+            .create_file(
+                project_id,
+                owner_id,
+                "refs.bib".to_string(),
+                "@misc{knuth, title = {TAOCP}}".to_string(),
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
+
+        let outline = service
+            .outline(project_id, owner_id, HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(outline.headings[0].text, "Stored");
+        assert_eq!(outline.citations[0].key, "knuth");
+        assert_eq!(outline.citations[0].file_id.to_hex(), bib.id);
+
+        let live = HashMap::from([(entry, "= Live <live>".to_string())]);
+        let outline = service.outline(project_id, owner_id, live).await.unwrap();
+        assert_eq!(outline.headings[0].text, "Live");
+        assert_eq!(outline.labels[0].name, "live");
+
+        let res = service
+            .outline(project_id, ObjectId::new(), HashMap::new())
+            .await;
+        assert!(matches!(res, Err(ProjectServiceError::AccessDenied)));
+    }
+
+    #[tokio::test]
+    async fn test_export_archives_the_tree_with_live_text_pdf_and_manifest() {
+        use futures_util::TryStreamExt as _;
+        use std::io::Read as _;
+
+        let owner_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let (service, _) = upload_service(project_id, owner_id);
+        service
+            .create_file(
+                project_id,
+                owner_id,
+                "chapters/intro.typ".to_string(),
+                "= Stored".to_string(),
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
+        let intro = file_id_at(&service, project_id, "chapters/intro.typ").await;
+        service
+            .upload_file(
+                project_id,
+                owner_id,
+                "logo.png".to_string(),
+                PNG.to_vec(),
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
+        {
+            // The tree names the file's folder differently from its stored
+            // path: the tree wins.
+            let mut projects = service.project_repo.projects.lock().unwrap();
+            let mut nodes = tree::project_nodes(&projects[0].files, &[]).unwrap();
+            for n in &mut nodes {
+                if n.path == "chapters" {
+                    n.node.name = "parts".to_string();
+                }
+            }
+            projects[0].nodes = Some(nodes);
+        }
+
+        let live = HashMap::from([(intro, "= Live".to_string())]);
+        let export = service
+            .export(project_id, owner_id, live, true, true)
+            .await
+            .unwrap();
+        let bytes = export.into_stream().try_concat().await.unwrap();
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes)).unwrap();
+        let mut names: Vec<_> = archive
+            .file_names()
+            .map(|name| name.unwrap().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "logo.png",
+                "main.pdf",
+                "main.typ",
+                "manifest.json",
+                "parts/intro.typ"
+            ]
+        );
+        let mut read = |path: &str| {
+            let mut bytes = Vec::new();
+            archive
+                .by_name(path)
+                .unwrap()
+                .read_to_end(&mut bytes)
+                .unwrap();
+            bytes
         };
+        assert_eq!(read("parts/intro.typ"), b"= Live");
+        assert_eq!(read("logo.png"), PNG);
+        assert!(read("main.pdf").starts_with(b"%PDF-"));
+        let manifest: Manifest = serde_json::from_slice(&read("manifest.json")).unwrap();
+        assert_eq!(
+            manifest,
+            Manifest {
+                entry: Some("main.typ".to_string()),
+                pinned_version: None,
+            }
+        );
 
-        let res = service
-            .update(
+        // Without extras, nothing but the files.
+        let export = service
+            .export(project_id, owner_id, HashMap::new(), false, false)
+            .await
+            .unwrap();
+        let bytes = export.into_stream().try_concat().await.unwrap();
+        let archive = zip::ZipArchive::new(std::io::Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), 3);
+
+        // A project file where the manifest would go.
+        service
+            .create_file(
                 project_id,
-                other_user_id,
-                "x".to_string(),
-                other_user_id,
-                OwnerType::User,
+                owner_id,
+                "manifest.json".to_string(),
+                "{}".to_string(),
+                &ProjectServer::detached(),
             )
-            .await;
-        assert!(matches!(res, Err(ProjectServiceError::AccessDenied)));
+            .await
+            .unwrap();
+        assert!(matches!(
+            service
+                .export(project_id, owner_id, HashMap::new(), false, true)
+                .await,
+            Err(ProjectServiceError::PathConflict(path)) if path == "manifest.json"
+        ));
+        assert!(matches!(
+            service
+                .export(project_id, ObjectId::new(), HashMap::new(), false, false)
+                .await,
+            Err(ProjectServiceError::AccessDenied)
+        ));
+    }
+
+    /// A zip of `files`, as [`ProjectService::import`] takes it.
+    fn zip_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+        use std::io::Write as _;
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (path, contents) in files {
+            zip.start_file(*path, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
     }
 
     #[tokio::test]
-    async fn test_update_project_not_found() {
+    async fn test_import_stores_text_inline_assets_as_blobs_and_finds_the_entry() {
+        let creator_id = ObjectId::new();
+        let store = Arc::new(InMemoryObjectStore::new());
         let service = ProjectService {
             project_repo: MockProjectRepo::default(),
-            user_repo: MockUserRepo::default(),
+            user_repo: MockUserRepo {
+                users: Mutex::new(vec![dummy_user(creator_id)]),
+            },
             team_repo: MockTeamRepo::default(),
+            store: Some(store.clone()),
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
         };
-
-        let user_id = ObjectId::new();
-        let res = service
-            .update(
-                ObjectId::new(),
-                user_id,
-                "x".to_string(),
-                user_id,
+        let limits = || UnpackLimits {
+            max_file_bytes: 1024,
+            max_total_bytes: 4096,
+            max_files: 16,
+        };
+        let import = |bytes| {
+            service.import(
+                creator_id,
+                creator_id,
                 OwnerType::User,
+                "paper".to_string(),
+                bytes,
+                limits(),
             )
-            .await;
-        assert!(matches!(res, Err(ProjectServiceError::ProjectNotFound)));
+        };
+
+        let manifest = br#"{"entry": "parts/intro.typ", "pinned_version": "0.14.2"}"#;
+        let payload = import(zip_of(&[
+            ("paper/main.typ", b"= Hi"),
+            ("paper/parts/intro.typ", b"= Intro"),
+            ("paper/logo.png", PNG),
+            ("paper/manifest.json", manifest),
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(payload.name, "paper");
+        {
+            let project = service.project_repo.projects.lock().unwrap()[0].clone();
+            let paths: Vec<_> = project.files.iter().map(|f| f.path.as_str()).collect();
+            assert_eq!(paths, ["main.typ", "parts/intro.typ", "logo.png"]);
+            let entry = project.files.iter().find(|f| Some(f.id) == project.entry);
+            assert_eq!(entry.unwrap().path, "parts/intro.typ");
+            assert_eq!(project.pinned_version, Some(semver::Version::new(0, 14, 2)));
+            assert!(project.nodes.is_some());
+            let FileContent::Binary { storage_key } = &project.files[2].content else {
+                panic!("logo.png stored inline");
+            };
+            assert_eq!(store.get(storage_key).await.unwrap(), PNG);
+        }
+
+        // Without a manifest: root main.typ, else the one .typ file.
+        import(zip_of(&[("main.typ", b""), ("a.typ", b"")]))
+            .await
+            .unwrap();
+        import(zip_of(&[("lib/a.typ", b""), ("notes.txt", b"")]))
+            .await
+            .unwrap();
+        let projects = service.project_repo.projects.lock().unwrap().clone();
+        let entry_path = |i: usize| {
+            let project = &projects[i];
+            let entry = project.files.iter().find(|f| Some(f.id) == project.entry);
+            entry.unwrap().path.clone()
+        };
+        assert_eq!(entry_path(1), "main.typ");
+        assert_eq!(entry_path(2), "lib/a.typ");
+
+        for (bytes, check) in [
+            (
+                zip_of(&[("a.typ", b""), ("b.typ", b"")]),
+                (|e| matches!(e, ProjectServiceError::InvalidEntry(_))) as fn(&_) -> bool,
+            ),
+            (
+                zip_of(&[
+                    ("main.typ", b""),
+                    ("manifest.json", br#"{"entry": "x.typ"}"#),
+                ]),
+                |e| matches!(e, ProjectServiceError::InvalidEntry(_)),
+            ),
+            (zip_of(&[("main.typ", b""), ("logo.png", b"text")]), |e| {
+                matches!(e, ProjectServiceError::UnsupportedMediaType(_))
+            }),
+            (zip_of(&[("main.typ", b"\xff\xfe")]), |e| {
+                matches!(e, ProjectServiceError::UnsupportedMediaType(_))
+            }),
+            (zip_of(&[("a", b""), ("a/main.typ", b"")]), |e| {
+                matches!(e, ProjectServiceError::PathConflict(_))
+            }),
+            (zip_of(&[]), |e| {
+                matches!(e, ProjectServiceError::InvalidUpload(_))
+            }),
+        ] {
+            let Err(err) = import(bytes).await else {
+                panic!("imported");
+            };
+            assert!(check(&err), "{err}");
+        }
+        assert_eq!(service.project_repo.projects.lock().unwrap().len(), 3);
     }
 
     #[tokio::test]
-    async fn test_update_project_target_user_not_self() {
+    async fn test_compile_pdf_checks_access_and_the_pinned_version() {
         let owner_id = ObjectId::new();
-        let other_user_id = ObjectId::new();
         let project_id = ObjectId::new();
-        let file_id = ObjectId::new();
-        let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
-            },
-            user_repo: MockUserRepo {
-                users: Mutex::new(vec![dummy_user(owner_id), dummy_user(other_user_id)]),
-            },
-            team_repo: MockTeamRepo::default(),
-        };
+        let (service, _) = upload_service(project_id, owner_id);
 
-        // The owner tries to hand the project to another user directly.
-        let res = service
-            .update(
-                project_id,
-                owner_id,
-                "x".to_string(),
-                other_user_id,
-                OwnerType::User,
-            )
-            .await;
+        let res = service.compile_pdf(project_id, ObjectId::new()).await;
+        assert!(matches!(res, Err(ProjectServiceError::AccessDenied)));
+
+        service.project_repo.projects.lock().unwrap()[0].pinned_version =
+            Some(semver::Version::new(0, 13, 1));
+        let res = service.compile_pdf(project_id, owner_id).await;
         assert!(matches!(
             res,
-            Err(ProjectServiceError::CreatorNotMatchOwner)
+            Err(ProjectServiceError::Compile(
+                CompileError::UnsupportedVersion(_)
+            ))
+        ));
+
+        {
+            let mut projects = service.project_repo.projects.lock().unwrap();
+            projects[0].pinned_version = Some(compile::TYPST_VERSION);
+            projects[0].entry = None;
+        }
+        let res = service.compile_pdf(project_id, owner_id).await;
+        assert!(matches!(
+            res,
+            Err(ProjectServiceError::Compile(CompileError::NoEntry))
         ));
     }
 
     #[tokio::test]
-    async fn test_update_project_target_team_not_member() {
+    async fn test_page_render_is_keyed_by_inputs_and_cached() {
         let owner_id = ObjectId::new();
-        let team_id = ObjectId::new();
         let project_id = ObjectId::new();
-        let file_id = ObjectId::new();
-        let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
-            },
-            user_repo: MockUserRepo {
-                users: Mutex::new(vec![dummy_user(owner_id)]),
-            },
-            team_repo: MockTeamRepo {
-                teams: Mutex::new(vec![dummy_team(team_id, vec![ObjectId::new()])]),
-            },
-        };
-
-        let res = service
-            .update(
+        let (service, _) = upload_service(project_id, owner_id);
+        let entry = service.project_repo.projects.lock().unwrap()[0].files[0].id;
+        let cache = RenderCache::new(1024 * 1024);
+        let render =
+            |page, format, ppi| service.page_render(project_id, owner_id, page, format, ppi);
+
+        let png = render(1, ImageFormat::Png, 72.0).await.unwrap();
+        let key = png.key.clone();
+        let image = service.render_bytes(png, &cache).await.unwrap();
+        assert!(image.starts_with(b"\x89PNG"));
+        assert!(cache.get(&key).is_some());
+
+        // The same inputs give the same key; a resolution or an edit doesn't.
+        assert_eq!(render(1, ImageFormat::Png, 72.0).await.unwrap().key, key);
+        assert_ne!(render(1, ImageFormat::Png, 144.0).await.unwrap().key, key);
+        let svg = render(1, ImageFormat::Svg, 72.0).await.unwrap();
+        assert_eq!(
+            svg.key,
+            render(1, ImageFormat::Svg, 300.0).await.unwrap().key
+        );
+        assert!(
+            service
+                .render_bytes(svg, &cache)
+                .await
+                .unwrap()
+                .starts_with(b"<svg")
+        );
+        service
+            .update_file(
                 project_id,
                 owner_id,
-                "x".to_string(),
-                team_id,
-                OwnerType::Team,
+                entry,
+                "new".to_string(),
+                None,
+                &ProjectServer::detached(),
             )
-            .await;
+            .await
+            .unwrap();
+        assert_ne!(render(1, ImageFormat::Png, 72.0).await.unwrap().key, key);
+
+        let missing = render(2, ImageFormat::Png, 72.0).await.unwrap();
         assert!(matches!(
-            res,
-            Err(ProjectServiceError::CreatorNotMemberOfTeam)
+            service.render_bytes(missing, &cache).await,
+            Err(ProjectServiceError::PageNotFound(2))
         ));
-    }
-
-    #[tokio::test]
-    async fn test_update_project_target_owner_not_found() {
-        let owner_id = ObjectId::new();
-        let project_id = ObjectId::new();
-        let file_id = ObjectId::new();
-        let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
-            },
-            user_repo: MockUserRepo::default(),
-            team_repo: MockTeamRepo::default(),
-        };
 
-        let res = service
-            .update(
+        // A page too large to rasterize at the resolution asked for is
+        // refused, though an SVG of it renders.
+        service
+            .update_file(
                 project_id,
                 owner_id,
-                "x".to_string(),
-                ObjectId::new(),
-                OwnerType::Team,
+                entry,
+                "#set page(width: 5000pt, height: 5000pt)\nbig".to_string(),
+                None,
+                &ProjectServer::detached(),
             )
-            .await;
+            .await
+            .unwrap();
+        let huge = render(1, ImageFormat::Png, MAX_PPI).await.unwrap();
         assert!(matches!(
-            res,
-            Err(ProjectServiceError::OwnerNotFound(OwnerType::Team))
+            service.render_bytes(huge, &cache).await,
+            Err(ProjectServiceError::InvalidRender(_))
         ));
+        let svg = render(1, ImageFormat::Svg, MAX_PPI).await.unwrap();
+        assert!(service.render_bytes(svg, &cache).await.is_ok());
     }
 
     #[tokio::test]
-    async fn test_duplicate_project_success() {
-        let creator_id = ObjectId::new();
+    async fn test_team_projects_render_with_the_team_fonts() {
+        let owner_id = ObjectId::new();
         let project_id = ObjectId::new();
-        let file_id = ObjectId::new();
-        let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project_with_file(project_id, creator_id, file_id)]),
-            },
-            user_repo: MockUserRepo::default(),
-            team_repo: MockTeamRepo::default(),
-        };
-
-        let payload = service.duplicate(project_id, creator_id).await.unwrap();
+        let team_id = ObjectId::new();
+        let (service, store) = upload_service(project_id, owner_id);
+        {
+            let mut projects = service.project_repo.projects.lock().unwrap();
+            projects[0].owner_id = team_id;
+            projects[0].owner_type = OwnerType::Team;
+        }
+        service
+            .team_repo
+            .teams
+            .lock()
+            .unwrap()
+            .push(dummy_team(team_id, vec![owner_id]));
+        let cache = RenderCache::new(1024 * 1024);
+        let render = || service.page_render(project_id, owner_id, 1, ImageFormat::Svg, 72.0);
+        let without = render().await.unwrap().key;
+
+        let bytes = typst_assets::fonts().next().unwrap();
+        let blob = store.put(bytes).await.unwrap();
+        service.team_repo.teams.lock().unwrap()[0]
+            .fonts
+            .push(TeamFont {
+                id: ObjectId::new(),
+                filename: "corporate.otf".to_string(),
+                storage_key: blob.sha256,
+                size: blob.size,
+                faces: vec![],
+                uploaded_at: OffsetDateTime::now_utc(),
+            });
+
+        // A new font may change the layout, so it changes the key, and the
+        // font is fetched for the compile.
+        let with = render().await.unwrap();
+        assert_ne!(with.key, without);
+        assert!(service.render_bytes(with, &cache).await.is_ok());
+    }
 
-        assert_eq!(payload.name, "test copy");
-        assert_eq!(payload.owner_id, creator_id.to_hex());
-        assert_eq!(payload.creator_id, creator_id.to_hex());
-        assert_ne!(payload.id, project_id.to_hex());
-        assert_eq!(payload.files.len(), 1);
+    #[tokio::test]
+    async fn test_page_render_validates_the_request() {
+        let owner_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let (service, _) = upload_service(project_id, owner_id);
 
-        // The duplicated file gets a fresh id, distinct from the source
-        // file's, and `entry` is remapped to point at the new one.
-        let new_file_id = payload.files[0].id.clone();
-        assert_ne!(new_file_id, file_id.to_hex());
-        assert_eq!(payload.entry, Some(new_file_id));
+        for (page, ppi) in [(0, 72.0), (1, 0.0), (1, 10_000.0), (1, f32::NAN)] {
+            let res = service
+                .page_render(project_id, owner_id, page, ImageFormat::Png, ppi)
+                .await;
+            assert!(matches!(res, Err(ProjectServiceError::InvalidRender(_))));
+        }
+        let res = service
+            .page_render(project_id, ObjectId::new(), 1, ImageFormat::Png, 72.0)
+            .await;
+        assert!(matches!(res, Err(ProjectServiceError::AccessDenied)));
     }
 
     #[tokio::test]
-    async fn test_duplicate_project_team_owned_sets_requester_as_creator() {
-        let original_creator_id = ObjectId::new();
-        let team_id = ObjectId::new();
-        let member_id = ObjectId::new();
+    async fn test_raw_file_serves_binary_and_text_files() {
+        let owner_id = ObjectId::new();
         let project_id = ObjectId::new();
+        let (service, _) = upload_service(project_id, owner_id);
+        let uploaded = service
+            .upload_file(
+                project_id,
+                owner_id,
+                "logo.png".to_string(),
+                PNG.to_vec(),
+                &ProjectServer::detached(),
+            )
+            .await
+            .unwrap();
 
-        let project = Project {
-            id: project_id,
-            name: "team project".to_string(),
-            owner_id: team_id,
-            owner_type: OwnerType::Team,
-            creator_id: original_creator_id,
-            files: vec![],
-            created_at: OffsetDateTime::now_utc(),
-            updated_at: OffsetDateTime::now_utc(),
-            entry: None,
-            pinned_version: None,
-        };
-
-        let team = dummy_team(team_id, vec![original_creator_id, member_id]);
+        let raw = service
+            .raw_file(
+                project_id,
+                owner_id,
+                ObjectId::parse_str(&uploaded.id).unwrap(),
+                HashMap::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(raw.sha256, sha256_hex(PNG));
+        assert_eq!(raw.content_type, "image/png");
+        assert_eq!(raw.size, PNG.len() as u64);
+        let body = service.raw_body(raw, Some((1, 3))).await.unwrap();
+        assert_eq!(streamed(body).await, &PNG[1..=3]);
 
-        let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project]),
-            },
-            user_repo: MockUserRepo::default(),
-            team_repo: MockTeamRepo {
-                teams: Mutex::new(vec![team]),
-            },
+        let project = service
+            .project_repo
+            .find_by_id(project_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let main = project.files.iter().find(|f| f.path == "main.typ").unwrap();
+        let FileContent::Text { text } = &main.content else {
+            panic!("expected text content");
         };
+        let raw = service
+            .raw_file(project_id, owner_id, main.id, HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(raw.sha256, sha256_hex(text.as_bytes()));
+        assert_eq!(raw.content_type, TEXT_CONTENT_TYPE);
+        let body = service.raw_body(raw, None).await.unwrap();
+        assert_eq!(streamed(body).await, text.as_bytes());
+
+        // A file open in a collaboration room is served as it is there.
+        let live = HashMap::from([(main.id, "= Live".to_string())]);
+        let raw = service
+            .raw_file(project_id, owner_id, main.id, live)
+            .await
+            .unwrap();
+        assert_eq!(raw.sha256, sha256_hex(b"= Live"));
+        assert_eq!(raw.size, 6);
+        let body = service.raw_body(raw, None).await.unwrap();
+        assert_eq!(streamed(body).await, b"= Live");
+    }
 
-        // A team member other than the original creator duplicates the
-        // project: ownership stays with the team, but the duplicate's creator
-        // is the requester, not the original creator.
-        let payload = service.duplicate(project_id, member_id).await.unwrap();
-        assert_eq!(payload.owner_id, team_id.to_hex());
-        assert_eq!(payload.owner_type, OwnerType::Team);
-        assert_eq!(payload.creator_id, member_id.to_hex());
-        assert_ne!(payload.creator_id, original_creator_id.to_hex());
+    async fn streamed(body: ByteStream) -> Vec<u8> {
+        use futures_util::TryStreamExt as _;
+        let chunks: Vec<bytes::Bytes> = body.try_collect().await.unwrap();
+        chunks.concat()
     }
 
     #[tokio::test]
-    async fn test_duplicate_project_not_found() {
-        let service = ProjectService {
-            project_repo: MockProjectRepo::default(),
-            user_repo: MockUserRepo::default(),
-            team_repo: MockTeamRepo::default(),
-        };
+    async fn test_raw_file_checks_access_and_existence() {
+        let owner_id = ObjectId::new();
+        let project_id = ObjectId::new();
+        let (service, _) = upload_service(project_id, owner_id);
+        let file_id = service
+            .project_repo
+            .find_by_id(project_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .files[0]
+            .id;
 
-        let res = service.duplicate(ObjectId::new(), ObjectId::new()).await;
-        assert!(matches!(res, Err(ProjectServiceError::ProjectNotFound)));
+        let res = service
+            .raw_file(project_id, ObjectId::new(), file_id, HashMap::new())
+            .await;
+        assert!(matches!(res, Err(ProjectServiceError::AccessDenied)));
+        let res = service
+            .raw_file(project_id, owner_id, ObjectId::new(), HashMap::new())
+            .await;
+        assert!(matches!(res, Err(ProjectServiceError::FileNotFound)));
     }
 
     #[tokio::test]
-    async fn test_duplicate_project_access_denied() {
+    async fn test_upload_file_without_storage() {
         let owner_id = ObjectId::new();
-        let other_user_id = ObjectId::new();
         let project_id = ObjectId::new();
-        let file_id = ObjectId::new();
-        let service = ProjectService {
-            project_repo: MockProjectRepo {
-                projects: Mutex::new(vec![project_with_file(project_id, owner_id, file_id)]),
-            },
-            user_repo: MockUserRepo::default(),
-            team_repo: MockTeamRepo::default(),
-        };
+        let (mut service, _) = upload_service(project_id, owner_id);
+        service.store = None;
 
-        let res = service.duplicate(project_id, other_user_id).await;
-        assert!(matches!(res, Err(ProjectServiceError::AccessDenied)));
+        let res = service
+            .upload_file(
+                project_id,
+                owner_id,
+                "logo.png".to_string(),
+                PNG.to_vec(),
+                &ProjectServer::detached(),
+            )
+            .await;
+        assert!(matches!(res, Err(ProjectServiceError::StorageUnavailable)));
     }
 }
@@ -1,19 +1,29 @@
+use std::sync::Arc;
+
 use bson::oid::ObjectId;
 use derive_more::Display;
 use time::OffsetDateTime;
+use typst::text::{FontInfo, FontStyle};
 
 use crate::{
     models::{
         project::{OwnerType, ProjectPayload},
-        team::{Team, TeamPayload},
+        team::{FontFace, Team, TeamFont, TeamFontPayload, TeamPayload},
     },
     repo::{project::ProjectRepo, team::TeamRepo, user::UserRepo},
+    storage::{ObjectStore, StorageError, sha256_hex},
 };
 
+/// File extensions accepted as fonts: TrueType, OpenType, and collections.
+const FONT_EXTENSIONS: [&str; 3] = ["ttf", "otf", "ttc"];
+
 pub struct TeamService<R: TeamRepo, U: UserRepo, P: ProjectRepo> {
     pub user_repo: U,
     pub team_repo: R,
     pub project_repo: P,
+    /// Where font files live. `None` when no storage backend is configured,
+    /// in which case font uploads fail with `StorageUnavailable`.
+    pub store: Option<Arc<dyn ObjectStore>>,
 }
 
 #[derive(Debug, Display)]
@@ -26,6 +36,20 @@ pub enum TeamServiceError {
     TeamNotFound,
     #[display("Access denied: You are not a member of this team")]
     AccessDenied,
+    #[display("Font not found")]
+    FontNotFound,
+    #[display("Invalid font: {_0}")]
+    InvalidFont(String),
+    #[display("Font already uploaded as {_0}")]
+    DuplicateFont(String),
+    #[display("Invalid upload: {_0}")]
+    InvalidUpload(String),
+    #[display("File exceeds the {_0}-byte upload limit")]
+    PayloadTooLarge(usize),
+    #[display("Object storage is not configured")]
+    StorageUnavailable,
+    #[display("Storage error: {_0}")]
+    Storage(StorageError),
 }
 
 impl<R: TeamRepo, U: UserRepo, P: ProjectRepo> TeamService<R, U, P> {
@@ -48,6 +72,7 @@ impl<R: TeamRepo, U: UserRepo, P: ProjectRepo> TeamService<R, U, P> {
                 avatar_uri: None,
                 creator_id: creator.id,
                 member_ids: vec![creator.id],
+                fonts: vec![],
                 created_at: OffsetDateTime::now_utc(),
                 updated_at: OffsetDateTime::now_utc(),
             })
@@ -84,6 +109,115 @@ impl<R: TeamRepo, U: UserRepo, P: ProjectRepo> TeamService<R, U, P> {
 
         Ok(payloads)
     }
+
+    /// Add a font file to a team's library. The file must be a font by name
+    /// and by content; its faces are parsed here so they can be listed
+    /// without reading the file back. Members only.
+    pub async fn upload_font(
+        &self,
+        team_id: ObjectId,
+        user_id: ObjectId,
+        filename: String,
+        bytes: Vec<u8>,
+    ) -> Result<TeamFontPayload, TeamServiceError> {
+        let team = self.member_team(team_id, user_id).await?;
+        let extension = filename
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.to_ascii_lowercase())
+            .unwrap_or_default();
+        if !FONT_EXTENSIONS.contains(&extension.as_str()) {
+            return Err(TeamServiceError::InvalidFont(format!(
+                "{filename} is not a .ttf, .otf or .ttc file"
+            )));
+        }
+        let faces = font_faces(&bytes);
+        if faces.is_empty() {
+            return Err(TeamServiceError::InvalidFont(format!(
+                "{filename} contains no readable font"
+            )));
+        }
+
+        let sha256 = sha256_hex(&bytes);
+        if let Some(existing) = team.fonts.iter().find(|font| font.storage_key == sha256) {
+            return Err(TeamServiceError::DuplicateFont(existing.filename.clone()));
+        }
+        let store = self
+            .store
+            .as_ref()
+            .ok_or(TeamServiceError::StorageUnavailable)?;
+        let blob = store.put(&bytes).await.map_err(TeamServiceError::Storage)?;
+
+        let font = TeamFont {
+            id: ObjectId::new(),
+            filename,
+            storage_key: blob.sha256,
+            size: bytes.len() as u64,
+            faces,
+            uploaded_at: OffsetDateTime::now_utc(),
+        };
+        match self.team_repo.add_font(team_id, font.clone()).await {
+            Ok(true) => Ok(font.into()),
+            Ok(false) => Err(TeamServiceError::TeamNotFound),
+            Err(e) => Err(TeamServiceError::Database(e)),
+        }
+    }
+
+    /// A team's font library. Members only.
+    pub async fn list_fonts(
+        &self,
+        team_id: ObjectId,
+        user_id: ObjectId,
+    ) -> Result<Vec<TeamFontPayload>, TeamServiceError> {
+        let team = self.member_team(team_id, user_id).await?;
+        Ok(team.fonts.into_iter().map(|font| font.into()).collect())
+    }
+
+    /// Remove a font from a team's library. The blob is left for GC, like a
+    /// deleted project file's. Members only.
+    pub async fn delete_font(
+        &self,
+        team_id: ObjectId,
+        user_id: ObjectId,
+        font_id: ObjectId,
+    ) -> Result<(), TeamServiceError> {
+        self.member_team(team_id, user_id).await?;
+        match self.team_repo.remove_font(team_id, font_id).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(TeamServiceError::FontNotFound),
+            Err(e) => Err(TeamServiceError::Database(e)),
+        }
+    }
+
+    /// The team, if `user_id` is one of its members.
+    async fn member_team(
+        &self,
+        team_id: ObjectId,
+        user_id: ObjectId,
+    ) -> Result<Team, TeamServiceError> {
+        match self.team_repo.find_by_id(team_id).await {
+            Ok(Some(team)) if team.member_ids.contains(&user_id) => Ok(team),
+            Ok(Some(_)) => Err(TeamServiceError::AccessDenied),
+            Ok(None) => Err(TeamServiceError::TeamNotFound),
+            Err(e) => Err(TeamServiceError::Database(e)),
+        }
+    }
+}
+
+/// The faces in a font file, as Typst reads them. Empty if it isn't a font.
+fn font_faces(bytes: &[u8]) -> Vec<FontFace> {
+    FontInfo::iter(bytes)
+        .map(|info| FontFace {
+            family: info.family,
+            style: match info.variant.style {
+                FontStyle::Normal => "normal",
+                FontStyle::Italic => "italic",
+                FontStyle::Oblique => "oblique",
+            }
+            .to_string(),
+            weight: info.variant.weight.to_number(),
+            stretch: info.variant.stretch.to_ratio().get(),
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -94,6 +228,7 @@ mod tests {
     use crate::models::user::User;
     use crate::repo::project::tests::MockProjectRepo;
     use crate::repo::{team::tests::MockTeamRepo, user::tests::MockUserRepo};
+    use crate::storage::InMemoryObjectStore;
     use std::sync::Mutex;
 
     #[tokio::test]
@@ -112,6 +247,7 @@ mod tests {
             },
             team_repo: MockTeamRepo::default(),
             project_repo: MockProjectRepo::default(),
+            store: None,
         };
 
         let result = service
@@ -131,6 +267,7 @@ mod tests {
             user_repo: MockUserRepo::default(),
             team_repo: MockTeamRepo::default(),
             project_repo: MockProjectRepo::default(),
+            store: None,
         };
 
         let result = service
@@ -150,6 +287,7 @@ mod tests {
             avatar_uri: None,
             creator_id: member_ids[0],
             member_ids,
+            fonts: vec![],
             created_at: OffsetDateTime::now_utc(),
             updated_at: OffsetDateTime::now_utc(),
         }
@@ -167,6 +305,7 @@ mod tests {
             updated_at: OffsetDateTime::now_utc(),
             entry: None,
             pinned_version: None,
+            nodes: None,
         }
     }
 
@@ -183,6 +322,7 @@ mod tests {
             project_repo: MockProjectRepo {
                 projects: Mutex::new(vec![team_project(team_id, member_id)]),
             },
+            store: None,
         };
 
         let projects = service.list_projects(team_id, member_id).await.unwrap();
@@ -204,18 +344,123 @@ mod tests {
             project_repo: MockProjectRepo {
                 projects: Mutex::new(vec![team_project(team_id, member_id)]),
             },
+            store: None,
         };
 
         let result = service.list_projects(team_id, outsider_id).await;
         assert!(matches!(result, Err(TeamServiceError::AccessDenied)));
     }
 
+    fn font_service(
+        team_id: ObjectId,
+        member_id: ObjectId,
+    ) -> TeamService<MockTeamRepo, MockUserRepo, MockProjectRepo> {
+        TeamService {
+            user_repo: MockUserRepo::default(),
+            team_repo: MockTeamRepo {
+                teams: Mutex::new(vec![team_with_members(team_id, vec![member_id])]),
+            },
+            project_repo: MockProjectRepo::default(),
+            store: Some(Arc::new(InMemoryObjectStore::new())),
+        }
+    }
+
+    /// A real font file: one of those bundled with the server.
+    fn font_bytes() -> Vec<u8> {
+        typst_assets::fonts().next().unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_upload_font_parses_faces_and_lists_them() {
+        let (team_id, member_id) = (ObjectId::new(), ObjectId::new());
+        let service = font_service(team_id, member_id);
+
+        let font = service
+            .upload_font(
+                team_id,
+                member_id,
+                "Corporate.OTF".to_string(),
+                font_bytes(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(font.faces.len(), 1);
+        assert!(!font.faces[0].family.is_empty());
+        assert_eq!(font.storage_key, sha256_hex(&font_bytes()));
+
+        let fonts = service.list_fonts(team_id, member_id).await.unwrap();
+        assert_eq!(fonts.len(), 1);
+        assert_eq!(fonts[0].id, font.id);
+
+        let again = service
+            .upload_font(team_id, member_id, "copy.ttf".to_string(), font_bytes())
+            .await;
+        assert!(
+            matches!(again, Err(TeamServiceError::DuplicateFont(name)) if name == "Corporate.OTF")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_font_rejects_non_fonts_and_non_members() {
+        let (team_id, member_id) = (ObjectId::new(), ObjectId::new());
+        let service = font_service(team_id, member_id);
+
+        let result = service
+            .upload_font(team_id, member_id, "font.woff2".to_string(), font_bytes())
+            .await;
+        assert!(matches!(result, Err(TeamServiceError::InvalidFont(_))));
+        let result = service
+            .upload_font(
+                team_id,
+                member_id,
+                "font.ttf".to_string(),
+                b"not a font".to_vec(),
+            )
+            .await;
+        assert!(matches!(result, Err(TeamServiceError::InvalidFont(_))));
+        let result = service
+            .upload_font(
+                team_id,
+                ObjectId::new(),
+                "font.ttf".to_string(),
+                font_bytes(),
+            )
+            .await;
+        assert!(matches!(result, Err(TeamServiceError::AccessDenied)));
+    }
+
+    #[tokio::test]
+    async fn test_delete_font() {
+        let (team_id, member_id) = (ObjectId::new(), ObjectId::new());
+        let service = font_service(team_id, member_id);
+        let font = service
+            .upload_font(team_id, member_id, "font.ttf".to_string(), font_bytes())
+            .await
+            .unwrap();
+        let font_id = ObjectId::parse_str(&font.id).unwrap();
+
+        service
+            .delete_font(team_id, member_id, font_id)
+            .await
+            .unwrap();
+        assert!(
+            service
+                .list_fonts(team_id, member_id)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        let result = service.delete_font(team_id, member_id, font_id).await;
+        assert!(matches!(result, Err(TeamServiceError::FontNotFound)));
+    }
+
     #[tokio::test]
     async fn test_list_projects_team_not_found() {
         let service = TeamService {
             user_repo: MockUserRepo::default(),
             team_repo: MockTeamRepo::default(),
             project_repo: MockProjectRepo::default(),
+            store: None,
         };
 
         let result = service
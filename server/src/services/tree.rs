@@ -0,0 +1,638 @@
+//! Keeping a project's id-based file tree ([`crate::models::tree`]) in step with
+//! its path-keyed `files`, and migrating stored projects onto it.
+//!
+//! A migrated project carries `nodes`: the [`NodeProjection`] of a
+//! [`ProjectTree`] with a folder node for every path prefix and one file node
+//! per `ProjectFile`. A file node shares the file's id (hex), the same key its
+//! text room already uses, and references the file's bytes as a blob: a
+//! binary's `storage_key`, or the text body uploaded to `blobs/{sha256}`.
+//!
+//! The projection is *derived* from `files` ([`project_nodes`]), reusing the
+//! existing folder ids, so re-deriving an unchanged project is a no-op. REST
+//! file writes re-derive it afterwards ([`sync_nodes`]), a text room once when
+//! it is evicted or shut down, and a write that loses a race leaves the update
+//! to the later one (see `ProjectRepo::set_nodes`).
+//!
+//! The migration ([`MigrationService::migrate`]) is
+//! - **idempotent**: only projects without a projection are touched;
+//! - **resumable**: each project is committed on its own, and text blobs are
+//!   content-addressed, so a run cut short is simply finished by the next one;
+//! - **verified**: [`MigrationService::verify`] checks every stored projection
+//!   against a fresh derivation and against object storage.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use bson::oid::ObjectId;
+use derive_more::Display;
+
+use crate::{
+    models::{
+        project::{FileContent, Project, ProjectFile},
+        tree::{Node, NodeContent, NodeId, NodeProjection, ProjectTree, TreeError},
+    },
+    repo::project::ProjectRepo,
+    storage::{BLOB_PREFIX, Blob, ObjectStore, StorageError, is_valid_sha256, sha256_hex},
+};
+
+#[derive(Debug, Display)]
+pub enum ProjectionError {
+    #[display("Invalid file tree: {_0}")]
+    Tree(TreeError),
+    #[display("Storage error: {_0}")]
+    Storage(StorageError),
+    #[display("Object storage is not configured")]
+    StorageUnavailable,
+    #[display("Database error: {_0}")]
+    Database(mongodb::error::Error),
+}
+
+/// The blob a file node references for `file`.
+pub fn file_blob(file: &ProjectFile) -> Blob {
+    match &file.content {
+        FileContent::Text { text } => Blob {
+            sha256: sha256_hex(text.as_bytes()),
+            size: text.len() as u64,
+        },
+        FileContent::Binary { storage_key } => Blob {
+            sha256: storage_key.clone(),
+            size: file.size.max(0) as u64,
+        },
+    }
+}
+
+/// Derive the node projection of `files`, sorted by path. Folder nodes keep
+/// their ids from `previous` (matched by path); new folders get fresh ids.
+/// Fails if the files don't form a valid tree, e.g. one file's path runs
+/// through another.
+pub fn project_nodes(
+    files: &[ProjectFile],
+    previous: &[NodeProjection],
+) -> Result<Vec<NodeProjection>, TreeError> {
+    let previous_folders: HashMap<&str, &str> = previous
+        .iter()
+        .filter(|n| n.node.is_folder())
+        .map(|n| (n.path.as_str(), n.node.id.as_str()))
+        .collect();
+    let mut folders: HashMap<String, NodeId> = HashMap::new();
+    let mut nodes = Vec::new();
+    for file in files {
+        let segments: Vec<&str> = file.path.split('/').collect();
+        let (name, dirs) = segments
+            .split_last()
+            .expect("split always yields a segment");
+        let mut parent: Option<NodeId> = None;
+        for depth in 1..=dirs.len() {
+            let path = dirs[..depth].join("/");
+            let id = match folders.get(&path) {
+                Some(id) => id.clone(),
+                None => {
+                    let id = previous_folders
+                        .get(path.as_str())
+                        .map_or_else(|| ObjectId::new().to_hex(), |id| id.to_string());
+                    nodes.push(Node {
+                        id: id.clone(),
+                        parent: parent.clone(),
+                        name: dirs[depth - 1].to_string(),
+                        content: NodeContent::Folder,
+                    });
+                    folders.insert(path, id.clone());
+                    id
+                }
+            };
+            parent = Some(id);
+        }
+        nodes.push(Node {
+            id: file.id.to_hex(),
+            parent,
+            name: name.to_string(),
+            content: NodeContent::File {
+                blob: file_blob(file),
+            },
+        });
+    }
+    let mut projection = ProjectTree::from_nodes(nodes).projection()?;
+    projection.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(projection)
+}
+
+/// Upload the body of every text file whose hash isn't in `stored`, so the
+/// projection never references bytes that were never written.
+async fn store_texts(
+    store: Option<&dyn ObjectStore>,
+    files: &[ProjectFile],
+    stored: &HashSet<&str>,
+) -> Result<(), ProjectionError> {
+    for file in files {
+        let FileContent::Text { text } = &file.content else {
+            continue;
+        };
+        if stored.contains(sha256_hex(text.as_bytes()).as_str()) {
+            continue;
+        }
+        store
+            .ok_or(ProjectionError::StorageUnavailable)?
+            .put(text.as_bytes())
+            .await
+            .map_err(ProjectionError::Storage)?;
+    }
+    Ok(())
+}
+
+/// The first projection of `files`, for a new or unmigrated project: derived,
+/// with every text body uploaded first.
+pub async fn initial_nodes(
+    store: &dyn ObjectStore,
+    files: &[ProjectFile],
+) -> Result<Vec<NodeProjection>, ProjectionError> {
+    let nodes = project_nodes(files, &[]).map_err(ProjectionError::Tree)?;
+    store_texts(Some(store), files, &HashSet::new()).await?;
+    Ok(nodes)
+}
+
+/// Bring a migrated project's projection in step with its `files`, as just
+/// written (`project` is the state a file write returned). A no-op for an
+/// unmigrated project or an unchanged tree; new text bodies are uploaded
+/// before the projection references them.
+pub async fn sync_nodes(
+    repo: &impl ProjectRepo,
+    store: Option<&dyn ObjectStore>,
+    project: &Project,
+) -> Result<(), ProjectionError> {
+    let Some(previous) = &project.nodes else {
+        return Ok(());
+    };
+    let nodes = project_nodes(&project.files, previous).map_err(ProjectionError::Tree)?;
+    if &nodes == previous {
+        return Ok(());
+    }
+    let stored: HashSet<&str> = previous
+        .iter()
+        .filter_map(|n| n.node.blob())
+        .map(|blob| blob.sha256.as_str())
+        .collect();
+    store_texts(store, &project.files, &stored).await?;
+    repo.set_nodes(project.id, nodes, project.updated_at)
+        .await
+        .map_err(ProjectionError::Database)?;
+    Ok(())
+}
+
+/// Outcome of one migration run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// Projects given a projection by this run.
+    pub migrated: usize,
+    /// Projects that changed (or were migrated elsewhere) mid-run; the next
+    /// run picks up any still unmigrated.
+    pub deferred: usize,
+    /// Projects whose files don't form a valid tree, with why. They stay
+    /// unmigrated until their files are fixed.
+    pub failed: Vec<(ObjectId, String)>,
+    /// Projects whose binaries' legacy `ObjectId` storage keys were rewritten
+    /// as strings (see `ProjectRepo::rekey_legacy_binaries`).
+    pub rekeyed: u64,
+}
+
+/// Outcome of a verification pass.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Migrated projects checked.
+    pub checked: usize,
+    /// Projects still without a projection.
+    pub unmigrated: usize,
+    /// Every inconsistency found, by project.
+    pub problems: Vec<(ObjectId, String)>,
+}
+
+pub struct MigrationService<P: ProjectRepo> {
+    pub project_repo: P,
+    pub store: Arc<dyn ObjectStore>,
+}
+
+impl<P: ProjectRepo> MigrationService<P> {
+    /// Give every project without a projection its first one, after
+    /// rewriting legacy binary storage keys. A storage or database error
+    /// aborts the run; whatever was committed stays, and the
+    /// next run resumes with the rest.
+    pub async fn migrate(&self) -> Result<MigrationReport, ProjectionError> {
+        let rekeyed = self
+            .project_repo
+            .rekey_legacy_binaries()
+            .await
+            .map_err(ProjectionError::Database)?;
+        let ids = self
+            .project_repo
+            .project_ids(Some(false))
+            .await
+            .map_err(ProjectionError::Database)?;
+        let mut report = MigrationReport {
+            rekeyed,
+            ..MigrationReport::default()
+        };
+        for id in ids {
+            let project = match self.project_repo.find_by_id(id).await {
+                Ok(Some(project)) if project.nodes.is_none() => project,
+                // Deleted, or migrated by a concurrent run.
+                Ok(_) => continue,
+                Err(e) => return Err(ProjectionError::Database(e)),
+            };
+            let nodes = match initial_nodes(self.store.as_ref(), &project.files).await {
+                Ok(nodes) => nodes,
+                Err(ProjectionError::Tree(e)) => {
+                    report.failed.push((id, e.to_string()));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            match self
+                .project_repo
+                .init_nodes(id, nodes, project.updated_at)
+                .await
+            {
+                Ok(Some(_)) => report.migrated += 1,
+                Ok(None) => report.deferred += 1,
+                Err(e) => return Err(ProjectionError::Database(e)),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Check every migrated project: its projection must be a valid tree
+    /// whose stored paths are the derived ones, must match what its `files`
+    /// derive now, and must reference only blobs that exist.
+    pub async fn verify(&self) -> Result<VerifyReport, ProjectionError> {
+        let unmigrated = self
+            .project_repo
+            .project_ids(Some(false))
+            .await
+            .map_err(ProjectionError::Database)?;
+        let ids = self
+            .project_repo
+            .project_ids(Some(true))
+            .await
+            .map_err(ProjectionError::Database)?;
+        // One listing of the blobs serves every project, rather than a lookup
+        // per node.
+        let blobs: HashSet<String> = self
+            .store
+            .list(BLOB_PREFIX)
+            .await
+            .map_err(ProjectionError::Storage)?
+            .into_iter()
+            .filter_map(|meta| meta.key.strip_prefix(BLOB_PREFIX).map(str::to_string))
+            .collect();
+        let mut report = VerifyReport {
+            unmigrated: unmigrated.len(),
+            ..VerifyReport::default()
+        };
+        for id in ids {
+            let project = match self.project_repo.find_by_id(id).await {
+                Ok(Some(project)) => project,
+                Ok(None) => continue,
+                Err(e) => return Err(ProjectionError::Database(e)),
+            };
+            let Some(nodes) = &project.nodes else {
+                continue;
+            };
+            report.checked += 1;
+            for problem in check(&project.files, nodes, &blobs) {
+                report.problems.push((id, problem));
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// The problems with one project's projection, given the hashes of every
+/// stored blob.
+fn check(files: &[ProjectFile], nodes: &[NodeProjection], blobs: &HashSet<String>) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let tree = ProjectTree::from_nodes(nodes.iter().map(|n| n.node.clone()));
+    if tree.len() != nodes.len() {
+        problems.push("duplicate node ids".to_string());
+    }
+    match tree.paths() {
+        Ok(paths) => {
+            for n in nodes {
+                if paths.get(&n.node.id) != Some(&n.path) {
+                    problems.push(format!("node {} has a stale path {}", n.node.id, n.path));
+                }
+            }
+        }
+        Err(e) => problems.push(format!("invalid tree: {e}")),
+    }
+
+    let mut stored = nodes.to_vec();
+    stored.sort_by(|a, b| a.path.cmp(&b.path));
+    match project_nodes(files, nodes) {
+        Ok(expected) if expected == stored => {}
+        Ok(_) => problems.push("projection out of step with files".to_string()),
+        Err(e) => problems.push(format!("files don't form a valid tree: {e}")),
+    }
+
+    for n in nodes {
+        let Some(blob) = n.node.blob() else {
+            continue;
+        };
+        if !is_valid_sha256(&blob.sha256) {
+            problems.push(format!("invalid blob hash {} for {}", blob.sha256, n.path));
+        } else if !blobs.contains(&blob.sha256) {
+            problems.push(format!("missing blob {} for {}", blob.sha256, n.path));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::models::project::OwnerType;
+    use crate::repo::project::tests::MockProjectRepo;
+    use crate::storage::InMemoryObjectStore;
+    use std::sync::Mutex;
+    use time::OffsetDateTime;
+
+    fn text_file(path: &str, text: &str) -> ProjectFile {
+        ProjectFile {
+            path: path.to_string(),
+            content: FileContent::Text {
+                text: text.to_string(),
+            },
+            size: text.len() as i64,
+            ..ProjectFile::default()
+        }
+    }
+
+    fn project(files: Vec<ProjectFile>) -> Project {
+        Project {
+            id: ObjectId::new(),
+            name: "test".to_string(),
+            owner_id: ObjectId::new(),
+            owner_type: OwnerType::User,
+            creator_id: ObjectId::new(),
+            files,
+            created_at: OffsetDateTime::now_utc(),
+            updated_at: OffsetDateTime::now_utc(),
+            entry: None,
+            pinned_version: None,
+            nodes: None,
+        }
+    }
+
+    fn migration(
+        projects: Vec<Project>,
+    ) -> (MigrationService<MockProjectRepo>, Arc<InMemoryObjectStore>) {
+        let store = Arc::new(InMemoryObjectStore::new());
+        let service = MigrationService {
+            project_repo: MockProjectRepo {
+                projects: Mutex::new(projects),
+            },
+            store: store.clone(),
+        };
+        (service, store)
+    }
+
+    fn path_ids(nodes: &[NodeProjection]) -> Vec<(&str, &str)> {
+        nodes
+            .iter()
+            .map(|n| (n.path.as_str(), n.node.id.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_project_nodes_creates_folders_for_path_prefixes() {
+        let intro = text_file("chapters/intro.typ", "= Intro");
+        let logo = ProjectFile {
+            path: "chapters/img/logo.png".to_string(),
+            content: FileContent::Binary {
+                storage_key: "b".repeat(64),
+            },
+            size: 42,
+            ..ProjectFile::default()
+        };
+        let main = text_file("main.typ", "");
+        let files = vec![intro.clone(), logo.clone(), main.clone()];
+
+        let nodes = project_nodes(&files, &[]).unwrap();
+        let paths: Vec<&str> = nodes.iter().map(|n| n.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "chapters",
+                "chapters/img",
+                "chapters/img/logo.png",
+                "chapters/intro.typ",
+                "main.typ"
+            ]
+        );
+        let by_path: HashMap<&str, &NodeProjection> =
+            nodes.iter().map(|n| (n.path.as_str(), n)).collect();
+        assert!(by_path["chapters"].node.is_folder());
+        assert_eq!(
+            by_path["chapters/img"].node.parent.as_ref(),
+            Some(&by_path["chapters"].node.id)
+        );
+        let node = &by_path["chapters/intro.typ"].node;
+        assert_eq!(node.id, intro.id.to_hex());
+        assert_eq!(node.name, "intro.typ");
+        assert_eq!(node.parent.as_ref(), Some(&by_path["chapters"].node.id));
+        assert_eq!(node.blob().unwrap().sha256, sha256_hex(b"= Intro"));
+        assert_eq!(
+            by_path["chapters/img/logo.png"].node.blob(),
+            Some(&Blob {
+                sha256: "b".repeat(64),
+                size: 42
+            })
+        );
+        assert_eq!(by_path["main.typ"].node.parent, None);
+
+        // Re-deriving keeps the folder ids, so an unchanged project is a no-op.
+        assert_eq!(project_nodes(&files, &nodes).unwrap(), nodes);
+    }
+
+    #[test]
+    fn test_project_nodes_rejects_a_file_used_as_a_folder() {
+        let files = vec![text_file("a", ""), text_file("a/b.typ", "")];
+        assert!(matches!(
+            project_nodes(&files, &[]),
+            Err(TreeError::DuplicateName { .. })
+        ));
+        let files = vec![text_file("a/../b.typ", "")];
+        assert!(matches!(
+            project_nodes(&files, &[]),
+            Err(TreeError::InvalidName { .. })
+        ));
+    }
+
+    #[test]
+    fn test_projection_round_trips_through_bson() {
+        let mut project = project(vec![text_file("chapters/intro.typ", "= Intro")]);
+        project.nodes = Some(project_nodes(&project.files, &[]).unwrap());
+
+        let doc = bson::to_document(&project).unwrap();
+        let back: Project = bson::from_document(doc).unwrap();
+        assert_eq!(back.nodes, project.nodes);
+
+        // An unmigrated project has no `nodes` field at all.
+        project.nodes = None;
+        assert!(!bson::to_document(&project).unwrap().contains_key("nodes"));
+    }
+
+    #[tokio::test]
+    async fn test_migrate_is_idempotent_and_uploads_text_bodies() {
+        let legacy = project(vec![
+            text_file("main.typ", "= Main"),
+            text_file("chapters/intro.typ", "= Intro"),
+        ]);
+        let broken = project(vec![text_file("a", ""), text_file("a/b.typ", "")]);
+        let (service, store) = migration(vec![legacy.clone(), broken.clone()]);
+
+        let report = service.migrate().await.unwrap();
+        assert_eq!(report.migrated, 1);
+        assert_eq!(report.deferred, 0);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, broken.id);
+
+        let migrated = service
+            .project_repo
+            .find_by_id(legacy.id)
+            .await
+            .unwrap()
+            .unwrap();
+        let nodes = migrated.nodes.clone().unwrap();
+        assert_eq!(nodes.len(), 3);
+        for text in ["= Main", "= Intro"] {
+            assert!(store.exists(&sha256_hex(text.as_bytes())).await.unwrap());
+        }
+
+        // A second run finds nothing left to do and keeps the folder ids.
+        let again = service.migrate().await.unwrap();
+        assert_eq!(again.migrated, 0);
+        let after = service
+            .project_repo
+            .find_by_id(legacy.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(path_ids(&after.nodes.unwrap()), path_ids(&nodes));
+    }
+
+    #[tokio::test]
+    async fn test_verify_reports_drift_and_missing_blobs() {
+        let legacy = project(vec![text_file("chapters/intro.typ", "= Intro")]);
+        let (service, store) = migration(vec![legacy.clone(), project(vec![])]);
+        service.project_repo.projects.lock().unwrap()[1].files = vec![text_file("a", "")];
+        service.migrate().await.unwrap();
+
+        let report = service.verify().await.unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.unmigrated, 0);
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+        // Bypass the sync: move the file without touching the projection, and
+        // lose a text blob.
+        {
+            let mut projects = service.project_repo.projects.lock().unwrap();
+            projects[0].files[0].path = "intro.typ".to_string();
+        }
+        store.delete(&sha256_hex(b"")).await.unwrap();
+
+        let report = service.verify().await.unwrap();
+        let problems: Vec<&str> = report.problems.iter().map(|(_, p)| p.as_str()).collect();
+        assert_eq!(report.problems.len(), 2, "{problems:?}");
+        assert_eq!(report.problems[0].0, legacy.id);
+        assert_eq!(problems[0], "projection out of step with files");
+        assert!(problems[1].starts_with("missing blob"));
+    }
+
+    #[tokio::test]
+    async fn test_sync_nodes_follows_file_writes() {
+        let legacy = project(vec![text_file("chapters/intro.typ", "= Intro")]);
+        let file_id = legacy.files[0].id;
+        let (service, store) = migration(vec![legacy.clone()]);
+        service.migrate().await.unwrap();
+        let repo = &service.project_repo;
+        let before = repo.find_by_id(legacy.id).await.unwrap().unwrap();
+        let folder_id = before.nodes.as_ref().unwrap()[0].node.id.clone();
+
+        // An edit: the new text is uploaded before it is referenced.
+        let edited = repo
+            .update_file_content(
+                legacy.id,
+                file_id,
+                FileContent::Text {
+                    text: "= Edited".to_string(),
+                },
+                8,
+                None,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        sync_nodes(repo, Some(store.as_ref()), &edited)
+            .await
+            .unwrap();
+        let synced = repo.find_by_id(legacy.id).await.unwrap().unwrap();
+        let nodes = synced.nodes.clone().unwrap();
+        assert_eq!(nodes[0].node.id, folder_id);
+        assert_eq!(
+            nodes[1].node.blob().unwrap().sha256,
+            sha256_hex(b"= Edited")
+        );
+        assert!(store.exists(&sha256_hex(b"= Edited")).await.unwrap());
+
+        // A sync of an older state never overwrites a newer projection.
+        let moved = repo
+            .move_file(legacy.id, file_id, "intro.typ".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        sync_nodes(repo, Some(store.as_ref()), &edited)
+            .await
+            .unwrap();
+        let stale = repo.find_by_id(legacy.id).await.unwrap().unwrap();
+        assert_eq!(stale.nodes, synced.nodes);
+        sync_nodes(repo, Some(store.as_ref()), &moved)
+            .await
+            .unwrap();
+        let current = repo.find_by_id(legacy.id).await.unwrap().unwrap();
+        let paths: Vec<&str> = current
+            .nodes
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|n| n.path.as_str())
+            .collect();
+        assert_eq!(paths, ["intro.typ"]);
+    }
+
+    #[test]
+    fn test_listing_takes_paths_from_the_projection() {
+        let a = text_file("a.typ", "");
+        let b = text_file("b.typ", "");
+        let mut project = project(vec![b.clone(), a.clone()]);
+        project.nodes = Some(project_nodes(&project.files, &[]).unwrap());
+        // `b` moved in the projection; `c` isn't in it yet.
+        let moved = project.nodes.as_mut().unwrap();
+        moved[1].path = "0.typ".to_string();
+        let c = text_file("c.typ", "");
+        project.files.push(c.clone());
+
+        let listed: Vec<(ObjectId, String)> = project
+            .take_listed_files()
+            .into_iter()
+            .map(|f| (f.id, f.path))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                (b.id, "0.typ".to_string()),
+                (a.id, "a.typ".to_string()),
+                (c.id, "c.typ".to_string()),
+            ]
+        );
+    }
+}
@@ -322,6 +322,7 @@ mod tests {
             avatar_uri: None,
             creator_id: user_id,
             member_ids: vec![user_id],
+            fonts: vec![],
             created_at: OffsetDateTime::now_utc(),
             updated_at: OffsetDateTime::now_utc(),
         };
@@ -331,6 +332,7 @@ mod tests {
             avatar_uri: None,
             creator_id: user_id,
             member_ids: vec![user_id],
+            fonts: vec![],
             created_at: OffsetDateTime::now_utc(),
             updated_at: OffsetDateTime::now_utc(),
         };
@@ -0,0 +1,153 @@
+//! Identifying what kind of asset a blob holds from its leading bytes.
+//!
+//! Uploads are sniffed rather than trusting the client's `Content-Type`: the
+//! detected type decides whether an asset is accepted at all, must agree with
+//! the file's extension (which is what Typst goes by), and is what the asset is
+//! later served as. Only formats a Typst document can actually use are known
+//! here; anything else is rejected at upload.
+
+/// Every accepted asset type, with the extensions a file of that type may
+/// carry.
+const KNOWN: &[(&str, &[&str])] = &[
+    ("image/png", &["png"]),
+    ("image/jpeg", &["jpg", "jpeg"]),
+    ("image/gif", &["gif"]),
+    ("image/webp", &["webp"]),
+    ("image/svg+xml", &["svg"]),
+    ("application/pdf", &["pdf"]),
+    ("font/ttf", &["ttf"]),
+    ("font/otf", &["otf"]),
+    ("font/collection", &["ttc", "otc"]),
+    ("font/woff", &["woff"]),
+    ("font/woff2", &["woff2"]),
+];
+
+/// How far into a text file to look for an `<svg` root element.
+const SVG_SNIFF_LEN: usize = 4096;
+
+/// The MIME type of `bytes`, or `None` if they aren't a supported asset.
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    let magic: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"\x00\x01\x00\x00", "font/ttf"),
+        (b"true", "font/ttf"),
+        (b"OTTO", "font/otf"),
+        (b"ttcf", "font/collection"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+    ];
+    if let Some((_, mime)) = magic.iter().find(|(sig, _)| bytes.starts_with(sig)) {
+        return Some(mime);
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    is_svg(bytes).then_some("image/svg+xml")
+}
+
+/// An SVG is XML text whose root element is `<svg`, possibly after a BOM, an
+/// XML declaration, comments or a doctype.
+fn is_svg(bytes: &[u8]) -> bool {
+    let head = &bytes[..bytes.len().min(SVG_SNIFF_LEN)];
+    let head = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+    // A multi-byte character may straddle the cut; the valid prefix suffices.
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) => std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default(),
+    };
+    let text = text.trim_start();
+    let prolog =
+        text.starts_with("<?xml") || text.starts_with("<!--") || text.starts_with("<!DOCTYPE");
+    text.starts_with("<svg") || (prolog && text.contains("<svg"))
+}
+
+/// The asset type a file at `path` is served as, going by its extension
+/// (case-insensitively). Uploads guarantee the two agree.
+pub fn for_extension(path: &str) -> Option<&'static str> {
+    let (_, ext) = path.rsplit_once('.')?;
+    let ext = ext.to_ascii_lowercase();
+    KNOWN
+        .iter()
+        .find(|(_, exts)| exts.contains(&ext.as_str()))
+        .map(|(mime, _)| *mime)
+}
+
+/// Whether `path`'s extension (case-insensitively) is one a `mime` asset may
+/// carry.
+pub fn matches_extension(mime: &str, path: &str) -> bool {
+    let Some((_, ext)) = path.rsplit_once('.') else {
+        return false;
+    };
+    let ext = ext.to_ascii_lowercase();
+    KNOWN
+        .iter()
+        .find(|(known, _)| *known == mime)
+        .is_some_and(|(_, exts)| exts.contains(&ext.as_str()))
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff_recognizes_supported_formats() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", "image/png"),
+            (b"\xff\xd8\xff\xe0\0\x10JFIF", "image/jpeg"),
+            (b"GIF89a\x01\0\x01\0", "image/gif"),
+            (b"RIFF\x24\0\0\0WEBPVP8 ", "image/webp"),
+            (b"%PDF-1.7\n", "application/pdf"),
+            (b"\0\x01\0\0\0\x0f\0\x80", "font/ttf"),
+            (b"OTTO\0\x0b\0\x80", "font/otf"),
+            (b"wOF2\0\x01\0\0", "font/woff2"),
+            (
+                b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>",
+                "image/svg+xml",
+            ),
+            (
+                b"\xef\xbb\xbf<?xml version=\"1.0\"?>\n<!-- logo -->\n<svg></svg>",
+                "image/svg+xml",
+            ),
+        ];
+        for (bytes, mime) in cases {
+            assert_eq!(sniff(bytes), Some(*mime), "{bytes:?}");
+        }
+    }
+
+    #[test]
+    fn test_sniff_rejects_unknown_content() {
+        for bytes in [
+            &b""[..],
+            b"plain text",
+            b"<html><body>not an image</body></html>",
+            b"<?xml version=\"1.0\"?><note/>",
+            b"PK\x03\x04",
+            b"RIFF\x24\0\0\0WAVE",
+        ] {
+            assert_eq!(sniff(bytes), None, "{bytes:?}");
+        }
+    }
+
+    #[test]
+    fn test_matches_extension() {
+        assert!(matches_extension("image/png", "images/logo.png"));
+        assert!(matches_extension("image/jpeg", "photo.JPEG"));
+        assert!(matches_extension("font/collection", "fonts/noto.ttc"));
+        assert!(!matches_extension("image/png", "logo.jpg"));
+        assert!(!matches_extension("image/png", "logo"));
+        assert!(!matches_extension("text/html", "index.html"));
+    }
+
+    #[test]
+    fn test_for_extension() {
+        assert_eq!(for_extension("img/logo.PNG"), Some("image/png"));
+        assert_eq!(for_extension("fonts/noto.otc"), Some("font/collection"));
+        assert_eq!(for_extension("main.typ"), None);
+        assert_eq!(for_extension("README"), None);
+    }
+}
@@ -13,15 +13,34 @@
 //!   same content converge instead of racing.
 //!
 //! [`ObjectStore`] is the seam: [`MinioObjectStore`] talks to MinIO/S3 in
-//! production, [`InMemoryObjectStore`] backs tests. Nothing above this module
-//! knows which backend is in play.
+//! production, [`FilesystemObjectStore`] keeps everything under a local
+//! directory for single-box deployments, and [`InMemoryObjectStore`] backs
+//! tests. Nothing above this module knows which backend is in play.
 
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::collections::hash_map::Entry;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use derive_more::Display;
+use futures_util::{Stream, TryStreamExt as _, stream};
+use http::{HeaderMap, HeaderValue, header};
+use s3::command::Command;
+use s3::request::{Request as _, tokio_backend::ReqwestRequest};
 use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use time::format_description::well_known::{Rfc2822, Rfc3339};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncSeekExt as _};
+
+use crate::config::StorageConfig;
+
+pub mod mime;
 
 /// A stored blob: the lowercase-hex SHA-256 of its bytes plus their length.
 /// This is the durable reference a file node keeps; the bytes themselves live
@@ -43,6 +62,10 @@ pub enum StorageError {
     /// object key.
     #[display("invalid sha256: {_0}")]
     InvalidHash(String),
+    /// A named-object key that can't be mapped safely onto a backend path
+    /// (empty, absolute, or containing an empty, `.` or `..` segment).
+    #[display("invalid object key: {_0}")]
+    InvalidKey(String),
     /// The storage backend failed (network, auth, unexpected status, …).
     #[display("storage backend error: {_0}")]
     Backend(String),
@@ -50,6 +73,27 @@ pub enum StorageError {
 
 impl std::error::Error for StorageError {}
 
+/// One entry from [`ObjectStore::list`]: a stored object's full key (e.g.
+/// `blobs/{sha256}` or `ydoc/{project_id}`) with its size and last write time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: OffsetDateTime,
+}
+
+/// A blob's bytes as they arrive from the backend, in chunks.
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, StorageError>> + Send>>;
+
+/// `bytes`, or the inclusive byte `range` of them, as a one-chunk stream.
+pub fn slice_stream(bytes: Bytes, range: Option<(u64, u64)>) -> ByteStream {
+    let bytes = match range {
+        Some((start, end)) => bytes.slice(start as usize..=end as usize),
+        None => bytes,
+    };
+    Box::pin(stream::once(async { Ok(bytes) }))
+}
+
 /// Compute the lowercase-hex SHA-256 of `bytes`.
 pub fn sha256_hex(bytes: &[u8]) -> String {
     hex::encode(Sha256::digest(bytes))
@@ -64,28 +108,46 @@ pub fn is_valid_sha256(s: &str) -> bool {
             .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
 }
 
+/// Key prefix shared by every content-addressed blob.
+pub const BLOB_PREFIX: &str = "blobs/";
+
 /// Object key for a blob's bytes.
 fn blob_key(sha256: &str) -> String {
-    format!("blobs/{sha256}")
+    format!("{BLOB_PREFIX}{sha256}")
 }
 
 /// A content-addressed byte store. Keys are SHA-256 hashes; values are
 /// immutable blobs.
 #[async_trait]
 pub trait ObjectStore: Send + Sync {
-    /// Store `bytes` and return the resulting [`Blob`]. Idempotent: content that
-    /// already exists is not re-uploaded (a re-PUT of identical bytes would be a
-    /// no-op regardless). This is the *write-before-reference* primitive —
-    /// callers persist the returned hash only after this resolves.
+    /// Store `bytes` and return the resulting [`Blob`]. Idempotent: identical
+    /// content maps to the same object. Storing content that already exists
+    /// still marks it as written now, so a blob just reused starts a fresh GC
+    /// grace period like a new one. This is the *write-before-reference*
+    /// primitive — callers persist the returned hash only after this resolves.
     async fn put(&self, bytes: &[u8]) -> Result<Blob, StorageError>;
 
     /// Fetch a blob's bytes by content hash.
     async fn get(&self, sha256: &str) -> Result<Vec<u8>, StorageError>;
 
+    /// Stream a blob's bytes, or only the inclusive byte `range` of them, as
+    /// they are read — for downloads, which shouldn't hold a large blob in
+    /// memory. The range must lie within the blob.
+    async fn get_range(
+        &self,
+        sha256: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<ByteStream, StorageError>;
+
     /// Whether a blob exists, without downloading it — used by GC and to skip
     /// redundant uploads.
     async fn exists(&self, sha256: &str) -> Result<bool, StorageError>;
 
+    /// When a blob was last written (see [`put`](Self::put)), or `None` if it
+    /// doesn't exist. GC reads it again just before sweeping a blob, since a
+    /// writer may have reused it after the listing.
+    async fn modified(&self, sha256: &str) -> Result<Option<OffsetDateTime>, StorageError>;
+
     /// Remove a blob by content hash. Idempotent: deleting an absent blob
     /// succeeds. Only the GC sweep should ever call this — a file deletion must
     /// not, because other nodes may reference the same content.
@@ -97,8 +159,34 @@ pub trait ObjectStore: Send + Sync {
     /// Y.Doc snapshot at `ydoc/{project_id}`.
     async fn put_object(&self, key: &str, bytes: &[u8]) -> Result<(), StorageError>;
 
+    /// Store `bytes` at `key` only if no object is there yet, as one
+    /// conditional write: of two racing creates, exactly one lands. `false`
+    /// if the key was taken. For named objects that never change once
+    /// written, like uploaded packages.
+    async fn create_object(&self, key: &str, bytes: &[u8]) -> Result<bool, StorageError>;
+
     /// Fetch a named object's bytes, or `None` if it doesn't exist.
     async fn get_object(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Every object (blob or named) whose key starts with `prefix`, in no
+    /// particular order. Used by GC to enumerate `blobs/` and `ydoc/`; it reads
+    /// the whole listing into memory, so it isn't meant for request paths.
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, StorageError>;
+}
+
+/// Build the configured backend. Called once at startup; the resulting handle
+/// is shared by everything that needs storage.
+pub fn from_config(config: &StorageConfig) -> Result<Arc<dyn ObjectStore>, StorageError> {
+    match config {
+        StorageConfig::Minio(minio) => Ok(Arc::new(MinioObjectStore::new(
+            &minio.endpoint,
+            &minio.region,
+            &minio.bucket,
+            &minio.access_key,
+            &minio.secret_key,
+        )?)),
+        StorageConfig::Filesystem(fs) => Ok(Arc::new(FilesystemObjectStore::new(&fs.root)?)),
+    }
 }
 
 /// MinIO / S3-compatible backend (path-style addressing).
@@ -138,17 +226,16 @@ impl MinioObjectStore {
 impl ObjectStore for MinioObjectStore {
     async fn put(&self, bytes: &[u8]) -> Result<Blob, StorageError> {
         let sha256 = sha256_hex(bytes);
-        // Dedup: identical content is already durable — don't pay to re-upload.
-        if !self.exists(&sha256).await? {
-            let resp = self
-                .bucket
-                .put_object(blob_key(&sha256), bytes)
-                .await
-                .map_err(|e| StorageError::Backend(e.to_string()))?;
-            let code = resp.status_code();
-            if !(200..300).contains(&code) {
-                return Err(StorageError::Backend(format!("put returned status {code}")));
-            }
+        // Uploaded even when the blob exists: S3 can't touch an object in
+        // place, and re-PUTting identical bytes only refreshes LastModified.
+        let resp = self
+            .bucket
+            .put_object(blob_key(&sha256), bytes)
+            .await
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        let code = resp.status_code();
+        if !(200..300).contains(&code) {
+            return Err(StorageError::Backend(format!("put returned status {code}")));
         }
         Ok(Blob {
             sha256,
@@ -172,21 +259,66 @@ impl ObjectStore for MinioObjectStore {
         }
     }
 
+    async fn get_range(
+        &self,
+        sha256: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<ByteStream, StorageError> {
+        if !is_valid_sha256(sha256) {
+            return Err(StorageError::InvalidHash(sha256.to_string()));
+        }
+        let key = blob_key(sha256);
+        let resp = match range {
+            Some((start, end)) => {
+                let command = Command::GetObjectRange {
+                    start,
+                    end: Some(end),
+                };
+                match ReqwestRequest::new(&self.bucket, &key, command).await {
+                    Ok(request) => request.response_data_to_stream().await,
+                    Err(e) => Err(e),
+                }
+            }
+            None => self.bucket.get_object_stream(&key).await,
+        }
+        .map_err(|e| StorageError::Backend(e.to_string()))?;
+        match resp.status_code {
+            200 | 206 => Ok(Box::pin(
+                resp.bytes.map_err(|e| StorageError::Backend(e.to_string())),
+            )),
+            404 => Err(StorageError::NotFound(sha256.to_string())),
+            code => Err(StorageError::Backend(format!("get returned status {code}"))),
+        }
+    }
+
     async fn exists(&self, sha256: &str) -> Result<bool, StorageError> {
+        Ok(self.modified(sha256).await?.is_some())
+    }
+
+    async fn modified(&self, sha256: &str) -> Result<Option<OffsetDateTime>, StorageError> {
         if !is_valid_sha256(sha256) {
             return Err(StorageError::InvalidHash(sha256.to_string()));
         }
         match self.bucket.head_object(blob_key(sha256)).await {
-            Ok((_, 200)) => Ok(true),
-            Ok((_, 404)) => Ok(false),
-            Ok((_, code)) => Err(StorageError::Backend(format!("head returned status {code}"))),
+            Ok((head, 200)) => {
+                let last_modified = head
+                    .last_modified
+                    .ok_or_else(|| StorageError::Backend("head without Last-Modified".into()))?;
+                OffsetDateTime::parse(&last_modified, &Rfc2822)
+                    .map(Some)
+                    .map_err(|e| StorageError::Backend(format!("bad Last-Modified: {e}")))
+            }
+            Ok((_, 404)) => Ok(None),
+            Ok((_, code)) => Err(StorageError::Backend(format!(
+                "head returned status {code}"
+            ))),
             // Some backends surface a missing key as an error rather than a 404
             // status; treat an explicit not-found as "absent", everything else
             // as a real failure.
             Err(e) => {
                 let msg = e.to_string();
                 if msg.contains("404") || msg.to_lowercase().contains("not found") {
-                    Ok(false)
+                    Ok(None)
                 } else {
                     Err(StorageError::Backend(msg))
                 }
@@ -227,6 +359,23 @@ impl ObjectStore for MinioObjectStore {
         Ok(())
     }
 
+    async fn create_object(&self, key: &str, bytes: &[u8]) -> Result<bool, StorageError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("*"));
+        let resp = self
+            .bucket
+            .put_object_with_headers(key, bytes, Some(headers))
+            .await
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        match resp.status_code() {
+            200..300 => Ok(true),
+            412 => Ok(false),
+            code => Err(StorageError::Backend(format!(
+                "create_object returned status {code}"
+            ))),
+        }
+    }
+
     async fn get_object(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
         let resp = self
             .bucket
@@ -241,16 +390,321 @@ impl ObjectStore for MinioObjectStore {
             ))),
         }
     }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, StorageError> {
+        let pages = self
+            .bucket
+            .list(prefix.to_string(), None)
+            .await
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| {
+                let last_modified = OffsetDateTime::parse(&object.last_modified, &Rfc3339)
+                    .map_err(|e| StorageError::Backend(format!("bad LastModified: {e}")))?;
+                Ok(ObjectMeta {
+                    key: object.key,
+                    size: object.size,
+                    last_modified,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Local-directory backend for single-box deployments without MinIO. An object
+/// key maps directly onto a path under `root` (`blobs/{sha256}`,
+/// `ydoc/{project_id}`, …), so the layout mirrors the bucket's.
+///
+/// Every write goes to a temp file under `root/.tmp`, is fsynced, and is then
+/// renamed into place. A reader therefore sees either the old object or the
+/// complete new one, never a torn write; a crash mid-write leaves at most a
+/// stray temp file.
+pub struct FilesystemObjectStore {
+    root: PathBuf,
+}
+
+/// Directory, under the root, for writes in progress. `path_for` refuses keys
+/// inside it, so a temp file can never be read back as an object.
+const FS_TMP_DIR: &str = ".tmp";
+
+/// Distinguishes concurrent temp files written by this process.
+static FS_TMP_SEQ: AtomicU64 = AtomicU64::new(0);
+
+impl FilesystemObjectStore {
+    /// Open (creating if needed) a store rooted at `root`.
+    pub fn new(root: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let root = root.as_ref().to_path_buf();
+        std::fs::create_dir_all(root.join(FS_TMP_DIR)).map_err(backend_error)?;
+        Ok(Self { root })
+    }
+
+    /// The path an object key lives at. Rejects anything that could escape
+    /// `root` or land in the temp directory.
+    fn path_for(&self, key: &str) -> Result<PathBuf, StorageError> {
+        let invalid = key.is_empty()
+            || key.starts_with(FS_TMP_DIR)
+            || key
+                .split('/')
+                .any(|seg| seg.is_empty() || seg == "." || seg == ".." || seg.contains('\\'));
+        if invalid {
+            return Err(StorageError::InvalidKey(key.to_string()));
+        }
+        Ok(self.root.join(key))
+    }
+
+    /// Atomically replace the object at `path` with `bytes`.
+    async fn write_atomic(&self, path: &Path, bytes: &[u8]) -> Result<(), StorageError> {
+        let tmp = self.write_tmp(path, bytes).await?;
+        if let Err(e) = tokio::fs::rename(&tmp, path).await {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(backend_error(e));
+        }
+        Ok(())
+    }
+
+    /// Write `bytes` to a new file in the temp directory, synced, ready to be
+    /// moved to `path` (whose directory this creates).
+    async fn write_tmp(&self, path: &Path, bytes: &[u8]) -> Result<PathBuf, StorageError> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(backend_error)?;
+        }
+        let tmp = self.root.join(FS_TMP_DIR).join(format!(
+            "{}-{}",
+            std::process::id(),
+            FS_TMP_SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = async {
+            let mut file = tokio::fs::File::create(&tmp).await?;
+            tokio::io::AsyncWriteExt::write_all(&mut file, bytes).await?;
+            file.sync_all().await
+        }
+        .await;
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(backend_error(e));
+        }
+        Ok(tmp)
+    }
+
+    /// Set the modification time of the object at `path` to now. `false` if it
+    /// doesn't exist.
+    async fn touch(path: &Path) -> Result<bool, StorageError> {
+        let file = match tokio::fs::OpenOptions::new().write(true).open(path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(backend_error(e)),
+        };
+        file.into_std()
+            .await
+            .set_modified(SystemTime::now())
+            .map_err(backend_error)?;
+        Ok(true)
+    }
+
+    /// Read the object at `path`, or `None` if it doesn't exist.
+    async fn read(path: &Path) -> Result<Option<Vec<u8>>, StorageError> {
+        match tokio::fs::read(path).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+}
+
+fn backend_error(e: std::io::Error) -> StorageError {
+    StorageError::Backend(e.to_string())
+}
+
+/// How much of a file [`read_chunks`] reads at a time.
+const FS_CHUNK: usize = 64 * 1024;
+
+/// Everything `reader` yields, as a stream of chunks.
+fn read_chunks(reader: impl AsyncRead + Send + Unpin + 'static) -> ByteStream {
+    Box::pin(stream::try_unfold(reader, |mut reader| async move {
+        let mut chunk = vec![0; FS_CHUNK];
+        let n = reader.read(&mut chunk).await.map_err(backend_error)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        chunk.truncate(n);
+        Ok(Some((Bytes::from(chunk), reader)))
+    }))
+}
+
+#[async_trait]
+impl ObjectStore for FilesystemObjectStore {
+    async fn put(&self, bytes: &[u8]) -> Result<Blob, StorageError> {
+        let sha256 = sha256_hex(bytes);
+        let path = self.root.join(blob_key(&sha256));
+        // Dedup: identical content is already durable — don't rewrite it, just
+        // mark it as written now.
+        if !Self::touch(&path).await? {
+            self.write_atomic(&path, bytes).await?;
+        }
+        Ok(Blob {
+            sha256,
+            size: bytes.len() as u64,
+        })
+    }
+
+    async fn get(&self, sha256: &str) -> Result<Vec<u8>, StorageError> {
+        if !is_valid_sha256(sha256) {
+            return Err(StorageError::InvalidHash(sha256.to_string()));
+        }
+        Self::read(&self.root.join(blob_key(sha256)))
+            .await?
+            .ok_or_else(|| StorageError::NotFound(sha256.to_string()))
+    }
+
+    async fn get_range(
+        &self,
+        sha256: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<ByteStream, StorageError> {
+        if !is_valid_sha256(sha256) {
+            return Err(StorageError::InvalidHash(sha256.to_string()));
+        }
+        let mut file = match tokio::fs::File::open(self.root.join(blob_key(sha256))).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(StorageError::NotFound(sha256.to_string()));
+            }
+            Err(e) => return Err(backend_error(e)),
+        };
+        let Some((start, end)) = range else {
+            return Ok(read_chunks(file));
+        };
+        file.seek(SeekFrom::Start(start))
+            .await
+            .map_err(backend_error)?;
+        Ok(read_chunks(file.take(end - start + 1)))
+    }
+
+    async fn exists(&self, sha256: &str) -> Result<bool, StorageError> {
+        if !is_valid_sha256(sha256) {
+            return Err(StorageError::InvalidHash(sha256.to_string()));
+        }
+        tokio::fs::try_exists(self.root.join(blob_key(sha256)))
+            .await
+            .map_err(backend_error)
+    }
+
+    async fn modified(&self, sha256: &str) -> Result<Option<OffsetDateTime>, StorageError> {
+        if !is_valid_sha256(sha256) {
+            return Err(StorageError::InvalidHash(sha256.to_string()));
+        }
+        match tokio::fs::metadata(self.root.join(blob_key(sha256))).await {
+            Ok(meta) => Ok(Some(meta.modified().map_err(backend_error)?.into())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn delete(&self, sha256: &str) -> Result<(), StorageError> {
+        if !is_valid_sha256(sha256) {
+            return Err(StorageError::InvalidHash(sha256.to_string()));
+        }
+        match tokio::fs::remove_file(self.root.join(blob_key(sha256))).await {
+            // Deleting an already-absent blob is a no-op.
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(backend_error(e)),
+            _ => Ok(()),
+        }
+    }
+
+    async fn put_object(&self, key: &str, bytes: &[u8]) -> Result<(), StorageError> {
+        self.write_atomic(&self.path_for(key)?, bytes).await
+    }
+
+    async fn create_object(&self, key: &str, bytes: &[u8]) -> Result<bool, StorageError> {
+        let path = self.path_for(key)?;
+        let tmp = self.write_tmp(&path, bytes).await?;
+        // A link, unlike a rename, fails rather than replace an existing file.
+        let linked = tokio::fs::hard_link(&tmp, &path).await;
+        let _ = tokio::fs::remove_file(&tmp).await;
+        match linked {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn get_object(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        Self::read(&self.path_for(key)?).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, StorageError> {
+        // Walk only the directory the prefix names (everything up to its last
+        // `/`), then filter on the full prefix.
+        let start = prefix.rfind('/').map_or("", |i| &prefix[..i]);
+        let mut pending = vec![(self.root.join(start), start.to_string())];
+        let mut found = Vec::new();
+        while let Some((dir, key_prefix)) = pending.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(backend_error(e)),
+            };
+            while let Some(entry) = entries.next_entry().await.map_err(backend_error)? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let key = if key_prefix.is_empty() {
+                    name
+                } else {
+                    format!("{key_prefix}/{name}")
+                };
+                if key == FS_TMP_DIR {
+                    continue;
+                }
+                let meta = entry.metadata().await.map_err(backend_error)?;
+                if meta.is_dir() {
+                    pending.push((entry.path(), key));
+                } else if key.starts_with(prefix) {
+                    found.push(ObjectMeta {
+                        key,
+                        size: meta.len(),
+                        last_modified: meta.modified().map_err(backend_error)?.into(),
+                    });
+                }
+            }
+        }
+        Ok(found)
+    }
 }
 
 /// In-memory backend for tests. Holds every blob in a map keyed by content
 /// hash. The lock is never held across an `.await`, so a plain `Mutex` is fine.
 #[derive(Default)]
 pub struct InMemoryObjectStore {
-    blobs: Mutex<HashMap<String, Vec<u8>>>,
+    blobs: Mutex<HashMap<String, Stored>>,
     /// Named (mutable) objects, kept separate from content-addressed blobs so
     /// `len`/`is_empty` still reflect only blobs.
-    objects: Mutex<HashMap<String, Vec<u8>>>,
+    objects: Mutex<HashMap<String, Stored>>,
+}
+
+/// An in-memory object's bytes plus the time it was written, for `list`.
+struct Stored {
+    bytes: Vec<u8>,
+    modified: OffsetDateTime,
+}
+
+impl Stored {
+    fn new(bytes: &[u8]) -> Self {
+        Self {
+            bytes: bytes.to_vec(),
+            modified: OffsetDateTime::now_utc(),
+        }
+    }
+
+    fn meta(&self, key: String) -> ObjectMeta {
+        ObjectMeta {
+            key,
+            size: self.bytes.len() as u64,
+            last_modified: self.modified,
+        }
+    }
 }
 
 impl InMemoryObjectStore {
@@ -276,7 +730,8 @@ impl ObjectStore for InMemoryObjectStore {
             .lock()
             .unwrap()
             .entry(sha256.clone())
-            .or_insert_with(|| bytes.to_vec());
+            .and_modify(|stored| stored.modified = OffsetDateTime::now_utc())
+            .or_insert_with(|| Stored::new(bytes));
         Ok(Blob {
             sha256,
             size: bytes.len() as u64,
@@ -291,10 +746,19 @@ impl ObjectStore for InMemoryObjectStore {
             .lock()
             .unwrap()
             .get(sha256)
-            .cloned()
+            .map(|stored| stored.bytes.clone())
             .ok_or_else(|| StorageError::NotFound(sha256.to_string()))
     }
 
+    async fn get_range(
+        &self,
+        sha256: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<ByteStream, StorageError> {
+        let bytes = self.get(sha256).await?;
+        Ok(slice_stream(Bytes::from(bytes), range))
+    }
+
     async fn exists(&self, sha256: &str) -> Result<bool, StorageError> {
         if !is_valid_sha256(sha256) {
             return Err(StorageError::InvalidHash(sha256.to_string()));
@@ -302,6 +766,18 @@ impl ObjectStore for InMemoryObjectStore {
         Ok(self.blobs.lock().unwrap().contains_key(sha256))
     }
 
+    async fn modified(&self, sha256: &str) -> Result<Option<OffsetDateTime>, StorageError> {
+        if !is_valid_sha256(sha256) {
+            return Err(StorageError::InvalidHash(sha256.to_string()));
+        }
+        Ok(self
+            .blobs
+            .lock()
+            .unwrap()
+            .get(sha256)
+            .map(|stored| stored.modified))
+    }
+
     async fn delete(&self, sha256: &str) -> Result<(), StorageError> {
         if !is_valid_sha256(sha256) {
             return Err(StorageError::InvalidHash(sha256.to_string()));
@@ -314,12 +790,38 @@ impl ObjectStore for InMemoryObjectStore {
         self.objects
             .lock()
             .unwrap()
-            .insert(key.to_string(), bytes.to_vec());
+            .insert(key.to_string(), Stored::new(bytes));
         Ok(())
     }
 
+    async fn create_object(&self, key: &str, bytes: &[u8]) -> Result<bool, StorageError> {
+        match self.objects.lock().unwrap().entry(key.to_string()) {
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(Stored::new(bytes));
+                Ok(true)
+            }
+        }
+    }
+
     async fn get_object(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
-        Ok(self.objects.lock().unwrap().get(key).cloned())
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|stored| stored.bytes.clone()))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, StorageError> {
+        let blobs = self.blobs.lock().unwrap();
+        let objects = self.objects.lock().unwrap();
+        Ok(blobs
+            .iter()
+            .map(|(sha256, stored)| stored.meta(blob_key(sha256)))
+            .chain(objects.iter().map(|(key, stored)| stored.meta(key.clone())))
+            .filter(|meta| meta.key.starts_with(prefix))
+            .collect())
     }
 }
 
@@ -441,6 +943,185 @@ mod tests {
         assert!(store.is_empty());
     }
 
+    /// A fresh, empty directory under the system temp dir.
+    fn temp_root() -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "caduceus-storage-{}-{}",
+            std::process::id(),
+            FS_TMP_SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        root
+    }
+
+    #[tokio::test]
+    async fn test_filesystem_blob_roundtrip_dedup_and_delete() {
+        let root = temp_root();
+        let store = FilesystemObjectStore::new(&root).unwrap();
+
+        let blob = store.put(b"hello").await.unwrap();
+        assert_eq!(blob.sha256, HELLO_SHA);
+        assert_eq!(store.put(b"hello").await.unwrap(), blob);
+        assert!(root.join("blobs").join(HELLO_SHA).is_file());
+        assert_eq!(store.get(HELLO_SHA).await.unwrap(), b"hello");
+        assert!(store.exists(HELLO_SHA).await.unwrap());
+
+        store.delete(HELLO_SHA).await.unwrap();
+        store.delete(HELLO_SHA).await.unwrap();
+        assert!(!store.exists(HELLO_SHA).await.unwrap());
+        assert!(matches!(
+            store.get(HELLO_SHA).await,
+            Err(StorageError::NotFound(_))
+        ));
+        assert!(matches!(
+            store.get("../secret").await,
+            Err(StorageError::InvalidHash(_))
+        ));
+
+        // Nothing is left behind in the temp directory.
+        assert_eq!(std::fs::read_dir(root.join(FS_TMP_DIR)).unwrap().count(), 0);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_create_object_only_writes_a_free_key_on_both_local_backends() {
+        let root = temp_root();
+        let fs = FilesystemObjectStore::new(&root).unwrap();
+        let memory = InMemoryObjectStore::new();
+        for store in [&fs as &dyn ObjectStore, &memory] {
+            assert!(store.create_object("packages/a", b"first").await.unwrap());
+            assert!(!store.create_object("packages/a", b"second").await.unwrap());
+            assert_eq!(
+                store.get_object("packages/a").await.unwrap().as_deref(),
+                Some(&b"first"[..])
+            );
+        }
+        assert!(matches!(
+            fs.create_object("../escape", b"x").await,
+            Err(StorageError::InvalidKey(_))
+        ));
+        assert_eq!(std::fs::read_dir(root.join(FS_TMP_DIR)).unwrap().count(), 0);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_filesystem_named_objects_overwrite_and_reject_unsafe_keys() {
+        let root = temp_root();
+        let store = FilesystemObjectStore::new(&root).unwrap();
+
+        assert_eq!(store.get_object("ydoc/p1").await.unwrap(), None);
+        store.put_object("ydoc/p1", b"first").await.unwrap();
+        store.put_object("ydoc/p1", b"second").await.unwrap();
+        assert_eq!(
+            store.get_object("ydoc/p1").await.unwrap().as_deref(),
+            Some(&b"second"[..])
+        );
+
+        for bad in [
+            "",
+            "/etc/passwd",
+            "../escape",
+            "ydoc/../../x",
+            "ydoc//p1",
+            ".tmp/1",
+        ] {
+            assert!(matches!(
+                store.put_object(bad, b"x").await,
+                Err(StorageError::InvalidKey(_))
+            ));
+            assert!(matches!(
+                store.get_object(bad).await,
+                Err(StorageError::InvalidKey(_))
+            ));
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Keys from `list(prefix)`, sorted for comparison.
+    async fn listed(store: &dyn ObjectStore, prefix: &str) -> Vec<String> {
+        let mut keys: Vec<String> = store
+            .list(prefix)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|meta| meta.key)
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[tokio::test]
+    async fn test_list_filters_by_prefix_on_both_local_backends() {
+        let root = temp_root();
+        let fs = FilesystemObjectStore::new(&root).unwrap();
+        let memory = InMemoryObjectStore::new();
+        for store in [&fs as &dyn ObjectStore, &memory] {
+            store.put(b"hello").await.unwrap();
+            store.put_object("ydoc/p1", b"doc").await.unwrap();
+            store.put_object("ydoc/p2", b"doc").await.unwrap();
+
+            assert_eq!(listed(store, BLOB_PREFIX).await, vec![blob_key(HELLO_SHA)]);
+            assert_eq!(listed(store, "ydoc/").await, vec!["ydoc/p1", "ydoc/p2"]);
+            assert_eq!(listed(store, "ydoc/p2").await, vec!["ydoc/p2"]);
+            assert_eq!(listed(store, "").await.len(), 3);
+            assert!(listed(store, "missing/").await.is_empty());
+
+            let blob = &store.list(BLOB_PREFIX).await.unwrap()[0];
+            assert_eq!(blob.size, 5);
+            assert!(blob.last_modified <= OffsetDateTime::now_utc());
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_range_streams_a_blob_or_part_of_it_on_both_local_backends() {
+        let root = temp_root();
+        let fs = FilesystemObjectStore::new(&root).unwrap();
+        let memory = InMemoryObjectStore::new();
+        let streamed = |stream: ByteStream| async move {
+            let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+            chunks.concat()
+        };
+        for store in [&fs as &dyn ObjectStore, &memory] {
+            store.put(b"hello").await.unwrap();
+
+            let whole = store.get_range(HELLO_SHA, None).await.unwrap();
+            assert_eq!(streamed(whole).await, b"hello");
+            let part = store.get_range(HELLO_SHA, Some((1, 3))).await.unwrap();
+            assert_eq!(streamed(part).await, b"ell");
+            let last = store.get_range(HELLO_SHA, Some((4, 4))).await.unwrap();
+            assert_eq!(streamed(last).await, b"o");
+
+            let missing = store.get_range(&sha256_hex(b"missing"), None).await;
+            assert!(matches!(missing, Err(StorageError::NotFound(_))));
+            let invalid = store.get_range("../secret", None).await;
+            assert!(matches!(invalid, Err(StorageError::InvalidHash(_))));
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_put_of_an_existing_blob_refreshes_it_on_both_local_backends() {
+        let root = temp_root();
+        let fs = FilesystemObjectStore::new(&root).unwrap();
+        let memory = InMemoryObjectStore::new();
+        for store in [&fs as &dyn ObjectStore, &memory] {
+            assert_eq!(store.modified(HELLO_SHA).await.unwrap(), None);
+            store.put(b"hello").await.unwrap();
+            let first = store.modified(HELLO_SHA).await.unwrap().unwrap();
+
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            store.put(b"hello").await.unwrap();
+            let second = store.modified(HELLO_SHA).await.unwrap().unwrap();
+            assert!(second > first);
+            assert_eq!(
+                store.list(BLOB_PREFIX).await.unwrap()[0].last_modified,
+                second
+            );
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
     /// Round-trip against a real MinIO. Ignored by default (needs a running
     /// server + bucket); run with a local stack via:
     ///   `docker compose up -d minio createbuckets`
@@ -460,6 +1141,9 @@ mod tests {
         let blob = store.put(b"integration bytes").await.unwrap();
         assert!(store.exists(&blob.sha256).await.unwrap());
         assert_eq!(store.get(&blob.sha256).await.unwrap(), b"integration bytes");
+        let listed = store.list(&blob_key(&blob.sha256)).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].size, blob.size);
         store.delete(&blob.sha256).await.unwrap();
         assert!(!store.exists(&blob.sha256).await.unwrap());
     }
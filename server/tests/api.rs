@@ -14,10 +14,12 @@ use bson::oid::ObjectId;
 use server::{
     AppState,
     config::Config,
+    handler::ws::ProjectServer,
     repo::{project::MongoProjectRepo, team::MongoTeamRepo, user::MongoUserRepo},
     routes,
     services::{project::ProjectService, team::TeamService, user::UserService},
 };
+use std::sync::Arc;
 
 async fn test_app() -> (
     impl Service<actix_http::Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error>,
@@ -50,18 +52,33 @@ async fn test_app() -> (
             team_repo: team_repo.clone(),
             user_repo: user_repo.clone(),
             project_repo: project_repo.clone(),
+            store: None,
         },
         project_service: ProjectService {
-            project_repo,
+            project_repo: project_repo.clone(),
             user_repo,
-            team_repo,
+            team_repo: team_repo.clone(),
+            store: None,
+            packages: Arc::default(),
+            font_cache: Arc::default(),
+            compiler: Arc::default(),
         },
     });
+    let project_server = ProjectServer::new(
+        project_repo,
+        team_repo,
+        None,
+        Arc::default(),
+        Arc::default(),
+        Arc::default(),
+        config.ws.clone(),
+    );
 
     let jwt_secret = config.jwt_secret.clone();
     let app = test::init_service(
         App::new()
             .app_data(data)
+            .app_data(web::Data::new(project_server))
             .configure(move |cfg| routes::configure(cfg, jwt_secret.clone())),
     )
     .await;